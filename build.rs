@@ -0,0 +1,28 @@
+use std::{env, process::Command, time::{SystemTime, UNIX_EPOCH}};
+
+// embeds GIT_SHA and BUILD_TIMESTAMP for /api/public/version
+fn main() {
+    // images built without git can pass GIT_SHA through the environment instead
+    let git_sha = match env::var("GIT_SHA") {
+        Ok(git_sha) if !git_sha.is_empty() => git_sha,
+        _ => Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|git_sha| git_sha.trim().to_string())
+            .unwrap_or_else(|| String::from("unknown")),
+    };
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,237 @@
+use std::{str::FromStr, sync::Arc};
+
+use axum::Json;
+use chrono::{DateTime, Datelike};
+use mongodb::bson::{doc, to_bson, Document};
+use serde_json::json;
+
+use crate::{
+    lemonsqueezy::subscription::sync_brevo_plan_attributes,
+    utilities::{
+        helpers::{add_subscription_history_log_and_to_bson, valid_customer_id},
+        metrics::record_subscription_transition,
+    },
+    server::AppState,
+    types::{
+        customer::{Customer, GenericResponse},
+        stripe::{StripeEvent, StripeSubscription},
+        subscription::{Slug, SubscriptionFrequencyClass, SubscriptionHistoryLog, SubscriptionStatus},
+    }, storage::mongo::{find_customer_in, update_customer},
+};
+
+fn unix_to_rfc3339(timestamp: Option<i64>) -> String {
+    timestamp
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_default()
+}
+
+// stripe statuses translated to the LemonSqueezy vocabulary the rest of the api understands
+pub fn map_stripe_status(status: &str) -> SubscriptionStatus {
+    match status {
+        "trialing" => SubscriptionStatus::OnTrial,
+        "canceled" => SubscriptionStatus::Cancelled,
+        "incomplete" => SubscriptionStatus::Unpaid,
+        "incomplete_expired" => SubscriptionStatus::Expired,
+        status => SubscriptionStatus::from_str(status).unwrap_or(SubscriptionStatus::Unset),
+    }
+}
+
+async fn find_stripe_customer(subscription: &StripeSubscription, state: &Arc<AppState>) -> Result<(Document, Customer), Json<GenericResponse>> {
+    let customer_id = match subscription.metadata.get("customer_id") {
+        Some(customer_id) if valid_customer_id(customer_id) => customer_id,
+        _ => {
+            return Err(Json(GenericResponse {
+                message: String::from("invalid customer_id: expected 30 alphanumeric characters in the subscription metadata"),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    let filter = doc! {"id": customer_id};
+    match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
+        Ok((true, Some(customer))) => Ok((filter, customer)),
+        Ok(_) => Err(Json(GenericResponse {
+            message: String::from("invalid customer_id: not records"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error checking customer existence"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+// customer.subscription.created and customer.subscription.updated carry the full subscription, both are applied the same way
+pub async fn stripe_subscription_upserted(
+    event: &StripeEvent,
+    subscription: StripeSubscription,
+    state: Arc<AppState>,
+) -> Result<(), Json<GenericResponse>> {
+    let stripe = match &state.stripe {
+        Some(stripe) => stripe,
+        None => {
+            return Err(Json(GenericResponse {
+                message: String::from("stripe is not enabled"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
+    let price_id = subscription.price_id().unwrap_or_default().to_string();
+    let plan = match stripe.resolve(&price_id) {
+        Some(plan) => plan.clone(),
+        None => {
+            return Err(Json(GenericResponse {
+                message: format!("unknown price_id: {}", price_id),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    let (filter, customer) = find_stripe_customer(&subscription, &state).await?;
+
+    let updated_at = unix_to_rfc3339(Some(event.created));
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
+        event: event.event_type.clone(),
+        date: updated_at.clone(),
+    }).await;
+
+    let frequency = match to_bson(&plan.frequency) {
+        Ok(frequency) => frequency,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error converting subscription frequency to bson"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
+    // a subscription set to cancel keeps its tier until the period ends, it just won't renew
+    let (renews_at, ends_at) = match subscription.cancel_at_period_end {
+        true => (String::new(), unix_to_rfc3339(subscription.cancel_at.or(subscription.current_period_end))),
+        false => (unix_to_rfc3339(subscription.current_period_end), unix_to_rfc3339(subscription.ended_at)),
+    };
+
+    let billing_anchor = subscription
+        .billing_cycle_anchor
+        .and_then(|anchor| DateTime::from_timestamp(anchor, 0))
+        .map(|anchor| anchor.day() as i64)
+        .unwrap_or(0);
+
+    let status = map_stripe_status(&subscription.status);
+    let update = doc! {
+        "$set": doc!{
+            "subscription.id": subscription.id.clone(),
+            "subscription.product_id": 0_i64,
+            "subscription.variant_id": 0_i64,
+            "subscription.price_id": price_id,
+            "subscription.slug": plan.slug.to_string(),
+            "subscription.frequency": frequency,
+            "subscription.status": status.as_str(),
+            "subscription.starts_at": unix_to_rfc3339(Some(subscription.created)),
+            "subscription.renews_at": renews_at,
+            "subscription.ends_at": ends_at,
+            "subscription.billing_anchor": billing_anchor,
+            "subscription.updated_at": updated_at,
+            "subscription.history_logs": bson_history_logs,
+        },
+    };
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &plan.slug.to_string());
+            sync_brevo_plan_attributes(&state, email, plan.slug.to_string(), status);
+            Ok(())
+        },
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+// deleted subscriptions are over, the customer falls back to free like a LemonSqueezy expiration
+pub async fn stripe_subscription_deleted(
+    event: &StripeEvent,
+    subscription: StripeSubscription,
+    state: Arc<AppState>,
+) -> Result<(), Json<GenericResponse>> {
+    let (filter, customer) = find_stripe_customer(&subscription, &state).await?;
+
+    let updated_at = unix_to_rfc3339(Some(event.created));
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
+        event: event.event_type.clone(),
+        date: updated_at.clone(),
+    }).await;
+
+    let frequency = match to_bson(&SubscriptionFrequencyClass::UNDEFINED) {
+        Ok(frequency) => frequency,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error converting subscription frequency to bson"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
+    let status = map_stripe_status(&subscription.status);
+    let update = doc! {
+        "$set": doc!{
+            "subscription.slug": Slug::FREE.to_string(),
+            "subscription.frequency": frequency,
+            "subscription.product_id": 0_i64,
+            "subscription.variant_id": 0_i64,
+            "subscription.price_id": "",
+            "subscription.status": status.as_str(),
+            "subscription.updated_at": updated_at.clone(),
+            "subscription.ends_at": unix_to_rfc3339(subscription.ended_at.or(Some(event.created))),
+            "subscription.renews_at": "",
+            "subscription.history_logs": bson_history_logs,
+        },
+    };
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &Slug::FREE.to_string());
+            sync_brevo_plan_attributes(&state, email, Slug::FREE.to_string(), status);
+            Ok(())
+        },
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stripe_statuses_map_to_the_lemonsqueezy_vocabulary() {
+        assert_eq!(map_stripe_status("trialing"), SubscriptionStatus::OnTrial);
+        assert_eq!(map_stripe_status("canceled"), SubscriptionStatus::Cancelled);
+        assert_eq!(map_stripe_status("incomplete"), SubscriptionStatus::Unpaid);
+        assert_eq!(map_stripe_status("incomplete_expired"), SubscriptionStatus::Expired);
+    }
+
+    #[test]
+    fn shared_statuses_pass_through() {
+        assert_eq!(map_stripe_status("active"), SubscriptionStatus::Active);
+        assert_eq!(map_stripe_status("past_due"), SubscriptionStatus::PastDue);
+        assert_eq!(map_stripe_status("paused"), SubscriptionStatus::Paused);
+        assert_eq!(map_stripe_status("something_new"), SubscriptionStatus::Unknown(String::from("something_new")));
+    }
+}
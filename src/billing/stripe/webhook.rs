@@ -0,0 +1,293 @@
+use crate::{
+    billing::stripe::subscription::{stripe_subscription_deleted, stripe_subscription_upserted},
+    server::AppState,
+    types::customer::GenericResponse,
+    types::stripe::{StripeEvent, StripeSubscription},
+    utilities::metrics::record_webhook_duplicate,
+    utilities::webhooks::{
+        acquire_subscription_lock, claim_webhook_delivery, decode_webhook_body, record_dead_letter, record_webhook_delivery, release_subscription_lock,
+        release_webhook_delivery,
+    },
+};
+
+use axum::{http::HeaderMap, http::StatusCode, Json};
+
+use chrono::Utc;
+use hex;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use serde_json::json;
+use std::sync::Arc;
+use log::trace;
+
+// Stripe-Signature is "t=<unix>,v1=<hex>[,v1=<hex>...]", the signed payload is "<t>.<raw body>"
+pub fn verify_stripe_signature(
+    signature_header: &str,
+    payload: &str,
+    secret: &str,
+    tolerance: i64,
+    now: i64,
+) -> Result<(), String> {
+    let mut timestamp: Option<i64> = None;
+    let mut signatures: Vec<&str> = vec![];
+    for part in signature_header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => continue,
+        };
+    }
+
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return Err(String::from("missing signature timestamp")),
+    };
+
+    if signatures.is_empty() {
+        return Err(String::from("missing v1 signature"));
+    }
+
+    if (now - timestamp).abs() > tolerance {
+        return Err(String::from("signature timestamp outside the tolerance"));
+    }
+
+    for signature in signatures {
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => continue,
+        };
+
+        let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return Err(String::from("invalid signing secret")),
+        };
+
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+
+        // constant time comparison
+        if mac.verify_slice(&signature).is_ok() {
+            return Ok(());
+        }
+    }
+
+    Err(String::from("invalid signature"))
+}
+
+pub async fn stripe_webhook_events_listener(
+    headers: HeaderMap,
+    body: String,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let stripe = match &state.stripe {
+        Some(stripe) => stripe,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: String::from("stripe is not enabled"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    // the signature covers the raw body, so it's checked before anything is parsed
+    let signature_header = match headers.get("Stripe-Signature").map(|header| header.to_str()) {
+        Some(Ok(signature_header)) => signature_header,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: String::from("missing signature"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    match verify_stripe_signature(signature_header, &body, &stripe.webhook_secret, stripe.signature_tolerance, Utc::now().timestamp()) {
+        Ok(_) => (),
+        Err(err) => {
+            trace!("Stripe Signature Isn't Valid: {}", err);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: err,
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            );
+        }
+    };
+
+    let event: StripeEvent = match decode_webhook_body(&headers, body.as_bytes()) {
+        Ok(event) => event,
+        Err(err) => {
+            record_dead_letter(&state.redis_connection, "stripe", &err, body.as_bytes());
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: String::from("invalid stripe event"),
+                    data: json!({"error": err}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    trace!("STRIPE EVENT: {} {}", event.event_type, event.id);
+
+    let event_type = event.event_type.clone();
+    if !event_type.starts_with("customer.subscription.") {
+        return (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: String::from("ignored"),
+                data: json!({}),
+                exit_code: 0,
+            }),
+        );
+    }
+
+    match claim_webhook_delivery(&state.redis_connection, "stripe", &event.id, state.webhook_dedup_ttl) {
+        Ok(true) => (),
+        Ok(false) => {
+            record_webhook_duplicate(&event_type);
+            record_webhook_delivery(&state.redis_connection, "stripe", &event.id, &event_type, "duplicate");
+            return (
+                StatusCode::OK,
+                Json(GenericResponse {
+                    message: String::from("duplicate"),
+                    data: json!({}),
+                    exit_code: 0,
+                }),
+            );
+        }
+        // a redis outage shouldn't drop billing events
+        Err(err) => log::error!("error claiming webhook delivery {}: {}", event.id, err),
+    };
+
+    let mut subscription: StripeSubscription = match serde_json::from_value(event.data.object.clone()) {
+        Ok(subscription) => subscription,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: String::from("invalid stripe subscription"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    // event_type is matched against known names, status and customer are stored as received
+    let limits = &state.webhook_field_limits;
+    if let Err(field) = limits.enforce("status", &mut subscription.status).and_then(|_| limits.enforce("customer", &mut subscription.customer)) {
+        release_webhook_delivery(&state.redis_connection, "stripe", &event.id);
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: format!("{} is too long", field),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    // missing ids are rejected by the handlers, there's nothing to serialize on
+    let customer_id = subscription.metadata.get("customer_id").cloned().unwrap_or_default();
+    let lock_token = match customer_id.is_empty() {
+        true => None,
+        false => match acquire_subscription_lock(&state.redis_connection, &customer_id).await {
+            Ok(Some(lock_token)) => Some(lock_token),
+            Ok(None) => {
+                release_webhook_delivery(&state.redis_connection, "stripe", &event.id);
+                return (
+                    StatusCode::CONFLICT,
+                    Json(GenericResponse {
+                        message: String::from("subscription update in progress, retry later"),
+                        data: json!({}),
+                        exit_code: 1,
+                    }),
+                );
+            }
+            // same as the dedup claim, a redis outage shouldn't drop billing events
+            Err(err) => {
+                log::error!("error acquiring subscription lock for {}: {}", customer_id, err);
+                None
+            }
+        },
+    };
+
+    let result = match event_type.as_str() {
+        "customer.subscription.created" | "customer.subscription.updated" => {
+            stripe_subscription_upserted(&event, subscription, state.clone()).await
+        }
+        "customer.subscription.deleted" => stripe_subscription_deleted(&event, subscription, state.clone()).await,
+        _ => Ok(()),
+    };
+
+    if let Some(lock_token) = lock_token {
+        release_subscription_lock(&state.redis_connection, &customer_id, &lock_token);
+    }
+
+    match result {
+        Ok(_) => record_webhook_delivery(&state.redis_connection, "stripe", &event.id, &event_type, "processed"),
+        Err(json) => {
+            release_webhook_delivery(&state.redis_connection, "stripe", &event.id);
+            record_webhook_delivery(&state.redis_connection, "stripe", &event.id, &event_type, "failed");
+            return (StatusCode::BAD_REQUEST, json);
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: String::from("captured"),
+            data: json!({}),
+            exit_code: 0,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "whsec_test";
+    const PAYLOAD: &str = r#"{"id":"evt_1"}"#;
+
+    fn sign(timestamp: i64, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_any_matching_v1_signature() {
+        let header = format!("t=1000,v1={},v1={}", hex::encode([0u8; 32]), sign(1000, PAYLOAD));
+        assert_eq!(verify_stripe_signature(&header, PAYLOAD, SECRET, 300, 1100), Ok(()));
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let header = format!("t=1000,v1={}", sign(1000, PAYLOAD));
+        assert!(verify_stripe_signature(&header, r#"{"id":"evt_2"}"#, SECRET, 300, 1000).is_err());
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_tolerance() {
+        let header = format!("t=1000,v1={}", sign(1000, PAYLOAD));
+        assert!(verify_stripe_signature(&header, PAYLOAD, SECRET, 300, 1301).is_err());
+    }
+
+    #[test]
+    fn rejects_incomplete_headers() {
+        assert!(verify_stripe_signature(&format!("v1={}", sign(1000, PAYLOAD)), PAYLOAD, SECRET, 300, 1000).is_err());
+        assert!(verify_stripe_signature("t=1000", PAYLOAD, SECRET, 300, 1000).is_err());
+    }
+}
@@ -1,3 +1,8 @@
 pub mod identity;
 pub mod customer;
-pub mod email;
\ No newline at end of file
+pub mod email;
+pub mod subscription;
+pub mod admin;
+pub mod health;
+pub mod linked_providers;
+pub mod team;
\ No newline at end of file
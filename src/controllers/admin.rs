@@ -0,0 +1,1360 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::JsonRejection, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::{stream, StreamExt};
+use chrono::{DateTime, FixedOffset, Utc};
+use mongodb::{bson::{doc, to_bson, Document}, options::FindOptions};
+use serde_json::json;
+
+use crate::{
+    server::AppState,
+    storage::{
+        diesel_postgres::{archive_history_logs, ensure_history_archive_table, ArchivedHistoryLog},
+        mongo::{aggregate_customers, customers_cursor, find_customer_in, find_customers, find_invite_code, insert_invite_code, list_invite_codes, save_feature_map, update_customer},
+    },
+    types::{
+        customer::{AdminCustomerSummary, Customer, CustomerStatus, Email, GenericResponse},
+        feature_map::FeatureMap,
+        incoming_requests::{CreateInviteCode, CustomerListQueryParams, CustomerMergeRequest, FeatureMapUpdate, ImpersonationRequest, PruneHistoryRequest},
+        invite_code::InviteCode,
+        subscription::{Slug, Subscription, SubscriptionHistoryLog, SubscriptionStatus, SubscriptionView},
+    },
+    utilities::{
+        api_messages::{APIMessages, CustomerMessages, InputMessages, RedisMessages, SubscriptionMessages, TokenMessages},
+        audit::{recent_impersonations, record_impersonation, ImpersonationRecord},
+        helpers::{payload_analyzer, random_string, trim_history_logs},
+        token::revoke_customer_sessions,
+        webhooks::{dead_letter_webhooks, recent_webhook_deliveries},
+    },
+};
+
+use super::identity::{
+    forbid_impersonation, get_user_session_from_req, impersonation_scopes, insufficient_scopes_response, issue_impersonation_session,
+    SessionData, SessionScopes, IMPERSONATION_SESSION_TTL,
+};
+use super::subscription::resync_customer_subscription;
+
+pub async fn get_admin_session_from_req(
+    headers: HeaderMap,
+    state: &Arc<AppState>,
+) -> Result<SessionData, (StatusCode, Json<GenericResponse>)> {
+    let session_data = get_user_session_from_req(headers, &state.redis_connection).await?;
+    check_admin_session(session_data, state)
+}
+
+pub fn check_admin_session(
+    session_data: SessionData,
+    state: &Arc<AppState>,
+) -> Result<SessionData, (StatusCode, Json<GenericResponse>)> {
+    if !session_data.scopes.contains(&SessionScopes::AdminAccess) {
+        return Err(insufficient_scopes_response(
+            StatusCode::FORBIDDEN,
+            &[SessionScopes::AdminAccess],
+            &session_data.scopes,
+        ));
+    }
+
+    // ADMIN_REQUIRE_LISTED_ID, tokens issued before an admin was removed from ADMIN_CUSTOMER_IDS stop working right away
+    if state.admin_require_listed_id && !state.admin_customer_ids.contains(&session_data.customer_id) {
+        return Err(insufficient_scopes_response(
+            StatusCode::FORBIDDEN,
+            &[SessionScopes::AdminAccess],
+            &session_data.scopes,
+        ));
+    }
+
+    Ok(session_data)
+}
+
+// wraps every /api/admin route, handlers take the admin session from the request extensions
+pub async fn admin_access_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match get_admin_session_from_req(request.headers().clone(), &state).await {
+        Ok(session_data) => {
+            request.extensions_mut().insert(session_data);
+            next.run(request).await
+        }
+        Err(response) => response.into_response(),
+    }
+}
+
+async fn set_customer_status(
+    state: &Arc<AppState>,
+    customer_id: &str,
+    status: CustomerStatus,
+) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let filter = doc! {"id": customer_id};
+    let (found, customer) = find_customer_in(state.all_customers_dbs(), filter.clone()).await?;
+
+    if !found {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    let status = match to_bson(&status) {
+        Ok(status) => status,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::InternalServerError.to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let update = doc! {"$set": {
+            "status": status,
+            "updated_at": iso8601_string,
+        }
+    };
+
+    let db = state.customers_db(&customer.unwrap().region);
+    update_customer(db, filter, update).await
+}
+
+pub async fn suspend_customer(
+    Path(customer_id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    match set_customer_status(&state, &customer_id, CustomerStatus::Suspended).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let revoked_sessions = match revoke_customer_sessions(&state.redis_connection, &customer_id) {
+        Ok(revoked_sessions) => revoked_sessions,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorDeleting).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Suspended).to_string(),
+            data: json!({
+                "customer_id": customer_id,
+                "revoked_sessions": revoked_sessions,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn reactivate_customer(
+    Path(customer_id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    match set_customer_status(&state, &customer_id, CustomerStatus::Active).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Reactivated).to_string(),
+            data: json!({
+                "customer_id": customer_id,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub const DEFAULT_CUSTOMERS_PAGE_SIZE: i64 = 50;
+pub const MAX_CUSTOMERS_PAGE_SIZE: i64 = 200;
+
+// the cursor is opaque to clients, it points at the last (created_at, id) pair of a page
+pub fn encode_customers_cursor(customer: &Customer) -> String {
+    hex::encode(format!("{}\n{}", customer.created_at, customer.id))
+}
+
+pub fn decode_customers_cursor(cursor: &str) -> Option<(String, String)> {
+    let raw = hex::decode(cursor).ok()?;
+    let raw = String::from_utf8(raw).ok()?;
+    let (created_at, id) = raw.split_once('\n')?;
+
+    Some((created_at.to_string(), id.to_string()))
+}
+
+// keyset filter, ties on created_at are broken by id so no customer is skipped or repeated
+pub fn customers_after_filter(created_at: &str, id: &str) -> Document {
+    doc! {"$or": [
+        {"created_at": {"$gt": created_at}},
+        {"created_at": created_at, "id": {"$gt": id}},
+    ]}
+}
+
+pub const MAX_SEARCH_QUERY_LENGTH: usize = 100;
+
+// case insensitive prefixes, escaped so the query is never read as a pattern
+pub fn customers_search_filter(query: &str) -> Document {
+    let prefix = format!("^{}", regex::escape(query));
+    doc! {"$or": [
+        {"id": query},
+        {"emails.address": {"$regex": &prefix, "$options": "i"}},
+        {"name": {"$regex": &prefix, "$options": "i"}},
+    ]}
+}
+
+pub async fn list_customers(
+    Query(params): Query<CustomerListQueryParams>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let limit = params.limit.unwrap_or(DEFAULT_CUSTOMERS_PAGE_SIZE);
+    if !(1..=MAX_CUSTOMERS_PAGE_SIZE).contains(&limit) || params.page == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidPagination).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    // cursor mode takes precedence, offset mode is kept for small deployments and old clients
+    let (filter, skip) = match (&params.after, params.page) {
+        (Some(after), _) => match decode_customers_cursor(after) {
+            Some((created_at, id)) => (customers_after_filter(&created_at, &id), 0),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(GenericResponse {
+                        message: APIMessages::Input(InputMessages::InvalidCursor).to_string(),
+                        data: json!({}),
+                        exit_code: 1,
+                    }),
+                )
+            }
+        },
+        (None, Some(page)) => (doc! {}, (page - 1) * limit as u64),
+        (None, None) => (doc! {}, 0),
+    };
+
+    let filter = match params.q.as_deref().map(str::trim) {
+        None | Some("") => filter,
+        Some(query) if query.chars().count() > MAX_SEARCH_QUERY_LENGTH => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::InvalidSearchQuery).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+        Some(query) => doc! {"$and": [filter, customers_search_filter(query)]},
+    };
+
+    // one extra record tells us whether there is a next page
+    let options = FindOptions::builder()
+        .sort(doc! {"created_at": 1, "id": 1})
+        .skip(skip)
+        .limit(limit + 1)
+        .build();
+
+    // pagination is per region, cursors from one region aren't valid in another
+    let region = match &params.region {
+        Some(region) if state.is_known_region(region) => region.clone(),
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::InvalidRegion).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+        None => state.default_region.clone(),
+    };
+
+    let mut customers = match find_customers(state.customers_db(&region), filter, options).await {
+        Ok(customers) => customers,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let has_more = customers.len() as i64 > limit;
+    customers.truncate(limit as usize);
+
+    let next_cursor = match has_more {
+        true => customers.last().map(encode_customers_cursor),
+        false => None,
+    };
+
+    let customers = customers
+        .into_iter()
+        .map(|customer| AdminCustomerSummary {
+            id: customer.id,
+            name: customer.name,
+            class: customer.class,
+            emails: customer.emails,
+            status: customer.status,
+            subscription_slug: customer.subscription.slug,
+            created_at: customer.created_at,
+            deleted: customer.deleted,
+            last_login_at: customer.last_login_at,
+            last_login_provider: customer.last_login_provider,
+        })
+        .collect::<Vec<AdminCustomerSummary>>();
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Listed).to_string(),
+            data: json!({
+                "customers": customers,
+                "region": region,
+                "limit": limit,
+                "page": params.page,
+                "q": params.q,
+                "next_cursor": next_cursor,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// one bucket per (slug, status, frequency), counted by mongo
+pub fn subscription_stats_pipeline() -> Vec<Document> {
+    vec![
+        doc! {"$match": {"deleted": false}},
+        doc! {"$group": {
+            "_id": {
+                "slug": "$subscription.slug",
+                "status": "$subscription.status",
+                "frequency": "$subscription.frequency",
+            },
+            "count": {"$sum": 1},
+        }},
+    ]
+}
+
+pub async fn fetch_subscription_stats(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    // every region is aggregated on its own, buckets are merged here
+    let mut buckets: HashMap<(String, String, String), i64> = HashMap::new();
+    for db in state.all_customers_dbs() {
+        let documents = match aggregate_customers(db, subscription_stats_pipeline()).await {
+            Ok(documents) => documents,
+            Err((status_code, json)) => return (status_code, json),
+        };
+
+        for document in documents.iter() {
+            let group = match document.get_document("_id") {
+                Ok(group) => group,
+                Err(_) => continue,
+            };
+
+            let key = (
+                group.get_str("slug").unwrap_or_default().to_string(),
+                group.get_str("status").unwrap_or_default().to_string(),
+                group.get_str("frequency").unwrap_or_default().to_string(),
+            );
+
+            let count = match document.get_i32("count") {
+                Ok(count) => count as i64,
+                Err(_) => document.get_i64("count").unwrap_or(0),
+            };
+
+            *buckets.entry(key).or_insert(0) += count;
+        }
+    }
+
+    let mut groups = vec![];
+    let mut totals_by_slug: HashMap<String, i64> = HashMap::new();
+    let mut active_paid = 0;
+    let mut churned = 0;
+    let mut mrr_estimate = 0;
+    for ((slug, status, frequency), count) in buckets.iter() {
+        *totals_by_slug.entry(slug.clone()).or_insert(0) += count;
+
+        let paid = *slug != Slug::FREE.to_string();
+        let parsed_status = SubscriptionStatus::from_str(status).unwrap_or(SubscriptionStatus::Unset);
+        if paid && parsed_status == SubscriptionStatus::Active {
+            active_paid += count;
+            if let Some(prices) = state.plan_prices.get(slug) {
+                mrr_estimate += prices.monthly_amount(frequency) * count;
+            }
+        }
+
+        if paid && parsed_status.is_churned() {
+            churned += count;
+        }
+
+        groups.push(json!({
+            "slug": slug,
+            "status": status,
+            "frequency": frequency,
+            "count": count,
+        }));
+    }
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::Stats).to_string(),
+            data: json!({
+                "groups": groups,
+                "totals_by_slug": totals_by_slug,
+                "active_paid": active_paid,
+                "churned": churned,
+                "mrr_estimate_cents": mrr_estimate,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// target emails first, source ones appended unless the address is already there, the target keeps its main email
+pub fn merge_emails(target: &[Email], source: &[Email]) -> Vec<Email> {
+    let mut emails = target.to_vec();
+    for email in source.iter() {
+        if emails.iter().any(|existing| existing.address == email.address) {
+            continue;
+        }
+
+        emails.push(Email {
+            address: email.address.clone(),
+            verified: email.verified,
+            main: false,
+            disabled: email.disabled,
+            disabled_at: email.disabled_at.clone(),
+        });
+    }
+
+    emails
+}
+
+// the higher tier wins (target on ties), history logs of both are kept in chronological order
+pub fn merge_subscriptions(target: &Subscription, source: &Subscription) -> Subscription {
+    let target_rank = Slug::from_str(&target.slug).unwrap_or(Slug::FREE).rank();
+    let source_rank = Slug::from_str(&source.slug).unwrap_or(Slug::FREE).rank();
+
+    let mut subscription = match source_rank > target_rank {
+        true => source.clone(),
+        false => target.clone(),
+    };
+
+    let mut history_logs: Vec<SubscriptionHistoryLog> = target.history_logs.clone();
+    history_logs.extend(source.history_logs.iter().cloned());
+    history_logs.sort_by_key(|log| {
+        DateTime::parse_from_rfc3339(&log.date)
+            .map(|date| date.timestamp_millis())
+            .unwrap_or(0)
+    });
+
+    trim_history_logs(&mut history_logs);
+    subscription.history_logs = history_logs;
+    subscription
+}
+
+pub async fn merge_customers(
+    payload_result: Result<Json<CustomerMergeRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !payload.confirm {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::MergeNotConfirmed).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    if payload.source_id == payload.target_id {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::CannotMergeIntoItself).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let mut customers = vec![];
+    for customer_id in [&payload.source_id, &payload.target_id] {
+        let filter = doc! {"id": customer_id, "deleted": false};
+        match find_customer_in(state.all_customers_dbs(), filter).await {
+            Ok((true, Some(customer))) => customers.push(customer),
+            Ok(_) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(GenericResponse {
+                        message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                        data: json!({"customer_id": customer_id}),
+                        exit_code: 1,
+                    }),
+                )
+            },
+            Err((status_code, json)) => return (status_code, json),
+        };
+    }
+
+    let target = customers.pop().unwrap();
+    let source = customers.pop().unwrap();
+
+    let emails = merge_emails(&target.emails, &source.emails);
+    let subscription = merge_subscriptions(&target.subscription, &source.subscription);
+
+    let internal_error = (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GenericResponse {
+            message: APIMessages::InternalServerError.to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    );
+
+    let (bson_emails, bson_subscription) = match (to_bson(&emails), to_bson(&subscription)) {
+        (Ok(bson_emails), Ok(bson_subscription)) => (bson_emails, bson_subscription),
+        _ => return internal_error,
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    // the source gives its emails up first so no address is ever owned by two live customers
+    let source_update = doc! {"$set": {
+            "emails": [],
+            "deleted": true,
+            "updated_at": iso8601_string.clone(),
+        }
+    };
+
+    match update_customer(state.customers_db(&source.region), doc! {"id": &source.id}, source_update).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let target_update = doc! {"$set": {
+            "emails": bson_emails,
+            "subscription": bson_subscription,
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&target.region), doc! {"id": &target.id}, target_update).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let revoked_sessions = revoke_customer_sessions(&state.redis_connection, &source.id).unwrap_or(0);
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Merged).to_string(),
+            data: json!({
+                "source_id": source.id,
+                "target_id": target.id,
+                "emails": emails,
+                "subscription_slug": subscription.slug,
+                "revoked_source_sessions": revoked_sessions,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn create_invite_code(
+    Extension(session_data): Extension<SessionData>,
+    payload_result: Result<Json<CreateInviteCode>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let max_redemptions = payload.max_redemptions.unwrap_or(1);
+    if max_redemptions < 1 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidInviteCodeLimit).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let code = match &payload.code {
+        Some(code) if !code.trim().is_empty() => code.trim().to_string(),
+        _ => random_string(12).await,
+    };
+
+    match find_invite_code(&state.mongo_db, &code).await {
+        Ok(None) => (),
+        Ok(Some(_)) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::InviteCodeTaken).to_string(),
+                    data: json!({"code": code}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let invite_code = InviteCode {
+        code,
+        max_redemptions,
+        redemptions: 0,
+        redeemed_by: vec![],
+        created_by: session_data.customer_id,
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    match insert_invite_code(&state.mongo_db, &invite_code).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::CREATED,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::InviteCodeCreated).to_string(),
+            data: json!(invite_code),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn fetch_invite_codes(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let options = FindOptions::builder()
+        .sort(doc! {"created_at": -1})
+        .limit(200)
+        .build();
+
+    let invite_codes = match list_invite_codes(&state.mongo_db, options).await {
+        Ok(invite_codes) => invite_codes,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::InviteCodesListed).to_string(),
+            data: json!({"invite_codes": invite_codes}),
+            exit_code: 0,
+        }),
+    )
+}
+
+// one json object per line, secrets never leave the database
+fn customer_export_line(customer: &Customer) -> Bytes {
+    let mut value = json!(customer);
+    if let Some(object) = value.as_object_mut() {
+        object.remove("password");
+        object.remove("backup_security_codes");
+    }
+
+    Bytes::from(format!("{}\n", value))
+}
+
+pub async fn export_customers(
+    state: Arc<AppState>,
+) -> Response {
+    // cursors are opened upfront so a broken region fails before the body starts
+    let mut cursors = vec![];
+    for db in state.all_customers_dbs() {
+        let options = FindOptions::builder().sort(doc! {"created_at": 1}).build();
+        match customers_cursor(db, doc! {}, options).await {
+            Ok(cursor) => cursors.push(cursor),
+            Err((status_code, json)) => return (status_code, json).into_response(),
+        };
+    }
+
+    let lines = stream::iter(cursors)
+        .flatten()
+        .map(|customer| customer.map(|customer| customer_export_line(&customer)));
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+pub async fn fetch_recent_webhooks(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let deliveries = match recent_webhook_deliveries(&state.redis_connection) {
+        Ok(deliveries) => deliveries,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::RecentWebhooks).to_string(),
+            data: json!({
+                "deliveries": deliveries,
+                "dedup_ttl_secs": state.webhook_dedup_ttl,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn fetch_dead_letter_webhooks(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let dead_letters = match dead_letter_webhooks(&state.redis_connection) {
+        Ok(dead_letters) => dead_letters,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::DeadLetterWebhooks).to_string(),
+            data: json!({"dead_letters": dead_letters}),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn sync_customer_subscription(
+    Path(customer_id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let filter = doc! {"id": &customer_id, "deleted": false};
+    match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok((true, Some(customer))) => resync_customer_subscription(&state, customer).await,
+        Ok(_) => (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({"customer_id": customer_id}),
+                exit_code: 1,
+            }),
+        ),
+        Err((status_code, json)) => (status_code, json),
+    }
+}
+
+pub const DEFAULT_PRUNE_BATCH_SIZE: i64 = 100;
+pub const MAX_PRUNE_BATCH_SIZE: i64 = 1000;
+
+// logs dated before the cutoff, unparseable dates are kept rather than guessed
+pub fn split_history_logs(
+    history_logs: &[SubscriptionHistoryLog],
+    cutoff: &DateTime<FixedOffset>,
+) -> (Vec<SubscriptionHistoryLog>, Vec<SubscriptionHistoryLog>) {
+    history_logs.iter().cloned().partition(|log| match DateTime::parse_from_rfc3339(&log.date) {
+        Ok(date) => date >= *cutoff,
+        Err(_) => true,
+    })
+}
+
+async fn archive_pruned_logs(
+    state: &Arc<AppState>,
+    customer: &Customer,
+    pruned: &[SubscriptionHistoryLog],
+) -> Result<usize, String> {
+    let pool = match &state.postgres_conn {
+        Some(pool) => pool.clone(),
+        None => return Ok(0),
+    };
+
+    let archived_at = Utc::now().to_rfc3339();
+    let rows = pruned
+        .iter()
+        .map(|log| ArchivedHistoryLog {
+            customer_id: customer.id.clone(),
+            subscription_id: customer.subscription.id.clone(),
+            event: log.event.clone(),
+            date: log.date.clone(),
+            archived_at: archived_at.clone(),
+        })
+        .collect::<Vec<ArchivedHistoryLog>>();
+
+    match tokio::task::spawn_blocking(move || archive_history_logs(&pool, &rows)).await {
+        Ok(result) => result,
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// POST /api/admin/subscriptions/prune-history
+pub async fn prune_subscription_history(
+    payload_result: Result<Json<PruneHistoryRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let cutoff = match DateTime::parse_from_rfc3339(&payload.before) {
+        Ok(cutoff) => cutoff,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::InvalidPruneCutoff).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let batch_size = payload.batch_size.unwrap_or(DEFAULT_PRUNE_BATCH_SIZE);
+    if !(1..=MAX_PRUNE_BATCH_SIZE).contains(&batch_size) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidBatchSize).to_string(),
+                data: json!({"max_batch_size": MAX_PRUNE_BATCH_SIZE}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let archive_failed = |customers_pruned: u64, logs_pruned: u64| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericResponse {
+                message: APIMessages::Subscription(SubscriptionMessages::HistoryArchiveFailed).to_string(),
+                data: json!({
+                    "customers_pruned": customers_pruned,
+                    "logs_pruned": logs_pruned,
+                }),
+                exit_code: 1,
+            }),
+        )
+    };
+
+    if let Some(pool) = &state.postgres_conn {
+        let pool = pool.clone();
+        let prepared = match tokio::task::spawn_blocking(move || ensure_history_archive_table(&pool)).await {
+            Ok(result) => result,
+            Err(err) => Err(err.to_string()),
+        };
+
+        if let Err(err) = prepared {
+            log::error!("error preparing subscription history archive: {}", err);
+            return archive_failed(0, 0);
+        }
+    }
+
+    // string comparison only narrows the scan, split_history_logs makes the real decision
+    let cutoff_string = cutoff.with_timezone(&Utc).to_rfc3339();
+
+    let mut customers_pruned: u64 = 0;
+    let mut logs_pruned: u64 = 0;
+    let mut logs_archived: u64 = 0;
+
+    for db in state.all_customers_dbs() {
+        // paging by id always moves forward, even when a customer has nothing to prune
+        let mut last_id = String::new();
+        loop {
+            let filter = doc! {
+                "id": {"$gt": &last_id},
+                "subscription.history_logs.date": {"$lt": &cutoff_string},
+            };
+            let options = FindOptions::builder().sort(doc! {"id": 1}).limit(batch_size).build();
+
+            let customers = match find_customers(db, filter, options).await {
+                Ok(customers) => customers,
+                Err((status_code, json)) => return (status_code, json),
+            };
+
+            let batch_len = customers.len() as i64;
+            for customer in customers.iter() {
+                last_id = customer.id.clone();
+
+                let (_, pruned) = split_history_logs(&customer.subscription.history_logs, &cutoff);
+                if pruned.is_empty() {
+                    continue;
+                }
+
+                // archive first, a failed archive must never lose logs
+                match archive_pruned_logs(&state, customer, &pruned).await {
+                    Ok(archived) => logs_archived += archived as u64,
+                    Err(err) => {
+                        log::error!("error archiving history logs of {}: {}", customer.id, err);
+                        return archive_failed(customers_pruned, logs_pruned);
+                    }
+                };
+
+                // pulling by date leaves logs appended meanwhile untouched
+                let pruned_dates = pruned.iter().map(|log| log.date.clone()).collect::<Vec<String>>();
+                let update = doc! {"$pull": {"subscription.history_logs": {"date": {"$in": pruned_dates}}}};
+                match update_customer(db, doc! {"id": &customer.id}, update).await {
+                    Ok(_) => (),
+                    Err((status_code, json)) => return (status_code, json),
+                };
+
+                customers_pruned += 1;
+                logs_pruned += pruned.len() as u64;
+            }
+
+            if batch_len < batch_size {
+                break;
+            }
+        }
+    }
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::HistoryPruned).to_string(),
+            data: json!({
+                "before": cutoff.to_rfc3339(),
+                "customers_pruned": customers_pruned,
+                "logs_pruned": logs_pruned,
+                "logs_archived": logs_archived,
+                "archived": state.postgres_conn.is_some(),
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// GET /api/admin/config/feature-map
+pub async fn fetch_feature_map(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let feature_map = state.current_feature_map();
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::FeatureMap).to_string(),
+            data: json!({
+                "feature_map": feature_map,
+                "is_default": feature_map.updated_at.is_empty(),
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// PUT /api/admin/config/feature-map, only this instance's cache is refreshed, others pick it up on restart
+pub async fn update_feature_map(
+    Extension(session_data): Extension<SessionData>,
+    payload_result: Result<Json<FeatureMapUpdate>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let feature_map = FeatureMap {
+        slugs: payload.slugs.clone(),
+        blocked_statuses: payload.blocked_statuses.clone(),
+        updated_at: Utc::now().to_rfc3339(),
+        updated_by: session_data.customer_id,
+    };
+
+    if let Err(err) = feature_map.validate() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Subscription(SubscriptionMessages::InvalidFeatureMap).to_string(),
+                data: json!({"error": err}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    match save_feature_map(&state.mongo_db, &feature_map).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    state.replace_feature_map(feature_map.clone());
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::FeatureMapUpdated).to_string(),
+            data: json!({"feature_map": feature_map}),
+            exit_code: 0,
+        }),
+    )
+}
+
+// GET /api/admin/subscriptions/by-ls-id/:id, which customer owns a LemonSqueezy subscription
+pub async fn find_subscription_by_lemonsqueezy_id(
+    Path(subscription_id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    // LemonSqueezy ids are numeric, anything else can't match
+    let subscription_id = subscription_id.trim().to_string();
+    if subscription_id.is_empty() || !subscription_id.chars().all(|c| c.is_ascii_digit()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidPayload).to_string(),
+                data: json!({"field": "id"}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let filter = doc! {"subscription.id": &subscription_id, "subscription.price_id": {"$in": ["", null]}};
+    let customer = match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Subscription(SubscriptionMessages::NotFound).to_string(),
+                    data: json!({"subscription_id": subscription_id}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::Found).to_string(),
+            data: json!({
+                "customer_id": customer.id,
+                "region": customer.region,
+                "status": customer.status,
+                "subscription": SubscriptionView::from(customer.subscription),
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// POST /api/admin/customers/:id/impersonate, a short read only session for support, every issuance is audited
+pub async fn impersonate_customer(
+    Extension(session_data): Extension<SessionData>,
+    Path(customer_id): Path<String>,
+    payload_result: Result<Json<ImpersonationRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    match forbid_impersonation(&session_data) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if payload.reason.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidPayload).to_string(),
+                data: json!({"field": "reason"}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    // admins are never impersonated, not even by each other
+    if customer_id == session_data.customer_id || state.admin_customer_ids.contains(&customer_id) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let customer = match find_customer_in(state.all_customers_dbs(), doc! {"id": &customer_id, "deleted": false}).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // no audit record, no token
+    let record = ImpersonationRecord::new(&session_data.customer_id, &customer.id, &payload.reason, IMPERSONATION_SESSION_TTL);
+    match record_impersonation(&state.redis_connection, &record) {
+        Ok(_) => (),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorSettingKey).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let token = match issue_impersonation_session(&state, &customer, &session_data.customer_id).await {
+        Ok(token) => token,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let scopes = impersonation_scopes().iter().map(|scope| scope.to_string()).collect::<Vec<String>>();
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::Impersonating).to_string(),
+            data: json!({
+                "token": token,
+                "expires_in": IMPERSONATION_SESSION_TTL,
+                "scopes": scopes,
+                "customer_id": customer.id,
+                "impersonated_by": session_data.customer_id,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn fetch_recent_impersonations(
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let impersonations = match recent_impersonations(&state.redis_connection) {
+        Ok(impersonations) => impersonations,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::RecentImpersonations).to_string(),
+            data: json!({"impersonations": impersonations}),
+            exit_code: 0,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        types::subscription::{DefaultSubscription, SubscriptionFrequencyClass},
+        routers::admin::get_admin_router,
+        server::tests::test_state,
+        utilities::{
+            helpers::MAX_HISTORY_LOGS,
+            token::{create_token_with_ttl, tests::SIGNING_KEY},
+        },
+    };
+    use axum::body::Body;
+    use redis::{Client, Commands};
+    use std::env;
+    use tower::ServiceExt;
+
+    fn subscription(slug: Slug, log_count: usize, prefix: &str) -> Subscription {
+        let default_subscription = DefaultSubscription {
+            slug,
+            frequency: SubscriptionFrequencyClass::MONTHLY,
+            trial_days: 14,
+        };
+
+        let mut subscription = default_subscription.build(format!("{}_sub", prefix), Utc::now());
+        subscription.history_logs = (0..log_count)
+            .map(|minute| SubscriptionHistoryLog {
+                event: format!("{}_{}", prefix, minute),
+                date: format!("2024-01-01T{:02}:{:02}:00+00:00", minute / 60, minute % 60),
+            })
+            .collect();
+        subscription
+    }
+
+    #[test]
+    fn merged_history_is_trimmed_to_the_cap() {
+        let target = subscription(Slug::FREE, 150, "target");
+        let source = subscription(Slug::FREE, 150, "source");
+
+        let merged = merge_subscriptions(&target, &source);
+
+        assert_eq!(merged.history_logs.len(), MAX_HISTORY_LOGS);
+        assert_eq!(merged.history_logs.last().unwrap().event, "source_149");
+    }
+
+    fn email(address: &str, main: bool) -> Email {
+        Email {
+            address: address.to_string(),
+            verified: true,
+            main,
+            disabled: false,
+            disabled_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn merging_keeps_the_pro_subscription_of_either_side() {
+        let free = subscription(Slug::FREE, 1, "free");
+        let pro = subscription(Slug::PRO, 1, "pro");
+
+        assert_eq!(merge_subscriptions(&free, &pro).id, "pro_sub");
+        assert_eq!(merge_subscriptions(&pro, &free).id, "pro_sub");
+    }
+
+    #[test]
+    fn merging_interleaves_history_logs_by_date() {
+        let target = subscription(Slug::FREE, 2, "target");
+        let source = subscription(Slug::PRO, 2, "source");
+
+        let events: Vec<String> = merge_subscriptions(&target, &source).history_logs.into_iter().map(|log| log.event).collect();
+        assert_eq!(events, vec!["target_0", "source_0", "target_1", "source_1"]);
+    }
+
+    #[test]
+    fn merging_combines_emails_without_duplicates() {
+        let target = vec![email("ada@example.com", true)];
+        let source = vec![email("ada@example.com", true), email("ada@work.example.com", true)];
+
+        let emails = merge_emails(&target, &source);
+
+        assert_eq!(emails.iter().map(|email| email.address.as_str()).collect::<Vec<&str>>(), vec!["ada@example.com", "ada@work.example.com"]);
+        assert_eq!(emails.iter().filter(|email| email.main).count(), 1);
+    }
+
+    fn session(customer_id: &str, scopes: Vec<SessionScopes>) -> SessionData {
+        SessionData {
+            customer_id: String::from(customer_id),
+            scopes,
+            region: String::new(),
+            impersonated_by: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_session_without_admin_access_is_refused() {
+        let state = Arc::new(test_state().await);
+
+        let (status, _) = check_admin_session(session("customer", vec![SessionScopes::TotalAccess]), &state).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(check_admin_session(session("customer", vec![SessionScopes::AdminAccess]), &state).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_listed_id_is_required_when_configured() {
+        let mut state = test_state().await;
+        state.admin_require_listed_id = true;
+        let state = Arc::new(state);
+
+        let (status, _) = check_admin_session(session("removed", vec![SessionScopes::AdminAccess]), &state).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(check_admin_session(session("admin", vec![SessionScopes::AdminAccess]), &state).is_ok());
+    }
+
+    async fn admin_request(state: Arc<AppState>, token: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri("/customers");
+        if let Some(token) = token {
+            request = request.header("Authorization", token);
+        }
+
+        get_admin_router(state.clone())
+            .await
+            .with_state(state)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    // list_customers would fail on the unreachable mongo with a 500, a 401 means the middleware answered
+    #[tokio::test]
+    async fn the_admin_router_refuses_a_request_without_a_token() {
+        assert_eq!(admin_request(Arc::new(test_state().await), None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    // needs a reachable server, run with REDIS_URI set and --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn the_admin_router_refuses_a_non_admin_token() {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        let mut state = test_state().await;
+        state.redis_connection = Client::open(env::var("REDIS_URI").unwrap()).unwrap();
+
+        let token = create_token_with_ttl(&String::from("customer"), "", vec![SessionScopes::TotalAccess], 60).unwrap();
+        state.redis_connection.get_connection().unwrap().set_ex::<&String, &str, ()>(&token, "customer", 60).unwrap();
+
+        assert_eq!(admin_request(Arc::new(state), Some(&token)).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -1,37 +1,96 @@
 use crate::email::brevo_api::send_create_contact_request;
-use crate::storage::mongo::{build_customer_filter, find_customer, update_customer};
+use crate::storage::mongo::{build_customer_filter, get_customers_collection, consume_invite_code, find_customer, find_customer_in, find_invite_code, release_invite_code, update_customer};
 use crate::types::customer::{
-    AuthProviders, Customer, Email, Preferences, PrivateSensitiveCustomer,
+    AuthProviders, Customer, CustomerStatus, Email, Preferences, PublicCustomer,
+    PublicPreferences, UnverifiedNotificationsPolicy,
 };
 use crate::types::incoming_requests::{
-    CreateCustomerRecord, CustomerUpdateName, CustomerUpdatePassword, FetchCustomerByID
+    CreateCustomerQueryParams, CreateCustomerRecord, CustomerUpdate, CustomerUpdateLanguage, CustomerUpdateMetadata, CustomerUpdateName, CustomerUpdatePassword, CustomerUpdatePicture,
+    FetchCustomerByID,
 };
-use crate::types::subscription::{Slug, Subscription, SubscriptionFrequencyClass};
 use crate::utilities::api_messages::{
-    APIMessages, CustomerMessages, EmailMessages, InputMessages, MongoMessages,
-    TokenMessages,
+    APIMessages, CustomerMessages, EmailMessages, InputMessages, MongoMessages, RedisMessages,
 };
+use crate::utilities::captcha::{verify_captcha, CaptchaError};
+use crate::utilities::events::{emit_event, CustomerCreatedEvent, CUSTOMER_CREATED_EVENT};
+use crate::utilities::email::{check_email_domain, consume_email_send_budget};
+use crate::utilities::rate_limits::customer_rate_limits;
 use crate::utilities::helpers::{
-    parse_class, payload_analyzer, random_string, valid_email, valid_password,
+    has_confusable_script_mix, parse_class, CUSTOMER_ID_LENGTH, password_differs_from_emails, payload_analyzer, random_string, valid_email,
+    valid_metadata_entry, valid_password, valid_picture_url,
+};
+use crate::utilities::idempotency::{
+    begin_idempotent_request, extract_idempotency_key, finish_idempotent_request,
+    idempotency_redis_key, idempotency_request_hash,
 };
 use crate::{server::AppState, types::customer::GenericResponse};
 
-use axum::extract::Query;
+use axum::extract::{Path, Query};
 use axum::http::HeaderMap;
 use axum::{extract::rejection::JsonRejection, http::StatusCode, Json};
 use chrono::Utc;
-use mongodb::bson::doc;
+use mongodb::bson::{doc, to_document};
 use serde_json::json;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bcrypt::{hash, verify, DEFAULT_COST};
 
 use super::email::new_email_verification;
-use super::identity::{get_user_session_from_req, SessionScopes};
+use super::identity::{get_user_session_from_req, redact_for_scopes, require_any_scope, rotate_session, SessionScopes};
+
+pub const MAX_METADATA_KEYS: usize = 20;
+
+// any one of them is enough, a first party login only carries TotalAccess
+pub const UPDATE_NAME_SCOPES: [SessionScopes; 2] = [SessionScopes::TotalAccess, SessionScopes::UpdateName];
 
 pub async fn create_customer_record(
+    headers: HeaderMap,
+    Query(params): Query<CreateCustomerQueryParams>,
+    payload_result: Result<Json<CreateCustomerRecord>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    // dry runs don't create anything, so they don't claim idempotency keys either
+    let dry_run = params.dry_run.unwrap_or(false);
+    if dry_run {
+        return process_customer_record_creation(payload_result, state, true).await;
+    }
+
+    let idempotency_key = match extract_idempotency_key(&headers) {
+        Ok(idempotency_key) => idempotency_key,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let redis_key = match idempotency_key {
+        Some(key) => idempotency_redis_key("customers_create", &key),
+        None => return process_customer_record_creation(payload_result, state, false).await,
+    };
+
+    // a malformed body is rejected without claiming the key
+    let request_hash = match &payload_result {
+        Ok(Json(payload)) => match serde_json::to_vec(payload) {
+            Ok(bytes) => idempotency_request_hash(&bytes),
+            Err(_) => return process_customer_record_creation(payload_result, state, false).await,
+        },
+        Err(_) => return process_customer_record_creation(payload_result, state, false).await,
+    };
+
+    match begin_idempotent_request(&state.redis_connection, &redis_key, &request_hash) {
+        Ok(Some(stored_response)) => return stored_response.into_api_response(),
+        Ok(None) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let response = process_customer_record_creation(payload_result, state.clone(), false).await;
+    finish_idempotent_request(&state.redis_connection, &redis_key, &request_hash, &response, &HeaderMap::new());
+
+    response
+}
+
+async fn process_customer_record_creation(
     payload_result: Result<Json<CreateCustomerRecord>, JsonRejection>,
     state: Arc<AppState>,
+    dry_run: bool,
 ) -> (StatusCode, Json<GenericResponse>) {
     let payload = match payload_analyzer(payload_result) {
         Ok(payload) => payload,
@@ -49,6 +108,40 @@ pub async fn create_customer_record(
         );
     }
 
+    // dry runs create nothing, and would otherwise burn the single use token
+    if let (Some(captcha), false) = (&state.captcha, dry_run) {
+        let token = payload.captcha_token.as_deref().unwrap_or_default();
+        let verified = match token.is_empty() {
+            true => Err(CaptchaError::Rejected(vec![String::from("missing-input-response")])),
+            false => verify_captcha(captcha, token).await,
+        };
+
+        match verified {
+            Ok(_) => (),
+            Err(CaptchaError::Rejected(error_codes)) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(GenericResponse {
+                        message: APIMessages::Customer(CustomerMessages::CaptchaFailed).to_string(),
+                        data: json!({"error_codes": error_codes}),
+                        exit_code: 1,
+                    }),
+                )
+            },
+            Err(CaptchaError::Unreachable(err)) => {
+                log::error!("error verifying {:?} captcha: {}", captcha.provider, err);
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(GenericResponse {
+                        message: APIMessages::Customer(CustomerMessages::CaptchaFailed).to_string(),
+                        data: json!({}),
+                        exit_code: 1,
+                    }),
+                )
+            },
+        };
+    }
+
     let auth_provider: AuthProviders;
     match payload.provider.to_lowercase().as_str() {
         "legacy" => auth_provider = AuthProviders::LEGACY,
@@ -56,18 +149,29 @@ pub async fn create_customer_record(
         _ => auth_provider = AuthProviders::LEGACY,
     }
 
-    if payload.name.len() < 2 || payload.name.len() > 25 {
+    match validate_name(&state, &payload.name) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match valid_email(&payload.email).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !state.signup_domain_policy.is_allowed(&payload.email) {
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::FORBIDDEN,
             Json(GenericResponse {
-                message: APIMessages::Input(InputMessages::InvalidNameLength).to_string(),
+                message: APIMessages::Email(EmailMessages::DomainNotAllowed).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    match valid_email(&payload.email).await {
+    // after the policy so refused domains never cost a dns lookup
+    match check_email_domain(&state, &payload.email).await {
         Ok(_) => (),
         Err((status_code, json)) => return (status_code, json),
     };
@@ -93,38 +197,40 @@ pub async fn create_customer_record(
             );
         }
 
-        if payload.email.to_lowercase() == payload.password.to_lowercase() {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(GenericResponse {
-                    message: APIMessages::Email(EmailMessages::EmailAndPasswordMustBeDifferent)
-                        .to_string(),
-                    data: json!({}),
-                    exit_code: 1,
-                }),
-            );
-        }
-
-        hashed_password = match hash(&payload.password, DEFAULT_COST) {
-            Ok(hashed_password) => hashed_password,
-            Err(_) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(GenericResponse {
-                        message: APIMessages::Customer(CustomerMessages::ErrorHashingPassword)
-                            .to_string(),
-                        data: json!({}),
-                        exit_code: 1,
-                    }),
-                )
-            }
+        match password_differs_from_emails(&payload.password, std::slice::from_ref(&payload.email)).await {
+            Ok(_) => (),
+            Err((status_code, json)) => return (status_code, json),
         };
+
+        // dry runs skip the captcha, so they must not cost a bcrypt round either
+        if !dry_run {
+            hashed_password = match hash(&payload.password, DEFAULT_COST) {
+                Ok(hashed_password) => hashed_password,
+                Err(_) => {
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(GenericResponse {
+                            message: APIMessages::Customer(CustomerMessages::ErrorHashingPassword)
+                                .to_string(),
+                            data: json!({}),
+                            exit_code: 1,
+                        }),
+                    )
+                }
+            };
+        }
     }
 
-    let filter = build_customer_filter("", payload.email.to_lowercase().as_str()).await;
-    let (found, _) = match find_customer(&state.mongo_db, filter).await {
-        Ok(customer) => customer,
-        Err((status, json)) => return (status, json),
+    // left out of dry runs, without a captcha they would let anyone probe for registered emails
+    let found = match dry_run {
+        true => false,
+        false => {
+            let filter = build_customer_filter("", payload.email.to_lowercase().as_str()).await;
+            match find_customer_in(state.all_customers_dbs(), filter).await {
+                Ok((found, _)) => found,
+                Err((status, json)) => return (status, json),
+            }
+        }
     };
 
     if found {
@@ -142,32 +248,77 @@ pub async fn create_customer_record(
         address: payload.email.to_lowercase(),
         verified: false,
         main: true,
+        disabled: false,
+        disabled_at: String::new(),
     }];
 
-    let class = match parse_class(&payload.class).await {
-        Ok(class) => class,
-        Err((status_code, json)) => return (status_code, json),
+    // only an omitted class falls back, an invalid one is still rejected
+    let class = match &payload.class {
+        Some(raw_class) => match parse_class(raw_class).await {
+            Ok(class) => class,
+            Err((status_code, json)) => return (status_code, json),
+        },
+        None => state.default_customer_class,
+    };
+
+    // chosen at signup and never moved, the customer's data stays in that region's database
+    let region = match &payload.region {
+        Some(region) => region.trim().to_lowercase(),
+        None => state.default_region.clone(),
+    };
+
+    if !state.is_known_region(&region) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidRegion).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let invalid_invite_code = (
+        StatusCode::FORBIDDEN,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::InvalidInviteCode).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    );
+
+    // checked here so dry runs report it too, the redemption itself happens right before storing the customer
+    let invite_code = match (state.require_invite_code, &payload.invite_code) {
+        (false, _) => None,
+        (true, None) => return invalid_invite_code,
+        (true, Some(code)) => match find_invite_code(&state.mongo_db, code.trim()).await {
+            Ok(Some(invite_code)) if invite_code.is_available() => Some(invite_code.code),
+            Ok(_) => return invalid_invite_code,
+            Err((status, json)) => return (status, json),
+        },
     };
 
     let current_datetime = Utc::now();
     let iso8601_string = current_datetime.to_rfc3339();
     let subscription_id = random_string(10).await;
-    let subscription = Subscription {
-        id: subscription_id,
-        product_id: 0,
-        variant_id: 0,
-        slug: Slug::FREE.to_string(),
-        frequency: SubscriptionFrequencyClass::UNDEFINED,
-        created_at: iso8601_string.clone(),
-        updated_at: iso8601_string.clone(),
-        starts_at: "".to_string(),
-        ends_at: "".to_string(),
-        renews_at: "".to_string(),
-        status: "".to_string(),
-        history_logs: vec![],
+    let subscription = state.default_subscription.build(subscription_id, current_datetime);
+
+    // google signups send back the prefilled picture
+    let picture = match &payload.picture {
+        Some(picture) if !valid_picture_url(picture) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::InvalidPictureUrl).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        picture => picture.clone(),
     };
 
-    let id = random_string(30).await;
+    let id = random_string(CUSTOMER_ID_LENGTH).await;
     let customer = Customer {
         id,
         name: payload.name.clone(),
@@ -184,15 +335,94 @@ pub async fn create_customer_record(
             notifications: true,
         },
         subscription,
+        metadata: HashMap::new(),
 
         created_at: iso8601_string.clone(),
         updated_at: iso8601_string.clone(),
         deleted: false,
+        status: CustomerStatus::Active,
+        region,
+        last_login_at: "".to_string(),
+        last_login_provider: "".to_string(),
+        linked_providers: vec![],
+        picture,
     };
 
     let created_customer_list = std::env::var("BREVO_CUSTOMERS_LIST_ID");
     let api_key = std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY");
 
+    // everything has been validated at this point, report what would happen and stop
+    if dry_run {
+        let marketing_contact = created_customer_list.is_ok() && api_key.is_ok();
+        return (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::DryRunValidated).to_string(),
+                data: json!({
+                    "dry_run": true,
+                    "email_availability_checked": false,
+                    "customer": customer,
+                    "would_register_marketing_contact": marketing_contact,
+                    "would_send_welcome_email": marketing_contact
+                        && state.enabled_email_integration
+                        && state.email_provider_settings.send_welcome_email,
+                }),
+                exit_code: 0,
+            }),
+        );
+    }
+
+    // redeemed before anything outside the database happens, every later failure gives it back
+    if let Some(code) = &invite_code {
+        match consume_invite_code(&state.mongo_db, code, &customer.id).await {
+            Ok(true) => (),
+            Ok(false) => return invalid_invite_code,
+            Err((status, json)) => return (status, json),
+        };
+    }
+
+    if let Err((status, json)) = store_new_customer(&state, &customer, created_customer_list, api_key).await {
+        if let Some(code) = &invite_code {
+            let _ = release_invite_code(&state.mongo_db, code, &customer.id).await;
+        }
+
+        return (status, json);
+    }
+
+    let created_event = CustomerCreatedEvent::new(&customer.id, &customer.class.to_string(), &customer.subscription.slug);
+    emit_event(&state.event_publisher, CUSTOMER_CREATED_EVENT, &created_event);
+
+    (
+        StatusCode::CREATED,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Created).to_string(),
+            data: json!(customer),
+            exit_code: 0,
+        }),
+    )
+}
+
+// registers the marketing contact, sends the welcome email and stores the customer, in that order
+async fn store_new_customer(
+    state: &Arc<AppState>,
+    customer: &Customer,
+    created_customer_list: Result<String, std::env::VarError>,
+    api_key: Result<String, std::env::VarError>,
+) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let primary_email = match customer.primary_email() {
+        Some(email) => email.address.clone(),
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::NoEmailOnRecord).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
     if created_customer_list.is_ok() && api_key.is_ok() {
         let created_customer_list = match created_customer_list.unwrap().parse::<u32>() {
             Ok(list_id) => list_id,
@@ -204,13 +434,13 @@ pub async fn create_customer_record(
             &api_key,
             vec![created_customer_list],
             &customer.id,
-            &customer.emails[0].address,
+            &primary_email,
         )
         .await
         {
             Ok(_) => (),
             Err(_) => {
-                return (
+                return Err((
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json(GenericResponse {
                         message: APIMessages::Customer(
@@ -220,46 +450,35 @@ pub async fn create_customer_record(
                         data: json!({}),
                         exit_code: 1,
                     }),
-                )
+                ))
             }
         };
 
-        if state.enabled_email_integration {
-            match new_email_verification(
-                &state,
+        if state.enabled_email_integration && state.email_provider_settings.send_welcome_email {
+            consume_email_send_budget(state, &customer.id)?;
+
+            new_email_verification(
+                state,
                 api_key,
-                customer.emails[0].address.clone(),
+                primary_email.clone(),
                 customer.name.clone(),
-            ).await {
-                Ok(_) => (),
-                Err((status, json)) => return (status, json),
-            }
+                state.email_provider_settings.welcome_template_id(&customer.class),
+            ).await?;
         }
     }
 
-    let collection = state.mongo_db.collection("customers");
+    let collection = get_customers_collection(state.customers_db(&customer.region)).await;
     match collection.insert_one(customer.clone(), None).await {
-        Ok(_) => (),
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(GenericResponse {
-                    message: APIMessages::Mongo(MongoMessages::ErrorInserting).to_string(),
-                    data: json!({}),
-                    exit_code: 1,
-                }),
-            )
-        }
+        Ok(_) => Ok(()),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericResponse {
+                message: APIMessages::Mongo(MongoMessages::ErrorInserting).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
     }
-
-    (
-        StatusCode::CREATED,
-        Json(GenericResponse {
-            message: APIMessages::Customer(CustomerMessages::Created).to_string(),
-            data: json!(customer),
-            exit_code: 0,
-        }),
-    )
 }
 
 pub async fn fetch_customer_record_by_id(
@@ -287,7 +506,7 @@ pub async fn fetch_customer_record_by_id(
     };
 
     let filter = build_customer_filter(customer_id.as_str(), "").await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter).await {
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
         Ok(customer) => customer,
         Err((status, json)) => return (status, json),
     };
@@ -303,138 +522,130 @@ pub async fn fetch_customer_record_by_id(
         );
     }
 
-    let customer = customer.unwrap();
+    // same table the public scopes catalog is built from
+    let shared_customer_data = redact_for_scopes(customer.unwrap(), &session_data.scopes);
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Found).to_string(),
+            data: json!(shared_customer_data),
+            exit_code: 0,
+        }),
+    )
+}
 
-    let mut shared_customer_data = PrivateSensitiveCustomer {
-        id: Some(customer_id),
-        name: Some(customer.name),
-        class: Some(customer.class),
-        emails: Some(customer.emails),
-        auth_provider: Some(customer.auth_provider),
-        preferences: Some(customer.preferences),
-        subscription: Some(customer.subscription),
-        created_at: Some(customer.created_at),
-        updated_at: Some(customer.updated_at),
-        deleted: Some(customer.deleted),
+pub async fn fetch_public_profile(
+    Path(customer_id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let filter = doc! {"id": customer_id.as_str()};
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
     };
 
-    if session_data.scopes.contains(&SessionScopes::TotalAccess) {
+    if !found {
         return (
-            StatusCode::OK,
+            StatusCode::NOT_FOUND,
             Json(GenericResponse {
-                message: APIMessages::Customer(CustomerMessages::Found).to_string(),
-                data: json!(shared_customer_data),
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    if !session_data.scopes.contains(&SessionScopes::ViewPublicID) {
-        shared_customer_data.id = None;
-    }
-
-    if !session_data
-        .scopes
-        .contains(&SessionScopes::ViewEmailAddresses)
-    {
-        shared_customer_data.emails = None;
-    }
-
-    if !session_data
-        .scopes
-        .contains(&SessionScopes::ViewSubscription)
-    {
-        shared_customer_data.subscription = None;
+    let customer = customer.unwrap();
+    if customer.deleted {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
     }
 
-    if !session_data
-        .scopes
-        .contains(&SessionScopes::ViewPublicProfile)
-    {
-        shared_customer_data.name = None;
-        shared_customer_data.class = None;
-        shared_customer_data.preferences = None;
-        shared_customer_data.created_at = None;
-        shared_customer_data.updated_at = None;
-        shared_customer_data.deleted = None;
-    }
+    let public_customer = PublicCustomer {
+        id: customer.id,
+        name: customer.name,
+        class: customer.class,
+        picture: customer.picture,
+        preferences: PublicPreferences {
+            language: customer.preferences.language,
+        },
+        subscription_tier: customer.subscription.slug,
+        created_at: customer.created_at,
+    };
 
     (
         StatusCode::OK,
         Json(GenericResponse {
             message: APIMessages::Customer(CustomerMessages::Found).to_string(),
-            data: json!(shared_customer_data),
+            data: json!(public_customer),
             exit_code: 0,
         }),
     )
 }
 
-pub async fn update_name(
+pub async fn update_picture(
     headers: HeaderMap,
-    payload_result: Result<Json<CustomerUpdateName>, JsonRejection>,
+    payload_result: Result<Json<CustomerUpdatePicture>, JsonRejection>,
     state: Arc<AppState>,
 ) -> (StatusCode, Json<GenericResponse>) {
     let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
-        Ok(customer_id) => customer_id,
+        Ok(session_data) => session_data,
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if !(session_data.scopes.contains(&SessionScopes::TotalAccess)
-        && session_data.scopes.contains(&SessionScopes::UpdateName))
-    {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(GenericResponse {
-                message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction)
-                    .to_string(),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        );
-    }
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
 
     let payload = match payload_analyzer(payload_result) {
         Ok(payload) => payload,
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if payload.name.len() < 2 || payload.name.len() > 25 {
+    if !valid_picture_url(&payload.picture) {
         return (
             StatusCode::BAD_REQUEST,
             Json(GenericResponse {
-                message: APIMessages::Input(InputMessages::InvalidNameLength).to_string(),
+                message: APIMessages::Input(InputMessages::InvalidPictureUrl).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    let current_datetime = Utc::now();
-    let iso8601_string = current_datetime.to_rfc3339();
-
     let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
     let update = doc! {"$set": {
-            "name": &payload.name,
-            "updated_at": iso8601_string,
+            "picture": &payload.picture,
+            "updated_at": Utc::now().to_rfc3339(),
         }
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
         Ok(_) => (
             StatusCode::OK,
             Json(GenericResponse {
-                message: APIMessages::Customer(CustomerMessages::NameUpdated).to_string(),
-                data: json!({}),
+                message: APIMessages::Customer(CustomerMessages::PictureUpdated).to_string(),
+                data: json!({
+                    "picture": payload.picture,
+                }),
                 exit_code: 0,
             }),
         ),
-        Err((status, json)) => return (status, json),
+        Err((status_code, json)) => (status_code, json),
     }
 }
 
-pub async fn update_password(
+pub async fn update_name(
     headers: HeaderMap,
-    payload_result: Result<Json<CustomerUpdatePassword>, JsonRejection>,
+    payload_result: Result<Json<CustomerUpdateName>, JsonRejection>,
     state: Arc<AppState>,
 ) -> (StatusCode, Json<GenericResponse>) {
     let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
@@ -442,25 +653,116 @@ pub async fn update_password(
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if !session_data.scopes.contains(&SessionScopes::TotalAccess) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(GenericResponse {
-                message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction)
-                    .to_string(),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        );
-    }
-
-    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter).await {
-        Ok(customer) => customer,
-        Err((status, json)) => return (status, json),
+    match require_any_scope(&session_data, &UPDATE_NAME_SCOPES) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
     };
 
-    if !found {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match validate_name(&state, &payload.name) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let update = doc! {"$set": {
+            "name": &payload.name,
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NameUpdated).to_string(),
+                data: json!({}),
+                exit_code: 0,
+            }),
+        ),
+        Err((status, json)) => return (status, json),
+    }
+}
+
+pub async fn update_language(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerUpdateLanguage>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::UpdatePreferences]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let language = match validate_language(&state, &payload.language) {
+        Ok(language) => language,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let update = doc! {"$set": {
+            "preferences.language": &language,
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::LanguageUpdated).to_string(),
+                data: json!({
+                    "language": language,
+                }),
+                exit_code: 0,
+            }),
+        ),
+        Err((status, json)) => return (status, json),
+    }
+}
+
+pub async fn update_password(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerUpdatePassword>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers.clone(), &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
+    };
+
+    if !found {
         return (
             StatusCode::NOT_FOUND,
             Json(GenericResponse {
@@ -476,67 +778,242 @@ pub async fn update_password(
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if payload.old_password.len() < 8 || payload.old_password.len() > 100 {
-        return (
+    let hashed_new_password = match validate_password_change(&customer.unwrap(), &payload).await {
+        Ok(hashed_new_password) => hashed_new_password,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let update = doc! {"$set": {
+            "password": hashed_new_password,
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (),
+        Err((status, json)) => return (status, json),
+    };
+
+    let (token, expires_in) = match rotate_session(&headers, &session_data, &state).await {
+        Ok(rotated) => rotated,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::PasswordUpdated).to_string(),
+            data: json!({
+                "token": token,
+                "expires_in": expires_in,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// incoming keys overwrite existing ones, the limit applies to the merged result
+pub async fn merge_metadata(
+    mut metadata: HashMap<String, String>,
+    incoming: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, (StatusCode, Json<GenericResponse>)> {
+    for (key, value) in incoming.iter() {
+        valid_metadata_entry(key, value).await?;
+        metadata.insert(key.clone(), value.clone());
+    }
+
+    if metadata.len() > MAX_METADATA_KEYS {
+        return Err((
             StatusCode::BAD_REQUEST,
             Json(GenericResponse {
-                message: APIMessages::Input(InputMessages::InvalidOldPasswordLength).to_string(),
-                data: json!({}),
+                message: APIMessages::Input(InputMessages::TooManyMetadataKeys).to_string(),
+                data: json!({
+                    "max_keys": MAX_METADATA_KEYS,
+                }),
                 exit_code: 1,
             }),
-        );
+        ));
     }
 
-    if payload.new_password.len() < 8 || payload.new_password.len() > 100 {
+    Ok(metadata)
+}
+
+pub async fn update_metadata(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerUpdateMetadata>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::UpdateMetadata]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
+    };
+
+    if !found {
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
             Json(GenericResponse {
-                message: APIMessages::Input(InputMessages::InvalidNewPasswordLength).to_string(),
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    match valid_password(&payload.new_password).await {
+    let metadata = match merge_metadata(customer.unwrap().metadata, &payload.metadata).await {
+        Ok(metadata) => metadata,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let update = doc! {"$set": {
+            "metadata": to_document(&metadata).unwrap_or_default(),
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::MetadataUpdated).to_string(),
+                data: json!(metadata),
+                exit_code: 0,
+            }),
+        ),
+        Err((status, json)) => (status, json),
+    }
+}
+
+pub async fn delete_metadata_key(
+    headers: HeaderMap,
+    Path(key): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::UpdateMetadata]) {
         Ok(_) => (),
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if payload.new_password == payload.old_password {
+    match valid_metadata_entry(&key, "").await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
+    };
+
+    if !found {
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
             Json(GenericResponse {
-                message: APIMessages::Input(
-                    InputMessages::NewPasswordAndOldPasswordMustBeDifferent,
-                )
-                .to_string(),
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    if payload.new_password != payload.new_password_confirmation {
+    if !customer.unwrap().metadata.contains_key(&key) {
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
             Json(GenericResponse {
-                message: APIMessages::Input(InputMessages::NewPasswordConfirmationMustMatch)
-                    .to_string(),
+                message: APIMessages::Customer(CustomerMessages::MetadataKeyNotFound).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         );
     }
 
-    let hashed_new_password = match hash(&payload.new_password, DEFAULT_COST) {
-        Ok(hashed_password) => hashed_password,
+    let current_datetime = Utc::now();
+    let iso8601_string = current_datetime.to_rfc3339();
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let update = doc! {
+        "$unset": {
+            format!("metadata.{}", key): "",
+        },
+        "$set": {
+            "updated_at": iso8601_string,
+        }
+    };
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::MetadataKeyRemoved).to_string(),
+                data: json!({}),
+                exit_code: 0,
+            }),
+        ),
+        Err((status, json)) => (status, json),
+    }
+}
+
+// GET /api/me/rate-limit, lets clients back off before hitting a 429
+pub async fn fetch_rate_limits(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let rate_limits = match customer_rate_limits(&state, &customer) {
+        Ok(rate_limits) => rate_limits,
         Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(GenericResponse {
-                    message: APIMessages::Customer(CustomerMessages::ErrorHashingPassword)
-                        .to_string(),
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -544,54 +1021,412 @@ pub async fn update_password(
         }
     };
 
-    let customer = customer.unwrap();
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::RateLimits).to_string(),
+            data: json!({
+                "rate_limits": rate_limits,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// characters, not bytes, so accented names get the same room as ascii ones
+pub fn validate_name(state: &Arc<AppState>, name: &str) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let length = name.chars().count();
+    if length < 2 || length > state.name_max_length {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidNameLength).to_string(),
+                data: json!({"max_length": state.name_max_length}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    if state.name_confusable_check && has_confusable_script_mix(name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::ConfusableName).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_language(state: &Arc<AppState>, language: &str) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    let language = language.trim().to_lowercase();
+    if !state.supported_languages.contains(&language) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::UnsupportedLanguage).to_string(),
+                data: json!({
+                    "supported_languages": state.supported_languages,
+                }),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(language)
+}
+
+// checks the old password and the new one's rules, returns the hash to store
+pub async fn validate_password_change(
+    customer: &Customer,
+    payload: &CustomerUpdatePassword,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    let bad_request = |message: InputMessages| {
+        Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(message).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ))
+    };
+
+    if payload.old_password.len() < 8 || payload.old_password.len() > 100 {
+        return bad_request(InputMessages::InvalidOldPasswordLength);
+    }
+
+    if payload.new_password.len() < 8 || payload.new_password.len() > 100 {
+        return bad_request(InputMessages::InvalidNewPasswordLength);
+    }
+
+    valid_password(&payload.new_password).await?;
+
+    if payload.new_password == payload.old_password {
+        return bad_request(InputMessages::NewPasswordAndOldPasswordMustBeDifferent);
+    }
+
+    if payload.new_password != payload.new_password_confirmation {
+        return bad_request(InputMessages::NewPasswordConfirmationMustMatch);
+    }
+
+    let emails = customer
+        .emails
+        .iter()
+        .map(|email| email.address.clone())
+        .collect::<Vec<String>>();
+
+    password_differs_from_emails(&payload.new_password, &emails).await?;
 
     match verify(&payload.old_password, &customer.password) {
-        Ok(is_valid) => {
-            if !is_valid {
-                return (
-                    StatusCode::UNAUTHORIZED,
-                    Json(GenericResponse {
-                        message: APIMessages::Customer(CustomerMessages::IncorrectPassword)
-                            .to_string(),
-                        data: json!({}),
-                        exit_code: 1,
-                    }),
-                );
-            }
-        }
+        Ok(true) => (),
+        Ok(false) => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::IncorrectPassword).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        },
         Err(_) => {
-            return (
+            return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(GenericResponse {
-                    message: APIMessages::Customer(CustomerMessages::ErrorVerifyingPassword)
-                        .to_string(),
+                    message: APIMessages::Customer(CustomerMessages::ErrorVerifyingPassword).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
-            )
+            ))
         }
     };
 
-    let current_datetime = Utc::now();
-    let iso8601_string = current_datetime.to_rfc3339();
+    match hash(&payload.new_password, DEFAULT_COST) {
+        Ok(hashed_password) => Ok(hashed_password),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::ErrorHashingPassword).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+    }
+}
 
-    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
-    let update = doc! {"$set": {
-            "password": hashed_new_password,
-            "updated_at": iso8601_string,
-        }
+// PATCH /api/me, every present field is checked against its own scope and written in a single update
+pub async fn update_customer_profile(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerUpdate>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers.clone(), &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
-        Ok(_) => (
-            StatusCode::OK,
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let language = payload.preferences.as_ref().and_then(|preferences| preferences.language.as_ref());
+    let notifications = payload.preferences.as_ref().and_then(|preferences| preferences.notifications);
+    if payload.name.is_none() && language.is_none() && notifications.is_none() && payload.password.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
             Json(GenericResponse {
-                message: APIMessages::Customer(CustomerMessages::PasswordUpdated).to_string(),
+                message: APIMessages::Input(InputMessages::NothingToUpdate).to_string(),
                 data: json!({}),
-                exit_code: 0,
+                exit_code: 1,
             }),
-        ),
-        Err((status, json)) => return (status, json),
+        );
+    }
+
+    let mut required_scopes = vec![];
+    if payload.name.is_some() {
+        required_scopes.push(UPDATE_NAME_SCOPES.to_vec());
+    }
+    if language.is_some() || notifications.is_some() {
+        required_scopes.push(vec![SessionScopes::TotalAccess, SessionScopes::UpdatePreferences]);
+    }
+    if payload.password.is_some() {
+        required_scopes.push(vec![SessionScopes::TotalAccess]);
+    }
+
+    for required in required_scopes.iter() {
+        match require_any_scope(&session_data, required) {
+            Ok(_) => (),
+            Err((status_code, json)) => return (status_code, json),
+        };
+    }
+
+    let mut set_fields = doc! {};
+    let mut updated_fields = vec![];
+    let mut warnings = vec![];
+
+    // only the password and enabling notifications need the stored record
+    let customer = match payload.password.is_some() || notifications == Some(true) {
+        true => {
+            let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+            match find_customer(state.customers_db(&session_data.region), filter).await {
+                Ok((true, Some(customer))) => Some(customer),
+                Ok(_) => {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        Json(GenericResponse {
+                            message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                            data: json!({}),
+                            exit_code: 1,
+                        }),
+                    )
+                },
+                Err((status_code, json)) => return (status_code, json),
+            }
+        },
+        false => None,
+    };
+
+    if let Some(name) = &payload.name {
+        let name = name.trim();
+        match validate_name(&state, name) {
+            Ok(_) => (),
+            Err((status_code, json)) => return (status_code, json),
+        };
+
+        set_fields.insert("name", name);
+        updated_fields.push("name");
+    }
+
+    if let Some(language) = language {
+        let language = match validate_language(&state, language) {
+            Ok(language) => language,
+            Err((status_code, json)) => return (status_code, json),
+        };
+
+        set_fields.insert("preferences.language", language);
+        updated_fields.push("preferences.language");
+    }
+
+    if let Some(notifications) = notifications {
+        let deliverable = match &customer {
+            Some(customer) => customer.emails.iter().any(|email| email.verified && !email.disabled),
+            None => true, // only fetched when enabling
+        };
+        if notifications && !deliverable {
+            match state.unverified_notifications_policy {
+                UnverifiedNotificationsPolicy::Reject => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(GenericResponse {
+                            message: APIMessages::Email(EmailMessages::NoVerifiedEmail).to_string(),
+                            data: json!({"field": "preferences.notifications"}),
+                            exit_code: 1,
+                        }),
+                    )
+                },
+                UnverifiedNotificationsPolicy::Warn => warnings.push(APIMessages::Email(EmailMessages::NoVerifiedEmail).to_string()),
+            }
+        }
+
+        set_fields.insert("preferences.notifications", notifications);
+        updated_fields.push("preferences.notifications");
+    }
+
+    if let (Some(password), Some(customer)) = (&payload.password, &customer) {
+        let hashed_new_password = match validate_password_change(customer, password).await {
+            Ok(hashed_new_password) => hashed_new_password,
+            Err((status_code, json)) => return (status_code, json),
+        };
+
+        set_fields.insert("password", hashed_new_password);
+        updated_fields.push("password");
+    }
+
+    set_fields.insert("updated_at", Utc::now().to_rfc3339());
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    match update_customer(state.customers_db(&session_data.region), filter, doc! {"$set": set_fields}).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let mut data = json!({
+        "updated": updated_fields,
+    });
+
+    if !warnings.is_empty() {
+        data["warnings"] = json!(warnings);
+    }
+
+    // same as update_password, a new password ends every existing session
+    if payload.password.is_some() {
+        let (token, expires_in) = match rotate_session(&headers, &session_data, &state).await {
+            Ok(rotated) => rotated,
+            Err((status_code, json)) => return (status_code, json),
+        };
+
+        data["token"] = json!(token);
+        data["expires_in"] = json!(expires_in);
+    }
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Updated).to_string(),
+            data,
+            exit_code: 0,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::{email::UPDATE_EMAIL_SCOPES, identity::SessionData};
+    use crate::server::tests::test_state;
+
+    fn session(scopes: Vec<SessionScopes>) -> SessionData {
+        SessionData {
+            customer_id: String::from("customer"),
+            scopes,
+            region: String::new(),
+            impersonated_by: None,
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn a_total_access_session_can_update_its_name() {
+        assert!(require_any_scope(&session(vec![SessionScopes::TotalAccess]), &UPDATE_NAME_SCOPES).is_ok());
+    }
+
+    #[test]
+    fn a_total_access_session_can_add_an_email() {
+        assert!(require_any_scope(&session(vec![SessionScopes::TotalAccess]), &UPDATE_EMAIL_SCOPES).is_ok());
+    }
+
+    #[test]
+    fn the_update_name_scope_alone_is_enough() {
+        assert!(require_any_scope(&session(vec![SessionScopes::UpdateName]), &UPDATE_NAME_SCOPES).is_ok());
+    }
+
+    fn metadata(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn metadata_merges_into_the_existing_keys() {
+        let merged = merge_metadata(metadata(&[("crm", "1"), ("plan", "team")]), &metadata(&[("crm", "2"), ("source", "ads")]))
+            .await
+            .unwrap();
+
+        assert_eq!(merged, metadata(&[("crm", "2"), ("plan", "team"), ("source", "ads")]));
+    }
+
+    #[tokio::test]
+    async fn metadata_past_the_key_limit_is_rejected() {
+        let existing: HashMap<String, String> = (0..MAX_METADATA_KEYS).map(|index| (format!("key_{}", index), String::new())).collect();
+        let (status, Json(body)) = merge_metadata(existing, &metadata(&[("one_more", "value")])).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.message, APIMessages::Input(InputMessages::TooManyMetadataKeys).to_string());
+    }
+
+    #[tokio::test]
+    async fn oversized_metadata_values_are_rejected() {
+        let (_, Json(body)) = merge_metadata(HashMap::new(), &metadata(&[("crm", &"x".repeat(501))])).await.unwrap_err();
+        assert_eq!(body.message, APIMessages::Input(InputMessages::InvalidMetadataValueLength).to_string());
+    }
+
+    #[test]
+    fn unrelated_scopes_cannot_update_the_name() {
+        let (status, _) = require_any_scope(&session(vec![SessionScopes::ViewSubscription]), &UPDATE_NAME_SCOPES).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // test_state's mongo is unreachable, so an insert or lookup would come back as an error
+    #[tokio::test]
+    async fn a_dry_run_validates_without_touching_the_database() {
+        let state = Arc::new(test_state().await);
+        let payload: CreateCustomerRecord = serde_json::from_value(json!({
+            "name": "Ada Lovelace",
+            "email": "ada@example.com",
+            "password": "Engine_1843",
+            "password_confirmation": "Engine_1843",
+            "accepted_terms": true,
+            "provider": "legacy",
+        }))
+        .unwrap();
+
+        let (status, Json(body)) = process_customer_record_creation(Ok(Json(payload)), state, true).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::DryRunValidated).to_string());
+        assert_eq!(body.data["dry_run"], true);
+        assert_eq!(body.data["customer"]["emails"][0]["address"], "ada@example.com");
+    }
+
+    #[tokio::test]
+    async fn signups_without_an_invite_code_are_rejected_when_one_is_required() {
+        let mut state = test_state().await;
+        state.require_invite_code = true;
+        let payload: CreateCustomerRecord = serde_json::from_value(json!({
+            "name": "Ada Lovelace",
+            "email": "ada@example.com",
+            "password": "Engine_1843",
+            "password_confirmation": "Engine_1843",
+            "accepted_terms": true,
+            "provider": "legacy",
+        }))
+        .unwrap();
+
+        let (status, Json(body)) = process_customer_record_creation(Ok(Json(payload)), Arc::new(state), true).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::InvalidInviteCode).to_string());
+    }
+}
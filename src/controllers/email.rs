@@ -1,14 +1,18 @@
 use std::sync::Arc;
 
-use axum::{extract::{rejection::JsonRejection, Query}, http::{HeaderMap, StatusCode}, Json};
-use chrono::Utc;
+use axum::{extract::{rejection::JsonRejection, Query}, http::{header::{ACCEPT, LOCATION}, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json};
+use reqwest::Url;
+use chrono::{DateTime, Duration, Utc};
 use mongodb::bson::doc;
 use redis::{Commands, RedisError};
 use serde_json::json;
 
-use crate::{email::brevo_api::send_verification_email, server::AppState, storage::mongo::{build_customer_filter, find_customer, update_customer}, types::{customer::{Email, GenericResponse}, email::SendEmailData, incoming_requests::{CustomerAddEmail, VerifyEmailQueryParams}}, utilities::{api_messages::{APIMessages, CustomerMessages, EmailMessages, RedisMessages, TokenMessages}, helpers::{payload_analyzer, random_string, valid_email}}};
+use crate::{email::brevo_api::{build_verification_email_request, send_verification_email}, server::AppState, storage::mongo::{build_customer_filter, find_customer, find_customer_in, update_customer, update_customer_matched}, types::{customer::{Email, GenericResponse}, email::SendEmailData, incoming_requests::{CustomerAddEmail, CustomerToggleEmail, VerifyEmailQueryParams}}, utilities::{api_messages::{APIMessages, CustomerMessages, EmailMessages, RedisMessages, TokenMessages}, email::{check_email_domain, consume_email_send_budget, email_verification_key, pending_verification_key}, helpers::{payload_analyzer, random_string, valid_email}, integration_webhook::dispatch_email_verified}};
 
-use super::identity::{get_user_session_from_req, SessionScopes};
+use super::identity::{get_user_session_from_req, require_any_scope, SessionScopes};
+
+// any one of them is enough, a first party login only carries TotalAccess
+pub const UPDATE_EMAIL_SCOPES: [SessionScopes; 2] = [SessionScopes::TotalAccess, SessionScopes::UpdateEmailAddresses];
 
 pub async fn add_email(
     headers: HeaderMap,
@@ -20,21 +24,10 @@ pub async fn add_email(
         Err((status_code, json)) => return (status_code, json),
     };
 
-    if !(session_data.scopes.contains(&SessionScopes::TotalAccess)
-        && session_data
-            .scopes
-            .contains(&SessionScopes::UpdateEmailAddresses))
-    {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(GenericResponse {
-                message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction)
-                    .to_string(),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        );
-    }
+    match require_any_scope(&session_data, &UPDATE_EMAIL_SCOPES) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
 
     let payload = match payload_analyzer(payload_result) {
         Ok(payload) => payload,
@@ -42,7 +35,7 @@ pub async fn add_email(
     };
 
     let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter).await {
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
         Ok(customer) => customer,
         Err((status, json)) => return (status, json),
     };
@@ -78,6 +71,11 @@ pub async fn add_email(
         Err((status_code, json)) => return (status_code, json),
     };
 
+    match check_email_domain(&state, &email).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
     for registered_email in emails.iter() {
         if registered_email.address == email {
             return (
@@ -92,7 +90,7 @@ pub async fn add_email(
     }
 
     let filter = build_customer_filter("", email.as_str()).await;
-    let (found, customer_with_current_email) = match find_customer(&state.mongo_db, filter).await {
+    let (found, customer_with_current_email) = match find_customer_in(state.all_customers_dbs(), filter).await {
         Ok(customer) => customer,
         Err((status, json)) => return (status, json),
     };
@@ -123,6 +121,8 @@ pub async fn add_email(
         address: email.clone(),
         verified: false,
         main: false,
+        disabled: false,
+        disabled_at: String::new(),
     });
 
     let bson_emails = emails
@@ -132,10 +132,18 @@ pub async fn add_email(
                 "address": &email.address,
                 "verified": &email.verified,
                 "main": &email.main,
+                "disabled": &email.disabled,
+                "disabled_at": &email.disabled_at,
             }
         })
         .collect::<Vec<_>>();
 
+    // checked before the address is stored, an exhausted budget leaves the customer untouched
+    match consume_email_send_budget(&state, &customer.id) {
+        Ok(_) => (),
+        Err((status, json)) => return (status, json),
+    };
+
     let current_datetime = Utc::now();
     let iso8601_string = current_datetime.to_rfc3339();
 
@@ -146,7 +154,7 @@ pub async fn add_email(
         }
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
         Ok(_) => {
             let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
                 Ok(api_key) => api_key,
@@ -170,6 +178,7 @@ pub async fn add_email(
                 api_key,
                 email,
                 customer.name,
+                state.email_provider_settings.email_verification_template_id,
             ).await {
                 Ok(_) => (),
                 Err((status, json)) => return (status, json),
@@ -186,7 +195,275 @@ pub async fn add_email(
     }
 }
 
-pub async fn verify_email(
+// lets a landing page inspect the token before the customer confirms, the token is left untouched
+// GET /api/me/emails, every address with its flags, pending means a verification link is still outstanding
+pub async fn list_emails(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::ViewEmailAddresses]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let mut redis_conn = match state.redis_connection.get_connection() {
+        Ok(redis_conn) => redis_conn,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::FailedToConnect).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let mut emails = vec![];
+    let (mut verified_count, mut pending_count) = (0, 0);
+    for email in customer.emails.iter() {
+        let pending = match email.verified {
+            true => false,
+            false => match redis_conn.exists(pending_verification_key(&email.address)) {
+                Ok(pending) => pending,
+                Err(_) => {
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(GenericResponse {
+                            message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                            data: json!({}),
+                            exit_code: 1,
+                        }),
+                    )
+                }
+            },
+        };
+
+        if email.verified {
+            verified_count += 1;
+        }
+        if pending {
+            pending_count += 1;
+        }
+
+        emails.push(json!({
+            "address": email.address,
+            "verified": email.verified,
+            "main": email.main,
+            "pending": pending,
+            "disabled": email.disabled,
+            "recoverable_until": email.recoverable_until(state.email_provider_settings.email_recovery_window_days).map(|until| until.to_rfc3339()),
+        }));
+    }
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Email(EmailMessages::Listed).to_string(),
+            data: json!({
+                "emails": emails,
+                "verified": verified_count,
+                "pending": pending_count,
+                "total": customer.emails.len(),
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// POST /api/me/email/disable, the address stays on the account until the recovery window passes
+pub async fn disable_email(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerToggleEmail>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    set_email_disabled(headers, payload_result, state, true).await
+}
+
+// POST /api/me/email/enable, only within the recovery window, past it the address is removed
+pub async fn enable_email(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerToggleEmail>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    set_email_disabled(headers, payload_result, state, false).await
+}
+
+async fn set_email_disabled(
+    headers: HeaderMap,
+    payload_result: Result<Json<CustomerToggleEmail>, JsonRejection>,
+    state: Arc<AppState>,
+    disabled: bool,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &UPDATE_EMAIL_SCOPES) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let address = payload.email.to_lowercase();
+    let email = match customer.emails.iter().find(|email| email.address == address) {
+        Some(email) => email,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    // the main address is what notifications and recovery fall back to
+    if disabled && email.main {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Email(EmailMessages::CannotDisableMainEmail).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let recovery_window_days = state.email_provider_settings.email_recovery_window_days;
+    let now = Utc::now();
+
+    // nothing to change, answered like a success so retries are harmless
+    if email.disabled == disabled {
+        let message = match disabled {
+            true => EmailMessages::Disabled,
+            false => EmailMessages::Enabled,
+        };
+
+        return (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Email(message).to_string(),
+                data: json!({
+                    "address": address,
+                    "recoverable_until": email.recoverable_until(recovery_window_days).map(|until| until.to_rfc3339()),
+                }),
+                exit_code: 0,
+            }),
+        );
+    }
+
+    let filter = doc! {"id": &customer.id, "emails.address": &address};
+    if !disabled {
+        if recovery_window_expired(email, recovery_window_days, now) {
+            let update = doc! {
+                "$pull": {"emails": {"address": &address}},
+                "$set": {"updated_at": now.to_rfc3339()},
+            };
+
+            match update_customer(state.customers_db(&session_data.region), filter, update).await {
+                Ok(_) => (),
+                Err((status_code, json)) => return (status_code, json),
+            };
+
+            return (
+                StatusCode::GONE,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::RecoveryWindowExpired).to_string(),
+                    data: json!({"address": address}),
+                    exit_code: 1,
+                }),
+            );
+        }
+    }
+
+    let disabled_at = match disabled {
+        true => now.to_rfc3339(),
+        false => String::new(),
+    };
+
+    let update = doc! {"$set": {
+        "emails.$.disabled": disabled,
+        "emails.$.disabled_at": &disabled_at,
+        "updated_at": now.to_rfc3339(),
+    }};
+
+    match update_customer(state.customers_db(&session_data.region), filter, update).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // outstanding verification links are refused while disabled, no need to show them as pending
+    if disabled {
+        if let Ok(mut redis_conn) = state.redis_connection.get_connection() {
+            let _: Result<i64, RedisError> = redis_conn.del(pending_verification_key(&address));
+        }
+    }
+
+    let (message, recoverable_until) = match disabled {
+        true => (EmailMessages::Disabled, Some((now + Duration::days(recovery_window_days)).to_rfc3339())),
+        false => (EmailMessages::Enabled, None),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Email(message).to_string(),
+            data: json!({
+                "address": address,
+                "recoverable_until": recoverable_until,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn check_email_verification_token(
     Query(params): Query<VerifyEmailQueryParams>,
     state: Arc<AppState>,
 ) -> (StatusCode, Json<GenericResponse>) {
@@ -218,7 +495,7 @@ pub async fn verify_email(
         }
     };
 
-    let customer_email_address: String = match redis_conn.get(token.clone()) {
+    let customer_email_address: Option<String> = match redis_conn.get(email_verification_key(&token)) {
         Ok(customer_email_address) => customer_email_address,
         Err(_) => {
             return (
@@ -232,6 +509,151 @@ pub async fn verify_email(
         }
     };
 
+    match customer_email_address {
+        Some(email) if !email.is_empty() => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Email(EmailMessages::VerificationTokenValid).to_string(),
+                data: json!({
+                    "valid": true,
+                    "email": email,
+                }),
+                exit_code: 0,
+            }),
+        ),
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Email(EmailMessages::VerificationTokenInvalid).to_string(),
+                data: json!({
+                    "valid": false,
+                }),
+                exit_code: 1,
+            }),
+        ),
+    }
+}
+
+// browsers ask for html first, api clients for json or anything
+pub fn prefers_html(headers: &HeaderMap) -> bool {
+    let accept = match headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept.to_lowercase(),
+        None => return false,
+    };
+
+    accept.contains("text/html") && !accept.contains("application/json")
+}
+
+// both urls are validated at startup, a missing one keeps the json response for that outcome
+fn verification_redirect(state: &Arc<AppState>, status_code: StatusCode, message: &str) -> Option<Response> {
+    let settings = &state.email_provider_settings;
+    let mut url = match status_code.is_success() {
+        true => Url::parse(settings.verify_success_url.as_ref()?).ok()?,
+        false => Url::parse(settings.verify_failure_url.as_ref()?).ok()?,
+    };
+
+    if !status_code.is_success() {
+        url.query_pairs_mut().append_pair("reason", message);
+    }
+
+    Some((StatusCode::FOUND, [(LOCATION, url.to_string())]).into_response())
+}
+
+// GET on the link never consumes the token, mail scanners and prefetchers open links too
+pub async fn open_verification_link(
+    headers: HeaderMap,
+    query: Query<VerifyEmailQueryParams>,
+    state: Arc<AppState>,
+) -> Response {
+    if prefers_html(&headers) {
+        let landing_url = state.email_provider_settings.verify_landing_url.as_ref().and_then(|url| Url::parse(url).ok());
+        if let (Some(mut url), Some(token)) = (landing_url, &query.token) {
+            url.query_pairs_mut().append_pair("token", token);
+            return (StatusCode::FOUND, [(LOCATION, url.to_string())]).into_response();
+        }
+    }
+
+    check_email_verification_token(query, state).await.into_response()
+}
+
+// the landing page posts the token back, browsers get redirected to a result page instead of raw json
+pub async fn verify_email(
+    headers: HeaderMap,
+    query: Query<VerifyEmailQueryParams>,
+    state: Arc<AppState>,
+) -> Response {
+    let (status_code, json) = apply_email_verification(query, &state).await;
+    if prefers_html(&headers) {
+        if let Some(redirect) = verification_redirect(&state, status_code, &json.message) {
+            return redirect;
+        }
+    }
+
+    (status_code, json).into_response()
+}
+
+// the address may have been removed after the token was issued
+pub fn verification_matched(matched: u64) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    if matched > 0 {
+        return Ok(());
+    }
+
+    Err((
+        StatusCode::NOT_FOUND,
+        Json(GenericResponse {
+            message: APIMessages::Email(EmailMessages::NotFound).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    ))
+}
+
+async fn apply_email_verification(
+    Query(params): Query<VerifyEmailQueryParams>,
+    state: &Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let token = match params.token {
+        Some(token) => token,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::Missing).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let mut redis_conn = match state.redis_connection.get_connection() {
+        Ok(redis_conn) => redis_conn,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::FailedToConnect).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let customer_email_address: String = match redis_conn.get::<String, Option<String>>(email_verification_key(&token)) {
+        Ok(customer_email_address) => customer_email_address.unwrap_or_default(),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
     if customer_email_address.is_empty() {
         return (
             StatusCode::UNAUTHORIZED,
@@ -243,8 +665,9 @@ pub async fn verify_email(
         );
     }
 
+    // a disabled address keeps its token unusable until it's enabled again
     let filter = doc! {
-        "emails.address": customer_email_address,
+        "emails": {"$elemMatch": {"address": customer_email_address.clone(), "disabled": {"$ne": true}}},
     };
 
     let update = doc! {
@@ -253,12 +676,27 @@ pub async fn verify_email(
         }
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
-        Ok(_) => (),
-        Err((status, json)) => return (status, json),
-    };
+    // the token only knows the address, so every region is tried until one matches
+    let mut matched = 0;
+    let mut matched_db = None;
+    for db in state.all_customers_dbs() {
+        matched = match update_customer_matched(db, filter.clone(), update.clone()).await {
+            Ok(matched) => matched,
+            Err((status, json)) => return (status, json),
+        };
+
+        if matched > 0 {
+            matched_db = Some(db);
+            break;
+        }
+    }
 
-    let result: Result<bool, RedisError> = redis_conn.del(token.clone());
+    // returned before the token is deleted, so a retry is possible
+    if let Err(response) = verification_matched(matched) {
+        return response;
+    }
+
+    let result: Result<bool, RedisError> = redis_conn.del(email_verification_key(&token));
     match result {
         Ok(_) => (),
         Err(_) => {
@@ -273,6 +711,16 @@ pub async fn verify_email(
         }
     };
 
+    let _: Result<i64, RedisError> = redis_conn.del(pending_verification_key(&customer_email_address));
+
+    // the address is already verified, a failed lookup only costs the integrator their notification
+    if let (Some(_), Some(db)) = (&state.integration_webhook, matched_db) {
+        match find_customer(db, filter).await {
+            Ok((true, Some(customer))) => dispatch_email_verified(&state.integration_webhook, &customer.id, &customer_email_address),
+            _ => log::error!("verified customer not found, email.verified webhook skipped"),
+        };
+    }
+
     (
         StatusCode::OK,
         Json(GenericResponse {
@@ -283,11 +731,204 @@ pub async fn verify_email(
     )
 }
 
+// sends the verification template with sample data so the template configuration can be checked end to end
+pub async fn send_test_verification_email(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
+        Ok(api_key) if state.enabled_email_integration => api_key,
+        _ => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(GenericResponse {
+                    message: APIMessages::ServiceUnavailable.to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status, json)) => return (status, json),
+    };
+
+    // only verified addresses, so the endpoint can't be used to email arbitrary inboxes
+    let email = match customer.emails.iter().find(|email| email.verified && !email.disabled) {
+        Some(email) => email.address.clone(),
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::NoVerifiedEmail).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    match consume_email_send_budget(&state, &customer.id) {
+        Ok(_) => (),
+        Err((status, json)) => return (status, json),
+    };
+
+    let template_id = state.email_provider_settings.email_verification_template_id;
+    let send_email_data = SendEmailData {
+        api_key,
+        subject: "[Test] Verify Your Email Address".to_string(),
+        template_id,
+        customer_email: email.clone(),
+        customer_name: customer.name.clone(),
+        verification_link: format!("{}?token=sample", state.google_auth.redirect_url),
+        greetings_title: format!("Welcome to Test App {}", customer.name),
+        sender_email: state.master_email_entity.email.clone(),
+        sender_name: state.master_email_entity.name.clone(),
+    };
+
+    let message_id = match send_verification_email(send_email_data).await {
+        Ok(message_id) => message_id,
+        Err(_) => {
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::ErrorSendingVerificationEmail)
+                        .to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Email(EmailMessages::TestEmailSent).to_string(),
+            data: json!({
+                "message_id": message_id,
+                "email": email,
+                "template_id": template_id,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// shared by the real send and the preview so both render the same params
+fn verification_email_data(
+    state: &Arc<AppState>,
+    api_key: String,
+    customer_email: String,
+    customer_name: String,
+    template_id: u32,
+    token: &str,
+) -> SendEmailData {
+    SendEmailData {
+        api_key,
+        subject: "Verify Your New Email Address".to_string(),
+        template_id,
+        customer_email,
+        greetings_title: format!("Welcome to Test App {}", customer_name),
+        customer_name,
+        verification_link: format!("{}?token={}", state.google_auth.redirect_url, token),
+        sender_email: state.master_email_entity.email.clone(),
+        sender_name: state.master_email_entity.name.clone(),
+    }
+}
+
+// GET /api/me/email/verification/preview, nothing is sent and no token is issued
+pub async fn preview_verification_email(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => customer,
+        Ok(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err((status, json)) => return (status, json),
+    };
+
+    let email = match customer.primary_email() {
+        Some(email) => email.address.clone(),
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::NoEmailOnRecord).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    // the api key isn't part of the body, the placeholder token stands in for a real one
+    let template_id = state.email_provider_settings.email_verification_template_id;
+    let send_email_data = verification_email_data(&state, String::new(), email, customer.name.clone(), template_id, "preview");
+    let request = build_verification_email_request(&send_email_data);
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Email(EmailMessages::VerificationPreview).to_string(),
+            data: json!({
+                "template_id": template_id,
+                "request": request,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
 pub async fn new_email_verification(
     state: &Arc<AppState>,
     api_key: String,
     customer_email: String,
     customer_name: String,
+    template_id: u32,
 ) -> Result<(), (StatusCode, Json<GenericResponse>)> {
     let new_token = random_string(30).await;
     let mut redis_conn = match state.redis_connection.get_connection() {
@@ -304,11 +945,9 @@ pub async fn new_email_verification(
         }
     };
 
-    let result: Result<bool, RedisError> = redis_conn.set_ex(
-        new_token.clone(),
-        &customer_email,
-        state.api_tokens_expiration_time.try_into().unwrap_or(86000),
-    );
+    // the pending marker shares the token ttl so neither outlives the other
+    let token_ttl = state.email_provider_settings.email_verification_ttl;
+    let result: Result<bool, RedisError> = redis_conn.set_ex(email_verification_key(&new_token), &customer_email, token_ttl);
 
     match result {
         Ok(_) => (),
@@ -324,19 +963,13 @@ pub async fn new_email_verification(
         }
     };
 
-    let greetings_title = format!("Welcome to Test App {}", customer_name);
-    let verification_link = format!("{}?token={}", state.google_auth.redirect_url, new_token);
-    let send_email_data = SendEmailData {
-        api_key,
-        subject: "Verify Your New Email Address".to_string(),
-        template_id: state.email_provider_settings.email_verification_template_id,
-        customer_email: customer_email,
-        customer_name: customer_name.clone(),
-        verification_link,
-        greetings_title,
-        sender_email: state.master_email_entity.email.clone(),
-        sender_name: state.master_email_entity.name.clone(),
-    };
+    // only a flag, the key is guessable so it must never hold the token
+    let pending: Result<bool, RedisError> = redis_conn.set_ex(pending_verification_key(&customer_email), 1, token_ttl);
+    if pending.is_err() {
+        log::error!("error marking {} as pending verification", customer_email);
+    }
+
+    let send_email_data = verification_email_data(state, api_key, customer_email, customer_name, template_id, &new_token);
 
     match send_verification_email(send_email_data).await {
         Ok(_) => (),
@@ -355,3 +988,66 @@ pub async fn new_email_verification(
 
     Ok(())
 }
+
+// an unreadable timestamp counts as expired, the address can still be added again
+pub fn recovery_window_expired(email: &Email, recovery_window_days: i64, now: DateTime<Utc>) -> bool {
+    match email.recoverable_until(recovery_window_days) {
+        Some(until) => until < now,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mongo::build_login_filter;
+
+    fn disabled_email(disabled_at: String) -> Email {
+        Email {
+            address: String::from("ada@work.example.com"),
+            verified: true,
+            main: false,
+            disabled: true,
+            disabled_at,
+        }
+    }
+
+    #[test]
+    fn an_address_removed_before_verification_is_not_found() {
+        let (status, Json(body)) = verification_matched(0).unwrap_err();
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.message, APIMessages::Email(EmailMessages::NotFound).to_string());
+    }
+
+    #[test]
+    fn a_matched_address_is_verified() {
+        assert!(verification_matched(1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_disabled_email_cannot_sign_in() {
+        let filter = build_login_filter("ada@work.example.com").await;
+        let email_match = filter.get_document("emails").unwrap().get_document("$elemMatch").unwrap();
+
+        assert_eq!(email_match.get_str("address").unwrap(), "ada@work.example.com");
+        assert_eq!(email_match.get_document("disabled").unwrap(), &doc! {"$ne": true});
+    }
+
+    #[test]
+    fn a_disabled_email_can_be_enabled_within_the_window() {
+        let now = Utc::now();
+        let email = disabled_email((now - Duration::days(10)).to_rfc3339());
+
+        assert!(email.recoverable_until(30).unwrap() > now + Duration::days(19));
+        assert!(!recovery_window_expired(&email, 30, now));
+    }
+
+    #[test]
+    fn a_disabled_email_is_gone_after_the_window() {
+        let now = Utc::now();
+
+        assert!(recovery_window_expired(&disabled_email((now - Duration::days(31)).to_rfc3339()), 30, now));
+        assert!(recovery_window_expired(&disabled_email(String::from("not a date")), 30, now));
+    }
+}
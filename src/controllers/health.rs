@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, Json};
+use chrono::DateTime;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    email::brevo_api::send_account_info_request,
+    server::AppState,
+    types::customer::GenericResponse,
+    utilities::api_messages::APIMessages,
+};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("GIT_SHA"); // set by build.rs
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP"); // unix seconds, set by build.rs
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub enum IntegrationState {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrationStatus {
+    pub name: String,
+    pub state: IntegrationState,
+    pub detail: Option<String>,
+}
+
+// disabled integrations don't count against the overall health
+pub fn aggregate_integrations(integrations: &[IntegrationStatus]) -> bool {
+    integrations
+        .iter()
+        .all(|integration| integration.state != IntegrationState::Error)
+}
+
+async fn check_brevo(state: &Arc<AppState>) -> IntegrationStatus {
+    let name = String::from("brevo");
+    if !state.enabled_email_integration {
+        return IntegrationStatus { name, state: IntegrationState::Disabled, detail: None };
+    }
+
+    let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
+        Ok(api_key) => api_key,
+        Err(_) => {
+            return IntegrationStatus {
+                name,
+                state: IntegrationState::Disabled,
+                detail: Some(String::from("BREVO_CUSTOMERS_WEBFLOW_API_KEY isn't set")),
+            }
+        }
+    };
+
+    match send_account_info_request(&api_key).await {
+        Ok(_) => IntegrationStatus { name, state: IntegrationState::Ok, detail: None },
+        Err(err) => IntegrationStatus {
+            name,
+            state: IntegrationState::Error,
+            detail: Some(err.to_string()),
+        },
+    }
+}
+
+// lemonsqueezy has no endpoint to validate a signing secret, so only its format is checked
+fn check_lemonsqueezy(state: &Arc<AppState>) -> IntegrationStatus {
+    let name = String::from("lemonsqueezy");
+    let key_length = state.lemonsqueezy_webhook_signature_key.len();
+
+    if std::env::var("LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY").is_err() {
+        return IntegrationStatus {
+            name,
+            state: IntegrationState::Error,
+            detail: Some(String::from("LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY isn't set")),
+        };
+    }
+
+    if !(6..=40).contains(&key_length) {
+        return IntegrationStatus {
+            name,
+            state: IntegrationState::Error,
+            detail: Some(String::from("signature key must be between 6 and 40 characters")),
+        };
+    }
+
+    IntegrationStatus { name, state: IntegrationState::Ok, detail: None }
+}
+
+pub async fn check_integrations(state: Arc<AppState>) -> (StatusCode, Json<GenericResponse>) {
+    let integrations = vec![check_brevo(&state).await, check_lemonsqueezy(&state)];
+
+    match aggregate_integrations(&integrations) {
+        true => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: String::from("OK"),
+                data: json!({
+                    "integrations": integrations,
+                }),
+                exit_code: 0,
+            }),
+        ),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(GenericResponse {
+                message: APIMessages::ServiceUnavailable.to_string(),
+                data: json!({
+                    "integrations": integrations,
+                }),
+                exit_code: 1,
+            }),
+        ),
+    }
+}
+
+// GET /api, unknown paths below it still get the fallback 404
+pub async fn fetch_api_index() -> (StatusCode, Json<GenericResponse>) {
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: String::from("OK"),
+            data: json!({
+                "version": VERSION,
+                "version_endpoint": "/api/public/version",
+                "groups": {
+                    "public": "/api/public",
+                    "customers": "/api/customers",
+                    "me": "/api/me",
+                    "identity": "/api/identity",
+                    "webhooks": "/api/webhooks",
+                    "admin": "/api/admin",
+                },
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn fetch_version() -> (StatusCode, Json<GenericResponse>) {
+    let built_at = BUILD_TIMESTAMP
+        .parse::<i64>()
+        .ok()
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(|built_at| built_at.to_rfc3339())
+        .unwrap_or_default();
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: String::from("OK"),
+            data: json!({
+                "version": VERSION,
+                "git_sha": GIT_SHA,
+                "built_at": built_at,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
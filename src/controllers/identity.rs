@@ -1,14 +1,21 @@
-use crate::oauth::google::{get_google_user, request_token};
+use crate::oauth::google::{authorization_url, claim_authorization_code, get_google_user, release_authorization_code, request_token, OAuthResponse, OAuthTokenError};
 use crate::utilities::api_messages::{APIMessages, CustomerMessages, EmailMessages, RedisMessages, TokenMessages};
-use crate::utilities::helpers::payload_analyzer;
+use crate::email::brevo_api::send_verification_email;
+use crate::utilities::email::consume_email_send_budget;
+use crate::utilities::helpers::{payload_analyzer, random_string, valid_picture_url};
+use crate::utilities::token_delivery::cleared_session_cookie;
 use crate::server::AppState;
-use crate::storage::mongo::{build_customer_filter, find_customer};
-use crate::utilities::token::{create_token, extract_token_from_headers, get_session_from_redis, get_token_payload, string_to_scopes, validate_token};
-use crate::types::customer::{AuthProviders, GenericResponse};
-use crate::types::incoming_requests::SignIn;
+use crate::storage::mongo::{build_customer_filter, build_login_filter, consume_backup_security_code, find_customer, find_customer_in, linked_provider_filter, update_customer};
+use crate::storage::redis::{is_connection_error, with_retry};
+use crate::utilities::token::{create_impersonation_token, create_token, create_token_with_ttl, extract_token_from_headers, get_session_from_redis, get_token_payload, revoke_customer_sessions, revoke_session, string_to_scopes, track_session, validate_token};
+use crate::types::customer::{AuthProviders, Customer, CustomerStatus, GenericResponse, PrivateSensitiveCustomer};
+use crate::types::subscription::{next_renewal, SubscriptionView};
+use crate::types::email::SendEmailData;
+use crate::types::incoming_requests::{MagicLinkQueryParams, MagicLinkRequest, RecoverySignIn, SessionElevation, SignIn};
 
 use axum::extract::Query;
-use axum::http::HeaderMap;
+use axum::http::{header::SET_COOKIE, HeaderMap, HeaderValue};
+use axum::response::{Html, IntoResponse, Response};
 use axum::{
     extract::rejection::JsonRejection, 
     http::StatusCode, Json
@@ -17,24 +24,31 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bcrypt::verify;
+use chrono::Utc;
+use log::{info, warn};
+use mongodb::bson::doc;
 use redis::{Client, Commands, RedisError};
 use serde_json::json;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum SessionScopes {
     ViewPublicID,
     ViewEmailAddresses,
     ViewPublicProfile,
     ViewPrivateSensitiveProfile,
     ViewSubscription,
+    ViewMetadata,
     
     UpdateName,
     UpdateEmailAddresses,
     UpdatePreferences,
+    UpdateMetadata,
 
     TotalAccess, // never use this for 3rd party apps
+    AdminAccess, // only granted to ADMIN_CUSTOMER_IDS
 }
 
 impl ToString for SessionScopes {
@@ -45,12 +59,15 @@ impl ToString for SessionScopes {
             SessionScopes::ViewPublicProfile => String::from("view_public_profile"),
             SessionScopes::ViewPrivateSensitiveProfile => String::from("view_private_sensitive_profile"),
             SessionScopes::ViewSubscription => String::from("view_subscription"),
+            SessionScopes::ViewMetadata => String::from("view_metadata"),
             
             SessionScopes::UpdateName => String::from("update_name"),
             SessionScopes::UpdateEmailAddresses => String::from("update_email_addresses"),
             SessionScopes::UpdatePreferences => String::from("update_preferences"),
+            SessionScopes::UpdateMetadata => String::from("update_metadata"),
 
             SessionScopes::TotalAccess => String::from("total_access"),
+            SessionScopes::AdminAccess => String::from("admin_access"),
         }
     }
 }
@@ -65,21 +82,173 @@ impl FromStr for SessionScopes {
             "view_public_profile" => Ok(SessionScopes::ViewPublicProfile),
             "view_private_sensitive_profile" => Ok(SessionScopes::ViewPrivateSensitiveProfile),
             "view_subscription" => Ok(SessionScopes::ViewSubscription),
+            "view_metadata" => Ok(SessionScopes::ViewMetadata),
             
             "update_name" => Ok(SessionScopes::UpdateName),
             "update_email_addresses" => Ok(SessionScopes::UpdateEmailAddresses),
             "update_preferences" => Ok(SessionScopes::UpdatePreferences),
+            "update_metadata" => Ok(SessionScopes::UpdateMetadata),
 
             "total_access" => Ok(SessionScopes::TotalAccess),
+            "admin_access" => Ok(SessionScopes::AdminAccess),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// fields of fetch_customer_record_by_id, the scope table below decides which of them a session sees
+pub const CUSTOMER_RECORD_FIELDS: [&str; 16] = [
+    "id", "name", "class", "emails", "auth_provider", "preferences", "subscription", "next_renewal_at",
+    "metadata", "created_at", "updated_at", "deleted", "last_login_at", "last_login_provider", "linked_providers",
+    "picture",
+];
+
+// auth_provider is never redacted
+pub const ALWAYS_EXPOSED_FIELDS: [&str; 1] = ["auth_provider"];
+
+impl SessionScopes {
+    pub fn all() -> Vec<SessionScopes> {
+        vec![
+            SessionScopes::ViewPublicID,
+            SessionScopes::ViewEmailAddresses,
+            SessionScopes::ViewPublicProfile,
+            SessionScopes::ViewPrivateSensitiveProfile,
+            SessionScopes::ViewSubscription,
+            SessionScopes::ViewMetadata,
+            SessionScopes::UpdateName,
+            SessionScopes::UpdateEmailAddresses,
+            SessionScopes::UpdatePreferences,
+            SessionScopes::UpdateMetadata,
+            SessionScopes::TotalAccess,
+            SessionScopes::AdminAccess,
+        ]
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            SessionScopes::ViewPublicID => "read the customer id",
+            SessionScopes::ViewEmailAddresses => "read every email address and its verification state",
+            SessionScopes::ViewPublicProfile => "read the name, account class, preferences and account dates",
+            SessionScopes::ViewPrivateSensitiveProfile => "reserved, currently exposes nothing on its own",
+            SessionScopes::ViewSubscription => "read the subscription plan, status, dates and history",
+            SessionScopes::ViewMetadata => "read the customer metadata",
+            SessionScopes::UpdateName => "change the customer name",
+            SessionScopes::UpdateEmailAddresses => "add email addresses",
+            SessionScopes::UpdatePreferences => "change preferences such as the language",
+            SessionScopes::UpdateMetadata => "set and remove metadata keys",
+            SessionScopes::TotalAccess => "everything the customer can do, first party sessions only",
+            SessionScopes::AdminAccess => "admin endpoints, only granted to configured admins",
+        }
+    }
+
+    // customer record fields this scope reveals, on top of ALWAYS_EXPOSED_FIELDS
+    pub fn exposed_fields(&self) -> &'static [&'static str] {
+        match self {
+            SessionScopes::ViewPublicID => &["id"],
+            SessionScopes::ViewEmailAddresses => &["emails"],
+            SessionScopes::ViewPublicProfile => &["name", "class", "picture", "preferences", "created_at", "updated_at", "deleted"],
+            SessionScopes::ViewSubscription => &["subscription", "next_renewal_at"],
+            SessionScopes::ViewMetadata => &["metadata"],
+            SessionScopes::TotalAccess => &CUSTOMER_RECORD_FIELDS,
+            _ => &[],
+        }
+    }
+}
+
+pub fn exposed_customer_fields(scopes: &[SessionScopes]) -> Vec<&'static str> {
+    let mut fields = ALWAYS_EXPOSED_FIELDS.to_vec();
+    for scope in scopes.iter() {
+        for field in scope.exposed_fields().iter() {
+            if !fields.contains(field) {
+                fields.push(field);
+            }
+        }
+    }
+
+    fields
+}
+
+// the single redaction policy for customer records, every field starts hidden and only what the scopes expose is filled in
+pub fn redact_for_scopes(customer: Customer, scopes: &[SessionScopes]) -> PrivateSensitiveCustomer {
+    let exposed = exposed_customer_fields(scopes);
+    let expose = |field: &str| exposed.contains(&field);
+    let next_renewal_at = next_renewal(&customer.subscription).map(|date| date.to_rfc3339());
+
+    PrivateSensitiveCustomer {
+        id: expose("id").then_some(customer.id),
+        name: expose("name").then_some(customer.name),
+        class: expose("class").then_some(customer.class),
+        emails: expose("emails").then_some(customer.emails),
+        auth_provider: expose("auth_provider").then_some(customer.auth_provider),
+        preferences: expose("preferences").then_some(customer.preferences),
+        subscription: expose("subscription").then_some(SubscriptionView::from(customer.subscription)),
+        next_renewal_at: next_renewal_at.filter(|_| expose("next_renewal_at")),
+        metadata: expose("metadata").then_some(customer.metadata),
+        created_at: expose("created_at").then_some(customer.created_at),
+        updated_at: expose("updated_at").then_some(customer.updated_at),
+        deleted: expose("deleted").then_some(customer.deleted),
+        last_login_at: expose("last_login_at").then_some(customer.last_login_at),
+        last_login_provider: expose("last_login_provider").then_some(customer.last_login_provider),
+        linked_providers: expose("linked_providers").then_some(customer.linked_providers),
+        picture: customer.picture.filter(|_| expose("picture")),
+    }
+}
+
+// GET /api/public/scopes, lets integrators know what each scope reveals before asking for it
+pub async fn fetch_scopes_catalog() -> (StatusCode, Json<GenericResponse>) {
+    let catalog = SessionScopes::all()
+        .iter()
+        .map(|scope| {
+            json!({
+                "scope": scope.to_string(),
+                "description": scope.description(),
+                "exposed_fields": scope.exposed_fields(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: String::from("scopes catalog"),
+            data: json!({
+                "scopes": catalog,
+                "always_exposed_fields": ALWAYS_EXPOSED_FIELDS,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// GET /api/public/auth/providers, what a login screen can offer
+pub async fn fetch_auth_providers(state: Arc<AppState>) -> (StatusCode, Json<GenericResponse>) {
+    let magic_link_enabled = state.enabled_email_integration && std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY").is_ok();
+    let providers = vec![
+        json!({"provider": "legacy", "enabled": true, "start_url": null}),
+        json!({"provider": "magic_link", "enabled": magic_link_enabled, "start_url": null}),
+        json!({
+            "provider": "google",
+            "enabled": state.google_auth.is_configured(),
+            "start_url": format!("https://{}/api/identity/session/google/start", state.api_url),
+        }),
+    ];
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: String::from("auth providers"),
+            data: json!({"providers": providers}),
+            exit_code: 0,
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionData {
     pub customer_id: String,
     pub scopes: Vec<SessionScopes>,
+    pub region: String,
+    pub impersonated_by: Option<String>, // admin customer id when support is acting as the customer
 }
 
 pub async fn get_user_session_from_req(
@@ -87,30 +256,13 @@ pub async fn get_user_session_from_req(
     redis_connection: &Client,
 ) -> Result<SessionData, (StatusCode, Json<GenericResponse>)> {
     let token_string = extract_token_from_headers(&headers).await?;
-    let _ = match validate_token(token_string) {
-        Ok(_) => Ok(()),
-        Err(msg) => Err((
-            StatusCode::UNAUTHORIZED,
-            Json(GenericResponse {
-                message: String::from(format!("unauthorized: {}", msg)),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        )),
-    };
-
-    let customer_id = match get_session_from_redis(redis_connection, &token_string).await {
-        Ok(token) => token,
-        Err((status_code, json)) => return Err((status_code, json)),
-    };
-    
-    let token_data = match get_token_payload(&token_string) {
+    let token_data = match validate_token(token_string) {
         Ok(token_data) => token_data,
-        Err(_) => {
+        Err(msg) => {
             return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::UNAUTHORIZED,
                 Json(GenericResponse {
-                    message: APIMessages::Token(TokenMessages::ErrorParsingToken).to_string(),
+                    message: format!("unauthorized: {}", msg),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -118,6 +270,11 @@ pub async fn get_user_session_from_req(
         }
     };
 
+    let customer_id = match get_session_from_redis(redis_connection, token_string).await {
+        Ok(token) => token,
+        Err((status_code, json)) => return Err((status_code, json)),
+    };
+
     if customer_id != token_data.claims.sub {
         return Err((
             StatusCode::UNAUTHORIZED,
@@ -129,17 +286,236 @@ pub async fn get_user_session_from_req(
         ));
     }
 
-    let raw_scopes = token_data.claims.aud;
+    let raw_scopes = token_data.claims.aud.clone();
     let scopes: Vec<SessionScopes> = string_to_scopes(raw_scopes);
     
+    // every impersonated request is logged, not only the one that issued the token
+    let impersonated_by = match token_data.claims.impersonated_by.is_empty() {
+        true => None,
+        false => {
+            info!("impersonated request for {} by {}", customer_id, token_data.claims.impersonated_by);
+            Some(token_data.claims.impersonated_by)
+        },
+    };
+
     let session_data = SessionData {
         customer_id,
         scopes,
+        region: token_data.claims.region,
+        impersonated_by,
     };
 
     return Ok(session_data);
 }
 
+// lists what the action accepts next to what the token carries, so clients know what to re-authenticate with
+pub fn insufficient_scopes_response(
+    status_code: StatusCode,
+    required: &[SessionScopes],
+    granted: &[SessionScopes],
+) -> (StatusCode, Json<GenericResponse>) {
+    (
+        status_code,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction).to_string(),
+            data: json!({
+                "required": required.iter().map(|scope| scope.to_string()).collect::<Vec<String>>(),
+                "granted": granted.iter().map(|scope| scope.to_string()).collect::<Vec<String>>(),
+            }),
+            exit_code: 1,
+        }),
+    )
+}
+
+// suspension only blocks new sessions here, the ones already issued are revoked by the admin suspend
+pub fn ensure_not_suspended(customer: &Customer) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    if customer.status != CustomerStatus::Suspended {
+        return Ok(());
+    }
+
+    Err((
+        StatusCode::FORBIDDEN,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::Suspended).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    ))
+}
+
+// any one of the required scopes is enough
+pub fn require_any_scope(
+    session_data: &SessionData,
+    required: &[SessionScopes],
+) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    if required.iter().any(|scope| session_data.scopes.contains(scope)) {
+        return Ok(());
+    }
+
+    Err(insufficient_scopes_response(StatusCode::UNAUTHORIZED, required, &session_data.scopes))
+}
+
+// scopes granted to sessions started by the customer themselves
+pub fn first_party_scopes(state: &Arc<AppState>, customer_id: &String) -> Vec<SessionScopes> {
+    let mut scopes = vec![SessionScopes::TotalAccess];
+    if state.admin_customer_ids.contains(customer_id) {
+        scopes.push(SessionScopes::AdminAccess);
+    }
+
+    scopes
+}
+
+// fire-and-forget, a slow or failed write never delays nor breaks the sign in
+pub fn record_login(state: &Arc<AppState>, customer: &Customer, provider: &str) {
+    let state = state.clone();
+    let customer_id = customer.id.clone();
+    let region = customer.region.clone();
+    let provider = provider.to_string();
+
+    tokio::spawn(async move {
+        let update = doc! {"$set": {
+                "last_login_at": Utc::now().to_rfc3339(),
+                "last_login_provider": provider,
+            }
+        };
+
+        match update_customer(state.customers_db(&region), doc! {"id": &customer_id}, update).await {
+            Ok(_) => (),
+            Err(_) => warn!("error recording last login for {}", customer_id),
+        };
+    });
+}
+
+// creates the token, stores the session and tracks it so it can be revoked later
+pub async fn issue_session(
+    state: &Arc<AppState>,
+    customer_id: &String,
+    region: &str,
+    scopes: Vec<SessionScopes>,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    issue_session_with_ttl(state, customer_id, region, scopes, None).await
+}
+
+// same as issue_session, a ttl makes both the token and the stored session expire early
+pub async fn issue_session_with_ttl(
+    state: &Arc<AppState>,
+    customer_id: &String,
+    region: &str,
+    scopes: Vec<SessionScopes>,
+    ttl: Option<usize>,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    let token = match ttl {
+        Some(ttl) => create_token_with_ttl(customer_id, region, scopes, ttl),
+        None => create_token(customer_id, region, scopes),
+    };
+
+    let token = match token {
+        Ok(token) => token,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::ErrorCreating).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    store_session(state, customer_id, &token, ttl.unwrap_or(604800))?;
+
+    Ok(token)
+}
+
+pub const IMPERSONATION_SESSION_TTL: usize = 900;
+
+// read only, anything that changes the account needs a scope support never gets
+pub fn impersonation_scopes() -> Vec<SessionScopes> {
+    vec![
+        SessionScopes::ViewPublicID,
+        SessionScopes::ViewEmailAddresses,
+        SessionScopes::ViewPublicProfile,
+        SessionScopes::ViewPrivateSensitiveProfile,
+        SessionScopes::ViewSubscription,
+        SessionScopes::ViewMetadata,
+    ]
+}
+
+pub async fn issue_impersonation_session(
+    state: &Arc<AppState>,
+    customer: &Customer,
+    admin_id: &str,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    let token = match create_impersonation_token(&customer.id, &customer.region, impersonation_scopes(), IMPERSONATION_SESSION_TTL, admin_id) {
+        Ok(token) => token,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::ErrorCreating).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    // tracked like any other session, so revoking the customer's sessions ends it too
+    store_session(state, &customer.id, &token, IMPERSONATION_SESSION_TTL)?;
+
+    Ok(token)
+}
+
+// on top of the reduced scopes, for actions that must never happen on a customer's behalf
+pub fn forbid_impersonation(session_data: &SessionData) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    match &session_data.impersonated_by {
+        Some(admin_id) => Err((
+            StatusCode::FORBIDDEN,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::NotAllowedWhileImpersonating).to_string(),
+                data: json!({"impersonated_by": admin_id}),
+                exit_code: 1,
+            }),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn store_session(
+    state: &Arc<AppState>,
+    customer_id: &String,
+    token: &String,
+    ttl: usize,
+) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    // both writes are idempotent, so a retry after a dropped connection can safely repeat them
+    let result = with_retry(&state.redis_connection, |redis_conn| {
+        redis_conn.set_ex::<&String, &String, bool>(token, customer_id, ttl as u64)?;
+        track_session(redis_conn, customer_id, token)
+    });
+
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            let message = match is_connection_error(&err) {
+                true => RedisMessages::FailedToConnect,
+                false => RedisMessages::ErrorSettingKey,
+            };
+
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(message).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    Ok(())
+}
+
 pub async fn get_session(
     headers: HeaderMap,
     state: Arc<AppState>,
@@ -181,27 +557,18 @@ pub async fn renew_session(
         Err((status_code, json)) => return (status_code, json),
     };
 
-    let mut redis_conn = match state.redis_connection.get_connection() {
-        Ok(redis_conn) => redis_conn,
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(GenericResponse {
-                    message: APIMessages::Redis(RedisMessages::FailedToConnect).to_string(),
-                    data: json!({}),
-                    exit_code: 1,
-                }),
-            )
-        }
-    };
+    let customer_id: String = match with_retry(&state.redis_connection, |redis_conn| redis_conn.get::<_, Option<String>>(token_string)) {
+        Ok(customer_id) => customer_id.unwrap_or_default(),
+        Err(err) => {
+            let message = match is_connection_error(&err) {
+                true => RedisMessages::FailedToConnect,
+                false => RedisMessages::ErrorFetching,
+            };
 
-    let customer_id: String = match redis_conn.get(token_string.to_string()) {
-        Ok(customer_id) => customer_id,
-        Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(GenericResponse {
-                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                    message: APIMessages::Redis(message).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -220,9 +587,23 @@ pub async fn renew_session(
         );
     }
 
-    let result: Result<bool, RedisError> =
-        redis_conn
-            .set_ex(token_string.to_string(), customer_id, 604800);
+    // impersonation sessions end on schedule
+    if let Ok(token_data) = get_token_payload(token_string) {
+        if !token_data.claims.impersonated_by.is_empty() {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::NotAllowedWhileImpersonating).to_string(),
+                    data: json!({"impersonated_by": token_data.claims.impersonated_by}),
+                    exit_code: 1,
+                }),
+            );
+        }
+    }
+
+    let result: Result<bool, RedisError> = with_retry(&state.redis_connection, |redis_conn| {
+        redis_conn.set_ex(token_string, &customer_id, 604800)
+    });
 
     match result {
         Ok(_) => (),
@@ -248,6 +629,119 @@ pub async fn renew_session(
     );
 }
 
+// after a privilege sensitive change every session ends, including the one making it, and the caller
+// continues on a fresh token with the same scopes that expires when the old one would have
+pub async fn rotate_session(
+    headers: &HeaderMap,
+    session_data: &SessionData,
+    state: &Arc<AppState>,
+) -> Result<(String, usize), (StatusCode, Json<GenericResponse>)> {
+    let token_string = extract_token_from_headers(headers).await?;
+    let remaining = match get_token_payload(token_string) {
+        Ok(token_data) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as usize;
+            token_data.claims.exp.saturating_sub(now)
+        },
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::ErrorParsingToken).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    if remaining == 0 {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::Expired).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    match revoke_customer_sessions(&state.redis_connection, &session_data.customer_id) {
+        Ok(_) => (),
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorDeleting).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    let token = issue_session_with_ttl(
+        state,
+        &session_data.customer_id,
+        &session_data.region,
+        session_data.scopes.clone(),
+        Some(remaining),
+    ).await?;
+
+    Ok((token, remaining))
+}
+
+async fn revoke_current_session(
+    headers: HeaderMap,
+    state: &Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers.clone(), &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let token_string = match extract_token_from_headers(&headers).await {
+        Ok(token_string) => token_string,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let revoked = with_retry(&state.redis_connection, |redis_conn| {
+        revoke_session(redis_conn, &session_data.customer_id, token_string)
+    });
+
+    match revoked {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::Revoked).to_string(),
+                data: json!({}),
+                exit_code: 0,
+            }),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericResponse {
+                message: APIMessages::Redis(RedisMessages::ErrorDeleting).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ),
+    }
+}
+
+// DELETE /api/identity/session, the cookie is cleared even when the token was already gone
+pub async fn end_session(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response {
+    let mut response = revoke_current_session(headers, &state).await.into_response();
+    if state.token_delivery.sets_cookie() {
+        if let Ok(cookie) = HeaderValue::from_str(&cleared_session_cookie()) {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+
+    response
+}
 
 pub async fn legacy_authentication(
     payload_result: Result<Json<SignIn>, JsonRejection>,
@@ -270,8 +764,8 @@ pub async fn legacy_authentication(
         );
     }
 
-    let filter = build_customer_filter("", payload.email.as_str()).await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter).await {
+    let filter = build_login_filter(payload.email.as_str()).await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
         Ok((found, customer)) => (found, customer),
         Err((status_code, json)) => return (status_code, json),
     };
@@ -288,7 +782,7 @@ pub async fn legacy_authentication(
     }
 
     let customer = customer.unwrap();
-    if customer.auth_provider != AuthProviders::LEGACY {
+    if !customer.has_auth_method(AuthProviders::LEGACY) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(GenericResponse {
@@ -324,24 +818,148 @@ pub async fn legacy_authentication(
         }
     };
 
-    if customer.auth_provider != AuthProviders::LEGACY {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(GenericResponse {
-                message: APIMessages::Token(TokenMessages::OnlyLegacyProvider).to_string(),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        );
+    if let Err(response) = ensure_not_suspended(&customer) {
+        return response;
     }
 
-    let token = match create_token(&customer.id, vec![SessionScopes::TotalAccess]) {
+    let scopes = first_party_scopes(&state, &customer.id);
+    let token = match issue_session(&state, &customer.id, &customer.region, scopes).await {
         Ok(token) => token,
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    record_login(&state, &customer, "legacy");
+
+    return (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::Created).to_string(),
+            data: json!({
+                "token": token,
+            }),
+            exit_code: 0,
+        }),
+    );
+}
+
+pub const ELEVATED_SESSION_TTL: usize = 900;
+
+// re-authenticates the customer and swaps the current token for a short lived one with the requested scopes
+// the requested scopes, all of them when none are named, never more than a sign in would grant
+pub fn elevated_scopes(
+    requested: &[String],
+    allowed_scopes: Vec<SessionScopes>,
+) -> Result<Vec<SessionScopes>, (StatusCode, Json<GenericResponse>)> {
+    if requested.is_empty() {
+        return Ok(allowed_scopes);
+    }
+
+    let mut scopes = vec![];
+    for raw_scope in requested.iter() {
+        let scope = match SessionScopes::from_str(raw_scope) {
+            Ok(scope) => scope,
+            Err(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(GenericResponse {
+                        message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction).to_string(),
+                        data: json!({"scope": raw_scope}),
+                        exit_code: 1,
+                    }),
+                ))
+            }
+        };
+
+        let granted = match scope {
+            SessionScopes::AdminAccess => allowed_scopes.contains(&SessionScopes::AdminAccess),
+            _ => allowed_scopes.contains(&SessionScopes::TotalAccess),
+        };
+
+        if !granted {
+            return Err((
+                StatusCode::FORBIDDEN,
                 Json(GenericResponse {
-                    message: APIMessages::Token(TokenMessages::ErrorCreating).to_string(),
+                    message: APIMessages::Token(TokenMessages::NotAllowedScopesToPerformAction).to_string(),
+                    data: json!({"scope": raw_scope}),
+                    exit_code: 1,
+                }),
+            ));
+        }
+
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    Ok(scopes)
+}
+
+pub async fn elevate_session(
+    headers: HeaderMap,
+    payload_result: Result<Json<SessionElevation>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers.clone(), &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match forbid_impersonation(&session_data) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((found, customer)) => (found, customer),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let customer = customer.unwrap();
+    if !customer.has_auth_method(AuthProviders::LEGACY) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::OnlyLegacyProvider).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    match verify(&payload.password, &customer.password) {
+        Ok(true) => (),
+        Ok(false) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::IncorrectPassword).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        },
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::ErrorVerifyingPassword).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -349,6 +967,91 @@ pub async fn legacy_authentication(
         }
     };
 
+    if let Err(response) = ensure_not_suspended(&customer) {
+        return response;
+    }
+
+    // a session can never be elevated past what the customer would get by signing in
+    let allowed_scopes = first_party_scopes(&state, &customer.id);
+    let scopes = match elevated_scopes(&payload.scopes, allowed_scopes) {
+        Ok(scopes) => scopes,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let granted_scopes = scopes.iter().map(|scope| scope.to_string()).collect::<Vec<String>>();
+    let token = match issue_session_with_ttl(&state, &customer.id, &customer.region, scopes, Some(ELEVATED_SESSION_TTL)).await {
+        Ok(token) => token,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // the previous token stops working as soon as the elevated one exists
+    let old_token = match extract_token_from_headers(&headers).await {
+        Ok(old_token) => old_token,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let revoked = with_retry(&state.redis_connection, |redis_conn| revoke_session(redis_conn, &customer.id, old_token));
+
+    match revoked {
+        Ok(_) => (),
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorDeleting).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::Elevated).to_string(),
+            data: json!({
+                "token": token,
+                "scopes": granted_scopes,
+                "expires_in": ELEVATED_SESSION_TTL,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub const MAX_RECOVERY_ATTEMPTS: i64 = 5;
+pub const RECOVERY_ATTEMPTS_WINDOW: i64 = 900;
+
+pub fn recovery_attempts_key(email: &str) -> String {
+    format!("recovery_attempts:{}", email.to_lowercase())
+}
+
+// break-glass sign in when the authenticator is lost, every backup code works once
+// the stored hash the code belongs to, spending it is what makes a code single use
+pub fn matching_backup_code<'a>(hashed_codes: &'a [String], backup_code: &str) -> Option<&'a String> {
+    hashed_codes.iter().find(|hashed_code| verify(backup_code, hashed_code).unwrap_or(false))
+}
+
+pub async fn recovery_authentication(
+    payload_result: Result<Json<RecoverySignIn>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let email = payload.email.to_lowercase();
+    let invalid_code_response = (
+        StatusCode::UNAUTHORIZED,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::InvalidBackupSecurityCode).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    );
+
     let mut redis_conn = match state.redis_connection.get_connection() {
         Ok(redis_conn) => redis_conn,
         Err(_) => {
@@ -363,12 +1066,10 @@ pub async fn legacy_authentication(
         }
     };
 
-    let result: Result<bool, RedisError> =
-        redis_conn
-            .set_ex(token.clone(), &customer.id, 604800);
-
-    match result {
-        Ok(_) => (),
+    // attempts are counted per email, on top of the router rate limit
+    let attempts_key = recovery_attempts_key(&email);
+    let attempts: i64 = match redis_conn.incr(&attempts_key, 1) {
+        Ok(attempts) => attempts,
         Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -381,22 +1082,189 @@ pub async fn legacy_authentication(
         }
     };
 
-    return (
+    if attempts == 1 {
+        let _: Result<bool, RedisError> = redis_conn.expire(&attempts_key, RECOVERY_ATTEMPTS_WINDOW);
+    }
+
+    if attempts > MAX_RECOVERY_ATTEMPTS {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::TooManyRecoveryAttempts).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let filter = build_login_filter(email.as_str()).await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok((found, customer)) => (found, customer),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !found {
+        return invalid_code_response;
+    }
+
+    // deleted accounts answer like unknown ones
+    let customer = customer.unwrap();
+    if customer.deleted {
+        return invalid_code_response;
+    }
+
+    let hashed_code = match matching_backup_code(&customer.backup_security_codes, &payload.backup_code) {
+        Some(hashed_code) => hashed_code,
+        None => return invalid_code_response,
+    };
+
+    // checked before the code is spent, a suspended customer keeps it for after reactivation
+    if let Err(response) = ensure_not_suspended(&customer) {
+        return response;
+    }
+
+    match consume_backup_security_code(state.customers_db(&customer.region), &customer.id, hashed_code).await {
+        Ok(true) => (),
+        Ok(false) => return invalid_code_response,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let _: Result<bool, RedisError> = redis_conn.del(&attempts_key);
+
+    let scopes = first_party_scopes(&state, &customer.id);
+    let token = match issue_session(&state, &customer.id, &customer.region, scopes).await {
+        Ok(token) => token,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    record_login(&state, &customer, "recovery");
+
+    (
         StatusCode::OK,
         Json(GenericResponse {
             message: APIMessages::Token(TokenMessages::Created).to_string(),
             data: json!({
                 "token": token,
+                "remaining_backup_security_codes": customer.backup_security_codes.len() - 1,
             }),
             exit_code: 0,
         }),
-    );
+    )
 }
 
 #[derive(Debug, Deserialize)]
 pub struct GoogleOAuthQueryParams {
     pub code: Option<String>,
     pub error: Option<String>,
+    pub client: Option<String>,
+    pub state: Option<String>, // set to the client by the start endpoint when google calls back directly
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoogleOAuthStartQueryParams {
+    pub client: Option<String>,
+}
+
+// resolves the redirect uri the authorization code was (or will be) issued for
+pub fn resolve_google_redirect_uri(
+    state: &Arc<AppState>,
+    client: Option<&str>,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    match state.google_auth.redirect_uri_for(client) {
+        Some(redirect_uri) => Ok(redirect_uri.clone()),
+        None => Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::UnknownOAuthClient).to_string(),
+                data: json!({"client": client}),
+                exit_code: 1,
+            }),
+        )),
+    }
+}
+
+// claims the code first so a replay is reported as such, google's own rejection is mapped too
+pub async fn exchange_google_code(
+    authorization_code: &String,
+    redirect_uri: &str,
+    state: &Arc<AppState>,
+) -> Result<OAuthResponse, (StatusCode, Json<GenericResponse>)> {
+    let token_error = |status_code: StatusCode, message: TokenMessages| {
+        Err((
+            status_code,
+            Json(GenericResponse {
+                message: APIMessages::Token(message).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ))
+    };
+
+    match claim_authorization_code(&state.redis_connection, authorization_code) {
+        Ok(true) => (),
+        Ok(false) => return token_error(StatusCode::BAD_REQUEST, TokenMessages::AuthorizationCodeAlreadyUsed),
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorSettingKey).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    match request_token(authorization_code, redirect_uri, state).await {
+        Ok(token_response) => Ok(token_response),
+        Err(OAuthTokenError::InvalidGrant) => token_error(StatusCode::BAD_REQUEST, TokenMessages::InvalidAuthorizationCode),
+        Err(OAuthTokenError::Rejected(err)) => {
+            warn!("google rejected the authorization code exchange: {}", err);
+            token_error(StatusCode::INTERNAL_SERVER_ERROR, TokenMessages::ErrorRequestingGoogleToken)
+        },
+        Err(OAuthTokenError::Unreachable(err)) => {
+            warn!("error reaching google to exchange an authorization code: {}", err);
+            release_authorization_code(&state.redis_connection, authorization_code);
+            token_error(StatusCode::INTERNAL_SERVER_ERROR, TokenMessages::ErrorRequestingGoogleToken)
+        },
+    }
+}
+
+pub async fn start_google_authentication(
+    Query(params): Query<GoogleOAuthStartQueryParams>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let client = params.client.as_deref();
+    let redirect_uri = match resolve_google_redirect_uri(&state, client) {
+        Ok(redirect_uri) => redirect_uri,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let authorization_url = match authorization_url(&state, &redirect_uri, client) {
+        Ok(authorization_url) => authorization_url,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::InternalServerError.to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::GoogleAuthorizationUrl).to_string(),
+            data: json!({
+                "authorization_url": authorization_url.to_string(),
+                "redirect_uri": redirect_uri,
+            }),
+            exit_code: 0,
+        }),
+    )
 }
 
 pub async fn gooogle_authentication(
@@ -431,18 +1299,15 @@ pub async fn gooogle_authentication(
         }
     };
 
-    let token_response = match request_token(&authorization_code, &state).await {
+    let client = params.client.as_deref().or(params.state.as_deref());
+    let redirect_uri = match resolve_google_redirect_uri(&state, client) {
+        Ok(redirect_uri) => redirect_uri,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let token_response = match exchange_google_code(&authorization_code, &redirect_uri, &state).await {
         Ok(token_response) => token_response,
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(GenericResponse {
-                    message: APIMessages::Token(TokenMessages::ErrorRequestingGoogleToken).to_string(),
-                    data: json!({}),
-                    exit_code: 1,
-                }),
-            )
-        }
+        Err((status_code, json)) => return (status_code, json),
     };
     
     let google_user = match get_google_user(&token_response.access_token, &token_response.id_token).await {
@@ -474,36 +1339,52 @@ pub async fn gooogle_authentication(
         }
     };
 
-    let filter = build_customer_filter("", &google_user_email).await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter).await {
-        Ok((found, customer)) => (found, customer),
-        Err((status_code, json)) => return (status_code, json),
-    };
+    // a linked google account signs in to its customer even if the google email isn't one of the customer emails
+    let (mut found, mut customer) = (false, None);
+    if let Some(openid) = &google_user.id {
+        let filter = linked_provider_filter(AuthProviders::GOOGLE, openid);
+        (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+            Ok((found, customer)) => (found, customer),
+            Err((status_code, json)) => return (status_code, json),
+        };
+    }
 
+    if !found {
+        let filter = build_login_filter(&google_user_email).await;
+        (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+            Ok((found, customer)) => (found, customer),
+            Err((status_code, json)) => return (status_code, json),
+        };
+    }
+
+    // onboarding isn't an error, clients get the google profile to prefill the signup
     if !found {
         return (
-            StatusCode::NOT_FOUND,
+            StatusCode::OK,
             Json(GenericResponse {
-                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                message: APIMessages::Customer(CustomerMessages::NeedsRegistration).to_string(),
                 data: json!({
+                    "status": "needs_registration",
                     "action": "create_customer_record",
-                    "auth_provider": AuthProviders::GOOGLE,
-                    "openid": google_user.id,
-                    "email": google_user_email,
-                    "verified_email": google_user.verified_email,
-                    "name": google_user.name,
-                    "given_name": google_user.given_name,
-                    "family_name": google_user.family_name,
-                    "picture": google_user.picture,
-                    "locale": google_user.locale,
+                    "prefilled": {
+                        "auth_provider": AuthProviders::GOOGLE,
+                        "openid": google_user.id,
+                        "email": google_user_email,
+                        "verified_email": google_user.verified_email,
+                        "name": google_user.name,
+                        "given_name": google_user.given_name,
+                        "family_name": google_user.family_name,
+                        "picture": google_user.picture,
+                        "locale": google_user.locale,
+                    },
                 }),
-                exit_code: 1,
+                exit_code: 0,
             }),
         );
     }
 
     let customer = customer.unwrap();
-    if customer.auth_provider != AuthProviders::GOOGLE {
+    if !customer.has_auth_method(AuthProviders::GOOGLE) {
         return (
             StatusCode::UNAUTHORIZED,
             Json(GenericResponse {
@@ -514,13 +1395,189 @@ pub async fn gooogle_authentication(
         );
     }
 
-    let token = match create_token(&customer.id, vec![SessionScopes::TotalAccess]) {
+    if let Err(response) = ensure_not_suspended(&customer) {
+        return response;
+    }
+
+    let scopes = first_party_scopes(&state, &customer.id);
+    let token = match issue_session(&state, &customer.id, &customer.region, scopes).await {
         Ok(token) => token,
-        Err(_) => {
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    record_login(&state, &customer, "google");
+
+    // a picture the customer set themselves is never replaced by google's
+    if let (None, Some(picture)) = (&customer.picture, &google_user.picture) {
+        if valid_picture_url(picture) {
+            let update = doc! {"$set": {"picture": picture}};
+            if update_customer(state.customers_db(&customer.region), doc! {"id": &customer.id}, update).await.is_err() {
+                warn!("error storing google picture for {}", customer.id);
+            }
+        }
+    }
+
+    return (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::Created).to_string(),
+            data: json!({
+                "token": token,
+            }),
+            exit_code: 0,
+        }),
+    );
+}
+
+pub async fn request_magic_link(
+    payload_result: Result<Json<MagicLinkRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // same answer whether the customer exists or not, so emails can't be enumerated
+    let generic_response = (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Token(TokenMessages::MagicLinkSent).to_string(),
+            data: json!({}),
+            exit_code: 0,
+        }),
+    );
+
+    let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
+        Ok(api_key) if state.enabled_email_integration => api_key,
+        _ => {
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::SERVICE_UNAVAILABLE,
                 Json(GenericResponse {
-                    message: APIMessages::Token(TokenMessages::ErrorCreating).to_string(),
+                    message: APIMessages::ServiceUnavailable.to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let email = payload.email.to_lowercase();
+    let filter = build_login_filter(email.as_str()).await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok((found, customer)) => (found, customer),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !found {
+        return generic_response;
+    }
+
+    let customer = customer.unwrap();
+    if customer.deleted || customer.status == CustomerStatus::Suspended {
+        return generic_response;
+    }
+
+    // an exhausted budget answers like everything else here, so it can't be used to probe for accounts
+    if consume_email_send_budget(&state, &customer.id).is_err() {
+        return generic_response;
+    }
+
+    // failures from here on only happen for existing accounts, so they are logged and answered like the rest
+    let token = random_string(40).await;
+    let result: Result<bool, RedisError> = with_retry(&state.redis_connection, |redis_conn| {
+        redis_conn.set_ex(format!("magic_link:{}", token), &customer.id, state.email_provider_settings.magic_link_ttl)
+    });
+
+    if let Err(err) = result {
+        warn!("error storing magic link for {}: {}", customer.id, err);
+        return generic_response;
+    }
+
+    let send_email_data = SendEmailData {
+        api_key,
+        subject: "Your Sign In Link".to_string(),
+        template_id: state.email_provider_settings.magic_link_template_id,
+        customer_email: email,
+        customer_name: customer.name.clone(),
+        verification_link: format!("https://{}/api/identity/session/magic-link/consume?token={}", state.api_url, token),
+        greetings_title: format!("Hi {}", customer.name),
+        sender_email: state.master_email_entity.email.clone(),
+        sender_name: state.master_email_entity.name.clone(),
+    };
+
+    if let Err(err) = send_verification_email(send_email_data).await {
+        warn!("error sending magic link to {}: {}", customer.id, err);
+    }
+
+    generic_response
+}
+
+// our tokens are alphanumeric, anything else never reaches the page markup
+pub fn valid_magic_link_token(token: &str) -> bool {
+    !token.is_empty() && token.len() <= 64 && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// posts the token back to the same path, so mail scanners and prefetchers opening the link don't spend it
+pub fn magic_link_confirmation_page(token: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="robots" content="noindex"><title>Sign in</title></head>
+<body>
+<form method="post" action="?token={}">
+<button type="submit">Sign in</button>
+</form>
+</body>
+</html>"#,
+        token
+    )
+}
+
+// only the POST consumes the link, the GET is what the email opens
+pub async fn open_magic_link(Query(params): Query<MagicLinkQueryParams>) -> Response {
+    match params.token {
+        Some(token) if valid_magic_link_token(&token) => Html(magic_link_confirmation_page(&token)).into_response(),
+        Some(_) => (
+            StatusCode::UNAUTHORIZED,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::Expired).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )
+            .into_response(),
+        None => (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::Missing).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// single use, only the request that deleted a still existing key gets the session
+pub fn claimed_magic_link(customer_id: Option<String>, deleted: Result<i64, RedisError>) -> Option<String> {
+    match (customer_id, deleted) {
+        (Some(customer_id), Ok(1)) => Some(customer_id),
+        _ => None,
+    }
+}
+
+pub async fn consume_magic_link(
+    Query(params): Query<MagicLinkQueryParams>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let token = match params.token {
+        Some(token) => token,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::Missing).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -542,17 +1599,14 @@ pub async fn gooogle_authentication(
         }
     };
 
-    let result: Result<bool, RedisError> =
-        redis_conn
-            .set_ex(token.clone(), &customer.id, 604800);
-
-    match result {
-        Ok(_) => (),
+    let key = format!("magic_link:{}", token);
+    let customer_id: Option<String> = match redis_conn.get(&key) {
+        Ok(customer_id) => customer_id,
         Err(_) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(GenericResponse {
-                    message: APIMessages::Redis(RedisMessages::ErrorSettingKey).to_string(),
+                    message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -560,7 +1614,52 @@ pub async fn gooogle_authentication(
         }
     };
 
-    return (
+    let deleted: Result<i64, RedisError> = redis_conn.del(&key);
+    let customer_id = match claimed_magic_link(customer_id, deleted) {
+        Some(customer_id) => customer_id,
+        None => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(GenericResponse {
+                    message: APIMessages::Token(TokenMessages::Expired).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let filter = build_customer_filter(customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter).await {
+        Ok((found, customer)) => (found, customer),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let customer = customer.unwrap();
+    if let Err(response) = ensure_not_suspended(&customer) {
+        return response;
+    }
+
+    let scopes = first_party_scopes(&state, &customer.id);
+    let token = match issue_session(&state, &customer.id, &customer.region, scopes).await {
+        Ok(token) => token,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    record_login(&state, &customer, "magic_link");
+
+    (
         StatusCode::OK,
         Json(GenericResponse {
             message: APIMessages::Token(TokenMessages::Created).to_string(),
@@ -569,5 +1668,247 @@ pub async fn gooogle_authentication(
             }),
             exit_code: 0,
         }),
-    );
-}
\ No newline at end of file
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use bcrypt::hash;
+    use crate::server::tests::test_state;
+    use crate::utilities::token::tests::SIGNING_KEY;
+    use std::env;
+    use crate::types::customer::{CustomerType, Email, Preferences};
+    use crate::types::subscription::{DefaultSubscription, Slug, SubscriptionFrequencyClass};
+    use std::collections::HashMap;
+
+    pub fn customer() -> Customer {
+        let default_subscription = DefaultSubscription {
+            slug: Slug::FREE,
+            frequency: SubscriptionFrequencyClass::UNDEFINED,
+            trial_days: 0,
+        };
+
+        Customer {
+            id: String::from("customer"),
+            name: String::from("Ada"),
+            class: CustomerType::PERSONAL,
+            emails: vec![Email {
+                address: String::from("ada@example.com"),
+                verified: true,
+                main: true,
+                disabled: false,
+                disabled_at: String::new(),
+            }],
+            auth_provider: AuthProviders::LEGACY,
+            password: String::from("hashed"),
+            backup_security_codes: vec![String::from("hashed code")],
+            preferences: Preferences {
+                dark_mode: false,
+                language: String::from("en"),
+                notifications: true,
+            },
+            subscription: default_subscription.build(String::from("sub"), Utc::now()),
+            metadata: HashMap::from([(String::from("crm"), String::from("42"))]),
+            created_at: String::new(),
+            updated_at: String::new(),
+            deleted: false,
+            status: CustomerStatus::Active,
+            region: String::new(),
+            last_login_at: String::new(),
+            last_login_provider: String::new(),
+            linked_providers: vec![],
+            picture: Some(String::from("https://example.com/ada.png")),
+        }
+    }
+
+    #[test]
+    fn no_scopes_only_expose_the_auth_provider() {
+        let redacted = redact_for_scopes(customer(), &[]);
+
+        assert_eq!(redacted.auth_provider, Some(AuthProviders::LEGACY));
+        assert!(redacted.id.is_none());
+        assert!(redacted.emails.is_none());
+        assert!(redacted.subscription.is_none());
+        assert!(redacted.metadata.is_none());
+        assert!(redacted.picture.is_none());
+    }
+
+    #[test]
+    fn each_scope_exposes_only_its_fields() {
+        let redacted = redact_for_scopes(customer(), &[SessionScopes::ViewPublicID, SessionScopes::ViewEmailAddresses]);
+
+        assert_eq!(redacted.id.as_deref(), Some("customer"));
+        assert_eq!(redacted.emails.map(|emails| emails.len()), Some(1));
+        assert!(redacted.name.is_none());
+        assert!(redacted.subscription.is_none());
+        assert!(redacted.metadata.is_none());
+    }
+
+    #[test]
+    fn total_access_exposes_the_whole_record() {
+        let redacted = redact_for_scopes(customer(), &[SessionScopes::TotalAccess]);
+
+        assert_eq!(redacted.name.as_deref(), Some("Ada"));
+        assert_eq!(redacted.picture.as_deref(), Some("https://example.com/ada.png"));
+        assert!(redacted.subscription.is_some());
+        assert_eq!(redacted.metadata.and_then(|metadata| metadata.get("crm").cloned()).as_deref(), Some("42"));
+    }
+
+    #[test]
+    fn elevation_without_named_scopes_grants_the_sign_in_scopes() {
+        let scopes = elevated_scopes(&[], vec![SessionScopes::TotalAccess]).unwrap();
+        assert_eq!(scopes, vec![SessionScopes::TotalAccess]);
+    }
+
+    #[test]
+    fn elevation_grants_named_scopes_once() {
+        let requested = vec![SessionScopes::ViewEmailAddresses.to_string(), SessionScopes::ViewEmailAddresses.to_string()];
+        let scopes = elevated_scopes(&requested, vec![SessionScopes::TotalAccess]).unwrap();
+
+        assert_eq!(scopes, vec![SessionScopes::ViewEmailAddresses]);
+    }
+
+    #[test]
+    fn elevation_never_grants_admin_to_a_customer() {
+        let requested = vec![SessionScopes::AdminAccess.to_string()];
+        let (status, _) = elevated_scopes(&requested, vec![SessionScopes::TotalAccess]).unwrap_err();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn elevation_rejects_unknown_scopes() {
+        let (status, _) = elevated_scopes(&[String::from("EverythingPlease")], vec![SessionScopes::TotalAccess]).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn a_magic_link_is_claimed_once() {
+        assert_eq!(claimed_magic_link(Some(String::from("customer")), Ok(1)), Some(String::from("customer")));
+        // a second request that read the key before the first deleted it loses the race
+        assert_eq!(claimed_magic_link(Some(String::from("customer")), Ok(0)), None);
+    }
+
+    #[test]
+    fn an_expired_magic_link_is_not_claimed() {
+        assert_eq!(claimed_magic_link(None, Ok(0)), None);
+    }
+
+    #[test]
+    fn the_magic_link_page_posts_the_token_back() {
+        let page = magic_link_confirmation_page("abc123");
+        assert!(page.contains(r#"<form method="post" action="?token=abc123">"#));
+    }
+
+    #[test]
+    fn magic_link_tokens_must_be_alphanumeric() {
+        assert!(valid_magic_link_token("AbC123"));
+        assert!(!valid_magic_link_token(""));
+        assert!(!valid_magic_link_token("abc\"><script>"));
+    }
+
+    #[test]
+    fn suspended_customers_cannot_sign_in() {
+        let mut customer = customer();
+        assert!(ensure_not_suspended(&customer).is_ok());
+
+        customer.status = CustomerStatus::Suspended;
+        let (status, Json(body)) = ensure_not_suspended(&customer).unwrap_err();
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::Suspended).to_string());
+    }
+
+    #[test]
+    fn a_valid_backup_code_matches_its_hash() {
+        let hashed_codes = vec![hash("first-code", 4).unwrap(), hash("second-code", 4).unwrap()];
+        assert_eq!(matching_backup_code(&hashed_codes, "second-code"), Some(&hashed_codes[1]));
+        assert_eq!(matching_backup_code(&hashed_codes, "unknown-code"), None);
+    }
+
+    #[test]
+    fn a_spent_backup_code_is_rejected() {
+        let mut hashed_codes = vec![hash("first-code", 4).unwrap(), hash("second-code", 4).unwrap()];
+        let spent = matching_backup_code(&hashed_codes, "first-code").unwrap().clone();
+
+        // what consume_backup_security_code's $pull does to the stored list
+        hashed_codes.retain(|hashed_code| *hashed_code != spent);
+
+        assert_eq!(matching_backup_code(&hashed_codes, "first-code"), None);
+        assert!(matching_backup_code(&hashed_codes, "second-code").is_some());
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", token.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn rotation_refuses_a_token_with_no_lifetime_left() {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        let state = Arc::new(test_state().await);
+        let token = create_token_with_ttl(&String::from("customer"), "", vec![SessionScopes::TotalAccess], 0).unwrap();
+        let session_data = SessionData {
+            customer_id: String::from("customer"),
+            scopes: vec![SessionScopes::TotalAccess],
+            region: String::new(),
+            impersonated_by: None,
+        };
+
+        let (status, Json(body)) = rotate_session(&bearer(&token), &session_data, &state).await.unwrap_err();
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.message, APIMessages::Token(TokenMessages::Expired).to_string());
+    }
+
+    // needs a reachable server, run with REDIS_URI set and --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn rotation_ends_the_old_token_and_the_new_one_works() {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        let mut state = test_state().await;
+        state.redis_connection = Client::open(env::var("REDIS_URI").unwrap()).unwrap();
+        let state = Arc::new(state);
+
+        let old_token = issue_session(&state, &String::from("rotated"), "", vec![SessionScopes::TotalAccess]).await.unwrap();
+        let session_data = get_user_session_from_req(bearer(&old_token), &state.redis_connection).await.unwrap();
+
+        let (new_token, _) = rotate_session(&bearer(&old_token), &session_data, &state).await.unwrap();
+
+        let (status, _) = get_user_session_from_req(bearer(&old_token), &state.redis_connection).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_user_session_from_req(bearer(&new_token), &state.redis_connection).await.unwrap().customer_id, "rotated");
+    }
+
+    #[test]
+    fn an_impersonation_token_names_the_admin_and_expires_soon() {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as usize;
+
+        let token = create_impersonation_token(&String::from("customer"), "", impersonation_scopes(), IMPERSONATION_SESSION_TTL, "admin").unwrap();
+        let claims = validate_token(&token).unwrap().claims;
+
+        assert_eq!(claims.impersonated_by, "admin");
+        assert!(claims.exp <= now + IMPERSONATION_SESSION_TTL + 1);
+        assert!(claims.exp - claims.nbf <= 15 * 60);
+    }
+
+    #[test]
+    fn impersonation_scopes_are_read_only() {
+        let session_data = SessionData {
+            customer_id: String::from("customer"),
+            scopes: impersonation_scopes(),
+            region: String::new(),
+            impersonated_by: Some(String::from("admin")),
+        };
+
+        assert!(!impersonation_scopes().contains(&SessionScopes::TotalAccess));
+        assert!(require_any_scope(&session_data, &crate::controllers::customer::UPDATE_NAME_SCOPES).is_err());
+        assert!(require_any_scope(&session_data, &crate::controllers::email::UPDATE_EMAIL_SCOPES).is_err());
+
+        let (status, _) = forbid_impersonation(&session_data).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,376 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::JsonRejection, Path},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+
+use crate::{
+    oauth::google::get_google_user,
+    server::AppState,
+    storage::mongo::{build_customer_filter, find_customer, find_customer_in, linked_provider_filter, update_customer},
+    types::{
+        customer::{AuthProviders, Customer, GenericResponse, LinkedProvider},
+        incoming_requests::LinkProviderRequest,
+    },
+    utilities::{
+        api_messages::{APIMessages, CustomerMessages, TokenMessages},
+        helpers::{password_differs_from_emails, payload_analyzer, valid_password},
+    },
+};
+
+use super::identity::{exchange_google_code, get_user_session_from_req, require_any_scope, resolve_google_redirect_uri, SessionData, SessionScopes};
+
+// AuthProviders::from_str falls back to legacy, paths must name the provider exactly
+fn parse_linkable_provider(raw: &str) -> Option<AuthProviders> {
+    match raw.to_lowercase().as_str() {
+        "google" => Some(AuthProviders::GOOGLE),
+        "legacy" => Some(AuthProviders::LEGACY),
+        _ => None,
+    }
+}
+
+fn error_response(status_code: StatusCode, message: APIMessages) -> (StatusCode, Json<GenericResponse>) {
+    (
+        status_code,
+        Json(GenericResponse {
+            message: message.to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    )
+}
+
+// linking changes how the account can be accessed, so only first party sessions may do it
+async fn find_session_customer(
+    headers: HeaderMap,
+    state: &Arc<AppState>,
+) -> Result<(SessionData, Customer), (StatusCode, Json<GenericResponse>)> {
+    let session_data = get_user_session_from_req(headers, &state.redis_connection).await?;
+
+    require_any_scope(&session_data, &[SessionScopes::TotalAccess])?;
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    match find_customer(state.customers_db(&session_data.region), filter).await? {
+        (true, Some(customer)) => Ok((session_data, customer)),
+        _ => Err(error_response(
+            StatusCode::NOT_FOUND,
+            APIMessages::Customer(CustomerMessages::NotFound),
+        )),
+    }
+}
+
+// exchanges the authorization code and makes sure the google account isn't someone else's
+async fn verify_google_identity(
+    code: &String,
+    client: Option<&str>,
+    customer: &Customer,
+    state: &Arc<AppState>,
+) -> Result<LinkedProvider, (StatusCode, Json<GenericResponse>)> {
+    let redirect_uri = resolve_google_redirect_uri(state, client)?;
+    let token_response = exchange_google_code(code, &redirect_uri, state).await?;
+
+    let google_user = match get_google_user(&token_response.access_token, &token_response.id_token).await {
+        Ok(google_user) => google_user,
+        Err(_) => {
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                APIMessages::Token(TokenMessages::ErrorFetchingUserFromGoogle),
+            ))
+        }
+    };
+
+    let (openid, email) = match (google_user.id, google_user.email) {
+        (Some(openid), Some(email)) => (openid, email.to_lowercase()),
+        _ => {
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                APIMessages::Token(TokenMessages::ErrorFetchingUserFromGoogle),
+            ))
+        }
+    };
+
+    let linked_to_other_customer = error_response(
+        StatusCode::CONFLICT,
+        APIMessages::Customer(CustomerMessages::ProviderLinkedToOtherCustomer),
+    );
+
+    for filter in [linked_provider_filter(AuthProviders::GOOGLE, &openid), build_customer_filter("", &email).await] {
+        match find_customer_in(state.all_customers_dbs(), filter).await? {
+            (true, Some(owner)) if owner.id != customer.id => return Err(linked_to_other_customer),
+            _ => (),
+        };
+    }
+
+    Ok(LinkedProvider {
+        provider: AuthProviders::GOOGLE,
+        subject: openid,
+        email,
+        linked_at: Utc::now().to_rfc3339(),
+    })
+}
+
+// POST /api/me/link/:provider
+// a provider can only be linked once, and only while there's room under MAX_LINKED_PROVIDERS
+pub fn check_linkable(customer: &Customer, provider: AuthProviders, max_linked_providers: usize) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    if customer.has_auth_method(provider) {
+        return Err(error_response(StatusCode::CONFLICT, APIMessages::Customer(CustomerMessages::ProviderAlreadyLinked)));
+    }
+
+    if customer.linked_providers.len() >= max_linked_providers {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::TooManyLinkedProviders).to_string(),
+                data: json!({
+                    "max_linked_providers": max_linked_providers,
+                }),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+// the primary provider and linked ones left once `provider` is gone, the last method is never removed
+pub fn methods_after_unlink(customer: &Customer, provider: AuthProviders) -> Result<(AuthProviders, Vec<LinkedProvider>), (StatusCode, Json<GenericResponse>)> {
+    if !customer.has_auth_method(provider) {
+        return Err(error_response(StatusCode::NOT_FOUND, APIMessages::Customer(CustomerMessages::ProviderNotLinked)));
+    }
+
+    if customer.auth_methods().len() <= 1 {
+        return Err(error_response(StatusCode::CONFLICT, APIMessages::Customer(CustomerMessages::CannotUnlinkLastAuthMethod)));
+    }
+
+    let mut linked_providers = customer.linked_providers.clone();
+    linked_providers.retain(|linked| linked.provider != provider);
+
+    // the oldest linked method takes over as primary
+    let mut auth_provider = customer.auth_provider;
+    if auth_provider == provider {
+        auth_provider = linked_providers.remove(0).provider;
+    }
+
+    Ok((auth_provider, linked_providers))
+}
+
+pub async fn link_provider(
+    headers: HeaderMap,
+    Path(provider): Path<String>,
+    payload_result: Result<Json<LinkProviderRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let (session_data, customer) = match find_session_customer(headers, &state).await {
+        Ok(found) => found,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let provider = match parse_linkable_provider(&provider) {
+        Some(provider) => provider,
+        None => return error_response(StatusCode::NOT_FOUND, APIMessages::Customer(CustomerMessages::UnknownAuthProvider)),
+    };
+
+    if let Err(response) = check_linkable(&customer, provider, state.max_linked_providers) {
+        return response;
+    }
+
+    let mut set_fields = doc! {"updated_at": Utc::now().to_rfc3339()};
+    let linked_provider = match provider {
+        AuthProviders::GOOGLE => {
+            let code = match &payload.code {
+                Some(code) if !code.is_empty() => code,
+                _ => return error_response(StatusCode::BAD_REQUEST, APIMessages::Token(TokenMessages::Missing)),
+            };
+
+            match verify_google_identity(code, payload.client.as_deref(), &customer, &state).await {
+                Ok(linked_provider) => linked_provider,
+                Err((status_code, json)) => return (status_code, json),
+            }
+        },
+        AuthProviders::LEGACY => {
+            let password = match &payload.password {
+                Some(password) => password,
+                None => return error_response(StatusCode::BAD_REQUEST, APIMessages::BadRequest),
+            };
+
+            match valid_password(password).await {
+                Ok(_) => (),
+                Err((status_code, json)) => return (status_code, json),
+            };
+
+            let emails = customer.emails.iter().map(|email| email.address.clone()).collect::<Vec<String>>();
+            match password_differs_from_emails(password, &emails).await {
+                Ok(_) => (),
+                Err((status_code, json)) => return (status_code, json),
+            };
+
+            let hashed_password = match hash(password, DEFAULT_COST) {
+                Ok(hashed_password) => hashed_password,
+                Err(_) => {
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        APIMessages::Customer(CustomerMessages::ErrorHashingPassword),
+                    )
+                }
+            };
+
+            set_fields.insert("password", hashed_password);
+            LinkedProvider {
+                provider: AuthProviders::LEGACY,
+                subject: String::new(),
+                email: String::new(),
+                linked_at: Utc::now().to_rfc3339(),
+            }
+        },
+    };
+
+    let bson_linked_provider = match to_bson(&linked_provider) {
+        Ok(bson_linked_provider) => bson_linked_provider,
+        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, APIMessages::InternalServerError),
+    };
+
+    let update = doc! {
+        "$set": set_fields,
+        "$push": {"linked_providers": bson_linked_provider},
+    };
+
+    match update_customer(state.customers_db(&session_data.region), doc! {"id": &customer.id}, update).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let mut auth_methods = customer.auth_methods();
+    auth_methods.push(provider);
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::ProviderLinked).to_string(),
+            data: json!({
+                "auth_methods": auth_methods,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// DELETE /api/me/link/:provider, the primary provider can go too as long as another method is left
+pub async fn unlink_provider(
+    headers: HeaderMap,
+    Path(provider): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let (session_data, customer) = match find_session_customer(headers, &state).await {
+        Ok(found) => found,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let provider = match parse_linkable_provider(&provider) {
+        Some(provider) => provider,
+        None => return error_response(StatusCode::NOT_FOUND, APIMessages::Customer(CustomerMessages::UnknownAuthProvider)),
+    };
+
+    let (auth_provider, linked_providers) = match methods_after_unlink(&customer, provider) {
+        Ok(methods) => methods,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let (bson_auth_provider, bson_linked_providers) = match (to_bson(&auth_provider), to_bson(&linked_providers)) {
+        (Ok(bson_auth_provider), Ok(bson_linked_providers)) => (bson_auth_provider, bson_linked_providers),
+        _ => return error_response(StatusCode::INTERNAL_SERVER_ERROR, APIMessages::InternalServerError),
+    };
+
+    let mut set_fields = doc! {
+        "auth_provider": bson_auth_provider,
+        "linked_providers": bson_linked_providers,
+        "updated_at": Utc::now().to_rfc3339(),
+    };
+
+    // a left over hash would keep working if legacy were linked again without a new password
+    if provider == AuthProviders::LEGACY {
+        set_fields.insert("password", "");
+    }
+
+    match update_customer(state.customers_db(&session_data.region), doc! {"id": &customer.id}, doc! {"$set": set_fields}).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let mut auth_methods = vec![auth_provider];
+    auth_methods.extend(linked_providers.iter().map(|linked| linked.provider));
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::ProviderUnlinked).to_string(),
+            data: json!({
+                "auth_methods": auth_methods,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::identity::tests::customer;
+
+    fn google() -> LinkedProvider {
+        LinkedProvider {
+            provider: AuthProviders::GOOGLE,
+            subject: String::from("google-subject"),
+            email: String::from("ada@example.com"),
+            linked_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn a_second_provider_can_be_linked() {
+        assert!(check_linkable(&customer(), AuthProviders::GOOGLE, 2).is_ok());
+    }
+
+    #[test]
+    fn a_provider_is_only_linked_once() {
+        let (status, Json(body)) = check_linkable(&customer(), AuthProviders::LEGACY, 2).unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::ProviderAlreadyLinked).to_string());
+    }
+
+    #[test]
+    fn linking_stops_at_max_linked_providers() {
+        let (_, Json(body)) = check_linkable(&customer(), AuthProviders::GOOGLE, 0).unwrap_err();
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::TooManyLinkedProviders).to_string());
+    }
+
+    #[test]
+    fn the_last_sign_in_method_cannot_be_unlinked() {
+        let (status, Json(body)) = methods_after_unlink(&customer(), AuthProviders::LEGACY).unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.message, APIMessages::Customer(CustomerMessages::CannotUnlinkLastAuthMethod).to_string());
+    }
+
+    #[test]
+    fn unlinking_the_primary_promotes_the_linked_provider() {
+        let mut customer = customer();
+        customer.linked_providers.push(google());
+
+        let (auth_provider, linked_providers) = methods_after_unlink(&customer, AuthProviders::LEGACY).unwrap();
+
+        assert_eq!(auth_provider, AuthProviders::GOOGLE);
+        assert!(linked_providers.is_empty());
+    }
+}
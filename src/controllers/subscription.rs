@@ -0,0 +1,524 @@
+use std::{convert::Infallible, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, FixedOffset};
+use futures::{stream, StreamExt};
+use serde_json::json;
+
+use crate::{
+    lemonsqueezy::{api::fetch_subscription, subscription::{lemonsqueezy_subscription_id, subscription_synced}},
+    server::AppState,
+    storage::mongo::{build_customer_filter, find_customer},
+    types::{
+        customer::{Customer, GenericResponse},
+        feature_map::FeatureMap,
+        incoming_requests::SubscriptionHistoryQueryParams,
+        lemonsqueezy::Products,
+        subscription::{Slug, Subscription, SubscriptionFeatures, SubscriptionHistoryLog},
+    },
+    utilities::{
+        api_messages::{APIMessages, CustomerMessages, InputMessages, SubscriptionMessages},
+        helpers::csv_field,
+        webhooks::{acquire_subscription_lock, release_subscription_lock},
+    },
+};
+
+use super::identity::{get_user_session_from_req, require_any_scope, SessionScopes};
+
+// plan features come from the variant map when the variant is known, otherwise from the runtime feature map
+pub fn resolve_feature_access(subscription: &Subscription, products: &Products, feature_map: &FeatureMap, feature: SubscriptionFeatures) -> (bool, String) {
+    let slug = Slug::from_str(&subscription.slug).unwrap_or(Slug::FREE);
+    let required_slug = feature.required_slug();
+
+    let plan_features = match products.resolve(subscription.variant_id) {
+        Some(plan) => plan.features.clone(),
+        None => feature_map.features_for(&slug),
+    };
+
+    if !plan_features.contains(&feature) {
+        return (false, format!("requires_{}", required_slug.to_string()));
+    }
+
+    if subscription.default_trial_ended() && required_slug != Slug::FREE {
+        return (false, String::from("trial_ended"));
+    }
+
+    if required_slug != Slug::FREE && feature_map.blocks_paid_features(&subscription.status) {
+        return (false, format!("subscription_{}", subscription.status.as_str()));
+    }
+
+    (true, String::from("included_in_plan"))
+}
+
+pub async fn check_feature_access(
+    headers: HeaderMap,
+    Path(feature): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::ViewSubscription]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let parsed_feature = match SubscriptionFeatures::from_str(feature.to_lowercase().as_str()) {
+        Ok(parsed_feature) => parsed_feature,
+        Err(_) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Subscription(SubscriptionMessages::UnknownFeature).to_string(),
+                    data: json!({
+                        "allowed": false,
+                        "reason": "unknown_feature",
+                    }),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
+    };
+
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    let customer = customer.unwrap();
+    let (allowed, reason) = resolve_feature_access(&customer.subscription, &state.products, &state.current_feature_map(), parsed_feature);
+
+    let message = match allowed {
+        true => APIMessages::Subscription(SubscriptionMessages::FeatureAllowed),
+        false => APIMessages::Subscription(SubscriptionMessages::FeatureDenied),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: message.to_string(),
+            data: json!({
+                "feature": parsed_feature.to_string(),
+                "allowed": allowed,
+                "reason": reason,
+                "subscription_active": customer.subscription.is_active(),
+                "seats": customer.seat_limit(),
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+pub async fn fetch_subscription_portal(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::ViewSubscription]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json),
+    };
+
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    // free customers never went through checkout, so lemonsqueezy has no portal for them
+    let subscription = customer.unwrap().subscription;
+    if subscription.slug == Slug::FREE.to_string() || subscription.customer_portal_url.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Subscription(SubscriptionMessages::NoPortalForFreeTier).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::PortalLinks).to_string(),
+            data: json!({
+                "customer_portal": subscription.customer_portal_url,
+                "update_payment_method": subscription.update_payment_method_url,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+fn parse_history_date(raw: &Option<String>) -> Result<Option<DateTime<FixedOffset>>, ()> {
+    match raw {
+        Some(raw) => match DateTime::parse_from_rfc3339(raw) {
+            Ok(date) => Ok(Some(date)),
+            Err(_) => Err(()),
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn filter_history_logs(
+    history_logs: Vec<SubscriptionHistoryLog>,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+) -> Vec<SubscriptionHistoryLog> {
+    if from.is_none() && to.is_none() {
+        return history_logs;
+    }
+
+    history_logs
+        .into_iter()
+        .filter(|log| {
+            let date = match DateTime::parse_from_rfc3339(&log.date) {
+                Ok(date) => date,
+                Err(_) => return false,
+            };
+
+            from.is_none_or(|from| date >= from) && to.is_none_or(|to| date <= to)
+        })
+        .collect()
+}
+
+pub async fn download_subscription_history_csv(
+    headers: HeaderMap,
+    Query(params): Query<SubscriptionHistoryQueryParams>,
+    state: Arc<AppState>,
+) -> Response {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json).into_response(),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::ViewSubscription]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json).into_response(),
+    };
+
+    let (from, to) = match (parse_history_date(&params.from), parse_history_date(&params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::InvalidDateRange).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ).into_response()
+        }
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let (found, customer) = match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok(customer) => customer,
+        Err((status, json)) => return (status, json).into_response(),
+    };
+
+    if !found {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ).into_response();
+    }
+
+    let history_logs = filter_history_logs(customer.unwrap().subscription.history_logs, from, to);
+
+    // rows are rendered lazily while the body is being sent
+    let header_row = stream::iter(vec![Ok::<String, Infallible>(String::from("event,date\n"))]);
+    let rows = stream::iter(history_logs.into_iter().map(|log| {
+        Ok::<String, Infallible>(format!("{},{}\n", csv_field(&log.event), csv_field(&log.date)))
+    }));
+
+    let body = Body::from_stream(header_row.chain(rows));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"subscription_history.csv\""),
+        ],
+        body,
+    ).into_response()
+}
+
+// shared by the customer and admin sync endpoints
+pub async fn resync_customer_subscription(
+    state: &Arc<AppState>,
+    customer: Customer,
+) -> (StatusCode, Json<GenericResponse>) {
+    let api_key = match &state.lemonsqueezy_api_key {
+        Some(api_key) => api_key,
+        None => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(GenericResponse {
+                    message: APIMessages::ServiceUnavailable.to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+    };
+
+    let no_subscription = (
+        StatusCode::NOT_FOUND,
+        Json(GenericResponse {
+            message: APIMessages::Subscription(SubscriptionMessages::NoLemonSqueezySubscription).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    );
+
+    let subscription_id = match lemonsqueezy_subscription_id(&customer.subscription) {
+        Some(subscription_id) => subscription_id.to_string(),
+        None => return no_subscription,
+    };
+
+    let data = match fetch_subscription(api_key, &subscription_id).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return no_subscription,
+        Err(err) => {
+            log::error!("error fetching lemonsqueezy subscription {}: {}", subscription_id, err);
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(GenericResponse {
+                    message: APIMessages::Subscription(SubscriptionMessages::SyncFailed).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            );
+        }
+    };
+
+    // same lock as the webhooks, both append to history_logs
+    let lock_token = match acquire_subscription_lock(&state.redis_connection, &customer.id).await {
+        Ok(Some(lock_token)) => Some(lock_token),
+        Ok(None) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(GenericResponse {
+                    message: APIMessages::Subscription(SubscriptionMessages::UpdateInProgress).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+        Err(err) => {
+            log::error!("error acquiring subscription lock for {}: {}", customer.id, err);
+            None
+        }
+    };
+
+    // read again under the lock, a webhook may have appended history since the customer was loaded
+    let filter = build_customer_filter(customer.id.as_str(), "").await;
+    let result = match find_customer(state.customers_db(&customer.region), filter).await {
+        Ok((true, Some(customer))) => Ok(subscription_synced(&customer, data, state).await),
+        Ok(_) => Err((
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+        Err((status_code, json)) => Err((status_code, json)),
+    };
+
+    if let Some(lock_token) = lock_token {
+        release_subscription_lock(&state.redis_connection, &customer.id, &lock_token);
+    }
+
+    let result = match result {
+        Ok(result) => result,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match result {
+        Ok((slug, status)) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Subscription(SubscriptionMessages::Synced).to_string(),
+                data: json!({
+                    "customer_id": customer.id,
+                    "slug": slug,
+                    "status": status,
+                }),
+                exit_code: 0,
+            }),
+        ),
+        Err(json) => (StatusCode::UNPROCESSABLE_ENTITY, json),
+    }
+}
+
+pub async fn sync_subscription(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(customer_id) => customer_id,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    match find_customer(state.customers_db(&session_data.region), filter).await {
+        Ok((true, Some(customer))) => resync_customer_subscription(&state, customer).await,
+        Ok(_) => (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ),
+        Err((status, json)) => (status, json),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        lemonsqueezy::VariantPlan,
+        subscription::{DefaultSubscription, SubscriptionFrequencyClass, SubscriptionStatus},
+    };
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    fn subscription(slug: Slug, status: SubscriptionStatus, variant_id: i64) -> Subscription {
+        let default_subscription = DefaultSubscription {
+            slug,
+            frequency: SubscriptionFrequencyClass::MONTHLY,
+            trial_days: 14,
+        };
+
+        let mut subscription = default_subscription.build(String::from("sub"), Utc::now());
+        subscription.status = status;
+        subscription.variant_id = variant_id;
+        subscription
+    }
+
+    fn no_products() -> Products {
+        Products { variants: HashMap::new() }
+    }
+
+    fn log(date: &str) -> SubscriptionHistoryLog {
+        SubscriptionHistoryLog {
+            event: String::from("subscription_updated"),
+            date: date.to_string(),
+        }
+    }
+
+    fn date(date: &str) -> Option<DateTime<FixedOffset>> {
+        Some(DateTime::parse_from_rfc3339(date).unwrap())
+    }
+
+    #[test]
+    fn free_plan_only_includes_core() {
+        let subscription = subscription(Slug::FREE, SubscriptionStatus::Unset, 0);
+        let feature_map = FeatureMap::default();
+
+        assert_eq!(
+            resolve_feature_access(&subscription, &no_products(), &feature_map, SubscriptionFeatures::CORE),
+            (true, String::from("included_in_plan"))
+        );
+        assert_eq!(
+            resolve_feature_access(&subscription, &no_products(), &feature_map, SubscriptionFeatures::ADVANCED),
+            (false, String::from("requires_pro"))
+        );
+    }
+
+    #[test]
+    fn blocked_status_loses_paid_features() {
+        let subscription = subscription(Slug::PRO, SubscriptionStatus::PastDue, 0);
+
+        assert_eq!(
+            resolve_feature_access(&subscription, &no_products(), &FeatureMap::default(), SubscriptionFeatures::ADVANCED),
+            (false, String::from("subscription_past_due"))
+        );
+    }
+
+    #[test]
+    fn known_variant_overrides_the_feature_map() {
+        let subscription = subscription(Slug::PRO, SubscriptionStatus::Active, 42);
+        let products = Products {
+            variants: HashMap::from([(42, VariantPlan {
+                slug: Slug::PRO,
+                frequency: SubscriptionFrequencyClass::MONTHLY,
+                features: vec![SubscriptionFeatures::CORE],
+            })]),
+        };
+
+        assert_eq!(
+            resolve_feature_access(&subscription, &products, &FeatureMap::default(), SubscriptionFeatures::ADVANCED),
+            (false, String::from("requires_pro"))
+        );
+    }
+
+    #[test]
+    fn history_without_bounds_is_untouched() {
+        let logs = vec![log("2024-01-01T00:00:00+00:00"), log("not a date")];
+        assert_eq!(filter_history_logs(logs, None, None).len(), 2);
+    }
+
+    #[test]
+    fn history_bounds_are_inclusive() {
+        let logs = vec![
+            log("2024-01-01T00:00:00+00:00"),
+            log("2024-02-01T00:00:00+00:00"),
+            log("2024-03-01T00:00:00+00:00"),
+            log("not a date"),
+        ];
+
+        let filtered = filter_history_logs(logs, date("2024-02-01T00:00:00+00:00"), date("2024-03-01T00:00:00+00:00"));
+        let dates: Vec<&str> = filtered.iter().map(|log| log.date.as_str()).collect();
+        assert_eq!(dates, vec!["2024-02-01T00:00:00+00:00", "2024-03-01T00:00:00+00:00"]);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::email::brevo_api::send_verification_email;
+use crate::server::AppState;
+use crate::storage::mongo::{
+    build_customer_filter, count_team_members, find_customer, find_team_member_by_email, insert_team_member, list_team_members,
+    remove_team_member,
+};
+use crate::types::customer::{Customer, CustomerType, GenericResponse};
+use crate::types::email::SendEmailData;
+use crate::types::incoming_requests::TeamInviteRequest;
+use crate::types::team::{TeamMember, TeamMemberStatus};
+use crate::utilities::api_messages::{APIMessages, CustomerMessages};
+use crate::utilities::email::{check_email_domain, consume_email_send_budget};
+use crate::utilities::helpers::{payload_analyzer, random_string, valid_email};
+
+use axum::extract::{rejection::JsonRejection, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::Utc;
+use serde_json::json;
+use std::sync::Arc;
+
+use super::identity::{get_user_session_from_req, require_any_scope, SessionData, SessionScopes};
+
+// the session's customer, only managers get past this
+async fn find_manager(state: &Arc<AppState>, session_data: &SessionData) -> Result<Customer, (StatusCode, Json<GenericResponse>)> {
+    let filter = build_customer_filter(session_data.customer_id.as_str(), "").await;
+    let customer = match find_customer(state.customers_db(&session_data.region), filter).await? {
+        (true, Some(customer)) => customer,
+        _ => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::NotFound).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    if customer.class != CustomerType::MANAGER {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::OnlyManagersHaveTeams).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(customer)
+}
+
+// the manager holds a seat, so members fill the rest of what was bought
+pub fn check_seat_available(manager: &Customer, members: i64) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let seats = manager.seat_limit();
+    if members + 1 >= seats {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::TeamSeatLimitReached).to_string(),
+                data: json!({"seats": seats, "used_seats": members + 1}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+// GET /api/me/team
+pub async fn list_team(
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // member emails are as sensitive as the manager's own
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess, SessionScopes::ViewEmailAddresses]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let manager = match find_manager(&state, &session_data).await {
+        Ok(manager) => manager,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let members = match list_team_members(&state.mongo_db, &manager.id).await {
+        Ok(members) => members,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::TeamListed).to_string(),
+            data: json!({
+                "seats": manager.seat_limit(),
+                "used_seats": members.len() + 1, // the manager holds a seat
+                "members": members,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// POST /api/me/team/invite, pending invites hold a seat until removed
+pub async fn invite_team_member(
+    headers: HeaderMap,
+    payload_result: Result<Json<TeamInviteRequest>, JsonRejection>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let payload = match payload_analyzer(payload_result) {
+        Ok(payload) => payload,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let email = payload.email.to_lowercase();
+    match valid_email(&email).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match check_email_domain(&state, &email).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let manager = match find_manager(&state, &session_data).await {
+        Ok(manager) => manager,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    if manager.emails.iter().any(|manager_email| manager_email.address == email) {
+        return (
+            StatusCode::CONFLICT,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::TeamMemberAlreadyInvited).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    match find_team_member_by_email(&state.mongo_db, &manager.id, &email).await {
+        Ok(None) => (),
+        Ok(Some(_)) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(GenericResponse {
+                    message: APIMessages::Customer(CustomerMessages::TeamMemberAlreadyInvited).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            )
+        }
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let members = match count_team_members(&state.mongo_db, &manager.id).await {
+        Ok(members) => members as i64,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match check_seat_available(&manager, members) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
+        Ok(api_key) if state.enabled_email_integration => Some(api_key),
+        _ => None,
+    };
+
+    if api_key.is_some() {
+        match consume_email_send_budget(&state, &manager.id) {
+            Ok(_) => (),
+            Err((status_code, json)) => return (status_code, json),
+        };
+    }
+
+    let member = TeamMember {
+        id: random_string(20).await,
+        manager_id: manager.id.clone(),
+        email: email.clone(),
+        status: TeamMemberStatus::Pending,
+        customer_id: String::new(),
+        invited_at: Utc::now().to_rfc3339(),
+    };
+
+    match insert_team_member(&state.mongo_db, &member).await {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    // the invite stands even if the email can't go out, the manager can share the link
+    let invite_url = match &state.email_provider_settings.team_invite_url {
+        Some(url) => format!("{}?team_invite={}", url, member.id),
+        None => format!("https://{}/?team_invite={}", state.api_url, member.id),
+    };
+
+    let email_sent = match api_key {
+        Some(api_key) => {
+            let send_email_data = SendEmailData {
+                api_key,
+                subject: format!("{} invited you to their team", manager.name),
+                template_id: state.email_provider_settings.team_invite_template_id,
+                customer_email: email.clone(),
+                customer_name: email.clone(),
+                verification_link: invite_url.clone(),
+                greetings_title: format!("{} invited you to their team", manager.name),
+                sender_email: state.master_email_entity.email.clone(),
+                sender_name: state.master_email_entity.name.clone(),
+            };
+
+            match send_verification_email(send_email_data).await {
+                Ok(_) => true,
+                Err(err) => {
+                    log::error!("error sending team invite {} for {}: {}", member.id, manager.id, err);
+                    false
+                }
+            }
+        }
+        None => false,
+    };
+
+    (
+        StatusCode::OK,
+        Json(GenericResponse {
+            message: APIMessages::Customer(CustomerMessages::TeamMemberInvited).to_string(),
+            data: json!({
+                "member": member,
+                "invite_url": invite_url,
+                "email_sent": email_sent,
+                "seats": manager.seat_limit(),
+                "used_seats": members + 2,
+            }),
+            exit_code: 0,
+        }),
+    )
+}
+
+// DELETE /api/me/team/:id, frees the seat whether the invite was accepted or not
+pub async fn remove_team_member_by_id(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    state: Arc<AppState>,
+) -> (StatusCode, Json<GenericResponse>) {
+    let session_data = match get_user_session_from_req(headers, &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match require_any_scope(&session_data, &[SessionScopes::TotalAccess]) {
+        Ok(_) => (),
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    let manager = match find_manager(&state, &session_data).await {
+        Ok(manager) => manager,
+        Err((status_code, json)) => return (status_code, json),
+    };
+
+    match remove_team_member(&state.mongo_db, &manager.id, &id).await {
+        Ok(true) => (
+            StatusCode::OK,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::TeamMemberRemoved).to_string(),
+                data: json!({"id": id}),
+                exit_code: 0,
+            }),
+        ),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::TeamMemberNotFound).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ),
+        Err((status_code, json)) => (status_code, json),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::identity::tests::customer;
+
+    fn manager(seats: i64) -> Customer {
+        let mut manager = customer();
+        manager.class = CustomerType::MANAGER;
+        manager.subscription.seats = seats;
+        manager
+    }
+
+    #[test]
+    fn invites_are_allowed_within_the_seats() {
+        assert!(check_seat_available(&manager(3), 0).is_ok());
+        assert!(check_seat_available(&manager(3), 1).is_ok());
+    }
+
+    #[test]
+    fn an_invite_beyond_the_seats_is_refused() {
+        let (status, Json(response)) = check_seat_available(&manager(3), 2).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response.data["used_seats"], 3);
+
+        // a single seat is the manager's own
+        assert!(check_seat_available(&manager(1), 0).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use std::error::Error;
-use crate::types::email::{CreateContact, CreateEmailRequest, Params, SendEmailData, Sender as EmailSender, To};
+use std::{collections::HashMap, error::Error};
+use crate::types::email::{CreateContact, CreateEmailRequest, Params, SendEmailData, Sender as EmailSender, To, UpdateContact};
 
 // add customer to campaign list in Brevo
 pub async fn send_create_contact_request(api_key: &String, list_ids: Vec<u32>, ext_id: &String, email: &String) -> Result<(), Box<dyn Error>> {
@@ -34,31 +34,122 @@ pub async fn send_create_contact_request(api_key: &String, list_ids: Vec<u32>, e
     Ok(())
 }
 
-// Verify Email
-pub async fn send_verification_email(data: SendEmailData) -> Result<(), Box<dyn Error>> {
-    let api_url = "https://api.brevo.com/v3/smtp/email";
+// update contact attributes in Brevo, used for marketing segmentation (e.g. PLAN=pro)
+pub async fn send_update_contact_attributes_request(api_key: &str, email: &str, attributes: HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    update_contact_attributes_at("https://api.brevo.com/v3/contacts", api_key, email, attributes).await
+}
+
+// `contacts_url` is only swapped for a local server in tests
+pub async fn update_contact_attributes_at(contacts_url: &str, api_key: &str, email: &str, attributes: HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    let mut api_url = reqwest::Url::parse(contacts_url)?;
+    match api_url.path_segments_mut() {
+        Ok(mut segments) => {
+            segments.push(email);
+        },
+        Err(_) => return Err(Box::from("invalid brevo contacts url")),
+    };
+
+    let client = reqwest::Client::new();
+
+    let update_contact = UpdateContact {
+        attributes,
+    };
+
+    let json_body = serde_json::to_value(update_contact)?;
+
+    let response = client
+        .put(api_url)
+        .header("accept", "application/json")
+        .header("content-type", "application/json")
+        .header("api-key", api_key)
+        .body(json_body.to_string())
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error_message = response.text().await?;
+        return Err(Box::from(error_message));
+    }
+
+    Ok(())
+}
+
+// remove a customer from Brevo, e.g. when the account is cleaned up
+pub async fn send_delete_contact_request(api_key: &String, email: &str) -> Result<(), Box<dyn Error>> {
+    let mut api_url = reqwest::Url::parse("https://api.brevo.com/v3/contacts")?;
+    match api_url.path_segments_mut() {
+        Ok(mut segments) => {
+            segments.push(email);
+        },
+        Err(_) => return Err(Box::from("invalid brevo contacts url")),
+    };
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .delete(api_url)
+        .header("accept", "application/json")
+        .header("api-key", api_key)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let error_message = response.text().await?;
+        return Err(Box::from(error_message));
+    }
+
+    Ok(())
+}
+
+// lightweight authenticated call, only used to check the api key is valid
+pub async fn send_account_info_request(api_key: &String) -> Result<(), Box<dyn Error>> {
+    let api_url = "https://api.brevo.com/v3/account";
     let client = reqwest::Client::new();
 
-    let create_email_request = CreateEmailRequest {
+    let response = client
+        .get(api_url)
+        .header("accept", "application/json")
+        .header("api-key", api_key)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(Box::from(format!("brevo responded with {}", response.status())));
+    }
+
+    Ok(())
+}
+
+// Verify Email, returns the Brevo message id
+// the exact body posted to Brevo, also served as a preview
+pub fn build_verification_email_request(data: &SendEmailData) -> CreateEmailRequest {
+    CreateEmailRequest {
         sender: EmailSender {
             email: data.sender_email.clone(),
             name: data.sender_name.clone(),
         },
-        subject: Some(data.subject),
+        subject: Some(data.subject.clone()),
         template_id: data.template_id,
         params: Params {
-            verification_link: data.verification_link,
-            greetings_title: data.greetings_title,
+            verification_link: data.verification_link.clone(),
+            greetings_title: data.greetings_title.clone(),
         },
         to: vec![To{
-                email: data.customer_email,
-                name: data.customer_name,
+                email: data.customer_email.clone(),
+                name: data.customer_name.clone(),
         }],
         reply_to: To{
-            email: data.sender_email,
-            name: data.sender_name,
+            email: data.sender_email.clone(),
+            name: data.sender_name.clone(),
         },
-    };
+    }
+}
+
+pub async fn send_verification_email(data: SendEmailData) -> Result<String, Box<dyn Error>> {
+    let api_url = "https://api.brevo.com/v3/smtp/email";
+    let client = reqwest::Client::new();
+
+    let create_email_request = build_verification_email_request(&data);
 
     let json_body = serde_json::to_value(create_email_request)?;
 
@@ -75,6 +166,9 @@ pub async fn send_verification_email(data: SendEmailData) -> Result<(), Box<dyn
         let error_message = response.text().await?;
         return Err(Box::from(error_message));
     }
-    
-    Ok(())
+
+    let body: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    let message_id = body["messageId"].as_str().unwrap_or_default().to_string();
+
+    Ok(message_id)
 }
\ No newline at end of file
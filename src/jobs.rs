@@ -0,0 +1,2 @@
+pub mod grace_periods;
+pub mod unverified_accounts;
\ No newline at end of file
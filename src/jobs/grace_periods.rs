@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{info, warn};
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+};
+
+use crate::{
+    lemonsqueezy::subscription::{expired_subscription_fields, sync_brevo_plan_attributes},
+    server::AppState,
+    storage::mongo::{find_customer, find_customers, update_customer},
+    types::subscription::{Slug, SubscriptionHistoryLog, SubscriptionStatus},
+    utilities::{
+        helpers::add_subscription_history_log_and_to_bson,
+        metrics::record_subscription_transition,
+        webhooks::{acquire_subscription_lock, release_subscription_lock},
+    },
+};
+
+pub const GRACE_PERIOD_SWEEP_INTERVAL_SECS: u64 = 3600;
+
+// subscriptions LemonSqueezy expired during a grace period that has ended since
+pub fn ended_grace_period_filter(now: &str) -> Document {
+    doc! {
+        "deleted": false,
+        "subscription.expire_after_grace": true,
+        "subscription.grace_period_ends_at": {"$lt": now},
+    }
+}
+
+pub fn start_grace_period_expiry(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(GRACE_PERIOD_SWEEP_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            let downgraded = expire_ended_grace_periods(&state).await;
+            if downgraded > 0 {
+                info!("Grace period expiry: {} customers downgraded to free", downgraded);
+            }
+        }
+    });
+}
+
+pub async fn expire_ended_grace_periods(state: &Arc<AppState>) -> usize {
+    let now = Utc::now().to_rfc3339();
+
+    let mut downgraded = 0;
+    for db in state.all_customers_dbs() {
+        let options = FindOptions::builder().limit(500).build();
+        let candidates = match find_customers(db, ended_grace_period_filter(&now), options).await {
+            Ok(candidates) => candidates,
+            Err(_) => {
+                warn!("Grace period expiry: error fetching candidates");
+                continue;
+            }
+        };
+
+        for candidate in candidates.iter() {
+            // same lock as the webhooks, a late payment event must not interleave with the downgrade
+            let lock_token = match acquire_subscription_lock(&state.redis_connection, &candidate.id).await {
+                Ok(Some(lock_token)) => lock_token,
+                _ => continue,
+            };
+
+            let filter = doc! {"id": &candidate.id, "subscription.expire_after_grace": true};
+            if let Ok((true, Some(customer))) = find_customer(db, filter.clone()).await {
+                let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs.clone(), SubscriptionHistoryLog {
+                    event: String::from("grace_period_ended"),
+                    date: now.clone(),
+                }).await;
+
+                let set_fields = expired_subscription_fields(
+                    &SubscriptionStatus::Expired,
+                    now.clone(),
+                    customer.subscription.ends_at.clone(),
+                    bson_history_logs,
+                );
+
+                if let Ok(set_fields) = set_fields {
+                    if update_customer(db, filter, doc! {"$set": set_fields}).await.is_ok() {
+                        downgraded += 1;
+                        let email = customer.emails.first().map(|email| email.address.clone());
+                        record_subscription_transition(&customer.id, &customer.subscription.slug, &Slug::FREE.to_string());
+                        sync_brevo_plan_attributes(state, email, Slug::FREE.to_string(), SubscriptionStatus::Expired);
+                    }
+                }
+            }
+
+            release_subscription_lock(&state.redis_connection, &candidate.id, &lock_token);
+        }
+    }
+
+    downgraded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_matches_held_expiries_whose_grace_period_ended() {
+        let filter = ended_grace_period_filter("2024-01-01T00:00:00+00:00");
+
+        assert_eq!(filter.get_bool("deleted"), Ok(false));
+        assert_eq!(filter.get_bool("subscription.expire_after_grace"), Ok(true));
+        assert_eq!(
+            filter.get_document("subscription.grace_period_ends_at").unwrap(),
+            &doc! {"$lt": "2024-01-01T00:00:00+00:00"}
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{info, warn};
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+};
+
+use crate::{
+    email::brevo_api::send_delete_contact_request,
+    server::AppState,
+    storage::mongo::{find_customers, update_customer},
+    types::subscription::Slug,
+};
+
+// free customers whose only email was never verified and who signed up before the cutoff
+pub fn stale_unverified_filter(cutoff: &str) -> Document {
+    doc! {
+        "deleted": false,
+        "emails": {"$size": 1},
+        "emails.0.verified": false,
+        "subscription.slug": Slug::FREE.to_string(),
+        "created_at": {"$lt": cutoff},
+    }
+}
+
+pub fn start_unverified_accounts_cleanup(state: Arc<AppState>) {
+    let settings = state.unverified_accounts_cleanup.clone();
+    if !settings.enabled {
+        return;
+    }
+
+    info!(
+        "Unverified accounts cleanup enabled, every {}s for accounts older than {} days",
+        settings.interval_secs, settings.ttl_days
+    );
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_secs));
+        loop {
+            interval.tick().await;
+            let cleaned = cleanup_unverified_accounts(&state, settings.ttl_days).await;
+            info!("Unverified accounts cleanup: {} accounts soft deleted", cleaned);
+        }
+    });
+}
+
+pub async fn cleanup_unverified_accounts(state: &Arc<AppState>, ttl_days: i64) -> usize {
+    let cutoff = (Utc::now() - chrono::Duration::days(ttl_days)).to_rfc3339();
+    let api_key = match state.enabled_email_integration {
+        true => std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY").ok(),
+        false => None,
+    };
+
+    let mut cleaned = 0;
+    for db in state.all_customers_dbs() {
+        let options = FindOptions::builder().limit(500).build();
+        let customers = match find_customers(db, stale_unverified_filter(&cutoff), options).await {
+            Ok(customers) => customers,
+            Err(_) => {
+                warn!("Unverified accounts cleanup: error fetching candidates");
+                continue;
+            }
+        };
+
+        for customer in customers.iter() {
+            let update = doc! {"$set": {
+                    "deleted": true,
+                    "updated_at": Utc::now().to_rfc3339(),
+                }
+            };
+
+            match update_customer(db, doc! {"id": &customer.id}, update).await {
+                Ok(_) => cleaned += 1,
+                Err(_) => continue,
+            };
+
+            if let (Some(api_key), Some(email)) = (&api_key, customer.emails.first()) {
+                match send_delete_contact_request(api_key, &email.address).await {
+                    Ok(_) => (),
+                    Err(err) => warn!("error removing Brevo contact for {}: {}", customer.id, err),
+                };
+            }
+        }
+    }
+
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_matches_free_single_unverified_email_accounts_before_the_cutoff() {
+        let filter = stale_unverified_filter("2024-01-01T00:00:00+00:00");
+
+        assert_eq!(filter.get_bool("deleted"), Ok(false));
+        assert_eq!(filter.get_document("emails").unwrap(), &doc! {"$size": 1});
+        assert_eq!(filter.get_bool("emails.0.verified"), Ok(false));
+        assert_eq!(filter.get_str("subscription.slug"), Ok("free"));
+        assert_eq!(filter.get_document("created_at").unwrap(), &doc! {"$lt": "2024-01-01T00:00:00+00:00"});
+    }
+}
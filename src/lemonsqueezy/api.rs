@@ -0,0 +1,31 @@
+use std::error::Error;
+
+use crate::types::lemonsqueezy::{SubscriptionData, SubscriptionResponse};
+
+const LEMONSQUEEZY_API_URL: &str = "https://api.lemonsqueezy.com/v1";
+
+// None when LemonSqueezy doesn't know the subscription
+pub async fn fetch_subscription(api_key: &str, subscription_id: &str) -> Result<Option<SubscriptionData>, Box<dyn Error>> {
+    let api_url = format!("{}/subscriptions/{}", LEMONSQUEEZY_API_URL, subscription_id);
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(api_url)
+        .header("accept", "application/vnd.api+json")
+        .header("content-type", "application/vnd.api+json")
+        .bearer_auth(api_key)
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if !response.status().is_success() {
+        return Err(Box::from(format!("lemonsqueezy responded with {}", response.status())));
+    }
+
+    let subscription: SubscriptionResponse = serde_json::from_str(&response.text().await?)?;
+
+    Ok(Some(subscription.data))
+}
@@ -1,26 +1,118 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::Json;
-use mongodb::bson::{doc, to_bson, Bson};
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use mongodb::bson::{doc, to_bson, Bson, Document};
 use serde_json::json;
 
 use crate::{
-    utilities::helpers::{random_string, add_subscription_history_log_and_to_bson},
+    email::brevo_api::send_update_contact_attributes_request,
+    utilities::{
+        helpers::{add_subscription_history_log_and_to_bson, trim_history_logs},
+        metrics::record_subscription_transition,
+    },
     server::AppState,
     types::{
-        customer::GenericResponse,
-        lemonsqueezy::SubscriptionEvent,
-        subscription::{Slug, Subscription, SubscriptionFrequencyClass, SubscriptionHistoryLog},
-    }, storage::mongo::{build_customer_filter, find_customer, update_customer},
+        customer::{Customer, GenericResponse},
+        lemonsqueezy::{SubscriptionAttributes, SubscriptionData, SubscriptionEvent, VariantPlan},
+        subscription::{Slug, Subscription, SubscriptionFrequencyClass, SubscriptionHistoryLog, SubscriptionStatus},
+    }, storage::mongo::{build_customer_filter, find_customer_in, update_customer},
 };
 
+pub fn plan_attributes(slug: String, status: SubscriptionStatus) -> HashMap<String, String> {
+    HashMap::from([
+        (String::from("PLAN"), slug),
+        (String::from("PLAN_STATUS"), status.as_str().to_string()),
+    ])
+}
+
+// reflect the plan in Brevo for segmentation, never blocks nor fails the webhook
+pub fn sync_brevo_plan_attributes(state: &Arc<AppState>, email: Option<String>, slug: String, status: SubscriptionStatus) {
+    if !state.enabled_email_integration {
+        return;
+    }
+
+    let api_key = match std::env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY") {
+        Ok(api_key) => api_key,
+        Err(_) => return,
+    };
+
+    let email = match email {
+        Some(email) => email,
+        None => return,
+    };
+
+    let attributes = plan_attributes(slug, status);
+    tokio::spawn(async move {
+        match send_update_contact_attributes_request(&api_key, &email, attributes).await {
+            Ok(_) => (),
+            Err(err) => warn!("error syncing plan attributes to Brevo: {}", err),
+        };
+    });
+}
+
+// portal links change over time, so every event carrying them refreshes the stored ones
+fn subscription_urls_fields(attributes: &SubscriptionAttributes) -> Document {
+    match &attributes.urls {
+        Some(urls) => doc! {
+            "subscription.customer_portal_url": urls.customer_portal.clone(),
+            "subscription.update_payment_method_url": urls.update_payment_method.clone(),
+        },
+        None => doc! {},
+    }
+}
+
+pub fn created_subscription(data: SubscriptionData, event_name: String, plan: &VariantPlan, customer: &Customer) -> Subscription {
+    let mut history_logs = customer.subscription.history_logs.clone();
+    history_logs.push(SubscriptionHistoryLog {
+        event: event_name,
+        date: data.attributes.updated_at.clone(),
+    });
+    trim_history_logs(&mut history_logs);
+
+    let seats = data.attributes.seats();
+    let ends_at = match data.attributes.ends_at {
+        Some(ends_at) => ends_at,
+        None => "".to_string(),
+    };
+
+    let (customer_portal_url, update_payment_method_url) = match &data.attributes.urls {
+        Some(urls) => (urls.customer_portal.clone(), urls.update_payment_method.clone()),
+        None => (String::new(), String::new()),
+    };
+
+    Subscription {
+        // the real LemonSqueezy id, admins reconcile with it and re-syncs fetch by it
+        id: data.id,
+        product_id: data.attributes.product_id,
+        variant_id: data.attributes.variant_id,
+        price_id: String::new(),
+        slug: plan.slug.to_string(),
+        frequency: plan.frequency,
+        status: data.attributes.status,
+        created_at: customer.created_at.clone(),
+        updated_at: data.attributes.updated_at,
+        starts_at: data.attributes.created_at,
+        ends_at,
+        renews_at: data.attributes.renews_at,
+        grace_period_ends_at: "".to_string(),
+        expire_after_grace: false,
+        customer_portal_url,
+        update_payment_method_url,
+        billing_anchor: data.attributes.billing_anchor,
+        seats,
+        history_logs,
+    }
+}
+
 pub async fn subscription_created(
     event: SubscriptionEvent,
     state: Arc<AppState>,
 ) -> Result<(), Json<GenericResponse>> {
     let customer_id = event.meta.custom_data.unwrap().customer_id;
     let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter.clone()).await {
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
         Ok(customer) => customer,
         Err(_) => {
             return Err(Json(GenericResponse {
@@ -39,52 +131,22 @@ pub async fn subscription_created(
         }));
     }
 
-    let frequency: SubscriptionFrequencyClass;
-    if event.data.attributes.variant_id == state.products.pro_monthly_variant_id {
-        frequency = SubscriptionFrequencyClass::MONTHLY;
-    } else if event.data.attributes.variant_id == state.products.pro_annually_variant_id {
-        frequency = SubscriptionFrequencyClass::ANNUALLY;
-    } else {
-        return Err(Json(GenericResponse {
-            message: String::from("invalid variant_id"),
-            data: json!({}),
-            exit_code: 1,
-        }));
-    }
+    let plan = match state.products.resolve(event.data.attributes.variant_id) {
+        Some(plan) => plan.clone(),
+        None => {
+            return Err(Json(GenericResponse {
+                message: format!("unknown variant_id: {}", event.data.attributes.variant_id),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
 
     let customer = customer.unwrap();
+    let update_subscription = created_subscription(event.data, event.meta.event_name, &plan, &customer);
 
-    let subscription_id = random_string(15).await;
-    let mut history_logs = customer.subscription.history_logs.clone();
-    history_logs.push(SubscriptionHistoryLog {
-        event: event.meta.event_name,
-        date: event.data.attributes.updated_at.clone(),
-    });
-
-    let mut slug = Slug::FREE.to_string();
-    if event.data.attributes.product_id == state.products.pro_product_id {
-        slug = Slug::PRO.to_string();
-    }
-
-    let ends_at = match event.data.attributes.ends_at {
-        Some(ends_at) => ends_at,
-        None => "".to_string(),
-    };
-    
-    let update_subscription = Subscription {
-        id: subscription_id,
-        product_id: event.data.attributes.product_id,
-        variant_id: event.data.attributes.variant_id,
-        slug,
-        frequency,
-        status: event.data.attributes.status,
-        created_at: customer.created_at,
-        updated_at: event.data.attributes.updated_at,
-        starts_at: event.data.attributes.created_at,
-        ends_at,
-        renews_at: event.data.attributes.renews_at,
-        history_logs,
-    };
+    let brevo_slug = update_subscription.slug.clone();
+    let brevo_status = update_subscription.status.clone();
 
     let update_subscription = match to_bson(&update_subscription) {
         Ok(Bson::Document(document)) => document,
@@ -103,8 +165,13 @@ pub async fn subscription_created(
         },
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
-        Ok(_) => Ok(()),
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &brevo_slug);
+            sync_brevo_plan_attributes(&state, email, brevo_slug, brevo_status);
+            Ok(())
+        },
         Err(_) => {
             return Err(Json(GenericResponse {
                 message: String::from("error updating customer subscription"),
@@ -122,7 +189,7 @@ pub async fn subscription_updated(
     let customer_id = event.meta.custom_data.unwrap().customer_id;
     let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
 
-    let (found, customer) = match find_customer(&state.mongo_db, filter.clone()).await {
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
         Ok(customer) => customer,
         Err(_) => {
             return Err(Json(GenericResponse {
@@ -141,23 +208,61 @@ pub async fn subscription_updated(
         }));
     }
 
+    let plan = match state.products.resolve(event.data.attributes.variant_id) {
+        Some(plan) => plan.clone(),
+        None => {
+            return Err(Json(GenericResponse {
+                message: format!("unknown variant_id: {}", event.data.attributes.variant_id),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    let frequency = match to_bson(&plan.frequency) {
+        Ok(frequency) => frequency,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error converting subscription frequency to bson"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
     let customer = customer.unwrap();
     let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
         event: event.meta.event_name,
         date: event.data.attributes.updated_at.clone(),
     }).await;
 
-    let update = doc! {
-        "$set": doc!{
-            "subscription.variant_id": event.data.attributes.variant_id as i64,
-            "subscription.status": event.data.attributes.status,
-            "subscription.updated_at": event.data.attributes.updated_at,
-            "subscription.history_logs": bson_history_logs,
-        },
+    let seats = event.data.attributes.seats();
+    let urls_fields = subscription_urls_fields(&event.data.attributes);
+    // also backfills subscriptions created back when a random id was stored
+    let mut set_fields = doc!{
+        "subscription.id": event.data.id.clone(),
+        "subscription.product_id": event.data.attributes.product_id,
+        "subscription.variant_id": event.data.attributes.variant_id as i64,
+        "subscription.slug": plan.slug.to_string(),
+        "subscription.frequency": frequency,
+        "subscription.status": event.data.attributes.status.as_str(),
+        "subscription.renews_at": event.data.attributes.renews_at,
+        "subscription.billing_anchor": event.data.attributes.billing_anchor,
+        "subscription.seats": seats,
+        "subscription.updated_at": event.data.attributes.updated_at,
+        "subscription.history_logs": bson_history_logs,
     };
+    set_fields.extend(urls_fields);
 
-    match update_customer(&state.mongo_db, filter, update).await {
-        Ok(_) => Ok(()),
+    let update = doc! {"$set": set_fields};
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &plan.slug.to_string());
+            sync_brevo_plan_attributes(&state, email, plan.slug.to_string(), event.data.attributes.status);
+            Ok(())
+        },
         Err(_) => {
             return Err(Json(GenericResponse {
                 message: String::from("error updating customer subscription"),
@@ -176,7 +281,7 @@ pub async fn subscription_update_status(
     let customer_id = event.meta.custom_data.unwrap().customer_id;
     let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
 
-    let (found, customer) = match find_customer(&state.mongo_db, filter.clone()).await {
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
         Ok(customer) => customer,
         Err(_) => {
             return Err(Json(GenericResponse {
@@ -201,16 +306,142 @@ pub async fn subscription_update_status(
         date: event.data.attributes.updated_at.clone(),
     }).await;
 
-    let update = doc! {
-        "$set": doc!{
-            "subscription.status": event.data.attributes.status.clone(),
-            "subscription.updated_at": event.data.attributes.updated_at,
-            "subscription.history_logs": bson_history_logs,
-        },
+    let seats = event.data.attributes.seats();
+    let urls_fields = subscription_urls_fields(&event.data.attributes);
+    let mut set_fields = doc!{
+        "subscription.status": event.data.attributes.status.as_str(),
+        "subscription.renews_at": event.data.attributes.renews_at,
+        "subscription.billing_anchor": event.data.attributes.billing_anchor,
+        "subscription.seats": seats,
+        "subscription.updated_at": event.data.attributes.updated_at,
+        "subscription.history_logs": bson_history_logs,
     };
+    set_fields.extend(urls_fields);
 
-    match update_customer(&state.mongo_db, filter, update).await {
+    let update = doc! {"$set": set_fields};
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
         Ok(_) => Ok(()),
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+// the $set for an expired subscription, the free tier without any of the paid-only fields
+pub fn expired_subscription_fields(
+    status: &SubscriptionStatus,
+    updated_at: String,
+    ends_at: String,
+    history_logs: Vec<Document>,
+) -> Result<Document, Json<GenericResponse>> {
+    let frequency = match to_bson(&SubscriptionFrequencyClass::UNDEFINED) {
+        Ok(frequency) => frequency,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error converting subscription frequency to bson"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
+    Ok(doc! {
+        "subscription.slug": Slug::FREE.to_string(),
+        "subscription.frequency": frequency,
+        "subscription.product_id": 0_i64,
+        "subscription.variant_id": 0_i64,
+        "subscription.status": status.as_str(),
+        "subscription.updated_at": updated_at,
+        "subscription.ends_at": ends_at,
+        "subscription.renews_at": "",
+        "subscription.grace_period_ends_at": "",
+        "subscription.expire_after_grace": false,
+        "subscription.customer_portal_url": "",
+        "subscription.update_payment_method_url": "",
+        "subscription.billing_anchor": 0_i64,
+        "subscription.seats": 1_i64,
+        "subscription.history_logs": history_logs,
+    })
+}
+
+// a failed payment still being retried keeps the plan, the grace period job downgrades once it ends
+pub fn expiry_waits_for_grace(subscription: &Subscription) -> bool {
+    subscription.in_grace_period()
+}
+
+// expired subscriptions lose the paid tier, so the customer falls back to free
+pub async fn subscription_expired(
+    event: SubscriptionEvent,
+    state: Arc<AppState>,
+) -> Result<(), Json<GenericResponse>> {
+    let customer_id = event.meta.custom_data.unwrap().customer_id;
+    let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
+
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
+        Ok(customer) => customer,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error checking customer existence"),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    if !found {
+        return Err(Json(GenericResponse {
+            message: String::from("invalid customer_id: not records"),
+            data: json!({}),
+            exit_code: 1,
+        }));
+    }
+
+    let customer = customer.unwrap();
+    let held = expiry_waits_for_grace(&customer.subscription);
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
+        event: event.meta.event_name,
+        date: event.data.attributes.updated_at.clone(),
+    }).await;
+
+    if held {
+        let update = doc! {
+            "$set": doc!{
+                "subscription.expire_after_grace": true,
+                "subscription.ends_at": event.data.attributes.ends_at.unwrap_or_default(),
+                "subscription.updated_at": event.data.attributes.updated_at,
+                "subscription.history_logs": bson_history_logs,
+            },
+        };
+
+        return match update_customer(state.customers_db(&customer.region), filter, update).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Json(GenericResponse {
+                message: String::from("error updating customer subscription"),
+                data: json!({}),
+                exit_code: 1,
+            })),
+        };
+    }
+
+    let set_fields = expired_subscription_fields(
+        &event.data.attributes.status,
+        event.data.attributes.updated_at,
+        event.data.attributes.ends_at.unwrap_or_default(),
+        bson_history_logs,
+    )?;
+
+    let update = doc! {"$set": set_fields};
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &Slug::FREE.to_string());
+            sync_brevo_plan_attributes(&state, email, Slug::FREE.to_string(), event.data.attributes.status);
+            Ok(())
+        },
         Err(_) => {
             return Err(Json(GenericResponse {
                 message: String::from("error updating customer subscription"),
@@ -221,13 +452,25 @@ pub async fn subscription_update_status(
     }
 }
 
-pub async fn subscription_update_history_logs(
+// LemonSqueezy retries a failed charge for about two weeks before it expires the subscription
+pub const PAYMENT_GRACE_PERIOD_DAYS: i64 = 14;
+
+// retries of the same failed charge keep the running grace period instead of extending it
+pub fn grace_period_ends_at(subscription: &Subscription, now: DateTime<Utc>) -> String {
+    match subscription.in_grace_period() {
+        true => subscription.grace_period_ends_at.clone(),
+        false => (now + Duration::days(PAYMENT_GRACE_PERIOD_DAYS)).to_rfc3339(),
+    }
+}
+
+// a failed charge starts the grace period, past_due keeps paid access until it ends
+pub async fn subscription_payment_failed(
     event: SubscriptionEvent,
     state: Arc<AppState>,
 ) -> Result<(), Json<GenericResponse>> {
     let customer_id = event.meta.custom_data.unwrap().customer_id;
     let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
-    let (found, customer) = match find_customer(&state.mongo_db, filter.clone()).await {
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
         Ok(customer) => customer,
         Err(_) => {
             return Err(Json(GenericResponse {
@@ -247,19 +490,135 @@ pub async fn subscription_update_history_logs(
     }
 
     let customer = customer.unwrap();
+    let grace_period_ends_at = grace_period_ends_at(&customer.subscription, Utc::now());
     let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
         event: event.meta.event_name,
         date: event.data.attributes.updated_at.clone(),
     }).await;
 
-    let update = doc!  {
+    let update = doc! {
         "$set": doc!{
+            "subscription.grace_period_ends_at": grace_period_ends_at,
             "subscription.updated_at": event.data.attributes.updated_at,
             "subscription.history_logs": bson_history_logs,
         },
     };
 
-    match update_customer(&state.mongo_db, filter, update).await {
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+// the $set for a successful charge, full access comes back and any grace period is over
+pub fn payment_success_fields(attributes: &SubscriptionAttributes, history_logs: Vec<Document>) -> Document {
+    let mut set_fields = doc!{
+        "subscription.status": SubscriptionStatus::Active.as_str(),
+        "subscription.renews_at": attributes.renews_at.clone(),
+        "subscription.billing_anchor": attributes.billing_anchor,
+        "subscription.seats": attributes.seats(),
+        "subscription.grace_period_ends_at": "",
+        "subscription.updated_at": attributes.updated_at.clone(),
+        "subscription.history_logs": history_logs,
+    };
+    set_fields.extend(subscription_urls_fields(attributes));
+
+    set_fields
+}
+
+// a successful charge restores full access, even if a previous payment failed
+pub async fn subscription_payment_success(
+    event: SubscriptionEvent,
+    state: Arc<AppState>,
+) -> Result<(), Json<GenericResponse>> {
+    let customer_id = event.meta.custom_data.unwrap().customer_id;
+    let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
+        Ok(customer) => customer,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error checking customer existence"),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    if !found {
+        return Err(Json(GenericResponse {
+            message: String::from("invalid customer_id: not records"),
+            data: json!({}),
+            exit_code: 1,
+        }));
+    }
+
+    let customer = customer.unwrap();
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
+        event: event.meta.event_name,
+        date: event.data.attributes.updated_at.clone(),
+    }).await;
+
+    let update = doc! {"$set": payment_success_fields(&event.data.attributes, bson_history_logs)};
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            sync_brevo_plan_attributes(&state, email, customer.subscription.slug, SubscriptionStatus::Active);
+            Ok(())
+        },
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+pub async fn subscription_update_history_logs(
+    event: SubscriptionEvent,
+    state: Arc<AppState>,
+) -> Result<(), Json<GenericResponse>> {
+    let customer_id = event.meta.custom_data.unwrap().customer_id;
+    let filter = build_customer_filter(customer_id.as_str(), event.data.attributes.user_email.as_str()).await;
+    let (found, customer) = match find_customer_in(state.all_customers_dbs(), filter.clone()).await {
+        Ok(customer) => customer,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error checking customer existence"),
+                data: json!({}),
+                exit_code: 1,
+            }));
+        }
+    };
+
+    if !found {
+        return Err(Json(GenericResponse {
+            message: String::from("invalid customer_id: not records"),
+            data: json!({}),
+            exit_code: 1,
+        }));
+    }
+
+    let customer = customer.unwrap();
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs, SubscriptionHistoryLog {
+        event: event.meta.event_name,
+        date: event.data.attributes.updated_at.clone(),
+    }).await;
+
+    let urls_fields = subscription_urls_fields(&event.data.attributes);
+    let mut set_fields = doc!{
+        "subscription.updated_at": event.data.attributes.updated_at,
+        "subscription.history_logs": bson_history_logs,
+    };
+    set_fields.extend(urls_fields);
+
+    let update = doc! {"$set": set_fields};
+
+    match update_customer(state.customers_db(&customer.region), filter, update).await {
         Ok(_) => Ok(()),
         Err(_) => {
             return Err(Json(GenericResponse {
@@ -270,3 +629,266 @@ pub async fn subscription_update_history_logs(
         }
     }
 }
+
+// lemonsqueezy ids are numeric, stripe ones start with sub_ and default trials never had one
+pub fn lemonsqueezy_subscription_id(subscription: &Subscription) -> Option<&str> {
+    if subscription.id.is_empty()
+        || !subscription.price_id.is_empty()
+        || !subscription.id.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    Some(&subscription.id)
+}
+
+// overwrites the local subscription with what LemonSqueezy reports, used when webhooks were missed
+pub async fn subscription_synced(
+    customer: &Customer,
+    data: SubscriptionData,
+    state: &Arc<AppState>,
+) -> Result<(String, SubscriptionStatus), Json<GenericResponse>> {
+    let attributes = data.attributes;
+
+    let (slug, frequency, product_id, variant_id) = if attributes.status == SubscriptionStatus::Expired {
+        (Slug::FREE.to_string(), SubscriptionFrequencyClass::UNDEFINED, 0_i64, 0_i64)
+    } else {
+        match state.products.resolve(attributes.variant_id) {
+            Some(plan) => (plan.slug.to_string(), plan.frequency, attributes.product_id, attributes.variant_id),
+            None => {
+                return Err(Json(GenericResponse {
+                    message: format!("unknown variant_id: {}", attributes.variant_id),
+                    data: json!({}),
+                    exit_code: 1,
+                }));
+            }
+        }
+    };
+
+    let frequency = match to_bson(&frequency) {
+        Ok(frequency) => frequency,
+        Err(_) => {
+            return Err(Json(GenericResponse {
+                message: String::from("error converting subscription frequency to bson"),
+                data: json!({}),
+                exit_code: 1,
+            }))
+        }
+    };
+
+    let bson_history_logs = add_subscription_history_log_and_to_bson(customer.subscription.history_logs.clone(), SubscriptionHistoryLog {
+        event: String::from("subscription_synced"),
+        date: Utc::now().to_rfc3339(),
+    }).await;
+
+    let seats = attributes.seats();
+    let urls_fields = subscription_urls_fields(&attributes);
+    let mut set_fields = doc!{
+        "subscription.id": data.id,
+        "subscription.product_id": product_id,
+        "subscription.variant_id": variant_id,
+        "subscription.slug": slug.clone(),
+        "subscription.frequency": frequency,
+        "subscription.status": attributes.status.as_str(),
+        "subscription.renews_at": attributes.renews_at,
+        "subscription.ends_at": attributes.ends_at.unwrap_or_default(),
+        "subscription.billing_anchor": attributes.billing_anchor,
+        "subscription.seats": seats,
+        "subscription.updated_at": attributes.updated_at,
+        "subscription.history_logs": bson_history_logs,
+    };
+    set_fields.extend(urls_fields);
+
+    let update = doc! {"$set": set_fields};
+
+    match update_customer(state.customers_db(&customer.region), doc! {"id": &customer.id}, update).await {
+        Ok(_) => {
+            let email = customer.emails.first().map(|email| email.address.clone());
+            record_subscription_transition(&customer.id, &customer.subscription.slug, &slug);
+            sync_brevo_plan_attributes(state, email, slug.clone(), attributes.status.clone());
+            Ok((slug, attributes.status))
+        },
+        Err(_) => Err(Json(GenericResponse {
+            message: String::from("error updating customer subscription"),
+            data: json!({}),
+            exit_code: 1,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controllers::identity::tests::customer;
+    use crate::email::brevo_api::update_contact_attributes_at;
+    use crate::types::subscription::DefaultSubscription;
+    use axum::{extract::Path, http::{HeaderMap, StatusCode}, routing::put, Router};
+
+    fn attributes(status: &str) -> SubscriptionAttributes {
+        serde_json::from_value(json!({
+            "store_id": 1,
+            "customer_id": 2,
+            "order_id": 3,
+            "order_item_id": 4,
+            "product_id": 5,
+            "variant_id": 6,
+            "product_name": "Pro",
+            "variant_name": "Monthly",
+            "user_name": "Ada",
+            "user_email": "ada@example.com",
+            "status": status,
+            "status_formatted": status,
+            "card_brand": "visa",
+            "card_last_four": "4242",
+            "pause": null,
+            "cancelled": false,
+            "trial_ends_at": null,
+            "billing_anchor": 12,
+            "first_subscription_item": {
+                "id": 7,
+                "price_id": 8,
+                "subscription_id": 9,
+                "quantity": 5,
+                "created_at": "2024-01-12T00:00:00+00:00",
+                "updated_at": "2024-01-12T00:00:00+00:00",
+                "is_usage_based": false
+            },
+            "urls": {
+                "update_payment_method": "https://example.com/payment",
+                "customer_portal": "https://example.com/portal"
+            },
+            "renews_at": "2024-03-12T00:00:00+00:00",
+            "ends_at": null,
+            "created_at": "2024-01-12T00:00:00+00:00",
+            "updated_at": "2024-02-12T00:00:00+00:00",
+            "test_mode": true
+        }))
+        .unwrap()
+    }
+
+    fn subscription() -> Subscription {
+        let default_subscription = DefaultSubscription {
+            slug: Slug::PRO,
+            frequency: SubscriptionFrequencyClass::MONTHLY,
+            trial_days: 14,
+        };
+
+        default_subscription.build(String::from("1"), Utc::now())
+    }
+
+    #[test]
+    fn a_failed_payment_starts_the_grace_period() {
+        let now = Utc::now();
+        let ends_at = grace_period_ends_at(&subscription(), now);
+
+        assert_eq!(ends_at, (now + Duration::days(PAYMENT_GRACE_PERIOD_DAYS)).to_rfc3339());
+    }
+
+    #[test]
+    fn retries_keep_the_running_grace_period() {
+        let mut subscription = subscription();
+        subscription.grace_period_ends_at = (Utc::now() + Duration::days(3)).to_rfc3339();
+
+        assert_eq!(grace_period_ends_at(&subscription, Utc::now()), subscription.grace_period_ends_at);
+    }
+
+    #[test]
+    fn payment_success_after_a_failure_restores_active() {
+        let mut subscription = subscription();
+        subscription.status = SubscriptionStatus::PastDue;
+        subscription.grace_period_ends_at = grace_period_ends_at(&subscription, Utc::now());
+        assert!(subscription.in_grace_period());
+
+        let set_fields = payment_success_fields(&attributes("past_due"), vec![]);
+
+        assert_eq!(set_fields.get_str("subscription.status"), Ok("active"));
+        assert_eq!(set_fields.get_str("subscription.grace_period_ends_at"), Ok(""));
+        assert_eq!(set_fields.get_str("subscription.renews_at"), Ok("2024-03-12T00:00:00+00:00"));
+    }
+
+    #[test]
+    fn expiry_downgrades_to_free_and_clears_paid_fields() {
+        let set_fields = expired_subscription_fields(
+            &SubscriptionStatus::Expired,
+            String::from("2024-02-12T00:00:00+00:00"),
+            String::from("2024-02-12T00:00:00+00:00"),
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(set_fields.get_str("subscription.slug"), Ok("free"));
+        assert_eq!(set_fields.get_str("subscription.status"), Ok("expired"));
+        assert_eq!(set_fields.get_i64("subscription.variant_id"), Ok(0));
+        assert_eq!(set_fields.get_str("subscription.customer_portal_url"), Ok(""));
+        assert_eq!(set_fields.get_str("subscription.update_payment_method_url"), Ok(""));
+        assert_eq!(set_fields.get_i64("subscription.billing_anchor"), Ok(0));
+        assert_eq!(set_fields.get_i64("subscription.seats"), Ok(1));
+        assert_eq!(set_fields.get_str("subscription.grace_period_ends_at"), Ok(""));
+    }
+
+    #[test]
+    fn expiry_waits_while_the_grace_period_runs() {
+        let mut subscription = subscription();
+        subscription.grace_period_ends_at = (Utc::now() + Duration::days(3)).to_rfc3339();
+        assert!(expiry_waits_for_grace(&subscription));
+
+        subscription.grace_period_ends_at = (Utc::now() - Duration::days(1)).to_rfc3339();
+        assert!(!expiry_waits_for_grace(&subscription));
+
+        subscription.grace_period_ends_at = String::new();
+        assert!(!expiry_waits_for_grace(&subscription));
+    }
+
+    #[test]
+    fn a_created_subscription_keeps_the_lemonsqueezy_id_and_seats() {
+        let data = SubscriptionData {
+            r#type: String::from("subscriptions"),
+            id: String::from("ls-sub-981"),
+            attributes: attributes("active"),
+            relationships: None,
+            links: None,
+        };
+        let plan = VariantPlan {
+            slug: Slug::PRO,
+            frequency: SubscriptionFrequencyClass::MONTHLY,
+            features: vec![],
+        };
+
+        let created = created_subscription(data, String::from("subscription_created"), &plan, &customer());
+
+        assert_eq!(created.id, "ls-sub-981");
+        assert_eq!(created.seats, 5);
+        assert_eq!(created.slug, Slug::PRO.to_string());
+        assert_eq!(created.history_logs.last().unwrap().event, "subscription_created");
+    }
+
+    // a local stand-in for Brevo records what the upgrade sends
+    #[tokio::test]
+    async fn upgrades_send_the_plan_to_brevo() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(String, String, String)>();
+        let brevo = Router::new().route(
+            "/v3/contacts/:email",
+            put(move |Path(email): Path<String>, headers: HeaderMap, body: String| async move {
+                let api_key = headers.get("api-key").and_then(|api_key| api_key.to_str().ok()).unwrap_or_default().to_string();
+                sender.send((email, api_key, body)).unwrap();
+                StatusCode::NO_CONTENT
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, brevo).await.unwrap() });
+
+        let attributes = plan_attributes(Slug::PRO.to_string(), SubscriptionStatus::Active);
+        update_contact_attributes_at(&format!("http://{}/v3/contacts", address), "brevo-key", "ada@example.com", attributes)
+            .await
+            .unwrap();
+
+        let (email, api_key, body) = receiver.recv().await.unwrap();
+        assert_eq!(email, "ada@example.com");
+        assert_eq!(api_key, "brevo-key");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!({"attributes": {"PLAN": "pro", "PLAN_STATUS": "active"}})
+        );
+    }
+}
@@ -1,34 +1,55 @@
 use crate::{
-    utilities::helpers::payload_analyzer,
+    utilities::api_messages::{APIMessages, InputMessages},
+    utilities::helpers::valid_customer_id,
+    utilities::metrics::record_webhook_duplicate,
+    utilities::webhooks::{
+        acquire_subscription_lock, claim_webhook_delivery, decode_webhook_body, record_dead_letter, record_webhook_delivery, release_subscription_lock,
+        release_webhook_delivery, WebhookFieldLimits,
+    },
     lemonsqueezy::subscription::{
-        subscription_created, subscription_update_history_logs, subscription_update_status,
-        subscription_updated,
+        subscription_created, subscription_expired, subscription_payment_failed, subscription_payment_success,
+        subscription_update_history_logs,
+        subscription_update_status, subscription_updated,
     },
     server::AppState,
     types::customer::GenericResponse,
     types::lemonsqueezy::{OrderEvent, SubscriptionEvent},
+    types::subscription::SubscriptionStatus,
 };
 
-use axum::{extract::rejection::JsonRejection, http::HeaderMap, http::StatusCode, Json};
+use axum::{body::Bytes, http::HeaderMap, http::StatusCode, Json};
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use hex;
 use hmac::{Hmac, Mac};
-use serde::Serialize;
 use sha2::Sha256;
 
 use serde_json::json;
 use std::sync::Arc;
 use log::trace;
 
+const SIGNATURE_LENGTH: usize = 32; // hmac sha256 digest bytes
+
+// LemonSqueezy sends hex, but proxies may re-encode it as base64, either way it must decode to a sha256 digest
+pub fn decode_signature(signature: &str) -> Option<Vec<u8>> {
+    let decoded = match hex::decode(signature) {
+        Ok(decoded) => decoded,
+        Err(_) => BASE64_STANDARD.decode(signature).ok()?,
+    };
+
+    if decoded.len() != SIGNATURE_LENGTH {
+        return None;
+    }
+
+    Some(decoded)
+}
+
 // built with the help of https://www.linkedin.com/pulse/verifying-custom-headers-hmac-signature-rust-axum-abdurachman--r8ltc
-pub async fn signature_verification<T>(
-    headers: HeaderMap,
-    payload: Json<T>,
+pub async fn signature_verification(
+    headers: &HeaderMap,
+    body: &[u8],
     state: Arc<AppState>,
-) -> (bool, Json<GenericResponse>)
-where
-    T: Serialize,
-{
+) -> (bool, Json<GenericResponse>) {
     let signature_key = state.lemonsqueezy_webhook_signature_key.clone();
     let signature = match headers.get("X-Signature") {
         Some(signature) => signature,
@@ -58,24 +79,13 @@ where
         }
     };
 
-    if signature.len() != 64 {
-        return (
-            false,
-            Json(GenericResponse {
-                message: String::from("invalid signature length"),
-                data: json!({}),
-                exit_code: 1,
-            }),
-        );
-    }
-
-    let mut mac = match Hmac::<Sha256>::new_from_slice(signature_key.as_bytes()) {
-        Ok(mac) => mac,
-        Err(_) => {
+    let signature = match decode_signature(signature) {
+        Some(signature) => signature,
+        None => {
             return (
                 false,
                 Json(GenericResponse {
-                    message: String::from("invalid signature"),
+                    message: String::from("invalid signature length"),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -83,13 +93,13 @@ where
         }
     };
 
-    let payload_into_bytes = match serde_json::to_vec(&payload.0) {
-        Ok(payload_into_bytes) => payload_into_bytes,
+    let mut mac = match Hmac::<Sha256>::new_from_slice(signature_key.as_bytes()) {
+        Ok(mac) => mac,
         Err(_) => {
             return (
                 false,
                 Json(GenericResponse {
-                    message: String::from("error verifying signature payload"),
+                    message: String::from("invalid signature"),
                     data: json!({}),
                     exit_code: 1,
                 }),
@@ -97,11 +107,8 @@ where
         }
     };
 
-    mac.update(&payload_into_bytes);
-    let result = mac.finalize().into_bytes();
-    let result = hex::encode(result);
-
-    if result != signature {
+    mac.update(body);
+    if mac.verify_slice(&signature).is_err() {
         return (
             false,
             Json(GenericResponse {
@@ -122,20 +129,51 @@ where
     );
 }
 
+// a 400 stops the retries, the body goes to the dead letter list for inspection
+fn invalid_event(state: &AppState, body: &[u8], err: String) -> (StatusCode, Json<GenericResponse>) {
+    record_dead_letter(&state.redis_connection, "lemonsqueezy", &err, body);
+    (
+        StatusCode::BAD_REQUEST,
+        Json(GenericResponse {
+            message: APIMessages::Input(InputMessages::InvalidPayload).to_string(),
+            data: json!({"error": err}),
+            exit_code: 1,
+        }),
+    )
+}
+
+// without LEMONSQUEEZY_STORE_ID every store is accepted, as before it existed
+pub fn from_configured_store(state: &AppState, store_id: i64) -> bool {
+    match state.lemonsqueezy_store_id {
+        Some(configured) => configured == store_id,
+        None => true,
+    }
+}
+
 pub async fn orders_webhook_events_listener(
     headers: HeaderMap,
-    payload_result: Result<Json<OrderEvent>, JsonRejection>,
+    body: Bytes,
     state: Arc<AppState>,
 ) -> (StatusCode, Json<GenericResponse>) {
-    let payload = match payload_analyzer(payload_result) {
+    let (verified, error_response) = signature_verification(&headers, &body, state.clone()).await;
+    if !verified {
+        return (StatusCode::BAD_REQUEST, error_response);
+    }
+
+    let payload: OrderEvent = match decode_webhook_body(&headers, &body) {
         Ok(payload) => payload,
-        Err((status_code, json)) => return (status_code, json),
+        Err(err) => return invalid_event(&state, &body, err),
     };
 
-    let (verified, error_response) =
-        signature_verification(headers, payload.clone(), state.clone()).await;
-    if !verified {
-        return (StatusCode::BAD_REQUEST, error_response);
+    if !from_configured_store(&state, payload.data.attributes.store_id) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: String::from("unknown store_id"),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
     }
 
     // order managing, i dont need this currently
@@ -150,23 +188,35 @@ pub async fn orders_webhook_events_listener(
     );
 }
 
+// provider strings that get persisted, known statuses are short so only an unknown one is checked
+fn limit_subscription_fields(limits: &WebhookFieldLimits, payload: &mut SubscriptionEvent) -> Result<(), String> {
+    limits.enforce("event_name", &mut payload.meta.event_name)?;
+    limits.enforce("user_email", &mut payload.data.attributes.user_email)?;
+    limits.enforce("user_name", &mut payload.data.attributes.user_name)?;
+    if let SubscriptionStatus::Unknown(status) = &mut payload.data.attributes.status {
+        limits.enforce("status", status)?;
+    }
+
+    Ok(())
+}
+
 pub async fn subscription_webhook_events_listener(
-    _headers: HeaderMap,
-    payload_result: Result<Json<SubscriptionEvent>, JsonRejection>,
+    headers: HeaderMap,
+    body: Bytes,
     state: Arc<AppState>,
 ) -> (StatusCode, Json<GenericResponse>) {
-    let payload = match payload_analyzer(payload_result) {
+    // everything below trusts payload fields, so nothing runs before the signature is checked
+    let (verified, error_response) = signature_verification(&headers, &body, state.clone()).await;
+    if !verified {
+        trace!("Signature Isn't Valid");
+        return (StatusCode::BAD_REQUEST, error_response);
+    }
+
+    let mut payload: SubscriptionEvent = match decode_webhook_body(&headers, &body) {
         Ok(payload) => payload,
-        Err((status_code, json)) => return (status_code, json),
+        Err(err) => return invalid_event(&state, &body, err),
     };
 
-    //let (verified, error_response) = signature_verification(headers, payload.clone(), state.clone()).await;
-
-    //if !verified {
-      //  trace!("Signature Isn't Valid");
-      //  return (StatusCode::BAD_REQUEST, error_response);
-    //}
-
     let custom_data = match &payload.meta.custom_data {
         Some(custom_data) => custom_data,
         None => {
@@ -183,8 +233,20 @@ pub async fn subscription_webhook_events_listener(
 
     trace!("CUSTOM DATA: {:?}", custom_data);
 
+    if !from_configured_store(&state, payload.data.attributes.store_id) {
+        trace!("STORE MISMATCH: {:?}", payload.data.attributes.store_id);
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: String::from("unknown store_id"),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
     let customer_id = custom_data.customer_id.clone();
-    if customer_id.len() > 100 || customer_id.len() < 1 {
+    if customer_id.is_empty() {
         return (
             StatusCode::BAD_REQUEST,
             Json(GenericResponse {
@@ -195,95 +257,104 @@ pub async fn subscription_webhook_events_listener(
         );
     }
 
+    if !valid_customer_id(&customer_id) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: String::from("invalid customer_id: expected 30 alphanumeric characters"),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
+    if let Err(field) = limit_subscription_fields(&state.webhook_field_limits, &mut payload) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: format!("{} is too long", field),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        );
+    }
+
     trace!("EVENT NAME: {:?}", payload.meta.event_name);
     trace!("CUSTOMER ID: {:?}", customer_id);
     trace!("CUSTOMER EMAIL: {:?}", payload.data.attributes.user_email);
 
     let event_name = payload.meta.event_name.clone();
-    match event_name.as_str() {
-        "subscription_created" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_created(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_updated" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_updated(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_cancelled" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_status(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_resumed" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_status(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_expired" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_status(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_paused" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_status(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
-        }
-        "subscription_unpaused" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_status(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
+    let delivery_id = payload.delivery_id();
+
+    match claim_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id, state.webhook_dedup_ttl) {
+        Ok(true) => (),
+        Ok(false) => {
+            trace!("DUPLICATE DELIVERY: {:?}", delivery_id);
+            record_webhook_duplicate(&event_name);
+            record_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id, &event_name, "duplicate");
+            return (
+                StatusCode::OK,
+                Json(GenericResponse {
+                    message: String::from("duplicate"),
+                    data: json!({}),
+                    exit_code: 0,
+                }),
+            );
         }
-        "subscription_payment_success" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_history_logs(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
+        // a redis outage shouldn't drop billing events
+        Err(err) => log::error!("error claiming webhook delivery {}: {}", delivery_id, err),
+    };
+
+    let lock_token = match acquire_subscription_lock(&state.redis_connection, &customer_id).await {
+        Ok(Some(lock_token)) => Some(lock_token),
+        Ok(None) => {
+            trace!("SUBSCRIPTION LOCKED: {:?}", customer_id);
+            release_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id);
+            return (
+                StatusCode::CONFLICT,
+                Json(GenericResponse {
+                    message: String::from("subscription update in progress, retry later"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            );
         }
-        "subscription_payment_failed" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_history_logs(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
+        // same as the dedup claim, a redis outage shouldn't drop billing events
+        Err(err) => {
+            log::error!("error acquiring subscription lock for {}: {}", customer_id, err);
+            None
         }
+    };
+
+    let result = match event_name.as_str() {
+        "subscription_created" => subscription_created(payload, state.clone()).await,
+        "subscription_updated" => subscription_updated(payload, state.clone()).await,
+        "subscription_cancelled"
+        | "subscription_resumed"
+        | "subscription_paused"
+        | "subscription_unpaused" => subscription_update_status(payload, state.clone()).await,
+        "subscription_expired" => subscription_expired(payload, state.clone()).await,
+        "subscription_payment_success" => subscription_payment_success(payload, state.clone()).await,
+        "subscription_payment_failed" => subscription_payment_failed(payload, state.clone()).await,
         "subscription_payment_recovered" => {
-            let state = state.clone();
-            let payload = payload.clone();
-            match subscription_update_history_logs(payload.0, state).await {
-                Ok(_) => (),
-                Err(json) => return (StatusCode::BAD_REQUEST, json),
-            }
+            subscription_update_history_logs(payload, state.clone()).await
         }
-        _ => {}
+        _ => Ok(()),
+    };
+
+    if let Some(lock_token) = lock_token {
+        release_subscription_lock(&state.redis_connection, &customer_id, &lock_token);
     }
 
+    match result {
+        Ok(_) => record_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id, &event_name, "processed"),
+        Err(json) => {
+            release_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id);
+            record_webhook_delivery(&state.redis_connection, "lemonsqueezy", &delivery_id, &event_name, "failed");
+            return (StatusCode::BAD_REQUEST, json);
+        }
+    };
+
     return (
         StatusCode::OK,
         Json(GenericResponse {
@@ -293,3 +364,23 @@ pub async fn subscription_webhook_events_listener(
         }),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_hex_and_base64_digests() {
+        let digest = [7u8; SIGNATURE_LENGTH];
+
+        assert_eq!(decode_signature(&hex::encode(digest)), Some(digest.to_vec()));
+        assert_eq!(decode_signature(&BASE64_STANDARD.encode(digest)), Some(digest.to_vec()));
+    }
+
+    #[test]
+    fn rejects_wrong_lengths_and_garbage() {
+        assert_eq!(decode_signature(&hex::encode([7u8; 16])), None);
+        assert_eq!(decode_signature("not a signature!"), None);
+        assert_eq!(decode_signature(""), None);
+    }
+}
@@ -2,12 +2,14 @@ mod server;
 
 mod controllers;
 mod lemonsqueezy;
+mod billing;
 mod storage;
 mod types;
 mod utilities;
 mod routers;
 mod email;
 mod oauth;
+mod jobs;
 
 use std::env;
 use chrono::Local;
@@ -15,6 +17,12 @@ use diesel::{r2d2::ConnectionManager, PgConnection};
 use r2d2::Pool;
 use storage::{mongo, redis, diesel_postgres};
 use log::{warn, info, debug};
+use types::customer::UnverifiedNotificationsPolicy;
+use utilities::events::EventsBroker;
+use utilities::token::token_round_trip_check;
+use utilities::token_delivery::TokenDelivery;
+use utilities::webhooks::OversizedFieldPolicy;
+use utilities::config::{load_captcha_settings, load_default_subscription, load_email_mx_resolver, load_integration_webhook, load_plan_prices, load_products, load_oauth_redirect_uris, load_stripe_settings, load_trusted_proxies, ConfigReport};
 
 #[tokio::main]
 async fn main() {
@@ -25,6 +33,11 @@ async fn main() {
     let postgres_uri = load_env().await;
     debug!("Environment variables loaded");
 
+    match token_round_trip_check() {
+        Ok(_) => debug!("JWT self-test passed"),
+        Err(err) => panic!("JWT self-test failed, check API_TOKENS_SIGNING_KEY: {}", err),
+    };
+
     debug!("Connecting to MongoDB...");
     let mongo_client = match mongo::init_connection().await {
         Ok(client) => {
@@ -73,31 +86,83 @@ async fn main() {
 
 async fn load_env() -> String {
     dotenv::dotenv().ok();
-    env::var("HOST").expect("ADDRESS must be set");
-    let port = env::var("PORT").expect("PORT must be set");
-    match port.parse::<u16>() {
-        Ok(_) => (),
-        Err(_) => panic!("PORT must be a number"),
-    };
+    let mut report = ConfigReport::new();
+
+    report.require("Server", "HOST");
+    report.require_parsed::<u16>("Server", "PORT", "number");
+    report.require("Server", "API_URL");
 
     let postgres_uri = match env::var("POSTGRES_URI") {
         Ok(val) => val,
         Err(_) => String::new(),
     };
 
-    env::var("API_URL").expect("API_URL must be set");
-    env::var("MONGO_URI").expect("DATABASE_URL must be set");
-    env::var("MONGO_DB_NAME").expect("DB_NAME must be set");
-    env::var("REDIS_URI").expect("REDIS_URI must be set");
+    report.require("Mongo", "MONGO_URI");
+    report.require("Mongo", "MONGO_DB_NAME");
+    if let Ok(collection) = env::var("MONGO_CUSTOMERS_COLLECTION") {
+        if !mongo::valid_collection_name(collection.trim()) {
+            report.add_issue("Mongo", String::from("MONGO_CUSTOMERS_COLLECTION must be a valid collection name"));
+        }
+    }
+    if let Ok(regions) = env::var("MONGO_REGIONS") {
+        for region in regions.split(',').map(|region| region.trim()).filter(|region| !region.is_empty()) {
+            report.require("Mongo", &format!("MONGO_DB_NAME_{}", region.to_uppercase()));
+        }
+    }
+    report.require("Redis", "REDIS_URI");
 
-    env::var("API_TOKENS_SIGNING_KEY").expect("API_SIGNING_KEY must be set");
-    env::var("LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY").expect("LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY must be set");
+    report.require("Tokens", "API_TOKENS_SIGNING_KEY");
+    report.require_parsed::<usize>("Tokens", "API_TOKENS_EXPIRATION_TIME", "number");
+    if env::var("JWT_LEEWAY_SECS").is_ok() {
+        report.require_parsed::<u64>("Tokens", "JWT_LEEWAY_SECS", "number");
+    }
+    if let Ok(token_delivery) = env::var("TOKEN_DELIVERY") {
+        if token_delivery.parse::<TokenDelivery>().is_err() {
+            report.add_issue("Tokens", String::from("TOKEN_DELIVERY must be body, cookie or both"));
+        }
+    }
 
-    let email_integration = match env::var("ENABLE_EMAIL_INTEGRATION").expect("ENABLE_EMAIL_INTEGRATION must be set").parse::<bool>() {
-        Ok(val) => val,
-        Err(_) => panic!("ENABLE_EMAIL_INTEGRATION must be a boolean"),
+    report.require("LemonSqueezy", "LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY");
+    if env::var("LEMONSQUEEZY_STORE_ID").is_ok() {
+        report.require_parsed::<i64>("LemonSqueezy", "LEMONSQUEEZY_STORE_ID", "number");
+    }
+    match load_products() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("LemonSqueezy", err),
+    };
+    match load_plan_prices() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("LemonSqueezy", format!("PLAN_PRICES {}", err)),
+    };
+    match load_default_subscription() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Subscriptions", err),
+    };
+    match load_stripe_settings() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Stripe", err),
+    };
+    match load_captcha_settings() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Captcha", err),
+    };
+    match load_email_mx_resolver() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Email", err),
+    };
+    match load_integration_webhook() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Integrations", err),
+    };
+    match load_trusted_proxies() {
+        Ok(_) => (),
+        Err(err) => report.add_issue("Server", err),
     };
 
+    let email_integration = report
+        .require_parsed::<bool>("Brevo", "ENABLE_EMAIL_INTEGRATION", "boolean")
+        .unwrap_or(false);
+
     let created_customer_list = env::var("BREVO_CUSTOMERS_LIST_ID");
     let api_key = env::var("BREVO_CUSTOMERS_WEBFLOW_API_KEY");
     let master_email_address = env::var("BREVO_MASTER_EMAIL_ADDRESS");
@@ -130,20 +195,151 @@ async fn load_env() -> String {
         }
     }
 
-    env::var("GOOGLE_OAUTH_CLIENT_ID").expect("GOOGLE_OAUTH_CLIENT_ID must be set");
-    env::var("GOOGLE_OAUTH_CLIENT_SECRET").expect("GOOGLE_OAUTH_CLIENT_SECRET must be set");
-    env::var("GOOGLE_OAUTH_CLIENT_REDIRECT_ENDPOINT").expect("GOOGLE_CLIENT_OAUTH_REDIRECT_URL must be set");
-        
-    let expiration_time = match env::var("API_TOKENS_EXPIRATION_TIME") {
-        Ok(expiration_time) => expiration_time,
-        Err(_) => panic!("API_TOKENS_EXPIRATION_TIME must be set"),
-    };
+    report.require("Brevo", "BREVO_MASTER_EMAIL_ADDRESS");
+    report.require("Brevo", "BREVO_MASTER_NAME");
+    report.require_parsed::<u32>("Brevo", "BREVO_EMAIL_VERIFY_TEMPLATE_ID", "number");
+    if env::var("BREVO_MAGIC_LINK_TEMPLATE_ID").is_ok() {
+        report.require_parsed::<u32>("Brevo", "BREVO_MAGIC_LINK_TEMPLATE_ID", "number");
+    }
+
+    if env::var("BREVO_TEAM_INVITE_TEMPLATE_ID").is_ok() {
+        report.require_parsed::<u32>("Brevo", "BREVO_TEAM_INVITE_TEMPLATE_ID", "number");
+    }
+
+    if env::var("ADMIN_REQUIRE_LISTED_ID").is_ok() {
+        report.require_parsed::<bool>("Admin", "ADMIN_REQUIRE_LISTED_ID", "boolean");
+    }
+
+    if let Ok(limit) = env::var("ADMIN_RATE_LIMIT_PER_MINUTE") {
+        if !matches!(limit.parse::<u64>(), Ok(limit) if limit > 0) {
+            report.add_issue("Admin", String::from("ADMIN_RATE_LIMIT_PER_MINUTE must be a positive number"));
+        }
+    }
+
+    if env::var("ENABLE_API_INDEX").is_ok() {
+        report.require_parsed::<bool>("Server", "ENABLE_API_INDEX", "boolean");
+    }
+
+    if env::var("ENABLE_INTEGRATIONS_HEALTH_CHECK").is_ok() {
+        report.require_parsed::<bool>("Server", "ENABLE_INTEGRATIONS_HEALTH_CHECK", "boolean");
+    }
+
+    if env::var("ENABLE_UNVERIFIED_ACCOUNTS_CLEANUP").is_ok() {
+        report.require_parsed::<bool>("Jobs", "ENABLE_UNVERIFIED_ACCOUNTS_CLEANUP", "boolean");
+    }
+
+    if let Ok(days) = env::var("UNVERIFIED_ACCOUNT_TTL_DAYS") {
+        if !matches!(days.parse::<i64>(), Ok(days) if days > 0) {
+            report.add_issue("Jobs", String::from("UNVERIFIED_ACCOUNT_TTL_DAYS must be a positive number"));
+        }
+    }
+
+    if let Ok(secs) = env::var("UNVERIFIED_ACCOUNTS_CLEANUP_INTERVAL_SECS") {
+        if !matches!(secs.parse::<u64>(), Ok(secs) if secs > 0) {
+            report.add_issue("Jobs", String::from("UNVERIFIED_ACCOUNTS_CLEANUP_INTERVAL_SECS must be a positive number"));
+        }
+    }
+
+    if env::var("WEBHOOK_DEDUP_TTL_SECS").is_ok() {
+        report.require_parsed::<u64>("Webhooks", "WEBHOOK_DEDUP_TTL_SECS", "number");
+    }
+
+    if let Ok(max_length) = env::var("NAME_MAX_LENGTH") {
+        if !matches!(max_length.parse::<usize>(), Ok(max_length) if max_length >= 2) {
+            report.add_issue("Customers", String::from("NAME_MAX_LENGTH must be a number of at least 2"));
+        }
+    }
+
+    if env::var("NAME_CONFUSABLE_CHECK").is_ok() {
+        report.require_parsed::<bool>("Customers", "NAME_CONFUSABLE_CHECK", "boolean");
+    }
+
+    if let Ok(policy) = env::var("NOTIFICATIONS_WITHOUT_VERIFIED_EMAIL") {
+        if policy.parse::<UnverifiedNotificationsPolicy>().is_err() {
+            report.add_issue("Customers", String::from("NOTIFICATIONS_WITHOUT_VERIFIED_EMAIL must be warn or reject"));
+        }
+    }
+
+    if let Ok(broker) = env::var("EVENTS_BROKER") {
+        if broker.parse::<EventsBroker>().is_err() {
+            report.add_issue("Events", String::from("EVENTS_BROKER must be redis or none"));
+        }
+    }
+
+    if let Ok(max_length) = env::var("WEBHOOK_MAX_FIELD_LENGTH") {
+        if !matches!(max_length.parse::<usize>(), Ok(max_length) if max_length > 0) {
+            report.add_issue("Webhooks", String::from("WEBHOOK_MAX_FIELD_LENGTH must be a positive number"));
+        }
+    }
+
+    if let Ok(policy) = env::var("WEBHOOK_OVERSIZED_FIELDS") {
+        if policy.parse::<OversizedFieldPolicy>().is_err() {
+            report.add_issue("Webhooks", String::from("WEBHOOK_OVERSIZED_FIELDS must be reject or truncate"));
+        }
+    }
+
+    if env::var("MAX_LINKED_PROVIDERS").is_ok() {
+        report.require_parsed::<usize>("Customers", "MAX_LINKED_PROVIDERS", "number");
+    }
+
+    if let Ok(class) = env::var("DEFAULT_CUSTOMER_CLASS") {
+        if utilities::helpers::signup_class(&class).is_none() {
+            report.add_issue("Customers", String::from("DEFAULT_CUSTOMER_CLASS must be personal or manager"));
+        }
+    }
+    if env::var("REQUIRE_INVITE_CODE").is_ok() {
+        report.require_parsed::<bool>("Customers", "REQUIRE_INVITE_CODE", "boolean");
+    }
+
+    if env::var("SEND_WELCOME_EMAIL").is_ok() {
+        report.require_parsed::<bool>("Brevo", "SEND_WELCOME_EMAIL", "boolean");
+    }
+
+    for class in ["PERSONAL", "MANAGER", "DEVELOPER"] {
+        let key = format!("BREVO_WELCOME_TEMPLATE_ID_{}", class);
+        if env::var(&key).is_ok() {
+            report.require_parsed::<u32>("Brevo", &key, "number");
+        }
+    }
 
-    match expiration_time.parse::<usize>() {
+    if env::var("MAGIC_LINK_TTL_SECS").is_ok() {
+        report.require_parsed::<u64>("Tokens", "MAGIC_LINK_TTL_SECS", "number");
+    }
+
+    if let Ok(ttl) = env::var("EMAIL_VERIFICATION_TTL_SECS") {
+        if !matches!(ttl.parse::<u64>(), Ok(ttl) if ttl > 0) {
+            report.add_issue("Tokens", String::from("EMAIL_VERIFICATION_TTL_SECS must be a positive number"));
+        }
+    }
+
+    if let Ok(days) = env::var("EMAIL_RECOVERY_WINDOW_DAYS") {
+        if !matches!(days.parse::<i64>(), Ok(days) if days > 0) {
+            report.add_issue("Brevo", String::from("EMAIL_RECOVERY_WINDOW_DAYS must be a positive number"));
+        }
+    }
+
+    if env::var("EMAIL_DAILY_SEND_BUDGET").is_ok() {
+        report.require_parsed::<i64>("Brevo", "EMAIL_DAILY_SEND_BUDGET", "number");
+    }
+
+    for key in ["EMAIL_VERIFY_SUCCESS_URL", "EMAIL_VERIFY_FAILURE_URL", "EMAIL_VERIFY_LANDING_URL"] {
+        if env::var(key).is_ok() {
+            report.require_parsed::<reqwest::Url>("Brevo", key, "url");
+        }
+    }
+
+    report.require("OAuth", "GOOGLE_OAUTH_CLIENT_ID");
+    report.require("OAuth", "GOOGLE_OAUTH_CLIENT_SECRET");
+    report.require("OAuth", "GOOGLE_OAUTH_CLIENT_REDIRECT_ENDPOINT");
+    match load_oauth_redirect_uris() {
         Ok(_) => (),
-        Err(_) => panic!("API_TOKENS_EXPIRATION_TIME must be a number"),
+        Err(err) => report.add_issue("OAuth", err),
     };
 
+    if !report.is_empty() {
+        report.exit();
+    }
+
     return postgres_uri
 }
 
@@ -166,4 +362,42 @@ async fn configure_logger() -> Result<(), fern::InitError>  {
         .apply()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::{Path, PathBuf}};
+
+    fn source_files(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                source_files(&path, files);
+            } else if path.extension().is_some_and(|extension| extension == "rs") {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_modules_are_gone() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for legacy in ["account.rs", "suscription.rs", "account_routers.rs", "identity_routers.rs", "token.rs", "requests_interfaces.rs"] {
+            assert!(!src.join(legacy).exists(), "src/{} is back", legacy);
+        }
+    }
+
+    // the needles are split so this file doesn't match itself
+    #[test]
+    fn nothing_references_the_legacy_symbols() {
+        let mut files = vec![];
+        source_files(&Path::new(env!("CARGO_MANIFEST_DIR")).join("src"), &mut files);
+
+        let needles = [concat!("last_", "account_id"), concat!("struct ", "Account "), concat!("mod ", "account;"), concat!("mod ", "suscription;")];
+        for file in files.iter() {
+            let source = fs::read_to_string(file).unwrap();
+            for needle in needles {
+                assert!(!source.contains(needle), "{} mentions {}", file.display(), needle);
+            }
+        }
+    }
+}
@@ -1,9 +1,14 @@
+use redis::{Client as RedisClient, Commands, RedisError};
 use reqwest::{Client, Url};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::{error::Error, sync::Arc};
 
 use crate::server::AppState;
 
+// a code can't be exchanged twice anyway, this only has to outlive google's own code lifetime
+pub const AUTHORIZATION_CODE_TTL: u64 = 600;
+
 #[derive(Deserialize)]
 pub struct OAuthResponse {
     pub access_token: String,
@@ -22,11 +27,72 @@ pub struct GoogleUserResult {
     pub locale: Option<String>,
 }
 
+// the consent screen the client sends the customer to, state echoes the client back to the callback
+pub fn authorization_url(state: &Arc<AppState>, redirect_uri: &str, client: Option<&str>) -> Result<Url, Box<dyn Error>> {
+    let mut url = Url::parse("https://accounts.google.com/o/oauth2/v2/auth")?;
+    url.query_pairs_mut()
+        .append_pair("client_id", &state.google_auth.client_id)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("response_type", "code")
+        .append_pair("scope", "openid email profile");
+
+    if let Some(client) = client {
+        url.query_pairs_mut().append_pair("state", client);
+    }
+
+    Ok(url)
+}
+
+// google's token endpoint error body, e.g. {"error": "invalid_grant", "error_description": "Bad Request"}
+#[derive(Debug, Deserialize)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OAuthTokenError {
+    InvalidGrant, // expired, revoked or already exchanged code
+    Rejected(String),
+    Unreachable(String), // google never answered, the code may still be unused
+}
+
+// authorization codes are single use, claiming one before the exchange turns a replay into a clear error
+// instead of whatever google answers the second time
+pub fn claim_authorization_code(redis_connection: &RedisClient, authorization_code: &str) -> Result<bool, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(authorization_code_key(authorization_code))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(AUTHORIZATION_CODE_TTL)
+        .query(&mut redis_conn)?;
+
+    Ok(claimed.is_some())
+}
+
+pub fn release_authorization_code(redis_connection: &RedisClient, authorization_code: &str) {
+    let result: Result<i64, RedisError> = redis_connection
+        .get_connection()
+        .and_then(|mut redis_conn| redis_conn.del(authorization_code_key(authorization_code)));
+
+    if let Err(err) = result {
+        log::warn!("error releasing google authorization code: {}", err);
+    }
+}
+
+// codes are credentials, only their digest is stored
+fn authorization_code_key(authorization_code: &str) -> String {
+    format!("oauth_code:google:{}", hex::encode(Sha256::digest(authorization_code.as_bytes())))
+}
+
+// google only hands out tokens when redirect_uri matches the one the code was issued for
 pub async fn request_token(
     authorization_code: &String,
+    redirect_url: &str,
     state: &Arc<AppState>,
-) -> Result<OAuthResponse, Box<dyn Error>> {
-    let redirect_url = state.google_auth.redirect_url.to_owned();
+) -> Result<OAuthResponse, OAuthTokenError> {
     let client_secret = state.google_auth.client_secret.to_owned();
     let client_id = state.google_auth.client_id.to_owned();
 
@@ -35,20 +101,30 @@ pub async fn request_token(
 
     let params = [
         ("grant_type", "authorization_code"),
-        ("redirect_uri", redirect_url.as_str()),
+        ("redirect_uri", redirect_url),
         ("client_id", client_id.as_str()),
         ("code", authorization_code),
         ("client_secret", client_secret.as_str()),
     ];
-    let response = client.post(root_url).form(&params).send().await?;
+    let response = match client.post(root_url).form(&params).send().await {
+        Ok(response) => response,
+        Err(err) => return Err(OAuthTokenError::Unreachable(err.to_string())),
+    };
 
-    if response.status().is_success() {
-        let oauth_response = response.text().await?;
-        let oauth_response: OAuthResponse = serde_json::from_str(&oauth_response)?;        
-        Ok(oauth_response)
-    } else {
-        let err = response.text().await?;
-        Err(From::from(err))
+    let success = response.status().is_success();
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(err) => return Err(OAuthTokenError::Unreachable(err.to_string())),
+    };
+
+    if success {
+        return serde_json::from_str::<OAuthResponse>(&body).map_err(|err| OAuthTokenError::Rejected(err.to_string()));
+    }
+
+    match serde_json::from_str::<OAuthErrorResponse>(&body) {
+        Ok(error) if error.error == "invalid_grant" => Err(OAuthTokenError::InvalidGrant),
+        Ok(error) => Err(OAuthTokenError::Rejected(format!("{}: {}", error.error, error.error_description.unwrap_or_default()))),
+        Err(_) => Err(OAuthTokenError::Rejected(body)),
     }
 }
 
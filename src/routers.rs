@@ -2,4 +2,5 @@ pub mod identity;
 pub mod customer_actions;
 pub mod customers;
 pub mod webhooks;
-pub mod public;
\ No newline at end of file
+pub mod public;
+pub mod admin;
\ No newline at end of file
@@ -0,0 +1,151 @@
+use axum::{BoxError, Json};
+use axum::extract::rejection::JsonRejection;
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::{middleware, Extension, Router, routing::{get, post}};
+use crate::controllers::admin::{admin_access_middleware, create_invite_code, export_customers, fetch_feature_map, fetch_dead_letter_webhooks, fetch_invite_codes, fetch_recent_impersonations, fetch_recent_webhooks, fetch_subscription_stats, find_subscription_by_lemonsqueezy_id, impersonate_customer, list_customers, merge_customers, prune_subscription_history, reactivate_customer, suspend_customer, sync_customer_subscription, update_feature_map};
+use crate::types::incoming_requests::{CreateInviteCode, CustomerMergeRequest, FeatureMapUpdate, ImpersonationRequest, PruneHistoryRequest};
+
+use crate::controllers::identity::SessionData;
+use crate::server::AppState;
+use std::{sync::Arc, time::Duration};
+
+use tower::{buffer::BufferLayer, limit::RateLimitLayer, ServiceBuilder};
+
+// /api/admin
+pub async fn get_admin_router(app_state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/customers",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |query: Query<_>| list_customers(query, app_state)
+            }),
+        )
+        .route(
+            "/customers/export.ndjson",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || export_customers(app_state)
+            }),
+        )
+        .route(
+            "/customers/merge",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |payload: Result<Json<CustomerMergeRequest>, JsonRejection>| merge_customers(payload, app_state)
+            }),
+        )
+        .route(
+            "/customers/:id/suspend",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |id: Path<String>| suspend_customer(id, app_state)
+            }),
+        )
+        .route(
+            "/customers/:id/reactivate",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |id: Path<String>| reactivate_customer(id, app_state)
+            }),
+        )
+        .route(
+            "/customers/:id/impersonate",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(session, id, payload): (Extension<SessionData>, Path<String>, Result<Json<ImpersonationRequest>, JsonRejection>)| {
+                    impersonate_customer(session, id, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/impersonations/recent",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_recent_impersonations(app_state)
+            }),
+        )
+        .route(
+            "/invite-codes",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(session, payload): (Extension<SessionData>, Result<Json<CreateInviteCode>, JsonRejection>)| {
+                    create_invite_code(session, payload, app_state)
+                }
+            })
+            .get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_invite_codes(app_state)
+            }),
+        )
+        .route(
+            "/customers/:id/subscription/sync",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |id: Path<String>| sync_customer_subscription(id, app_state)
+            }),
+        )
+        .route(
+            "/stats/subscriptions",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_subscription_stats(app_state)
+            }),
+        )
+        .route(
+            "/webhooks/recent",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_recent_webhooks(app_state)
+            }),
+        )
+        .route(
+            "/webhooks/dead-letter",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_dead_letter_webhooks(app_state)
+            }),
+        )
+        .route(
+            "/subscriptions/prune-history",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |payload: Result<Json<PruneHistoryRequest>, JsonRejection>| prune_subscription_history(payload, app_state)
+            }),
+        )
+        .route(
+            "/subscriptions/by-ls-id/:id",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |id: Path<String>| find_subscription_by_lemonsqueezy_id(id, app_state)
+            }),
+        )
+        .route(
+            "/config/feature-map",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_feature_map(app_state)
+            })
+            .put({
+                let app_state = Arc::clone(&app_state);
+                move |(session, payload): (Extension<SessionData>, Result<Json<FeatureMapUpdate>, JsonRejection>)| {
+                    update_feature_map(session, payload, app_state)
+                }
+            }),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|err: BoxError| async move {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Unhandled error: {}", err),
+                    )
+                }))
+                .layer(BufferLayer::new(64))
+                .layer(RateLimitLayer::new(app_state.admin_rate_limit, Duration::from_secs(60))),
+        )
+        // route_layer so unknown admin paths still get the plain 404
+        .route_layer(middleware::from_fn_with_state(app_state, admin_access_middleware))
+}
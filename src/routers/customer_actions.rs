@@ -2,11 +2,17 @@ use axum::{BoxError, Json};
 use axum::error_handling::HandleErrorLayer;
 use axum::extract::rejection::JsonRejection;
 use axum::http::{StatusCode, HeaderMap};
-use axum::{Router, routing::{get, patch}};
-use crate::controllers::customer::{update_name, update_password};
-use crate::controllers::email::{add_email, verify_email};
+use axum::extract::{Path, Query};
+use axum::{middleware, Router, routing::{delete, get, patch, post}};
+use crate::controllers::customer::{delete_metadata_key, fetch_rate_limits, update_customer_profile, update_language, update_metadata, update_name, update_password, update_picture};
+use crate::controllers::linked_providers::{link_provider, unlink_provider};
+use crate::controllers::email::{add_email, check_email_verification_token, disable_email, enable_email, list_emails, open_verification_link, preview_verification_email, send_test_verification_email, verify_email};
+use crate::controllers::team::{invite_team_member, list_team, remove_team_member_by_id};
+use crate::controllers::subscription::{check_feature_access, download_subscription_history_csv, fetch_subscription_portal, sync_subscription};
 use crate::server::AppState;
-use crate::types::incoming_requests::{CustomerUpdateName, CustomerUpdatePassword, CustomerAddEmail};
+use crate::utilities::idempotency::idempotency_middleware;
+use crate::utilities::token_delivery::token_delivery_middleware;
+use crate::types::incoming_requests::{LinkProviderRequest, CustomerUpdate, CustomerUpdateLanguage, CustomerUpdateName, CustomerUpdatePassword, CustomerUpdatePicture, CustomerAddEmail, CustomerToggleEmail, CustomerUpdateMetadata, SubscriptionHistoryQueryParams, TeamInviteRequest, VerifyEmailQueryParams};
 use std::{sync::Arc, time::Duration};
 
 use tower::{buffer::BufferLayer, limit::RateLimitLayer, ServiceBuilder};
@@ -14,6 +20,15 @@ use tower::{buffer::BufferLayer, limit::RateLimitLayer, ServiceBuilder};
 // /api/me
 pub async fn get_customer_actions_router(app_state: Arc<AppState>) -> Router<Arc<AppState>> {
     return Router::new()
+        .route(
+            "/",
+            patch({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerUpdate>, JsonRejection>)| {
+                    update_customer_profile(headers, payload, app_state)
+                }
+            }),
+        )
         .route(
             "/update/name",
             patch({
@@ -23,6 +38,24 @@ pub async fn get_customer_actions_router(app_state: Arc<AppState>) -> Router<Arc
                 }
             }),
         )
+        .route(
+            "/picture",
+            patch({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerUpdatePicture>, JsonRejection>)| {
+                    update_picture(headers, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/preferences/language",
+            patch({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerUpdateLanguage>, JsonRejection>)| {
+                    update_language(headers, payload, app_state)
+                }
+            }),
+        )
         .route(
             "/update/password",
             patch({
@@ -42,14 +75,161 @@ pub async fn get_customer_actions_router(app_state: Arc<AppState>) -> Router<Arc
             }),
         )
         .route(
-            "/verify/email",
+            "/metadata",
+            patch({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerUpdateMetadata>, JsonRejection>)| {
+                    update_metadata(headers, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/metadata/:key",
+            delete({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, key): (HeaderMap, Path<String>)| {
+                    delete_metadata_key(headers, key, app_state)
+                }
+            }),
+        )
+        .route(
+            "/features/:feature",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, feature): (HeaderMap, Path<String>)| {
+                    check_feature_access(headers, feature, app_state)
+                }
+            }),
+        )
+        .route(
+            "/subscription/history.csv",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, query): (HeaderMap, Query<SubscriptionHistoryQueryParams>)| {
+                    download_subscription_history_csv(headers, query, app_state)
+                }
+            }),
+        )
+        .route(
+            "/subscription/portal",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |headers| fetch_subscription_portal(headers, app_state)
+            }),
+        )
+        .route(
+            "/link/:provider",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, provider, payload): (HeaderMap, Path<String>, Result<Json<LinkProviderRequest>, JsonRejection>)| {
+                    link_provider(headers, provider, payload, app_state)
+                }
+            })
+            .delete({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, provider): (HeaderMap, Path<String>)| unlink_provider(headers, provider, app_state)
+            }),
+        )
+        .route(
+            "/subscription/sync",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |headers| sync_subscription(headers, app_state)
+            }),
+        )
+        .route(
+            "/emails",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |headers| list_emails(headers, app_state)
+            }),
+        )
+        .route(
+            "/email/disable",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerToggleEmail>, JsonRejection>)| {
+                    disable_email(headers, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/email/enable",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<CustomerToggleEmail>, JsonRejection>)| {
+                    enable_email(headers, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/email/verify",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, query_params): (HeaderMap, Query<VerifyEmailQueryParams>)| {
+                   open_verification_link(headers, query_params, app_state)
+                }
+            })
+            .post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, query_params): (HeaderMap, Query<VerifyEmailQueryParams>)| {
+                   verify_email(headers, query_params, app_state)
+                }
+            }),
+        )
+        .route(
+            "/email/verify/check",
             get({
                 let app_state = Arc::clone(&app_state);
                 move |query_params| {
-                   verify_email(query_params, app_state)
+                   check_email_verification_token(query_params, app_state)
+                }
+            }),
+        )
+        .route(
+            "/email/verification/preview",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |headers| preview_verification_email(headers, app_state)
+            }),
+        )
+        .route(
+            "/email/test",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |headers| send_test_verification_email(headers, app_state)
+            }),
+        )
+        .route(
+            "/team",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |headers| list_team(headers, app_state)
+            }),
+        )
+        .route(
+            "/team/invite",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<TeamInviteRequest>, JsonRejection>)| {
+                    invite_team_member(headers, payload, app_state)
                 }
             }),
         )
+        .route(
+            "/team/:id",
+            delete({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, id): (HeaderMap, Path<String>)| remove_team_member_by_id(headers, id, app_state)
+            }),
+        )
+        .route(
+            "/rate-limit",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |headers| fetch_rate_limits(headers, app_state)
+            }),
+        )
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|err: BoxError| async move {
@@ -60,5 +240,8 @@ pub async fn get_customer_actions_router(app_state: Arc<AppState>) -> Router<Arc
                 }))
                 .layer(BufferLayer::new(128))
                 .layer(RateLimitLayer::new(10, Duration::from_secs(60))),
-        );
+        )
+        // password changes hand back a rotated token
+        .layer(middleware::from_fn_with_state(app_state.clone(), idempotency_middleware))
+        .layer(middleware::from_fn_with_state(app_state, token_delivery_middleware));
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
-use axum::BoxError;
+use axum::{BoxError, Json};
 use axum::error_handling::HandleErrorLayer;
-use axum::http::StatusCode;
+use axum::extract::{rejection::JsonRejection, Query};
+use axum::http::{HeaderMap, StatusCode};
 use axum::{Router, routing::post};
 use crate::controllers::customer::create_customer_record;
+use crate::types::incoming_requests::{CreateCustomerQueryParams, CreateCustomerRecord};
 
 use crate::server::AppState;
 use std::{sync::Arc, time::Duration};
@@ -16,7 +18,9 @@ pub async fn get_customers_router(app_state: Arc<AppState>) -> Router<Arc<AppSta
             "/create",
             post({
                 let app_state = Arc::clone(&app_state);
-                move |payload| create_customer_record(payload, app_state)
+                move |(headers, query, payload): (HeaderMap, Query<CreateCustomerQueryParams>, Result<Json<CreateCustomerRecord>, JsonRejection>)| {
+                    create_customer_record(headers, query, payload, app_state)
+                }
             }),
         )
         .layer(
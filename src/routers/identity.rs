@@ -1,10 +1,14 @@
 use axum::BoxError;
 use axum::error_handling::HandleErrorLayer;
-use axum::http::StatusCode;
-use axum::{Router, routing::{get, post, patch}};
-use crate::controllers::identity::{get_session, gooogle_authentication, legacy_authentication, renew_session};
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{HeaderMap, StatusCode};
+use axum::{middleware, Router, routing::{delete, get, post, patch}};
+use crate::controllers::identity::{consume_magic_link, elevate_session, end_session, get_session, gooogle_authentication, legacy_authentication, open_magic_link, recovery_authentication, renew_session, request_magic_link, start_google_authentication};
 
 use crate::server::AppState;
+use crate::utilities::token_delivery::token_delivery_middleware;
+use crate::types::incoming_requests::SessionElevation;
 use std::{sync::Arc, time::Duration};
 
 use tower::{buffer::BufferLayer, limit::RateLimitLayer, ServiceBuilder};
@@ -33,6 +37,13 @@ pub async fn get_identity_router(app_state: Arc<AppState>) -> Router<Arc<AppStat
                 move |headers| renew_session(headers, app_state)
             }),
         )
+        .route(
+            "/session",
+            delete({
+                let app_state = Arc::clone(&app_state);
+                move |headers| end_session(headers, app_state)
+            }),
+        )
         .route(
             "/session/google",
             get({
@@ -40,6 +51,43 @@ pub async fn get_identity_router(app_state: Arc<AppState>) -> Router<Arc<AppStat
                 move |headers| gooogle_authentication(headers, app_state)
             }),
         )
+        .route(
+            "/session/google/start",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |query| start_google_authentication(query, app_state)
+            }),
+        )
+        .route(
+            "/session/elevate",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, payload): (HeaderMap, Result<Json<SessionElevation>, JsonRejection>)| {
+                    elevate_session(headers, payload, app_state)
+                }
+            }),
+        )
+        .route(
+            "/session/recovery",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |payload| recovery_authentication(payload, app_state)
+            }),
+        )
+        .route(
+            "/session/magic-link",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |payload| request_magic_link(payload, app_state)
+            }),
+        )
+        .route(
+            "/session/magic-link/consume",
+            get(open_magic_link).post({
+                let app_state = Arc::clone(&app_state);
+                move |query| consume_magic_link(query, app_state)
+            }),
+        )
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|err: BoxError| async move {
@@ -50,5 +98,6 @@ pub async fn get_identity_router(app_state: Arc<AppState>) -> Router<Arc<AppStat
                 }))
                 .layer(BufferLayer::new(256))
                 .layer(RateLimitLayer::new(30, Duration::from_secs(60))),
-        );
+        )
+        .layer(middleware::from_fn_with_state(app_state, token_delivery_middleware));
 }
\ No newline at end of file
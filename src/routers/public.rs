@@ -1,9 +1,11 @@
-use axum::extract::Query;
+use axum::extract::{Path, Query};
 use axum::BoxError;
 use axum::error_handling::HandleErrorLayer;
 use axum::http::{HeaderMap, StatusCode};
 use axum::{Router, routing::get};
-use crate::controllers::customer::fetch_customer_record_by_id;
+use crate::controllers::customer::{fetch_customer_record_by_id, fetch_public_profile};
+use crate::controllers::health::fetch_version;
+use crate::controllers::identity::{fetch_auth_providers, fetch_scopes_catalog};
 
 use crate::server::AppState;
 use crate::types::incoming_requests::FetchCustomerByID;
@@ -21,6 +23,22 @@ pub async fn get_public_router(app_state: Arc<AppState>) -> Router<Arc<AppState>
                 move |(headers, query): (HeaderMap, Query<FetchCustomerByID>)| fetch_customer_record_by_id(headers, query, app_state)
             }),
         )
+        .route(
+            "/profile/:id",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move |id: Path<String>| fetch_public_profile(id, app_state)
+            }),
+        )
+        .route(
+            "/auth/providers",
+            get({
+                let app_state = Arc::clone(&app_state);
+                move || fetch_auth_providers(app_state)
+            }),
+        )
+        .route("/scopes", get(fetch_scopes_catalog))
+        .route("/version", get(fetch_version))
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(|err: BoxError| async move {
@@ -1,12 +1,12 @@
-use axum::{BoxError, Json};
+use axum::BoxError;
+use axum::body::Bytes;
 use axum::error_handling::HandleErrorLayer;
-use axum::extract::rejection::JsonRejection;
 use axum::http::{StatusCode, HeaderMap};
 use axum::{Router, routing::post};
 
+use crate::billing::stripe::webhook::stripe_webhook_events_listener;
 use crate::lemonsqueezy::webhook::{orders_webhook_events_listener, subscription_webhook_events_listener};
 use crate::server::AppState;
-use crate::types::lemonsqueezy::{SubscriptionEvent, OrderEvent};
 use std::{sync::Arc, time::Duration};
 
 use tower::{buffer::BufferLayer, limit::RateLimitLayer, ServiceBuilder};
@@ -18,8 +18,8 @@ pub async fn get_webhooks_router(app_state: Arc<AppState>) -> Router<Arc<AppStat
             "/lemonsqueezy/events/orders",
             post({
                 let app_state = Arc::clone(&app_state);
-                move |(headers, payload): (HeaderMap, Result<Json<OrderEvent>, JsonRejection>)| {
-                    orders_webhook_events_listener(headers, payload, app_state)
+                move |(headers, body): (HeaderMap, Bytes)| {
+                    orders_webhook_events_listener(headers, body, app_state)
                 }
             }),
         )
@@ -27,11 +27,18 @@ pub async fn get_webhooks_router(app_state: Arc<AppState>) -> Router<Arc<AppStat
             "/lemonsqueezy/events/subscriptions",
             post({
                 let app_state = Arc::clone(&app_state);
-                move |(headers, payload): (
-                    HeaderMap,
-                    Result<Json<SubscriptionEvent>, JsonRejection>,
-                )| {
-                    subscription_webhook_events_listener(headers, payload, app_state)
+                // raw bytes, the signature covers the body exactly as received
+                move |(headers, body): (HeaderMap, Bytes)| {
+                    subscription_webhook_events_listener(headers, body, app_state)
+                }
+            }),
+        )
+        .route(
+            "/stripe/events",
+            post({
+                let app_state = Arc::clone(&app_state);
+                move |(headers, body): (HeaderMap, String)| {
+                    stripe_webhook_events_listener(headers, body, app_state)
                 }
             }),
         )
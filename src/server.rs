@@ -1,28 +1,35 @@
 use crate::{
-    utilities::helpers::fallback,
-    types::lemonsqueezy::Products, 
+    controllers::health::{check_integrations, fetch_api_index},
+    jobs::{grace_periods::start_grace_period_expiry, unverified_accounts::start_unverified_accounts_cleanup},
+    storage::mongo::{ensure_customer_indexes, ensure_invite_code_indexes, ensure_team_member_indexes, find_feature_map, init_connection_with_uri},
+    utilities::{config::{ConfigReport, load_captcha_settings, load_default_subscription, load_email_mx_resolver, load_integration_webhook, load_oauth_redirect_uris, load_plan_prices, load_products, load_stripe_settings, load_trusted_proxies}, client_ip::client_ip_middleware, helpers::{domain_matches, fallback, handle_panic, signup_class, DEFAULT_NAME_MAX_LENGTH}, captcha::CaptchaSettings, events::{EventPublisher, EventsBroker, NoopPublisher, RedisStreamPublisher, DEFAULT_EVENTS_STREAM}, integration_webhook::IntegrationWebhook, metrics::init_metrics, token_delivery::TokenDelivery, webhooks::{OversizedFieldPolicy, WebhookFieldLimits, DEFAULT_WEBHOOK_MAX_FIELD_LENGTH}},
+    types::{customer::{CustomerType, UnverifiedNotificationsPolicy}, feature_map::FeatureMap, lemonsqueezy::Products, stripe::StripeSettings, subscription::{DefaultSubscription, PlanPrices}},
     routers::{
-        customer_actions::get_customer_actions_router, customers::get_customers_router, identity::get_identity_router, public::get_public_router, webhooks::get_webhooks_router
+        admin::get_admin_router, customer_actions::get_customer_actions_router, customers::get_customers_router, identity::get_identity_router, public::get_public_router, webhooks::get_webhooks_router
     },
 };
 use axum::{
     http::Method,
+    middleware,
     routing::get,
     Router,
 };
 use diesel::{r2d2::ConnectionManager, PgConnection};
+use hickory_resolver::TokioAsyncResolver;
+use ipnet::IpNet;
 use mongodb::{Client as MongoClient, Database};
 use r2d2::Pool;
 use redis::Client as RedisClient;
-use std::{env, sync::Arc, time::Duration};
+use std::{collections::HashMap, env, net::SocketAddr, sync::{Arc, RwLock}, time::Duration};
 
 use tower_http::timeout::TimeoutLayer;
 use tower_http::{
+    catch_panic::CatchPanicLayer,
     compression::CompressionLayer,
     cors::{Any, CorsLayer},
 };
 
-use log::info;
+use log::{info, warn};
 
 #[derive(Clone)]
 pub struct MasterEmailEntity {
@@ -33,6 +40,63 @@ pub struct MasterEmailEntity {
 #[derive(Clone)]
 pub struct EmailProviderSettings {
     pub email_verification_template_id: u32,
+    pub magic_link_template_id: u32,
+    pub team_invite_template_id: u32,
+    pub magic_link_ttl: u64,
+    pub email_verification_ttl: u64, // seconds a verification link stays valid
+    pub email_recovery_window_days: i64, // a disabled address can be enabled again for this long
+    pub daily_send_budget: i64, // emails per customer per day, across every email sending endpoint
+
+    pub send_welcome_email: bool,
+    pub welcome_template_ids: HashMap<String, u32>, // by customer class
+
+    // page team invite emails link to, the invite id is appended as ?team_invite=
+    pub team_invite_url: Option<String>,
+
+    // browsers opening the verification link are sent here instead of getting json
+    pub verify_success_url: Option<String>,
+    pub verify_failure_url: Option<String>,
+    // page that confirms the address, a GET on the link only forwards the token there
+    pub verify_landing_url: Option<String>,
+}
+
+impl EmailProviderSettings {
+    // classes without their own template keep using the verification one
+    pub fn welcome_template_id(&self, class: &CustomerType) -> u32 {
+        match self.welcome_template_ids.get(&class.to_string()) {
+            Some(template_id) => *template_id,
+            None => self.email_verification_template_id,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SignupDomainPolicy {
+    pub allowed_domains: Vec<String>, // empty means every domain is allowed
+    pub blocked_domains: Vec<String>,
+}
+
+impl SignupDomainPolicy {
+    pub fn is_allowed(&self, email: &str) -> bool {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return false,
+        };
+
+        if self.blocked_domains.iter().any(|rule| domain_matches(&domain, rule)) {
+            return false;
+        }
+
+        self.allowed_domains.is_empty()
+            || self.allowed_domains.iter().any(|rule| domain_matches(&domain, rule))
+    }
+}
+
+#[derive(Clone)]
+pub struct UnverifiedAccountsCleanup {
+    pub enabled: bool,
+    pub ttl_days: i64,
+    pub interval_secs: u64,
 }
 
 #[derive(Clone)]
@@ -40,6 +104,22 @@ pub struct GoogleAuth {
     pub client_id: String,
     pub client_secret: String,
     pub redirect_url: String,
+    pub redirect_uris: HashMap<String, String>, // extra front-ends, keyed by client name
+}
+
+impl GoogleAuth {
+    // no client means the api's own callback, unknown clients get nothing so callers can reject them
+    pub fn redirect_uri_for(&self, client: Option<&str>) -> Option<&String> {
+        match client {
+            None => Some(&self.redirect_url),
+            Some(client) => self.redirect_uris.get(&client.to_lowercase()),
+        }
+    }
+
+    // the env vars are required at startup, but blank values still leave google unusable
+    pub fn is_configured(&self) -> bool {
+        !self.client_id.trim().is_empty() && !self.client_secret.trim().is_empty()
+    }
 }
 
 
@@ -47,21 +127,89 @@ pub struct GoogleAuth {
 pub struct AppState {
     pub api_url: String,
     pub api_tokens_expiration_time: i64,
+    pub token_delivery: TokenDelivery, // body, cookie or both
 
     pub mongodb_client: MongoClient,
-    pub mongo_db: Database,
+    pub mongo_db: Database, // default region
+    pub regional_dbs: HashMap<String, Database>, // data residency, region -> customers database
+    pub default_region: String,
 
     pub redis_connection: RedisClient,
     pub postgres_conn: Option<Pool<ConnectionManager<PgConnection>>>,
 
     pub lemonsqueezy_webhook_signature_key: String,
+    pub lemonsqueezy_api_key: Option<String>, // only needed to re-sync subscriptions on demand
+    pub lemonsqueezy_store_id: Option<i64>, // events from any other store are rejected
     pub products: Products,
+    pub feature_map: Arc<RwLock<FeatureMap>>, // slug and status -> features, replaced by PUT /api/admin/config/feature-map
+    pub plan_prices: HashMap<String, PlanPrices>, // slug -> prices, only used for reporting
+    pub default_subscription: DefaultSubscription,
+    pub stripe: Option<StripeSettings>, // alternative billing provider, None unless STRIPE_WEBHOOK_SECRET is set
+    pub webhook_dedup_ttl: u64, // seconds a webhook delivery id is remembered
+    pub webhook_field_limits: WebhookFieldLimits,
+    pub integration_webhook: Option<IntegrationWebhook>, // signed outbound events, e.g. email.verified
+    pub event_publisher: Arc<dyn EventPublisher>, // domain events like customer.created, a no-op unless EVENTS_BROKER is set
 
     pub enabled_email_integration: bool,
     pub master_email_entity: MasterEmailEntity,
     pub email_provider_settings: EmailProviderSettings,
 
     pub google_auth: GoogleAuth,
+
+    pub admin_customer_ids: Vec<String>,
+    pub admin_require_listed_id: bool, // admin requests re-check ADMIN_CUSTOMER_IDS, not only the token scope
+    pub admin_rate_limit: u64, // requests per minute across /api/admin
+    pub max_linked_providers: usize, // sign in methods a customer can add on top of the signup one
+    pub signup_domain_policy: SignupDomainPolicy,
+    pub supported_languages: Vec<String>,
+    pub default_customer_class: CustomerType, // signups that don't pick a class
+    pub name_max_length: usize, // characters
+    pub name_confusable_check: bool, // rejects names mixing latin, greek and cyrillic letters
+    pub unverified_notifications_policy: UnverifiedNotificationsPolicy,
+
+    pub integrations_health_check: bool,
+    pub api_index: bool, // GET /api describes the route groups
+    pub trusted_proxies: Vec<IpNet>, // X-Forwarded-For is only read from these
+    pub require_invite_code: bool, // closed beta, signups need an invite code
+    pub captcha: Option<CaptchaSettings>, // signups need a captcha_token when set
+    pub email_mx_resolver: Option<TokioAsyncResolver>, // set by CHECK_EMAIL_MX, new addresses need a mail server
+    pub unverified_accounts_cleanup: UnverifiedAccountsCleanup,
+}
+
+impl AppState {
+    // customers without a region, or with one that is no longer configured, live in the default database
+    pub fn customers_db(&self, region: &str) -> &Database {
+        match self.regional_dbs.get(region) {
+            Some(db) => db,
+            None => &self.mongo_db,
+        }
+    }
+
+    pub fn all_customers_dbs(&self) -> Vec<&Database> {
+        let mut dbs = vec![&self.mongo_db];
+        dbs.extend(self.regional_dbs.values());
+
+        dbs
+    }
+
+    // a panicked writer can't leave a half written map, so a poisoned lock is still readable
+    pub fn current_feature_map(&self) -> FeatureMap {
+        match self.feature_map.read() {
+            Ok(feature_map) => feature_map.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn replace_feature_map(&self, feature_map: FeatureMap) {
+        match self.feature_map.write() {
+            Ok(mut current) => *current = feature_map,
+            Err(poisoned) => *poisoned.into_inner() = feature_map,
+        }
+    }
+
+    pub fn is_known_region(&self, region: &str) -> bool {
+        region == self.default_region || self.regional_dbs.contains_key(region)
+    }
 }
 
 pub async fn init(mongodb_client: MongoClient, redis_connection: RedisClient, postgres_conn: Option<Pool<ConnectionManager<PgConnection>>>) {
@@ -70,6 +218,15 @@ pub async fn init(mongodb_client: MongoClient, redis_connection: RedisClient, po
     // show products, for testing purposes
     info!("Products: {:?}", app_state.products);
 
+    for db in app_state.all_customers_dbs() {
+        ensure_customer_indexes(db).await;
+    }
+    ensure_invite_code_indexes(&app_state.mongo_db).await;
+    ensure_team_member_indexes(&app_state.mongo_db).await;
+
+    start_unverified_accounts_cleanup(app_state.clone());
+    start_grace_period_expiry(app_state.clone());
+
     // /api/public
     let public = get_public_router(app_state.clone()).await;
     // /api/customers
@@ -84,13 +241,22 @@ pub async fn init(mongodb_client: MongoClient, redis_connection: RedisClient, po
     // /api/webhooks
     let webhooks = get_webhooks_router(app_state.clone()).await;
     info!("Webhooks router loaded");
+    // /api/admin
+    let admin = get_admin_router(app_state.clone()).await;
+    info!("Admin router loaded");
     // /api
-    let api = Router::new()
+    let mut api = Router::new();
+    if app_state.api_index {
+        api = api.route("/", get(fetch_api_index));
+    }
+
+    let api = api
         .nest("/public", public)
         .nest("/customers", customers)
         .nest("/me", customers_actions)
         .nest("/identity", identity)
-        .nest("/webhooks", webhooks);
+        .nest("/webhooks", webhooks)
+        .nest("/admin", admin);
 
     info!("API router loaded");
 
@@ -99,12 +265,28 @@ pub async fn init(mongodb_client: MongoClient, redis_connection: RedisClient, po
         .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PATCH])
         .allow_origin(Any);
 
-    let app = Router::new()
-        .route("/health", get(|| async { "OK" }))
+    let mut app = Router::new()
+        .route("/health", get(|| async { "OK" }));
+
+    if let Some(metrics_handle) = init_metrics() {
+        app = app.route("/metrics", get(move || async move { metrics_handle.render() }));
+        info!("Metrics recorder installed");
+    }
+
+    // opt-in, it calls the providers on every request
+    if app_state.integrations_health_check {
+        let app_state = Arc::clone(&app_state);
+        app = app.route("/health/integrations", get(move || check_integrations(app_state)));
+        info!("Integrations health check enabled");
+    }
+
+    let app = app
         .nest("/api", api)
         .layer(cors)
         .layer(CompressionLayer::new())
         .layer(TimeoutLayer::new(Duration::from_secs(10)),)
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::from_fn_with_state(app_state.clone(), client_ip_middleware))
         .fallback(fallback)
         .with_state(app_state);
 
@@ -119,112 +301,238 @@ pub async fn init(mongodb_client: MongoClient, redis_connection: RedisClient, po
         Err(e) => panic!("Error binding to address: {}", e),
     };
 
-    match axum::serve(listener, app).await {
+    // the peer address is the fallback when no trusted proxy forwarded the request
+    match axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
         Ok(_) => info!("Server started"),
         Err(e) => panic!("Error starting server: {}", e),
     };
 }
 
 pub async fn set_app_state(mongodb_client: MongoClient, redis_connection: RedisClient, postgres_conn: Option<Pool<ConnectionManager<PgConnection>>>) -> Arc<AppState> {
-    let api_url = match env::var("API_URL") {
-        Ok(url) => url,
-        Err(_) => panic!("api_url not found"),
+    // load_env already validated all of this, anything that slipped through is reported the same way
+    let mut report = ConfigReport::new();
+
+    let api_url = report.require("Server", "API_URL").unwrap_or_default();
+    let mongo_db = report.require("Mongo", "MONGO_DB_NAME").unwrap_or_default();
+
+    let mongo_db = mongodb_client.database(&mongo_db);
+
+    // MONGO_DB_NAME serves the default region, MONGO_REGIONS=eu,us adds more
+    // with MONGO_DB_NAME_<REGION> and an optional MONGO_URI_<REGION> for other clusters
+    let default_region = match env::var("DEFAULT_MONGO_REGION") {
+        Ok(region) => region.trim().to_lowercase(),
+        Err(_) => String::from("default"),
     };
 
-    let mongo_db = match env::var("MONGO_DB_NAME") {
-        Ok(db) => db,
-        Err(_) => panic!("mongo_db_name not found"),
+    let mut regional_dbs = HashMap::new();
+    let regions = match env::var("MONGO_REGIONS") {
+        Ok(regions) => regions
+            .split(',')
+            .map(|region| region.trim().to_lowercase())
+            .filter(|region| !region.is_empty() && *region != default_region)
+            .collect::<Vec<String>>(),
+        Err(_) => vec![],
     };
 
-    let mongo_db = mongodb_client.database(&mongo_db);
+    for region in regions.iter() {
+        let db_name = match report.require("Mongo", &format!("MONGO_DB_NAME_{}", region.to_uppercase())) {
+            Some(db_name) => db_name,
+            None => continue,
+        };
+
+        let db = match env::var(format!("MONGO_URI_{}", region.to_uppercase())) {
+            Ok(uri) => match init_connection_with_uri(&uri).await {
+                Ok(client) => client.database(&db_name),
+                Err(e) => {
+                    report.add_issue("Mongo", format!("MONGO_URI_{} can't be reached: {}", region.to_uppercase(), e));
+                    continue;
+                },
+            },
+            Err(_) => mongodb_client.database(&db_name),
+        };
+
+        regional_dbs.insert(region.clone(), db);
+    }
     let lemonsqueezy_webhook_signature_key = match env::var("LEMONSQUEEZY_WEBHOOK_SIGNATURE_KEY") {
         Ok(uri) => uri,
         Err(_) => String::from("lemonsqueezy_webhook_signature_key not found"),
     };
 
-    let pro_product_id = match env::var("PRO_PRODUCT_ID") {
-        Ok(id) => match id.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => panic!("pro_product_id must be a number"),
-        },
-        Err(_) => panic!("pro_product_id not found"),
+    let lemonsqueezy_api_key = match env::var("LEMONSQUEEZY_API_KEY") {
+        Ok(api_key) if !api_key.is_empty() => Some(api_key),
+        _ => None,
     };
 
-    let pro_monthly_variant_id = match env::var("PRO_MONTHLY_VARIANT_ID") {
-        Ok(id) => match id.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => panic!("pro_monthly_variant_id must be a number"),
+    let lemonsqueezy_store_id = match env::var("LEMONSQUEEZY_STORE_ID") {
+        Ok(store_id) => match store_id.trim().parse::<i64>() {
+            Ok(store_id) => Some(store_id),
+            Err(_) => {
+                report.add_issue("LemonSqueezy", String::from("LEMONSQUEEZY_STORE_ID must be a number"));
+                None
+            },
         },
-        Err(_) => panic!("pro_monthly_variant_id not found"),
+        Err(_) => {
+            warn!("LEMONSQUEEZY_STORE_ID not set, webhook events from any store will be accepted");
+            None
+        }
     };
 
-    let pro_annually_variant_id = match env::var("PRO_ANNUALLY_VARIANT_ID") {
-        Ok(id) => match id.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => panic!("pro_annually_variant_id must be a number"),
-        },
-        Err(_) => panic!("pro_annually_variant_id not found"),
+    let products = report.check("LemonSqueezy", load_products());
+    let plan_prices = report.check("LemonSqueezy", load_plan_prices().map_err(|err| format!("PLAN_PRICES {}", err)));
+    let default_subscription = report.check("Subscriptions", load_default_subscription());
+    let trusted_proxies = report.check("Server", load_trusted_proxies());
+    let stripe = report.check("Stripe", load_stripe_settings());
+    let captcha = report.check("Captcha", load_captcha_settings());
+    let email_mx_resolver = report.check("Email", load_email_mx_resolver());
+    let integration_webhook = report.check("Integrations", load_integration_webhook());
+
+    // EVENTS_BROKER=redis publishes to the EVENTS_REDIS_STREAM stream of the main redis
+    let events_broker = report.optional_checked("Events", "EVENTS_BROKER", "EVENTS_BROKER must be redis or none", EventsBroker::None, |_| true);
+
+    let event_publisher: Arc<dyn EventPublisher> = match events_broker {
+        EventsBroker::RedisStreams => Arc::new(RedisStreamPublisher {
+            client: redis_connection.clone(),
+            stream: env::var("EVENTS_REDIS_STREAM").unwrap_or(String::from(DEFAULT_EVENTS_STREAM)),
+        }),
+        EventsBroker::None => Arc::new(NoopPublisher),
+    };
+
+    let enabled_email_integration = report.require_parsed::<bool>("Brevo", "ENABLE_EMAIL_INTEGRATION", "boolean").unwrap_or(false);
+    let api_tokens_expiration_time = report.require_parsed::<i64>("Tokens", "API_TOKENS_EXPIRATION_TIME", "number").unwrap_or_default();
+
+    let master_email_entity = MasterEmailEntity {
+        email: report.require("Brevo", "BREVO_MASTER_EMAIL_ADDRESS").unwrap_or_default(),
+        name: report.require("Brevo", "BREVO_MASTER_NAME").unwrap_or_default(),
     };
 
-    let products = Products {
-        pro_product_id,
-        pro_monthly_variant_id,
-        pro_annually_variant_id,
+    let email_verification_template_id = report.require_parsed::<u32>("Brevo", "BREVO_EMAIL_VERIFY_TEMPLATE_ID", "number").unwrap_or_default();
+    let magic_link_template_id = report.optional_parsed("Brevo", "BREVO_MAGIC_LINK_TEMPLATE_ID", "number", email_verification_template_id);
+    let team_invite_template_id = report.optional_parsed("Brevo", "BREVO_TEAM_INVITE_TEMPLATE_ID", "number", email_verification_template_id);
+    let magic_link_ttl = report.optional_parsed::<u64>("Tokens", "MAGIC_LINK_TTL_SECS", "number", 900);
+    let email_verification_ttl = report.optional_checked::<u64>("Tokens", "EMAIL_VERIFICATION_TTL_SECS", "EMAIL_VERIFICATION_TTL_SECS must be a positive number", 86400, |ttl| *ttl > 0);
+    let email_recovery_window_days = report.optional_checked::<i64>("Brevo", "EMAIL_RECOVERY_WINDOW_DAYS", "EMAIL_RECOVERY_WINDOW_DAYS must be a positive number", 30, |days| *days > 0);
+    let daily_send_budget = report.optional_parsed::<i64>("Brevo", "EMAIL_DAILY_SEND_BUDGET", "number", 10);
+    let send_welcome_email = report.optional_parsed("Brevo", "SEND_WELCOME_EMAIL", "boolean", true);
+
+    let mut welcome_template_ids = HashMap::new();
+    for class in [CustomerType::PERSONAL, CustomerType::MANAGER, CustomerType::DEVELOPER] {
+        let key = format!("BREVO_WELCOME_TEMPLATE_ID_{}", class.to_string().to_uppercase());
+        if env::var(&key).is_ok() {
+            if let Some(id) = report.require_parsed::<u32>("Brevo", &key, "number") {
+                welcome_template_ids.insert(class.to_string(), id);
+            }
+        }
+    }
+
+    let email_provider_settings = EmailProviderSettings {
+        email_verification_template_id,
+        magic_link_template_id,
+        team_invite_template_id,
+        magic_link_ttl,
+        email_verification_ttl,
+        email_recovery_window_days,
+        daily_send_budget,
+        send_welcome_email,
+        welcome_template_ids,
+        team_invite_url: env::var("TEAM_INVITE_URL").ok(),
+        verify_success_url: env::var("EMAIL_VERIFY_SUCCESS_URL").ok(),
+        verify_failure_url: env::var("EMAIL_VERIFY_FAILURE_URL").ok(),
+        verify_landing_url: env::var("EMAIL_VERIFY_LANDING_URL").ok(),
     };
 
-    let enabled_email_integration = match std::env::var("ENABLE_EMAIL_INTEGRATION").expect("ENABLE_EMAIL_INTEGRATION must be set").parse::<bool>() {
-        Ok(val) => val,
-        Err(_) => panic!("ENABLE_EMAIL_INTEGRATION must be a boolean"),
+    let google_oauth_redirect_endpoints = report.require("OAuth", "GOOGLE_OAUTH_CLIENT_REDIRECT_ENDPOINT").unwrap_or_default();
+    let google_oauth_redirect_url = format!("https://{}{}", api_url, google_oauth_redirect_endpoints);
+
+    let google_auth = GoogleAuth {
+        client_id: report.require("OAuth", "GOOGLE_OAUTH_CLIENT_ID").unwrap_or_default(),
+        client_secret: report.require("OAuth", "GOOGLE_OAUTH_CLIENT_SECRET").unwrap_or_default(),
+        redirect_url: google_oauth_redirect_url,
+        redirect_uris: report.check("OAuth", load_oauth_redirect_uris()).unwrap_or_default(),
     };
 
-    let api_tokens_expiration_time = match std::env::var("API_TOKENS_EXPIRATION_TIME").expect("API_TOKENS_EXPIRATION_TIME must be set").parse::<i64>() {
-        Ok(val) => val,
-        Err(_) => panic!("API_TOKENS_EXPIRATION_TIME must be a number"),
+    let admin_require_listed_id = report.optional_parsed("Admin", "ADMIN_REQUIRE_LISTED_ID", "boolean", false);
+    let admin_rate_limit = report.optional_checked::<u64>("Admin", "ADMIN_RATE_LIMIT_PER_MINUTE", "ADMIN_RATE_LIMIT_PER_MINUTE must be a positive number", 30, |limit| *limit > 0);
+
+    let admin_customer_ids = match env::var("ADMIN_CUSTOMER_IDS") {
+        Ok(ids) => ids
+            .split(',')
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect::<Vec<String>>(),
+        Err(_) => vec![],
     };
 
-    let master_email_address = env::var("BREVO_MASTER_EMAIL_ADDRESS");
-    let master_name = env::var("BREVO_MASTER_NAME");
+    let max_linked_providers = report.optional_parsed::<usize>("Customers", "MAX_LINKED_PROVIDERS", "number", 2);
 
-    let master_email_entity = MasterEmailEntity {
-        email: match master_email_address {
-            Ok(email) => email,
-            Err(_) => panic!("BREVO_MASTER_EMAIL_ADDRESS not found"),
-        },
-        name: match master_name {
-            Ok(name) => name,
-            Err(_) => panic!("BREVO_MASTER_NAME not found"),
-        },
+    let signup_domain_policy = SignupDomainPolicy {
+        allowed_domains: parse_env_list("SIGNUP_ALLOWED_DOMAINS"),
+        blocked_domains: parse_env_list("SIGNUP_BLOCKED_DOMAINS"),
     };
 
-    let email_verification_template_id = match env::var("BREVO_EMAIL_VERIFY_TEMPLATE_ID") {
-        Ok(id) => match id.parse::<u32>() {
-            Ok(id) => id,
-            Err(_) => panic!("BREVO_EMAIL_VERIFY_TEMPLATE_ID must be a number"),
-        },
-        Err(_) => panic!("BREVO_EMAIL_VERIFY_TEMPLATE_ID not found"),
+    let mut supported_languages = parse_env_list("SUPPORTED_LANGUAGES");
+    if supported_languages.is_empty() {
+        supported_languages = vec![String::from("en"), String::from("es")];
+    }
+
+    let api_index = report.optional_parsed("Server", "ENABLE_API_INDEX", "boolean", true);
+    let integrations_health_check = report.optional_parsed("Server", "ENABLE_INTEGRATIONS_HEALTH_CHECK", "boolean", false);
+
+    let unverified_accounts_cleanup = UnverifiedAccountsCleanup {
+        enabled: report.optional_parsed("Jobs", "ENABLE_UNVERIFIED_ACCOUNTS_CLEANUP", "boolean", false),
+        // a ttl of zero would sweep brand new accounts, an interval of zero panics inside the job
+        ttl_days: report.optional_checked::<i64>("Jobs", "UNVERIFIED_ACCOUNT_TTL_DAYS", "UNVERIFIED_ACCOUNT_TTL_DAYS must be a positive number", 30, |days| *days > 0),
+        interval_secs: report.optional_checked::<u64>("Jobs", "UNVERIFIED_ACCOUNTS_CLEANUP_INTERVAL_SECS", "UNVERIFIED_ACCOUNTS_CLEANUP_INTERVAL_SECS must be a positive number", 3600, |secs| *secs > 0),
     };
 
-    let email_provider_settings = EmailProviderSettings {
-        email_verification_template_id,
+    let webhook_dedup_ttl = report.optional_parsed::<u64>("Webhooks", "WEBHOOK_DEDUP_TTL_SECS", "number", 86400);
+
+    let webhook_field_limits = WebhookFieldLimits {
+        max_length: report.optional_checked::<usize>("Webhooks", "WEBHOOK_MAX_FIELD_LENGTH", "WEBHOOK_MAX_FIELD_LENGTH must be a positive number", DEFAULT_WEBHOOK_MAX_FIELD_LENGTH, |max_length| *max_length > 0),
+        policy: report.optional_checked("Webhooks", "WEBHOOK_OVERSIZED_FIELDS", "WEBHOOK_OVERSIZED_FIELDS must be reject or truncate", OversizedFieldPolicy::Reject, |_| true),
     };
 
-    let google_oauth_redirect_endpoints = match env::var("GOOGLE_OAUTH_CLIENT_REDIRECT_ENDPOINT") {
-        Ok(url) => url,
-        Err(_) => panic!("GOOGLE_OAUTH_CLIENT_REDIRECT_ENDPOINT not found"),
+    let token_delivery = report.optional_checked("Tokens", "TOKEN_DELIVERY", "TOKEN_DELIVERY must be body, cookie or both", TokenDelivery::Body, |_| true);
+
+    let default_customer_class = match env::var("DEFAULT_CUSTOMER_CLASS") {
+        Ok(val) => match signup_class(&val) {
+            Some(class) => class,
+            None => {
+                report.add_issue("Customers", String::from("DEFAULT_CUSTOMER_CLASS must be personal or manager"));
+                CustomerType::PERSONAL
+            },
+        },
+        Err(_) => CustomerType::PERSONAL,
     };
 
-    let google_oauth_redirect_url = format!("https://{}{}", api_url, google_oauth_redirect_endpoints);
+    let name_max_length = report.optional_checked::<usize>("Customers", "NAME_MAX_LENGTH", "NAME_MAX_LENGTH must be a number of at least 2", DEFAULT_NAME_MAX_LENGTH, |max_length| *max_length >= 2);
+    let name_confusable_check = report.optional_parsed("Customers", "NAME_CONFUSABLE_CHECK", "boolean", false);
+    let unverified_notifications_policy = report.optional_checked("Customers", "NOTIFICATIONS_WITHOUT_VERIFIED_EMAIL", "NOTIFICATIONS_WITHOUT_VERIFIED_EMAIL must be warn or reject", UnverifiedNotificationsPolicy::Warn, |_| true);
+    let require_invite_code = report.optional_parsed("Customers", "REQUIRE_INVITE_CODE", "boolean", false);
 
-    let google_auth = GoogleAuth {
-        client_id: match env::var("GOOGLE_OAUTH_CLIENT_ID") {
-            Ok(id) => id,
-            Err(_) => panic!(" GOOGLE_OAUTH_CLIENT_ID not found"),
+    let (Some(products), Some(plan_prices), Some(default_subscription), Some(trusted_proxies), Some(stripe), Some(captcha), Some(email_mx_resolver), Some(integration_webhook)) =
+        (products, plan_prices, default_subscription, trusted_proxies, stripe, captcha, email_mx_resolver, integration_webhook)
+    else {
+        report.exit();
+    };
+
+    if !report.is_empty() {
+        report.exit();
+    }
+
+    // admins edit it at runtime, a stored map that no longer validates falls back to the defaults
+    let feature_map = match find_feature_map(&mongo_db).await {
+        Ok(Some(feature_map)) => match feature_map.validate() {
+            Ok(_) => feature_map,
+            Err(err) => {
+                warn!("Stored feature map is invalid ({}), using the defaults", err);
+                FeatureMap::default()
+            },
         },
-        client_secret: match env::var("GOOGLE_OAUTH_CLIENT_SECRET") {
-            Ok(secret) => secret,
-            Err(_) => panic!("GOOGLE_OAUTH_CLIENT_SECRET not found"),
+        Ok(None) => FeatureMap::default(),
+        Err(_) => {
+            warn!("Error loading the feature map, using the defaults");
+            FeatureMap::default()
         },
-        redirect_url: google_oauth_redirect_url,
     };
 
     let app_state = Arc::new(AppState {
@@ -232,15 +540,151 @@ pub async fn set_app_state(mongodb_client: MongoClient, redis_connection: RedisC
         redis_connection,
         postgres_conn,
         mongo_db,
+        regional_dbs,
+        default_region,
         lemonsqueezy_webhook_signature_key,
+        lemonsqueezy_api_key,
+        lemonsqueezy_store_id,
         products,
+        feature_map: Arc::new(RwLock::new(feature_map)),
+        plan_prices,
+        default_subscription,
+        stripe,
+        webhook_dedup_ttl,
+        webhook_field_limits,
+        integration_webhook,
+        event_publisher,
         enabled_email_integration,
         api_tokens_expiration_time,
+        token_delivery,
         api_url,
         master_email_entity,
         email_provider_settings,
         google_auth,
+        admin_customer_ids,
+        admin_require_listed_id,
+        admin_rate_limit,
+        max_linked_providers,
+        signup_domain_policy,
+        supported_languages,
+        default_customer_class,
+        name_max_length,
+        name_confusable_check,
+        unverified_notifications_policy,
+        integrations_health_check,
+        api_index,
+        trusted_proxies,
+        require_invite_code,
+        captcha,
+        email_mx_resolver,
+        unverified_accounts_cleanup,
     });
 
     return app_state;
-}
\ No newline at end of file
+}
+// comma separated, trimmed and lowercased env list
+fn parse_env_list(key: &str) -> Vec<String> {
+    match env::var(key) {
+        Ok(domains) => domains
+            .split(',')
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect::<Vec<String>>(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::types::subscription::{Slug, SubscriptionFrequencyClass};
+
+    // mongo and redis point at a closed port, so a handler that reaches either one fails fast
+    pub async fn test_state() -> AppState {
+        let mongodb_client = MongoClient::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=200&connectTimeoutMS=200")
+            .await
+            .unwrap();
+        let mongo_db = mongodb_client.database("tests");
+
+        AppState {
+            api_url: String::from("api.example.com"),
+            api_tokens_expiration_time: 3600,
+            token_delivery: TokenDelivery::Body,
+            mongodb_client,
+            mongo_db,
+            regional_dbs: HashMap::new(),
+            default_region: String::from("default"),
+            redis_connection: RedisClient::open("redis://127.0.0.1:9/").unwrap(),
+            postgres_conn: None,
+            lemonsqueezy_webhook_signature_key: String::from("signature-key"),
+            lemonsqueezy_api_key: None,
+            lemonsqueezy_store_id: None,
+            products: Products { variants: HashMap::new() },
+            feature_map: Arc::new(RwLock::new(FeatureMap::default())),
+            plan_prices: HashMap::new(),
+            default_subscription: DefaultSubscription {
+                slug: Slug::FREE,
+                frequency: SubscriptionFrequencyClass::UNDEFINED,
+                trial_days: 0,
+            },
+            stripe: None,
+            webhook_dedup_ttl: 86400,
+            webhook_field_limits: WebhookFieldLimits {
+                max_length: DEFAULT_WEBHOOK_MAX_FIELD_LENGTH,
+                policy: OversizedFieldPolicy::Reject,
+            },
+            integration_webhook: None,
+            event_publisher: Arc::new(NoopPublisher),
+            enabled_email_integration: false,
+            master_email_entity: MasterEmailEntity {
+                email: String::from("team@example.com"),
+                name: String::from("Example"),
+            },
+            email_provider_settings: EmailProviderSettings {
+                email_verification_template_id: 1,
+                magic_link_template_id: 1,
+                team_invite_template_id: 1,
+                magic_link_ttl: 900,
+                email_verification_ttl: 86400,
+                email_recovery_window_days: 30,
+                daily_send_budget: 10,
+                send_welcome_email: false,
+                welcome_template_ids: HashMap::new(),
+                team_invite_url: None,
+                verify_success_url: None,
+                verify_failure_url: None,
+                verify_landing_url: None,
+            },
+            google_auth: GoogleAuth {
+                client_id: String::new(),
+                client_secret: String::new(),
+                redirect_url: String::new(),
+                redirect_uris: HashMap::new(),
+            },
+            admin_customer_ids: vec![String::from("admin")],
+            admin_require_listed_id: false,
+            admin_rate_limit: 30,
+            max_linked_providers: 2,
+            signup_domain_policy: SignupDomainPolicy {
+                allowed_domains: vec![],
+                blocked_domains: vec![],
+            },
+            supported_languages: vec![String::from("en"), String::from("es")],
+            default_customer_class: CustomerType::PERSONAL,
+            name_max_length: DEFAULT_NAME_MAX_LENGTH,
+            name_confusable_check: false,
+            unverified_notifications_policy: UnverifiedNotificationsPolicy::Warn,
+            integrations_health_check: false,
+            api_index: true,
+            trusted_proxies: vec![],
+            require_invite_code: false,
+            captcha: None,
+            email_mx_resolver: None,
+            unverified_accounts_cleanup: UnverifiedAccountsCleanup {
+                enabled: false,
+                ttl_days: 30,
+                interval_secs: 3600,
+            },
+        }
+    }
+}
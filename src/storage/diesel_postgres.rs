@@ -4,8 +4,11 @@ use r2d2 as original_r2d2;
 use diesel::{
     prelude::*,
     r2d2::{ConnectionManager, Pool},
+    sql_query,
 };
 
+use schemas::subscription_history_archive;
+
 pub async fn new_connection(uri: &str) -> Result<Pool<ConnectionManager<PgConnection>>, original_r2d2::Error> {
     let manager = ConnectionManager::<PgConnection>::new(uri);
     let pool = match Pool::builder().build(manager) {
@@ -14,4 +17,41 @@ pub async fn new_connection(uri: &str) -> Result<Pool<ConnectionManager<PgConnec
     };
 
     Ok(pool)
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = subscription_history_archive)]
+pub struct ArchivedHistoryLog {
+    pub customer_id: String,
+    pub subscription_id: String,
+    pub event: String,
+    pub date: String,
+    pub archived_at: String,
+}
+
+pub fn ensure_history_archive_table(pool: &Pool<ConnectionManager<PgConnection>>) -> Result<(), String> {
+    let mut conn = pool.get().map_err(|err| err.to_string())?;
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS subscription_history_archive (
+            id BIGSERIAL PRIMARY KEY,
+            customer_id TEXT NOT NULL,
+            subscription_id TEXT NOT NULL,
+            event TEXT NOT NULL,
+            date TEXT NOT NULL,
+            archived_at TEXT NOT NULL
+        )",
+    )
+    .execute(&mut conn)
+    .map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+// diesel is blocking, run it from spawn_blocking
+pub fn archive_history_logs(pool: &Pool<ConnectionManager<PgConnection>>, logs: &[ArchivedHistoryLog]) -> Result<usize, String> {
+    let mut conn = pool.get().map_err(|err| err.to_string())?;
+    diesel::insert_into(subscription_history_archive::table)
+        .values(logs)
+        .execute(&mut conn)
+        .map_err(|err| err.to_string())
+}
@@ -0,0 +1,11 @@
+// created on demand by ensure_history_archive_table, there are no migrations
+diesel::table! {
+    subscription_history_archive (id) {
+        id -> Int8,
+        customer_id -> Text,
+        subscription_id -> Text,
+        event -> Text,
+        date -> Text,
+        archived_at -> Text,
+    }
+}
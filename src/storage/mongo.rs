@@ -1,12 +1,14 @@
 use axum::{Json, http::StatusCode};
+use futures::TryStreamExt;
 use mongodb::{
-    bson::{doc, Document}, options::ClientOptions, options::ServerApi, options::ServerApiVersion, Client, Database, Collection,
+    bson::{doc, Document}, options::ClientOptions, options::FindOptions, options::ReplaceOptions, options::IndexOptions, options::ServerApi, options::ServerApiVersion, Client, Cursor, Database, Collection, IndexModel,
 };
+use log::{info, warn};
 use serde_json::json;
 
-use std::env;
+use std::{env, sync::OnceLock};
 
-use crate::types::customer::{GenericResponse, Customer};
+use crate::types::{customer::{AuthProviders, GenericResponse, Customer}, feature_map::FeatureMap, invite_code::InviteCode, team::TeamMember};
 
 pub async fn init_connection() -> mongodb::error::Result<Client> {
     let uri = match env::var("MONGO_URI") {
@@ -14,7 +16,12 @@ pub async fn init_connection() -> mongodb::error::Result<Client> {
         Err(_) => String::from("mongo_uri not found"),
     };
 
-    let mut client_options = ClientOptions::parse(&uri).await?;
+    init_connection_with_uri(&uri).await
+}
+
+// regions living in a different cluster get their own client
+pub async fn init_connection_with_uri(uri: &str) -> mongodb::error::Result<Client> {
+    let mut client_options = ClientOptions::parse(uri).await?;
 
     let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
     client_options.server_api = Some(server_api);
@@ -44,8 +51,277 @@ pub async fn build_customer_filter(id: &str, email: &str) -> Document {
     return customer_filter
 }
 
+// like build_customer_filter but disabled addresses can't be used to sign in
+pub async fn build_login_filter(email: &str) -> Document {
+    doc! {
+        "emails": {
+            "$elemMatch": {
+                "address": email,
+                "disabled": {"$ne": true},
+            }
+        }
+    }
+}
+
+// customers that linked the given provider account
+pub fn linked_provider_filter(provider: AuthProviders, subject: &str) -> Document {
+    doc! {
+        "deleted": false,
+        "linked_providers": {
+            "$elemMatch": {
+                "provider": provider.to_string(),
+                "subject": subject,
+            }
+        }
+    }
+}
+
+pub const DEFAULT_CUSTOMERS_COLLECTION: &str = "customers";
+
+static CUSTOMERS_COLLECTION: OnceLock<String> = OnceLock::new();
+
+// MONGO_CUSTOMERS_COLLECTION, read once, lets several environments share a database
+pub fn customers_collection_name() -> &'static str {
+    CUSTOMERS_COLLECTION.get_or_init(|| match env::var("MONGO_CUSTOMERS_COLLECTION") {
+        Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => String::from(DEFAULT_CUSTOMERS_COLLECTION),
+    })
+}
+
+// mongo refuses empty names, $ and null bytes, and system. is reserved
+pub fn valid_collection_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('$') && !name.contains('\0') && !name.starts_with("system.")
+}
+
 pub async fn get_customers_collection(db: &Database) -> Collection<Customer> {
-    return db.collection("customers");
+    return db.collection(customers_collection_name());
+}
+
+// indexes every customers collection must have, login and lookups go through id and emails.address
+pub fn customer_index_models() -> Vec<IndexModel> {
+    vec![
+        IndexModel::builder()
+            .keys(doc! {"id": 1})
+            .options(IndexOptions::builder().name(String::from("id_unique")).unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"emails.address": 1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("emails_address_unique"))
+                    .unique(true)
+                    .partial_filter_expression(doc! {"deleted": false})
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"subscription.id": 1})
+            .options(IndexOptions::builder().name(String::from("subscription_id")).build())
+            .build(),
+    ]
+}
+
+// createIndexes is a no-op for indexes that already exist with the same spec, so this runs on every startup
+pub async fn ensure_customer_indexes(db: &Database) {
+    let collection = get_customers_collection(db).await;
+    match collection.create_indexes(customer_index_models(), None).await {
+        Ok(result) => info!("Customers indexes ensured on {}: {:?}", db.name(), result.index_names),
+        Err(e) => warn!("Error ensuring customers indexes on {}: {}", db.name(), e),
+    };
+}
+
+pub async fn get_invite_codes_collection(db: &Database) -> Collection<InviteCode> {
+    db.collection("invite_codes")
+}
+
+// invite codes always live in the default region database
+pub async fn ensure_invite_code_indexes(db: &Database) {
+    let collection = get_invite_codes_collection(db).await;
+    let index = IndexModel::builder()
+        .keys(doc! {"code": 1})
+        .options(IndexOptions::builder().name(String::from("code_unique")).unique(true).build())
+        .build();
+
+    match collection.create_index(index, None).await {
+        Ok(result) => info!("Invite codes index ensured on {}: {}", db.name(), result.index_name),
+        Err(e) => warn!("Error ensuring invite codes index on {}: {}", db.name(), e),
+    };
+}
+
+// team members also live in the default region database, a team can span regions
+pub async fn get_team_members_collection(db: &Database) -> Collection<TeamMember> {
+    db.collection("team_members")
+}
+
+pub async fn ensure_team_member_indexes(db: &Database) {
+    let collection = get_team_members_collection(db).await;
+    let index = IndexModel::builder()
+        .keys(doc! {"manager_id": 1, "email": 1})
+        .options(IndexOptions::builder().name(String::from("manager_email_unique")).unique(true).build())
+        .build();
+
+    match collection.create_index(index, None).await {
+        Ok(result) => info!("Team members index ensured on {}: {}", db.name(), result.index_name),
+        Err(e) => warn!("Error ensuring team members index on {}: {}", db.name(), e),
+    };
+}
+
+fn team_members_error(action: &str, err: mongodb::error::Error) -> (StatusCode, Json<GenericResponse>) {
+    log::error!("error {} team members: {}", action, err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GenericResponse {
+            message: format!("error {} team members", action),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    )
+}
+
+pub async fn insert_team_member(db: &Database, member: &TeamMember) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let collection = get_team_members_collection(db).await;
+    match collection.insert_one(member, None).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(team_members_error("inserting", err)),
+    }
+}
+
+pub async fn find_team_member_by_email(db: &Database, manager_id: &str, email: &str) -> Result<Option<TeamMember>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_team_members_collection(db).await;
+    match collection.find_one(doc! {"manager_id": manager_id, "email": email}, None).await {
+        Ok(member) => Ok(member),
+        Err(err) => Err(team_members_error("fetching", err)),
+    }
+}
+
+pub async fn list_team_members(db: &Database, manager_id: &str) -> Result<Vec<TeamMember>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_team_members_collection(db).await;
+    let options = FindOptions::builder().sort(doc! {"invited_at": 1}).build();
+    let cursor = match collection.find(doc! {"manager_id": manager_id}, options).await {
+        Ok(cursor) => cursor,
+        Err(err) => return Err(team_members_error("listing", err)),
+    };
+
+    match cursor.try_collect().await {
+        Ok(members) => Ok(members),
+        Err(err) => Err(team_members_error("listing", err)),
+    }
+}
+
+pub async fn count_team_members(db: &Database, manager_id: &str) -> Result<u64, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_team_members_collection(db).await;
+    match collection.count_documents(doc! {"manager_id": manager_id}, None).await {
+        Ok(count) => Ok(count),
+        Err(err) => Err(team_members_error("counting", err)),
+    }
+}
+
+// scoped to the manager so nobody removes someone else's member
+pub async fn remove_team_member(db: &Database, manager_id: &str, id: &str) -> Result<bool, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_team_members_collection(db).await;
+    match collection.delete_one(doc! {"id": id, "manager_id": manager_id}, None).await {
+        Ok(result) => Ok(result.deleted_count == 1),
+        Err(err) => Err(team_members_error("removing", err)),
+    }
+}
+
+// a single document, like invite codes it lives in the default region database
+pub async fn get_feature_map_collection(db: &Database) -> Collection<FeatureMap> {
+    db.collection("feature_map")
+}
+
+pub async fn find_feature_map(db: &Database) -> Result<Option<FeatureMap>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_feature_map_collection(db).await;
+    match collection.find_one(doc! {}, None).await {
+        Ok(feature_map) => Ok(feature_map),
+        Err(err) => Err(feature_map_error("fetching", err)),
+    }
+}
+
+pub async fn save_feature_map(db: &Database, feature_map: &FeatureMap) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let collection = get_feature_map_collection(db).await;
+    let options = ReplaceOptions::builder().upsert(true).build();
+    match collection.replace_one(doc! {}, feature_map, options).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(feature_map_error("saving", err)),
+    }
+}
+
+fn feature_map_error(action: &str, err: mongodb::error::Error) -> (StatusCode, Json<GenericResponse>) {
+    log::error!("error {} feature map: {}", action, err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GenericResponse {
+            message: format!("error {} feature map", action),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    )
+}
+
+fn invite_codes_error(action: &str, err: mongodb::error::Error) -> (StatusCode, Json<GenericResponse>) {
+    log::error!("error {} invite codes: {}", action, err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GenericResponse {
+            message: format!("error {} invite codes", action),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    )
+}
+
+pub async fn insert_invite_code(db: &Database, invite_code: &InviteCode) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let collection = get_invite_codes_collection(db).await;
+    match collection.insert_one(invite_code, None).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(invite_codes_error("inserting", err)),
+    }
+}
+
+pub async fn find_invite_code(db: &Database, code: &str) -> Result<Option<InviteCode>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_invite_codes_collection(db).await;
+    match collection.find_one(doc! {"code": code}, None).await {
+        Ok(invite_code) => Ok(invite_code),
+        Err(err) => Err(invite_codes_error("fetching", err)),
+    }
+}
+
+pub async fn list_invite_codes(db: &Database, options: FindOptions) -> Result<Vec<InviteCode>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_invite_codes_collection(db).await;
+    let cursor = match collection.find(doc! {}, options).await {
+        Ok(cursor) => cursor,
+        Err(err) => return Err(invite_codes_error("listing", err)),
+    };
+
+    match cursor.try_collect().await {
+        Ok(invite_codes) => Ok(invite_codes),
+        Err(err) => Err(invite_codes_error("listing", err)),
+    }
+}
+
+// the remaining redemptions are part of the filter, so the last use can't be taken twice
+pub async fn consume_invite_code(db: &Database, code: &str, customer_id: &str) -> Result<bool, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_invite_codes_collection(db).await;
+    let filter = doc! {"code": code, "$expr": {"$lt": ["$redemptions", "$max_redemptions"]}};
+    let update = doc! {"$inc": {"redemptions": 1}, "$push": {"redeemed_by": customer_id}};
+
+    match collection.update_one(filter, update, None).await {
+        Ok(result) => Ok(result.modified_count == 1),
+        Err(err) => Err(invite_codes_error("consuming", err)),
+    }
+}
+
+// gives the redemption back when the signup fails after consuming it
+pub async fn release_invite_code(db: &Database, code: &str, customer_id: &str) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let collection = get_invite_codes_collection(db).await;
+    let filter = doc! {"code": code, "redeemed_by": customer_id};
+    let update = doc! {"$inc": {"redemptions": -1}, "$pull": {"redeemed_by": customer_id}};
+
+    match collection.update_one(filter, update, None).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(invite_codes_error("releasing", err)),
+    }
 }
 
 pub async fn find_customer(db: &Database, filter: Document) -> Result<(bool, Option<Customer>), (StatusCode, Json<GenericResponse>)> {
@@ -68,6 +344,122 @@ pub async fn find_customer(db: &Database, filter: Document) -> Result<(bool, Opt
     }
 }
 
+// lets callers stream large result sets instead of collecting them
+pub async fn customers_cursor(db: &Database, filter: Document, options: FindOptions) -> Result<Cursor<Customer>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_customers_collection(db).await;
+    match collection.find(filter, options).await {
+        Ok(cursor) => Ok(cursor),
+        Err(err) => {
+            log::error!("error opening customers cursor: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error fetching customers"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    }
+}
+
+pub async fn find_customers(db: &Database, filter: Document, options: FindOptions) -> Result<Vec<Customer>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_customers_collection(db).await;
+    let cursor = match collection.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(err) => {
+            log::error!("error listing customers: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error fetching customers"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ));
+        }
+    };
+
+    match cursor.try_collect().await {
+        Ok(customers) => Ok(customers),
+        Err(err) => {
+            log::error!("error listing customers: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error fetching customers"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    }
+}
+
+pub async fn aggregate_customers(db: &Database, pipeline: Vec<Document>) -> Result<Vec<Document>, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_customers_collection(db).await;
+    let cursor = match collection.aggregate(pipeline, None).await {
+        Ok(cursor) => cursor,
+        Err(err) => {
+            log::error!("error aggregating customers: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error aggregating customers"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ));
+        }
+    };
+
+    match cursor.try_collect().await {
+        Ok(documents) => Ok(documents),
+        Err(err) => {
+            log::error!("error aggregating customers: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error aggregating customers"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    }
+}
+
+// same as update_customer, but tells the caller how many customers matched the filter
+// used when the customer's region isn't known yet (sign in by email, webhooks), the first match wins
+pub async fn find_customer_in(dbs: Vec<&Database>, filter: Document) -> Result<(bool, Option<Customer>), (StatusCode, Json<GenericResponse>)> {
+    for db in dbs {
+        let (found, customer) = find_customer(db, filter.clone()).await?;
+        if found {
+            return Ok((found, customer));
+        }
+    }
+
+    Ok((false, None))
+}
+
+pub async fn update_customer_matched(db: &Database, filter: Document, update: Document) -> Result<u64, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_customers_collection(db).await;
+    match collection.update_one(filter, update, None).await {
+        Ok(result) => Ok(result.matched_count),
+        Err(err) => {
+            log::error!("error updating customer: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error updating record in database"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    }
+}
+
 pub async fn update_customer(db: &Database, filter: Document, update: Document) -> Result<(), (StatusCode, Json<GenericResponse>)> {
     let collection = get_customers_collection(db).await;
     match collection.update_one(filter, update, None).await {
@@ -84,4 +476,25 @@ pub async fn update_customer(db: &Database, filter: Document, update: Document)
             ));
         }
     }
-}
\ No newline at end of file
+}
+// the hash is part of the filter, so two concurrent requests can't both consume the same code
+pub async fn consume_backup_security_code(db: &Database, customer_id: &str, hashed_code: &str) -> Result<bool, (StatusCode, Json<GenericResponse>)> {
+    let collection = get_customers_collection(db).await;
+    let filter = doc! {"id": customer_id, "backup_security_codes": hashed_code};
+    let update = doc! {"$pull": {"backup_security_codes": hashed_code}};
+
+    match collection.update_one(filter, update, None).await {
+        Ok(result) => Ok(result.modified_count == 1),
+        Err(err) => {
+            log::error!("error consuming backup security code: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: String::from("error updating record in database"),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use redis::{Client, RedisError};
+use log::warn;
+use redis::{Client, Connection, RedisError};
 use std::env;
 
 pub fn init_connection() -> Result<Client, RedisError> {
@@ -11,3 +12,24 @@ pub fn init_connection() -> Result<Client, RedisError> {
 
     Ok(client)
 }
+
+// dropped sockets, refusals and timeouts, a missing key or a wrong type is not one of them
+pub fn is_connection_error(err: &RedisError) -> bool {
+    err.is_io_error() || err.is_connection_dropped() || err.is_connection_refusal() || err.is_timeout()
+}
+
+// runs the commands on a fresh connection, retrying once on a connection level failure so a failover blip isn't a 500
+pub fn with_retry<T, F>(client: &Client, mut commands: F) -> Result<T, RedisError>
+where
+    F: FnMut(&mut Connection) -> Result<T, RedisError>,
+{
+    let mut attempt = || client.get_connection().and_then(|mut redis_conn| commands(&mut redis_conn));
+
+    match attempt() {
+        Err(err) if is_connection_error(&err) => {
+            warn!("redis connection error, retrying once: {}", err);
+            attempt()
+        },
+        result => result,
+    }
+}
@@ -1,5 +1,9 @@
 pub mod customer;
 pub mod lemonsqueezy;
+pub mod stripe;
 pub mod incoming_requests;
 pub mod subscription;
-pub mod email;
\ No newline at end of file
+pub mod email;
+pub mod invite_code;
+pub mod feature_map;
+pub mod team;
\ No newline at end of file
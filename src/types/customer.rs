@@ -1,6 +1,8 @@
-use crate::types::subscription::Subscription;
+use crate::types::subscription::{Subscription, SubscriptionView};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::str::FromStr;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +17,23 @@ pub struct Email {
     pub address: String,
     pub verified: bool,
     pub main: bool,
+    #[serde(default)]
+    pub disabled: bool, // kept on the account but unusable until enabled again
+    #[serde(default)]
+    pub disabled_at: String,
+}
+
+impl Email {
+    // none when the address isn't disabled or the timestamp can't be read
+    pub fn recoverable_until(&self, recovery_window_days: i64) -> Option<DateTime<Utc>> {
+        if !self.disabled {
+            return None;
+        }
+
+        DateTime::parse_from_rfc3339(&self.disabled_at)
+            .ok()
+            .map(|disabled_at| disabled_at.with_timezone(&Utc) + Duration::days(recovery_window_days))
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -75,6 +94,22 @@ impl FromStr for AuthProviders {
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum CustomerStatus {
+    #[default]
+    Active,
+    Suspended, // blocked from authenticating, data is preserved
+}
+
+impl ToString for CustomerStatus {
+    fn to_string(&self) -> String {
+        match self {
+            CustomerStatus::Active => String::from("active"),
+            CustomerStatus::Suspended => String::from("suspended"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Customer {
     pub id: String,
@@ -90,24 +125,79 @@ pub struct Customer {
     // miscelaneous
     pub preferences: Preferences,
     pub subscription: Subscription,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>, // integrators key/value data, e.g. crm ids
 
     pub created_at: String,
     pub updated_at: String,
     pub deleted: bool,
+    #[serde(default)]
+    pub status: CustomerStatus,
+    #[serde(default)]
+    pub region: String, // database the customer lives in, empty for the default one
+    #[serde(default)]
+    pub last_login_at: String,
+    #[serde(default)]
+    pub last_login_provider: String, // legacy, google, recovery or magic_link
+    #[serde(default)]
+    pub linked_providers: Vec<LinkedProvider>, // sign in methods added after signup, auth_provider stays the primary one
+    #[serde(default)]
+    pub picture: Option<String>, // avatar url, from google or set through /api/me/picture
+}
+
+impl Customer {
+    // primary provider first
+    pub fn auth_methods(&self) -> Vec<AuthProviders> {
+        let mut methods = vec![self.auth_provider];
+        for linked in self.linked_providers.iter() {
+            if !methods.contains(&linked.provider) {
+                methods.push(linked.provider);
+            }
+        }
+
+        methods
+    }
+
+    pub fn has_auth_method(&self, provider: AuthProviders) -> bool {
+        self.auth_methods().contains(&provider)
+    }
+
+    // main address first, None for records left without emails (e.g. the source of a merge)
+    pub fn primary_email(&self) -> Option<&Email> {
+        self.emails.iter().find(|email| email.main).or(self.emails.iter().find(|email| !email.disabled))
+    }
+
+    // seats bought only count for manager accounts, everyone else is a single seat
+    pub fn seat_limit(&self) -> i64 {
+        match self.class {
+            CustomerType::MANAGER => self.subscription.seats.max(1),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkedProvider {
+    pub provider: AuthProviders,
+    #[serde(default)]
+    pub subject: String, // provider account id, e.g. the google openid, empty for legacy
+    #[serde(default)]
+    pub email: String,
+    pub linked_at: String,
 }
 
+// safe to show to anyone, even without a session
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicCustomer {
     pub id: String,
     pub name: String,
     pub class: CustomerType,
+    pub picture: Option<String>,
     
-    pub preferences: Preferences,
-    pub subscription: Subscription,
+    pub preferences: PublicPreferences,
+    pub subscription_tier: String,
 
     pub created_at: String,
-    pub updated_at: String,
-    pub deleted: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -120,13 +210,33 @@ pub struct PrivateSensitiveCustomer {
 
     // miscelaneous
     pub preferences: Option<Preferences>,
-    pub subscription: Option<Subscription>,
+    pub subscription: Option<SubscriptionView>, // includes the computed active flag
+    pub next_renewal_at: Option<String>, // derived from renews_at and the billing anchor
+    pub metadata: Option<HashMap<String, String>>,
 
     pub created_at: Option<String>,
     pub updated_at: Option<String>,
     pub deleted: Option<bool>,
+    pub last_login_at: Option<String>,
+    pub last_login_provider: Option<String>,
+    pub linked_providers: Option<Vec<LinkedProvider>>,
+    pub picture: Option<String>,
 }
 
+// what admins get when listing customers, no credentials or full subscription
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminCustomerSummary {
+    pub id: String,
+    pub name: String,
+    pub class: CustomerType,
+    pub emails: Vec<Email>,
+    pub status: CustomerStatus,
+    pub subscription_slug: String,
+    pub created_at: String,
+    pub deleted: bool,
+    pub last_login_at: String,
+    pub last_login_provider: String,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Preferences {
@@ -134,3 +244,27 @@ pub struct Preferences {
     pub language: String,
     pub notifications: bool,
 }
+
+// NOTIFICATIONS_WITHOUT_VERIFIED_EMAIL, turning notifications on with nowhere deliverable to send them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnverifiedNotificationsPolicy {
+    Warn, // saved, the response carries a warning
+    Reject,
+}
+
+impl FromStr for UnverifiedNotificationsPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<UnverifiedNotificationsPolicy, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "warn" => Ok(UnverifiedNotificationsPolicy::Warn),
+            "reject" => Ok(UnverifiedNotificationsPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicPreferences {
+    pub language: String,
+}
@@ -1,4 +1,5 @@
 use serde::Serialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize)]
 pub struct CreateContact {
@@ -14,6 +15,11 @@ pub struct CreateContact {
     pub list_ids: Vec<u32>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct UpdateContact {
+    pub attributes: HashMap<String, String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Sender {
     pub email: String,
@@ -0,0 +1,81 @@
+use std::{collections::HashMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::subscription::{Slug, SubscriptionFeatures, SubscriptionStatus};
+
+pub const KNOWN_SLUGS: [&str; 2] = ["free", "pro"];
+
+// slug -> features and the statuses that lose paid features, editable through /api/admin/config/feature-map
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeatureMap {
+    pub slugs: HashMap<String, Vec<String>>,
+    pub blocked_statuses: Vec<String>,
+    #[serde(default)]
+    pub updated_at: String, // empty while the built in defaults are used
+    #[serde(default)]
+    pub updated_by: String,
+}
+
+impl Default for FeatureMap {
+    fn default() -> FeatureMap {
+        let slugs = [Slug::FREE, Slug::PRO]
+            .iter()
+            .map(|slug| (slug.to_string(), slug.features().iter().map(|feature| feature.to_string()).collect()))
+            .collect();
+
+        FeatureMap {
+            slugs,
+            blocked_statuses: SubscriptionStatus::known()
+                .iter()
+                .filter(|status| status.blocks_paid_features())
+                .map(|status| status.as_str().to_string())
+                .collect(),
+            updated_at: String::new(),
+            updated_by: String::new(),
+        }
+    }
+}
+
+impl FeatureMap {
+    // every known slug needs an entry, features and statuses must be ones the api understands
+    pub fn validate(&self) -> Result<(), String> {
+        for slug in self.slugs.keys() {
+            if !KNOWN_SLUGS.contains(&slug.as_str()) {
+                return Err(format!("unknown slug {}", slug));
+            }
+        }
+
+        for slug in KNOWN_SLUGS.iter() {
+            let features = match self.slugs.get(*slug) {
+                Some(features) => features,
+                None => return Err(format!("missing slug {}", slug)),
+            };
+
+            for feature in features.iter() {
+                if SubscriptionFeatures::from_str(feature).is_err() {
+                    return Err(format!("unknown feature {} for {}", feature, slug));
+                }
+            }
+        }
+
+        for status in self.blocked_statuses.iter() {
+            if matches!(SubscriptionStatus::from_str(status), Ok(SubscriptionStatus::Unknown(_) | SubscriptionStatus::Unset) | Err(_)) {
+                return Err(format!("unknown status {}", status));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn features_for(&self, slug: &Slug) -> Vec<SubscriptionFeatures> {
+        match self.slugs.get(&slug.to_string()) {
+            Some(features) => features.iter().filter_map(|feature| SubscriptionFeatures::from_str(feature).ok()).collect(),
+            None => slug.features(),
+        }
+    }
+
+    pub fn blocks_paid_features(&self, status: &SubscriptionStatus) -> bool {
+        self.blocked_statuses.iter().any(|blocked| SubscriptionStatus::from_str(blocked).as_ref() == Ok(status))
+    }
+}
@@ -1,39 +1,152 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::utilities::helpers::{deserialize_email, deserialize_trimmed};
+
+// request bodies deny unknown fields so a misspelled key is reported instead of silently dropped, query params stay lenient
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SignIn {
+    #[serde(deserialize_with = "deserialize_email")]
     pub email: String,
     pub password: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionElevation {
+    pub password: String,
+    #[serde(default)]
+    pub scopes: Vec<String>, // defaults to every first party scope
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecoverySignIn {
+    #[serde(deserialize_with = "deserialize_email")]
+    pub email: String,
+    pub backup_code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MagicLinkRequest {
+    #[serde(deserialize_with = "deserialize_email")]
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CreateCustomerRecord {
+    #[serde(deserialize_with = "deserialize_trimmed")]
     pub name: String,
+    #[serde(deserialize_with = "deserialize_email")]
     pub email: String,
     pub password: String,
     pub password_confirmation: String,
-    pub class: String,
+    #[serde(default)]
+    pub class: Option<String>, // DEFAULT_CUSTOMER_CLASS when missing
     pub accepted_terms: bool,
+    #[serde(deserialize_with = "deserialize_trimmed")]
     pub provider: String,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub invite_code: Option<String>, // only checked when REQUIRE_INVITE_CODE is on
+    #[serde(default)]
+    pub captcha_token: Option<String>, // only checked when CAPTCHA_SECRET is set
+    #[serde(default)]
+    pub picture: Option<String>, // e.g. the google picture from the signup prefill
+}
+
+// google needs the oauth authorization code, legacy the password to sign in with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkProviderRequest {
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub client: Option<String>, // GOOGLE_OAUTH_REDIRECT_URIS entry the code was issued for
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCustomerQueryParams {
+    pub dry_run: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CustomerUpdateName {
+    #[serde(deserialize_with = "deserialize_trimmed")]
     pub name: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerUpdateLanguage {
+    #[serde(deserialize_with = "deserialize_trimmed")]
+    pub language: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerUpdatePicture {
+    #[serde(deserialize_with = "deserialize_trimmed")]
+    pub picture: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CustomerUpdatePassword {
     pub old_password: String,
     pub new_password: String,
     pub new_password_confirmation: String,
 }
 
+// PATCH /api/me, any subset of the fields
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerUpdate {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub preferences: Option<CustomerUpdatePreferences>,
+    #[serde(default)]
+    pub password: Option<CustomerUpdatePassword>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerUpdatePreferences {
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub notifications: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CustomerAddEmail {
+    #[serde(deserialize_with = "deserialize_email")]
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerToggleEmail {
+    #[serde(deserialize_with = "deserialize_email")]
     pub email: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerUpdateMetadata {
+    pub metadata: HashMap<String, String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FetchCustomerByID {
     pub id: Option<String>,
@@ -43,3 +156,73 @@ pub struct FetchCustomerByID {
 pub struct VerifyEmailQueryParams {
     pub token: Option<String>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkQueryParams {
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionHistoryQueryParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TeamInviteRequest {
+    #[serde(deserialize_with = "deserialize_email")]
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImpersonationRequest {
+    #[serde(deserialize_with = "deserialize_trimmed")]
+    pub reason: String, // kept in the audit trail, e.g. the support ticket
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateInviteCode {
+    #[serde(default)]
+    pub code: Option<String>, // generated when missing
+    #[serde(default)]
+    pub max_redemptions: Option<i64>, // defaults to a single use
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerMergeRequest {
+    #[serde(deserialize_with = "deserialize_trimmed")]
+    pub source_id: String, // soft deleted once merged
+    #[serde(deserialize_with = "deserialize_trimmed")]
+    pub target_id: String,
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PruneHistoryRequest {
+    pub before: String, // rfc3339, logs dated before it are removed
+    #[serde(default)]
+    pub batch_size: Option<i64>, // customers per round trip
+}
+
+// replaces the whole map, every known slug has to be listed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureMapUpdate {
+    pub slugs: HashMap<String, Vec<String>>, // slug -> feature names
+    pub blocked_statuses: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CustomerListQueryParams {
+    pub q: Option<String>, // exact id, or an email or name prefix
+    pub region: Option<String>,
+    pub limit: Option<i64>,
+    pub page: Option<u64>,
+    pub after: Option<String>,
+}
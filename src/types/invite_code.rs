@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+// closed beta signups, max_redemptions 1 makes a single use code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub max_redemptions: i64,
+    pub redemptions: i64,
+    #[serde(default)]
+    pub redeemed_by: Vec<String>, // customer ids
+    pub created_by: String,
+    pub created_at: String,
+}
+
+impl InviteCode {
+    pub fn is_available(&self) -> bool {
+        self.redemptions < self.max_redemptions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite_code(max_redemptions: i64) -> InviteCode {
+        InviteCode {
+            code: String::from("BETA"),
+            max_redemptions,
+            redemptions: 0,
+            redeemed_by: vec![],
+            created_by: String::from("admin"),
+            created_at: String::new(),
+        }
+    }
+
+    // what consume_invite_code's $inc and $push do to the stored code
+    fn redeem(invite_code: &mut InviteCode, customer_id: &str) {
+        invite_code.redemptions += 1;
+        invite_code.redeemed_by.push(customer_id.to_string());
+    }
+
+    #[test]
+    fn a_single_use_code_is_spent_by_its_first_signup() {
+        let mut invite_code = invite_code(1);
+        assert!(invite_code.is_available());
+
+        redeem(&mut invite_code, "first");
+        assert!(!invite_code.is_available());
+    }
+
+    #[test]
+    fn a_multi_use_code_lasts_until_max_redemptions() {
+        let mut invite_code = invite_code(2);
+
+        redeem(&mut invite_code, "first");
+        assert!(invite_code.is_available());
+
+        redeem(&mut invite_code, "second");
+        assert!(!invite_code.is_available());
+        assert_eq!(invite_code.redeemed_by, vec!["first", "second"]);
+    }
+}
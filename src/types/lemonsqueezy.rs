@@ -1,10 +1,33 @@
+use crate::types::subscription::{Slug, SubscriptionFeatures, SubscriptionFrequencyClass, SubscriptionStatus};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
+// variant id -> plan, loaded from LEMONSQUEEZY_VARIANT_MAP (or the legacy PRO_* ids)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Products {
-    pub pro_product_id: i64,
-    pub pro_monthly_variant_id: i64,
-    pub pro_annually_variant_id: i64,
+    pub variants: HashMap<i64, VariantPlan>,
+}
+
+impl Products {
+    pub fn resolve(&self, variant_id: i64) -> Option<&VariantPlan> {
+        self.variants.get(&variant_id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantPlan {
+    pub slug: Slug,
+    pub frequency: SubscriptionFrequencyClass,
+    pub features: Vec<SubscriptionFeatures>,
+}
+
+// raw shape of each LEMONSQUEEZY_VARIANT_MAP entry, e.g. {"slug": "pro", "frequency": "monthly", "features": ["core"]}
+#[derive(Debug, Clone, Deserialize)]
+pub struct VariantPlanConfig {
+    pub slug: String,
+    pub frequency: String,
+    #[serde(default)]
+    pub features: Vec<String>,
 }
 
 // events
@@ -21,6 +44,13 @@ pub struct SubscriptionEvent {
     pub data: SubscriptionData,
 }
 
+impl SubscriptionEvent {
+    // retries resend the exact same event, a new change always bumps updated_at
+    pub fn delivery_id(&self) -> String {
+        format!("{}:{}:{}", self.meta.event_name, self.data.id, self.data.attributes.updated_at)
+    }
+}
+
 // meta
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -154,6 +184,12 @@ pub struct OrderUrls {
 // Subscription //
 /////////////////
 
+// GET /v1/subscriptions/:id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionResponse {
+    pub data: SubscriptionData,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubscriptionData {
     pub r#type: String,
@@ -175,7 +211,7 @@ pub struct SubscriptionAttributes {
     pub variant_name: String,
     pub user_name: String,
     pub user_email: String,
-    pub status: String,
+    pub status: SubscriptionStatus,
     pub status_formatted: String,
     pub card_brand: String,
     pub card_last_four: String,
@@ -192,6 +228,16 @@ pub struct SubscriptionAttributes {
     pub test_mode: bool,
 }
 
+impl SubscriptionAttributes {
+    // the item quantity, never less than one seat
+    pub fn seats(&self) -> i64 {
+        match &self.first_subscription_item {
+            Some(item) => item.quantity.max(1),
+            None => 1,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FirstSubscriptionItem {
     pub id: i64,
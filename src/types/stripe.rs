@@ -0,0 +1,74 @@
+use crate::types::lemonsqueezy::VariantPlan;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// loaded from STRIPE_WEBHOOK_SECRET and STRIPE_PRICE_MAP, price id -> plan
+#[derive(Debug, Clone)]
+pub struct StripeSettings {
+    pub webhook_secret: String,
+    pub prices: HashMap<String, VariantPlan>,
+    pub signature_tolerance: i64, // seconds a signed timestamp stays valid
+}
+
+impl StripeSettings {
+    pub fn resolve(&self, price_id: &str) -> Option<&VariantPlan> {
+        self.prices.get(price_id)
+    }
+}
+
+// events, only the fields we use
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripeEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created: i64,
+    pub data: StripeEventData,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripeEventData {
+    pub object: serde_json::Value, // depends on the event type
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripeSubscription {
+    pub id: String,
+    pub customer: String,
+    pub status: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>, // customer_id is set by the checkout
+    pub items: StripeSubscriptionItems,
+    pub created: i64,
+    pub current_period_start: Option<i64>,
+    pub current_period_end: Option<i64>,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    pub cancel_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    pub billing_cycle_anchor: Option<i64>,
+}
+
+impl StripeSubscription {
+    // our plans are single item subscriptions
+    pub fn price_id(&self) -> Option<&str> {
+        self.items.data.first().map(|item| item.price.id.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripeSubscriptionItems {
+    pub data: Vec<StripeSubscriptionItem>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripeSubscriptionItem {
+    pub id: String,
+    pub price: StripePrice,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StripePrice {
+    pub id: String,
+}
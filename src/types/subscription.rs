@@ -1,7 +1,8 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Slug {
     FREE,
     PRO,
@@ -14,6 +15,21 @@ impl Slug {
             Slug::PRO => String::from("pro"),
         }
     }
+
+    // higher is better, used when two subscriptions compete (account merges)
+    pub fn rank(&self) -> u8 {
+        match self {
+            Slug::FREE => 0,
+            Slug::PRO => 1,
+        }
+    }
+
+    pub fn features(&self) -> Vec<SubscriptionFeatures> {
+        match self {
+            Slug::FREE => vec![SubscriptionFeatures::CORE],
+            Slug::PRO => vec![SubscriptionFeatures::CORE, SubscriptionFeatures::ADVANCED],
+        }
+    }
 }
 
 impl FromStr for Slug {
@@ -48,9 +64,125 @@ impl FromStr for SubscriptionFrequencyClass {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+// stored with the LemonSqueezy spelling, e.g. "past_due", so existing documents and mongo queries keep working
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    Unset, // free customers never had a paid subscription
+    Active,
+    PastDue,
+    Cancelled,
+    Paused,
+    Expired,
+    OnTrial,
+    Unpaid,
+    Unknown(String), // kept as received so nothing is lost on the next write
+}
+
+impl SubscriptionStatus {
+    // every variant but Unset and Unknown
+    pub fn known() -> Vec<SubscriptionStatus> {
+        vec![
+            SubscriptionStatus::Active,
+            SubscriptionStatus::PastDue,
+            SubscriptionStatus::Cancelled,
+            SubscriptionStatus::Paused,
+            SubscriptionStatus::Expired,
+            SubscriptionStatus::OnTrial,
+            SubscriptionStatus::Unpaid,
+        ]
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            SubscriptionStatus::Unset => "",
+            SubscriptionStatus::Active => "active",
+            SubscriptionStatus::PastDue => "past_due",
+            SubscriptionStatus::Cancelled => "cancelled",
+            SubscriptionStatus::Paused => "paused",
+            SubscriptionStatus::Expired => "expired",
+            SubscriptionStatus::OnTrial => "on_trial",
+            SubscriptionStatus::Unpaid => "unpaid",
+            SubscriptionStatus::Unknown(status) => status,
+        }
+    }
+
+    // paid features are withheld in these even if the slug is still paid
+    pub fn blocks_paid_features(&self) -> bool {
+        matches!(
+            self,
+            SubscriptionStatus::PastDue | SubscriptionStatus::Unpaid | SubscriptionStatus::Paused | SubscriptionStatus::Expired
+        )
+    }
+
+    pub fn is_churned(&self) -> bool {
+        matches!(self, SubscriptionStatus::Cancelled | SubscriptionStatus::Expired)
+    }
+
+    // the single status -> active mapping, past_due only counts while payment is still being retried
+    pub fn is_active(&self, in_grace_period: bool) -> bool {
+        match self {
+            SubscriptionStatus::Active | SubscriptionStatus::OnTrial => true,
+            SubscriptionStatus::PastDue => in_grace_period,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for SubscriptionStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SubscriptionStatus, Self::Err> {
+        let status = s.trim().to_lowercase();
+        match status.as_str() {
+            "" => Ok(SubscriptionStatus::Unset),
+            "active" => Ok(SubscriptionStatus::Active),
+            "past_due" => Ok(SubscriptionStatus::PastDue),
+            "cancelled" => Ok(SubscriptionStatus::Cancelled),
+            "paused" => Ok(SubscriptionStatus::Paused),
+            "expired" => Ok(SubscriptionStatus::Expired),
+            "on_trial" => Ok(SubscriptionStatus::OnTrial),
+            "unpaid" => Ok(SubscriptionStatus::Unpaid),
+            _ => Ok(SubscriptionStatus::Unknown(status)),
+        }
+    }
+}
+
+impl Serialize for SubscriptionStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SubscriptionStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let status = String::deserialize(deserializer)?;
+        Ok(SubscriptionStatus::from_str(&status).unwrap_or(SubscriptionStatus::Unset))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum SubscriptionFeatures {
     CORE,
+    ADVANCED,
+}
+
+impl ToString for SubscriptionFeatures {
+    fn to_string(&self) -> String {
+        match self {
+            SubscriptionFeatures::CORE => String::from("core"),
+            SubscriptionFeatures::ADVANCED => String::from("advanced"),
+        }
+    }
+}
+
+impl SubscriptionFeatures {
+    // lowest tier that unlocks the feature
+    pub fn required_slug(&self) -> Slug {
+        match self {
+            SubscriptionFeatures::CORE => Slug::FREE,
+            SubscriptionFeatures::ADVANCED => Slug::PRO,
+        }
+    }
 }
 
 impl FromStr for SubscriptionFeatures {
@@ -59,7 +191,28 @@ impl FromStr for SubscriptionFeatures {
     fn from_str(s: &str) -> Result<SubscriptionFeatures, Self::Err> {
         match s {
             "core" => Ok(SubscriptionFeatures::CORE),
-            _ => Ok(SubscriptionFeatures::CORE),
+            "advanced" => Ok(SubscriptionFeatures::ADVANCED),
+            _ => Err(()),
+        }
+    }
+}
+
+// plan prices in cents per billing period, loaded from PLAN_PRICES, e.g. {"pro": {"monthly": 900, "annually": 9000}}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlanPrices {
+    #[serde(default)]
+    pub monthly: i64,
+    #[serde(default)]
+    pub annually: i64,
+}
+
+impl PlanPrices {
+    // what one subscriber of the given frequency contributes to the MRR, in cents
+    pub fn monthly_amount(&self, frequency: &str) -> i64 {
+        match frequency {
+            "MONTHLY" => self.monthly,
+            "ANNUALLY" => self.annually / 12,
+            _ => 0,
         }
     }
 }
@@ -75,9 +228,11 @@ pub struct Subscription {
     pub id: String,
     pub product_id: i64,
     pub variant_id: i64,
+    #[serde(default)]
+    pub price_id: String, // stripe price, LemonSqueezy subscriptions use variant_id
     pub slug: String,
     pub frequency: SubscriptionFrequencyClass,
-    pub status: String,
+    pub status: SubscriptionStatus,
 
     pub created_at: String, // well, this is when the account created the account, the subscription is never deleted, only updated, if end so is free
     pub updated_at: String,
@@ -85,6 +240,196 @@ pub struct Subscription {
     pub starts_at: String,
     pub ends_at: String,
     pub renews_at: String,
+    #[serde(default)]
+    pub grace_period_ends_at: String, // set while a failed payment is being retried, empty otherwise
+    #[serde(default)]
+    pub expire_after_grace: bool, // expired during the grace period, the downgrade waits for it to end
+    #[serde(default)]
+    pub customer_portal_url: String,
+    #[serde(default)]
+    pub update_payment_method_url: String,
+    #[serde(default)]
+    pub billing_anchor: i64, // day of the month charges are anchored to, 0 when unknown
+    #[serde(default = "default_seats")]
+    pub seats: i64, // quantity bought, only manager accounts use more than one
 
     pub history_logs: Vec<SubscriptionHistoryLog>,
-}
\ No newline at end of file
+}
+
+fn default_seats() -> i64 {
+    1
+}
+
+impl Subscription {
+    pub fn in_grace_period(&self) -> bool {
+        match DateTime::parse_from_rfc3339(&self.grace_period_ends_at) {
+            Ok(grace_period_ends_at) => grace_period_ends_at > Utc::now(),
+            Err(_) => false,
+        }
+    }
+
+    // default trials aren't backed by LemonSqueezy, nothing moves them off on_trial once ends_at passes
+    pub fn default_trial_ended(&self) -> bool {
+        if self.status != SubscriptionStatus::OnTrial || self.variant_id != 0 {
+            return false;
+        }
+
+        match DateTime::parse_from_rfc3339(&self.ends_at) {
+            Ok(ends_at) => ends_at < Utc::now(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.default_trial_ended() && self.status.is_active(self.in_grace_period())
+    }
+}
+
+// what responses carry, the stored subscription plus the computed active flag so clients don't parse statuses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionView {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    pub active: bool,
+}
+
+impl From<Subscription> for SubscriptionView {
+    fn from(subscription: Subscription) -> SubscriptionView {
+        let active = subscription.is_active();
+        SubscriptionView { subscription, active }
+    }
+}
+// what new customers start on, FREE unless DEFAULT_SUBSCRIPTION_SLUG asks for a trial of a paid tier
+#[derive(Debug, Clone)]
+pub struct DefaultSubscription {
+    pub slug: Slug,
+    pub frequency: SubscriptionFrequencyClass,
+    pub trial_days: i64,
+}
+
+impl DefaultSubscription {
+    pub fn build(&self, id: String, now: DateTime<Utc>) -> Subscription {
+        let created_at = now.to_rfc3339();
+
+        let (frequency, status, starts_at, ends_at) = match self.slug {
+            Slug::FREE => (SubscriptionFrequencyClass::UNDEFINED, SubscriptionStatus::Unset, String::new(), String::new()),
+            _ => (
+                self.frequency,
+                SubscriptionStatus::OnTrial,
+                created_at.clone(),
+                (now + chrono::Duration::days(self.trial_days)).to_rfc3339(),
+            ),
+        };
+
+        Subscription {
+            id,
+            product_id: 0,
+            variant_id: 0,
+            price_id: String::new(),
+            slug: self.slug.to_string(),
+            frequency,
+            status,
+            created_at: created_at.clone(),
+            updated_at: created_at,
+            starts_at,
+            ends_at,
+            renews_at: String::new(),
+            grace_period_ends_at: String::new(),
+            expire_after_grace: false,
+            customer_portal_url: String::new(),
+            update_payment_method_url: String::new(),
+            billing_anchor: 0,
+            seats: 1,
+            history_logs: vec![],
+        }
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let (next_year, next_month) = match month {
+        12 => (year + 1, 1),
+        _ => (year, month + 1),
+    };
+
+    match NaiveDate::from_ymd_opt(next_year, next_month, 1) {
+        Some(first_of_next) => first_of_next.pred_opt().map(|last| last.day()).unwrap_or(28),
+        None => 28,
+    }
+}
+
+// the anchor day within the given month, clamped to the last day when the month is shorter (31 -> 30, 29 -> 28)
+pub fn anchored_date(year: i32, month: u32, anchor: u32, time: NaiveTime) -> Option<DateTime<Utc>> {
+    let day = anchor.min(days_in_month(year, month));
+    let date = NaiveDate::from_ymd_opt(year, month, day)?;
+
+    Some(date.and_time(time).and_utc())
+}
+
+// next charge date, renews_at moved onto the billing anchor and rolled forward when it's already behind us
+pub fn next_renewal(subscription: &Subscription) -> Option<DateTime<Utc>> {
+    let renews_at = DateTime::parse_from_rfc3339(&subscription.renews_at).ok()?.with_timezone(&Utc);
+
+    if !(1..=31).contains(&subscription.billing_anchor) {
+        return Some(renews_at);
+    }
+
+    let anchor = subscription.billing_anchor as u32;
+    let step = match subscription.frequency {
+        SubscriptionFrequencyClass::ANNUALLY => 12,
+        _ => 1,
+    };
+
+    // year and month are tracked apart from the clamped date so a 31 anchor comes back after a short month
+    let (mut year, mut month) = (renews_at.year(), renews_at.month());
+    let mut candidate = anchored_date(year, month, anchor, renews_at.time())?;
+
+    let now = Utc::now();
+    while candidate < now {
+        let months = month - 1 + step;
+        year += (months / 12) as i32;
+        month = months % 12 + 1;
+        candidate = anchored_date(year, month, anchor, renews_at.time())?;
+    }
+
+    Some(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_lemonsqueezy_status_maps_to_its_variant() {
+        let statuses = [
+            ("active", SubscriptionStatus::Active),
+            ("past_due", SubscriptionStatus::PastDue),
+            ("cancelled", SubscriptionStatus::Cancelled),
+            ("paused", SubscriptionStatus::Paused),
+            ("expired", SubscriptionStatus::Expired),
+            ("on_trial", SubscriptionStatus::OnTrial),
+            ("unpaid", SubscriptionStatus::Unpaid),
+        ];
+
+        for (raw, status) in statuses {
+            assert_eq!(SubscriptionStatus::from_str(raw), Ok(status.clone()));
+            assert_eq!(status.as_str(), raw);
+        }
+
+        assert_eq!(SubscriptionStatus::from_str(" Past_Due "), Ok(SubscriptionStatus::PastDue));
+    }
+
+    #[test]
+    fn unknown_statuses_survive_a_round_trip() {
+        let status: SubscriptionStatus = serde_json::from_str("\"Refunded\"").unwrap();
+
+        assert_eq!(status, SubscriptionStatus::Unknown(String::from("refunded")));
+        assert_eq!(serde_json::to_string(&status).unwrap(), "\"refunded\"");
+        assert!(!status.is_active(true));
+    }
+
+    #[test]
+    fn a_missing_status_is_unset() {
+        let status: SubscriptionStatus = serde_json::from_str("\"\"").unwrap();
+        assert_eq!(status, SubscriptionStatus::Unset);
+    }
+}
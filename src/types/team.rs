@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TeamMemberStatus {
+    Pending, // invited, hasn't joined yet
+    Active,
+}
+
+// a seat under a manager account, pending invites hold their seat too
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamMember {
+    pub id: String,
+    pub manager_id: String,
+    pub email: String,
+    pub status: TeamMemberStatus,
+    #[serde(default)]
+    pub customer_id: String, // set once the invitee joins
+    pub invited_at: String,
+}
@@ -1,4 +1,15 @@
 pub mod helpers;
 pub mod token;
 pub mod email;
-pub mod api_messages;
\ No newline at end of file
+pub mod api_messages;
+pub mod config;
+pub mod idempotency;
+pub mod metrics;
+pub mod webhooks;
+pub mod client_ip;
+pub mod rate_limits;
+pub mod token_delivery;
+pub mod integration_webhook;
+pub mod captcha;
+pub mod events;
+pub mod audit;
@@ -23,6 +23,8 @@ pub enum APIMessages{
     Redis(RedisMessages),
     // Customer
     Customer(CustomerMessages),
+    // Subscription
+    Subscription(SubscriptionMessages),
 }
 
 #[derive(Debug)]
@@ -35,6 +37,8 @@ pub enum TokenMessages {
     ErrorValidating,
     Renewed,
     ErrorRenewing,
+    Elevated,
+    Revoked,
 
     NotAllowedScopesToPerformAction,
 
@@ -43,26 +47,66 @@ pub enum TokenMessages {
 
     ErrorFetchingUserFromGoogle,
     ErrorRequestingGoogleToken,
+    InvalidAuthorizationCode,
+    AuthorizationCodeAlreadyUsed,
+    UnknownOAuthClient,
+    GoogleAuthorizationUrl,
 
     NotAuthorizationHeader,
     ErrorParsingToken,
+
+    MagicLinkSent,
+
+    InvalidBackupSecurityCode,
+    TooManyRecoveryAttempts,
+
+    Impersonating,
+    NotAllowedWhileImpersonating,
+    RecentImpersonations,
 }
 
 #[derive(Debug)]
 pub enum InputMessages {
     InvalidNameLength,
+    ConfusableName,
     InvalidOldPasswordLength,
     InvalidNewPasswordLength,
     PasswordMustHaveAtLeastOneLetterAndOneNumber,
     NewPasswordAndOldPasswordMustBeDifferent,
     NewPasswordConfirmationMustMatch,
+    TooManyMetadataKeys,
+    InvalidMetadataKey,
+    InvalidMetadataValueLength,
+    InvalidDateRange,
+    InvalidIdempotencyKey,
+    IdempotentRequestInProgress,
+    IdempotencyKeyReused,
+    InvalidCursor,
+    InvalidSearchQuery,
+    InvalidPagination,
+    InvalidPruneCutoff,
+    InvalidBatchSize,
+    InvalidRegion,
+    InvalidInviteCodeLimit,
+    DisallowedCharacters,
+    UnsupportedMediaType,
+    MalformedJson,
+    InvalidPayload,
+    MissingField,
+    NothingToUpdate,
+    UnknownField,
+    UnsupportedLanguage,
+    InvalidPictureUrl,
 }
 
 #[derive(Debug)]
 pub enum CustomerMessages {
     Created,
+    DryRunValidated,
     Found,
+    Listed,
     NotFound,
+    NeedsRegistration,
     NotAcceptedTerms,
     
     InvalidType,
@@ -75,10 +119,67 @@ pub enum CustomerMessages {
     ErrorRegisteringCustomerInMarketingPlatform,
 
     NameUpdated,
+    LanguageUpdated,
     PasswordUpdated,
     EmailAdded,
+    MetadataUpdated,
+    MetadataKeyRemoved,
+    MetadataKeyNotFound,
+
+    Suspended,
+    Reactivated,
+    Merged,
+    MergeNotConfirmed,
+    InvalidInviteCode,
+    InviteCodeCreated,
+    InviteCodeTaken,
+    InviteCodesListed,
+    CannotMergeIntoItself,
+    ProviderLinked,
+    ProviderUnlinked,
+    ProviderAlreadyLinked,
+    ProviderLinkedToOtherCustomer,
+    ProviderNotLinked,
+    UnknownAuthProvider,
+    TooManyLinkedProviders,
+    CannotUnlinkLastAuthMethod,
+    RateLimits,
+    Updated,
+    CaptchaFailed,
+    PictureUpdated,
 
     NotFoundByID,
+
+    TeamListed,
+    TeamMemberInvited,
+    TeamMemberRemoved,
+    TeamMemberNotFound,
+    TeamMemberAlreadyInvited,
+    TeamSeatLimitReached,
+    OnlyManagersHaveTeams,
+}
+
+#[derive(Debug)]
+pub enum SubscriptionMessages {
+    FeatureAllowed,
+    FeatureDenied,
+    UnknownFeature,
+    PortalLinks,
+    NoPortalForFreeTier,
+    Stats,
+    RecentWebhooks,
+    DeadLetterWebhooks,
+    Synced,
+    NoLemonSqueezySubscription,
+    SyncFailed,
+    UpdateInProgress,
+    HistoryPruned,
+    HistoryArchiveFailed,
+    FeatureMap,
+    FeatureMapUpdated,
+    InvalidFeatureMap,
+    Found,
+    NotFound,
 }
 
 #[derive(Debug)]
@@ -97,7 +198,12 @@ pub enum RedisMessages {
 #[derive(Debug)]
 pub enum EmailMessages {
     Verified,
+    VerificationTokenValid,
+    VerificationTokenInvalid,
     Invalid,
+    NotFound,
+    DomainNotAllowed,
+    NoMailServers,
 
     Taken,
     TakenByOtherCustomer,
@@ -106,6 +212,16 @@ pub enum EmailMessages {
     EmailAndPasswordMustBeDifferent,
     ErrorSendingVerificationEmail,
     MaxEmailsReached,
+    DailySendBudgetExceeded,
+    NoVerifiedEmail,
+    TestEmailSent,
+    Listed,
+    VerificationPreview,
+    NoEmailOnRecord,
+    Disabled,
+    Enabled,
+    CannotDisableMainEmail,
+    RecoveryWindowExpired,
 }
 
 impl ToString for APIMessages {
@@ -127,6 +243,7 @@ impl ToString for APIMessages {
             APIMessages::Mongo(mongo_message) => mongo_message.to_string(),
             APIMessages::Redis(redis_message) => redis_message.to_string(),
             APIMessages::Customer(customer_message) => customer_message.to_string(),
+            APIMessages::Subscription(subscription_message) => subscription_message.to_string(),
         }
     }
 }
@@ -142,13 +259,25 @@ impl ToString for TokenMessages {
             TokenMessages::ErrorValidating => "token.error_validating".to_string(),
             TokenMessages::Renewed => "token.renewed".to_string(),
             TokenMessages::ErrorRenewing => "token.error_renewing".to_string(),
+            TokenMessages::Elevated => "token.elevated".to_string(),
+            TokenMessages::Revoked => "token.revoked".to_string(),
             TokenMessages::OnlyLegacyProvider => "token.only_legacy_provider".to_string(),
             TokenMessages::OnlyGoogleProvider => "token.only_google_provider".to_string(),
             TokenMessages::ErrorFetchingUserFromGoogle => "token.error_fetching_user_from_google".to_string(),
             TokenMessages::ErrorRequestingGoogleToken => "token.error_requesting_google_token".to_string(),
+            TokenMessages::InvalidAuthorizationCode => "token.invalid_authorization_code".to_string(),
+            TokenMessages::AuthorizationCodeAlreadyUsed => "token.authorization_code_already_used".to_string(),
+            TokenMessages::UnknownOAuthClient => "token.unknown_oauth_client".to_string(),
+            TokenMessages::GoogleAuthorizationUrl => "token.google_authorization_url".to_string(),
             TokenMessages::NotAuthorizationHeader => "token.not_authorization_header".to_string(),
             TokenMessages::ErrorParsingToken => "token.error_parsing_token".to_string(),
             TokenMessages::NotAllowedScopesToPerformAction => "token.not_allowed_scopes_to_perform_action".to_string(),
+            TokenMessages::MagicLinkSent => "token.magic_link_sent".to_string(),
+            TokenMessages::InvalidBackupSecurityCode => "token.invalid_backup_security_code".to_string(),
+            TokenMessages::TooManyRecoveryAttempts => "token.too_many_recovery_attempts".to_string(),
+            TokenMessages::Impersonating => "token.impersonating".to_string(),
+            TokenMessages::NotAllowedWhileImpersonating => "token.not_allowed_while_impersonating".to_string(),
+            TokenMessages::RecentImpersonations => "token.recent_impersonations".to_string(),
         }
     }
 }
@@ -157,6 +286,7 @@ impl ToString for InputMessages {
     fn to_string(&self) -> String {
         match self {
             InputMessages::InvalidNameLength => "generic.invalid_name_length".to_string(),
+            InputMessages::ConfusableName => "generic.confusable_name".to_string(),
             InputMessages::InvalidOldPasswordLength => "generic.invalid_old_password_length".to_string(),
             InputMessages::InvalidNewPasswordLength => "generic.invalid_new_password_length".to_string(),
             InputMessages::NewPasswordAndOldPasswordMustBeDifferent => {
@@ -168,6 +298,29 @@ impl ToString for InputMessages {
             InputMessages::PasswordMustHaveAtLeastOneLetterAndOneNumber => {
                 "generic.password_must_have_at_least_one_letter_and_one_number".to_string()
             },
+            InputMessages::TooManyMetadataKeys => "generic.too_many_metadata_keys".to_string(),
+            InputMessages::InvalidMetadataKey => "generic.invalid_metadata_key".to_string(),
+            InputMessages::InvalidMetadataValueLength => "generic.invalid_metadata_value_length".to_string(),
+            InputMessages::InvalidDateRange => "generic.invalid_date_range".to_string(),
+            InputMessages::InvalidIdempotencyKey => "generic.invalid_idempotency_key".to_string(),
+            InputMessages::IdempotentRequestInProgress => "generic.idempotent_request_in_progress".to_string(),
+            InputMessages::IdempotencyKeyReused => "generic.idempotency_key_reused".to_string(),
+            InputMessages::InvalidCursor => "generic.invalid_cursor".to_string(),
+            InputMessages::InvalidSearchQuery => "generic.invalid_search_query".to_string(),
+            InputMessages::InvalidPagination => "generic.invalid_pagination".to_string(),
+            InputMessages::InvalidPruneCutoff => "generic.invalid_prune_cutoff".to_string(),
+            InputMessages::InvalidBatchSize => "generic.invalid_batch_size".to_string(),
+            InputMessages::InvalidRegion => "generic.invalid_region".to_string(),
+            InputMessages::InvalidInviteCodeLimit => "generic.invalid_invite_code_limit".to_string(),
+            InputMessages::DisallowedCharacters => "generic.disallowed_characters".to_string(),
+            InputMessages::UnsupportedMediaType => "generic.unsupported_media_type".to_string(),
+            InputMessages::MalformedJson => "generic.malformed_json".to_string(),
+            InputMessages::InvalidPayload => "generic.invalid_payload".to_string(),
+            InputMessages::MissingField => "generic.missing_field".to_string(),
+            InputMessages::NothingToUpdate => "generic.nothing_to_update".to_string(),
+            InputMessages::UnknownField => "generic.unknown_field".to_string(),
+            InputMessages::UnsupportedLanguage => "generic.unsupported_language".to_string(),
+            InputMessages::InvalidPictureUrl => "generic.invalid_picture_url".to_string(),
         }
     }
 }
@@ -176,8 +329,11 @@ impl ToString for CustomerMessages {
     fn to_string(&self) -> String {
         match self {
             CustomerMessages::Created => "customer.created".to_string(),
+            CustomerMessages::DryRunValidated => "customer.dry_run_validated".to_string(),
             CustomerMessages::Found => "customer.found".to_string(),
+            CustomerMessages::Listed => "customer.listed".to_string(),
             CustomerMessages::NotFound => "customer.not_found".to_string(),
+            CustomerMessages::NeedsRegistration => "customer.needs_registration".to_string(),
             CustomerMessages::NotAcceptedTerms => "customer.not_accepted_terms".to_string(),
             CustomerMessages::PasswordConfirmationDoesNotMatch => {
                 "customer.password_confirmation_does_not_match".to_string()
@@ -189,10 +345,68 @@ impl ToString for CustomerMessages {
                 "customer.error_registering_in_marketing_platform".to_string()
             }
             CustomerMessages::NameUpdated => "customer.name_updated".to_string(),
+            CustomerMessages::LanguageUpdated => "customer.language_updated".to_string(),
             CustomerMessages::PasswordUpdated => "customer.password_updated".to_string(),
             CustomerMessages::EmailAdded => "customer.email_added".to_string(),
+            CustomerMessages::MetadataUpdated => "customer.metadata_updated".to_string(),
+            CustomerMessages::MetadataKeyRemoved => "customer.metadata_key_removed".to_string(),
+            CustomerMessages::MetadataKeyNotFound => "customer.metadata_key_not_found".to_string(),
+            CustomerMessages::Suspended => "customer.suspended".to_string(),
+            CustomerMessages::Reactivated => "customer.reactivated".to_string(),
+            CustomerMessages::Merged => "customer.merged".to_string(),
+            CustomerMessages::MergeNotConfirmed => "customer.merge_not_confirmed".to_string(),
+            CustomerMessages::InvalidInviteCode => "customer.invalid_invite_code".to_string(),
+            CustomerMessages::InviteCodeCreated => "customer.invite_code_created".to_string(),
+            CustomerMessages::InviteCodeTaken => "customer.invite_code_taken".to_string(),
+            CustomerMessages::InviteCodesListed => "customer.invite_codes_listed".to_string(),
+            CustomerMessages::CannotMergeIntoItself => "customer.cannot_merge_into_itself".to_string(),
+            CustomerMessages::ProviderLinked => "customer.provider_linked".to_string(),
+            CustomerMessages::ProviderUnlinked => "customer.provider_unlinked".to_string(),
+            CustomerMessages::ProviderAlreadyLinked => "customer.provider_already_linked".to_string(),
+            CustomerMessages::ProviderLinkedToOtherCustomer => "customer.provider_linked_to_other_customer".to_string(),
+            CustomerMessages::ProviderNotLinked => "customer.provider_not_linked".to_string(),
+            CustomerMessages::UnknownAuthProvider => "customer.unknown_auth_provider".to_string(),
+            CustomerMessages::TooManyLinkedProviders => "customer.too_many_linked_providers".to_string(),
+            CustomerMessages::CannotUnlinkLastAuthMethod => "customer.cannot_unlink_last_auth_method".to_string(),
+            CustomerMessages::RateLimits => "customer.rate_limits".to_string(),
+            CustomerMessages::Updated => "customer.updated".to_string(),
+            CustomerMessages::CaptchaFailed => "customer.captcha_failed".to_string(),
+            CustomerMessages::PictureUpdated => "customer.picture_updated".to_string(),
             CustomerMessages::InvalidType => "customer.invalid_type".to_string(),
             CustomerMessages::NotFoundByID => "customer.not_found_by_id".to_string(),
+            CustomerMessages::TeamListed => "customer.team_listed".to_string(),
+            CustomerMessages::TeamMemberInvited => "customer.team_member_invited".to_string(),
+            CustomerMessages::TeamMemberRemoved => "customer.team_member_removed".to_string(),
+            CustomerMessages::TeamMemberNotFound => "customer.team_member_not_found".to_string(),
+            CustomerMessages::TeamMemberAlreadyInvited => "customer.team_member_already_invited".to_string(),
+            CustomerMessages::TeamSeatLimitReached => "customer.team_seat_limit_reached".to_string(),
+            CustomerMessages::OnlyManagersHaveTeams => "customer.only_managers_have_teams".to_string(),
+        }
+    }
+}
+
+impl ToString for SubscriptionMessages {
+    fn to_string(&self) -> String {
+        match self {
+            SubscriptionMessages::FeatureAllowed => "subscription.feature_allowed".to_string(),
+            SubscriptionMessages::FeatureDenied => "subscription.feature_denied".to_string(),
+            SubscriptionMessages::UnknownFeature => "subscription.unknown_feature".to_string(),
+            SubscriptionMessages::PortalLinks => "subscription.portal_links".to_string(),
+            SubscriptionMessages::NoPortalForFreeTier => "subscription.no_portal_for_free_tier".to_string(),
+            SubscriptionMessages::Stats => "subscription.stats".to_string(),
+            SubscriptionMessages::RecentWebhooks => "subscription.recent_webhooks".to_string(),
+            SubscriptionMessages::DeadLetterWebhooks => "subscription.dead_letter_webhooks".to_string(),
+            SubscriptionMessages::Synced => "subscription.synced".to_string(),
+            SubscriptionMessages::NoLemonSqueezySubscription => "subscription.no_lemonsqueezy_subscription".to_string(),
+            SubscriptionMessages::SyncFailed => "subscription.sync_failed".to_string(),
+            SubscriptionMessages::UpdateInProgress => "subscription.update_in_progress".to_string(),
+            SubscriptionMessages::HistoryPruned => "subscription.history_pruned".to_string(),
+            SubscriptionMessages::HistoryArchiveFailed => "subscription.history_archive_failed".to_string(),
+            SubscriptionMessages::FeatureMap => "subscription.feature_map".to_string(),
+            SubscriptionMessages::FeatureMapUpdated => "subscription.feature_map_updated".to_string(),
+            SubscriptionMessages::InvalidFeatureMap => "subscription.invalid_feature_map".to_string(),
+            SubscriptionMessages::Found => "subscription.found".to_string(),
+            SubscriptionMessages::NotFound => "subscription.not_found".to_string(),
         }
     }
 }
@@ -220,7 +434,12 @@ impl ToString for EmailMessages {
     fn to_string(&self) -> String {
         match self {
             EmailMessages::Verified => "email.verified".to_string(),
+            EmailMessages::VerificationTokenValid => "email.verification_token_valid".to_string(),
+            EmailMessages::VerificationTokenInvalid => "email.verification_token_invalid".to_string(),
             EmailMessages::Invalid => "email.invalid".to_string(),
+            EmailMessages::NoMailServers => "email.no_mail_servers".to_string(),
+            EmailMessages::NotFound => "email.not_found".to_string(),
+            EmailMessages::DomainNotAllowed => "email.domain_not_allowed".to_string(),
             EmailMessages::Taken => "email.taken".to_string(),
             EmailMessages::TakenByOtherCustomer => "email.taken_by_other_customer".to_string(),
             EmailMessages::TakenByYou => "email.taken_by_you".to_string(),
@@ -231,6 +450,16 @@ impl ToString for EmailMessages {
                 "email.error_sending_verification_email".to_string()
             }
             EmailMessages::MaxEmailsReached => "email.max_emails_reached".to_string(),
+            EmailMessages::DailySendBudgetExceeded => "email.daily_send_budget_exceeded".to_string(),
+            EmailMessages::NoVerifiedEmail => "email.no_verified_email".to_string(),
+            EmailMessages::TestEmailSent => "email.test_email_sent".to_string(),
+            EmailMessages::Listed => "email.listed".to_string(),
+            EmailMessages::VerificationPreview => "email.verification_preview".to_string(),
+            EmailMessages::NoEmailOnRecord => "email.no_email_on_record".to_string(),
+            EmailMessages::Disabled => "email.disabled".to_string(),
+            EmailMessages::Enabled => "email.enabled".to_string(),
+            EmailMessages::CannotDisableMainEmail => "email.cannot_disable_main_email".to_string(),
+            EmailMessages::RecoveryWindowExpired => "email.recovery_window_expired".to_string(),
         }
     }
 }
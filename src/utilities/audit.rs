@@ -0,0 +1,55 @@
+use chrono::Utc;
+use redis::{Client, Commands, RedisError};
+use serde::{Deserialize, Serialize};
+
+const IMPERSONATIONS_KEY: &str = "audit:impersonations";
+const IMPERSONATIONS_LIMIT: isize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImpersonationRecord {
+    pub admin_id: String,
+    pub customer_id: String,
+    pub reason: String, // e.g. the support ticket
+    pub issued_at: String,
+    pub expires_in: usize, // seconds
+}
+
+impl ImpersonationRecord {
+    pub fn new(admin_id: &str, customer_id: &str, reason: &str, expires_in: usize) -> ImpersonationRecord {
+        ImpersonationRecord {
+            admin_id: admin_id.to_string(),
+            customer_id: customer_id.to_string(),
+            reason: reason.to_string(),
+            issued_at: Utc::now().to_rfc3339(),
+            expires_in,
+        }
+    }
+}
+
+// capped like the recent webhooks list, the log line is the long term record
+pub fn record_impersonation(redis_connection: &Client, record: &ImpersonationRecord) -> Result<(), RedisError> {
+    log::warn!(
+        "impersonation of {} by {} for {}s: {}",
+        record.customer_id, record.admin_id, record.expires_in, record.reason
+    );
+
+    let stored = match serde_json::to_string(record) {
+        Ok(stored) => stored,
+        Err(_) => return Ok(()),
+    };
+
+    let mut redis_conn = redis_connection.get_connection()?;
+    redis_conn.lpush::<&str, String, i64>(IMPERSONATIONS_KEY, stored)?;
+    redis_conn.ltrim::<&str, ()>(IMPERSONATIONS_KEY, 0, IMPERSONATIONS_LIMIT - 1)
+}
+
+// newest first
+pub fn recent_impersonations(redis_connection: &Client) -> Result<Vec<ImpersonationRecord>, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let stored: Vec<String> = redis_conn.lrange(IMPERSONATIONS_KEY, 0, IMPERSONATIONS_LIMIT - 1)?;
+
+    Ok(stored
+        .iter()
+        .filter_map(|record| serde_json::from_str(record).ok())
+        .collect())
+}
@@ -0,0 +1,56 @@
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use log::trace;
+
+use crate::server::AppState;
+
+fn is_trusted(ip: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|range| range.contains(ip))
+}
+
+// X-Forwarded-For is walked from the right, each hop is only believed while the one after it is a trusted proxy,
+// so a client can't spoof its address by sending its own header
+pub fn resolve_client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpNet]) -> IpAddr {
+    let forwarded_for = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim())
+        .collect::<Vec<&str>>();
+
+    let mut client_ip = peer;
+    for hop in forwarded_for.iter().rev() {
+        if !is_trusted(&client_ip, trusted_proxies) {
+            break;
+        }
+
+        match hop.parse::<IpAddr>() {
+            Ok(hop) => client_ip = hop,
+            Err(_) => break,
+        };
+    }
+
+    client_ip
+}
+
+pub async fn client_ip_middleware(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client_ip = resolve_client_ip(request.headers(), peer.ip(), &state.trusted_proxies);
+    trace!("{} {} from {}", request.method(), request.uri().path(), client_ip);
+
+    // handlers that need the address can extract it with Extension<IpAddr>
+    request.extensions_mut().insert(client_ip);
+    next.run(request).await
+}
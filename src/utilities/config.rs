@@ -0,0 +1,504 @@
+use std::{collections::HashMap, env, net::IpAddr, str::FromStr, time::Duration};
+
+use hickory_resolver::{config::{ResolverConfig, ResolverOpts}, system_conf::read_system_conf, TokioAsyncResolver};
+use ipnet::IpNet;
+use log::error;
+use reqwest::Url;
+
+use crate::utilities::captcha::{CaptchaProvider, CaptchaSettings};
+use crate::utilities::integration_webhook::IntegrationWebhook;
+use crate::types::{
+    lemonsqueezy::{Products, VariantPlan, VariantPlanConfig},
+    stripe::StripeSettings,
+    subscription::{DefaultSubscription, PlanPrices, Slug, SubscriptionFeatures, SubscriptionFrequencyClass},
+};
+
+// collects every missing/invalid environment variable so they can be reported at once
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub issues: Vec<(String, String)>,
+}
+
+impl ConfigReport {
+    pub fn new() -> ConfigReport {
+        ConfigReport { issues: vec![] }
+    }
+
+    pub fn add_issue(&mut self, subsystem: &str, issue: String) {
+        self.issues.push((subsystem.to_string(), issue));
+    }
+
+    pub fn require(&mut self, subsystem: &str, key: &str) -> Option<String> {
+        match env::var(key) {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.add_issue(subsystem, format!("{} must be set", key));
+                None
+            }
+        }
+    }
+
+    pub fn require_parsed<T: FromStr>(&mut self, subsystem: &str, key: &str, kind: &str) -> Option<T> {
+        let val = self.require(subsystem, key)?;
+        match val.parse::<T>() {
+            Ok(val) => Some(val),
+            Err(_) => {
+                self.add_issue(subsystem, format!("{} must be a {}", key, kind));
+                None
+            }
+        }
+    }
+
+    // unset keys fall back to `default`, set ones must parse
+    pub fn optional_parsed<T: FromStr>(&mut self, subsystem: &str, key: &str, kind: &str, default: T) -> T {
+        self.optional_checked(subsystem, key, &format!("{} must be a {}", key, kind), default, |_| true)
+    }
+
+    // like optional_parsed, `valid` rejects values that parse but can't be used, e.g. a zero interval
+    pub fn optional_checked<T: FromStr>(&mut self, subsystem: &str, key: &str, issue: &str, default: T, valid: impl Fn(&T) -> bool) -> T {
+        let val = match env::var(key) {
+            Ok(val) => val,
+            Err(_) => return default,
+        };
+
+        match val.parse::<T>() {
+            Ok(val) if valid(&val) => val,
+            _ => {
+                self.add_issue(subsystem, issue.to_string());
+                default
+            }
+        }
+    }
+
+    // for the load_* helpers, their error already names the key
+    pub fn check<T>(&mut self, subsystem: &str, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(val) => Some(val),
+            Err(err) => {
+                self.add_issue(subsystem, err);
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn exit(&self) -> ! {
+        error!("Invalid configuration, fix the following environment variables:\n{}", self.render());
+        std::process::exit(1);
+    }
+
+    // issues grouped by subsystem, keeping the order subsystems were first reported
+    pub fn render(&self) -> String {
+        let mut subsystems: Vec<&String> = vec![];
+        for (subsystem, _) in self.issues.iter() {
+            if !subsystems.contains(&subsystem) {
+                subsystems.push(subsystem);
+            }
+        }
+
+        let mut lines: Vec<String> = vec![];
+        for subsystem in subsystems {
+            lines.push(format!("[{}]", subsystem));
+            for (issue_subsystem, issue) in self.issues.iter() {
+                if issue_subsystem == subsystem {
+                    lines.push(format!("  - {}", issue));
+                }
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+// rejects unknown slugs, frequencies and features, `key` names the entry in errors
+pub fn parse_plan_config(key: &str, plan: &VariantPlanConfig) -> Result<VariantPlan, String> {
+    let slug = match plan.slug.to_lowercase().as_str() {
+        "free" => Slug::FREE,
+        "pro" => Slug::PRO,
+        _ => return Err(format!("unknown slug {} for {}", plan.slug, key)),
+    };
+
+    let frequency = match plan.frequency.to_lowercase().as_str() {
+        "monthly" => SubscriptionFrequencyClass::MONTHLY,
+        "yearly" | "annually" => SubscriptionFrequencyClass::ANNUALLY,
+        _ => return Err(format!("unknown frequency {} for {}", plan.frequency, key)),
+    };
+
+    let mut features = vec![];
+    for feature in plan.features.iter() {
+        match SubscriptionFeatures::from_str(feature.to_lowercase().as_str()) {
+            Ok(feature) => features.push(feature),
+            Err(_) => return Err(format!("unknown feature {} for {}", feature, key)),
+        };
+    }
+
+    if features.is_empty() {
+        features = slug.features();
+    }
+
+    Ok(VariantPlan { slug, frequency, features })
+}
+
+// parses LEMONSQUEEZY_VARIANT_MAP
+pub fn parse_variant_map(raw: &str) -> Result<HashMap<i64, VariantPlan>, String> {
+    let raw_map: HashMap<String, VariantPlanConfig> = match serde_json::from_str(raw) {
+        Ok(raw_map) => raw_map,
+        Err(err) => return Err(format!("invalid json: {}", err)),
+    };
+
+    let mut variants = HashMap::new();
+    for (variant_id, plan) in raw_map.into_iter() {
+        let parsed_variant_id = match variant_id.parse::<i64>() {
+            Ok(variant_id) => variant_id,
+            Err(_) => return Err(format!("variant id {} must be a number", variant_id)),
+        };
+
+        let plan = parse_plan_config(&format!("variant {}", variant_id), &plan)?;
+        variants.insert(parsed_variant_id, plan);
+    }
+
+    Ok(variants)
+}
+
+// parses STRIPE_PRICE_MAP, same entries as LEMONSQUEEZY_VARIANT_MAP keyed by stripe price id
+pub fn parse_stripe_price_map(raw: &str) -> Result<HashMap<String, VariantPlan>, String> {
+    let raw_map: HashMap<String, VariantPlanConfig> = match serde_json::from_str(raw) {
+        Ok(raw_map) => raw_map,
+        Err(err) => return Err(format!("invalid json: {}", err)),
+    };
+
+    let mut prices = HashMap::new();
+    for (price_id, plan) in raw_map.into_iter() {
+        let plan = parse_plan_config(&format!("price {}", price_id), &plan)?;
+        prices.insert(price_id, plan);
+    }
+
+    Ok(prices)
+}
+
+// signup captcha, enabled by setting CAPTCHA_SECRET
+pub fn load_captcha_settings() -> Result<Option<CaptchaSettings>, String> {
+    let secret = match env::var("CAPTCHA_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret,
+        _ => return Ok(None),
+    };
+
+    let provider = match env::var("CAPTCHA_PROVIDER") {
+        Ok(provider) => match provider.parse::<CaptchaProvider>() {
+            Ok(provider) => provider,
+            Err(_) => return Err(String::from("CAPTCHA_PROVIDER must be hcaptcha or turnstile")),
+        },
+        Err(_) => CaptchaProvider::HCaptcha,
+    };
+
+    // lets a self hosted or mock siteverify stand in for the provider's
+    let verify_url = match env::var("CAPTCHA_VERIFY_URL") {
+        Ok(url) => match Url::parse(&url) {
+            Ok(_) => url,
+            Err(_) => return Err(String::from("CAPTCHA_VERIFY_URL must be a url")),
+        },
+        Err(_) => String::from(provider.siteverify_url()),
+    };
+
+    Ok(Some(CaptchaSettings { provider, secret, verify_url }))
+}
+
+// CHECK_EMAIL_MX, the system resolver when it can be read, a single short attempt per lookup
+pub fn load_email_mx_resolver() -> Result<Option<TokioAsyncResolver>, String> {
+    match env::var("CHECK_EMAIL_MX") {
+        Ok(val) => match val.parse::<bool>() {
+            Ok(true) => (),
+            Ok(false) => return Ok(None),
+            Err(_) => return Err(String::from("CHECK_EMAIL_MX must be a boolean")),
+        },
+        Err(_) => return Ok(None),
+    };
+
+    let (config, mut options) = read_system_conf().unwrap_or_else(|_| (ResolverConfig::default(), ResolverOpts::default()));
+    options.timeout = Duration::from_secs(2);
+    options.attempts = 1;
+
+    Ok(Some(TokioAsyncResolver::tokio(config, options)))
+}
+
+// outbound events for integrators, enabled by setting INTEGRATION_WEBHOOK_URL
+pub fn load_integration_webhook() -> Result<Option<IntegrationWebhook>, String> {
+    let url = match env::var("INTEGRATION_WEBHOOK_URL") {
+        Ok(url) if !url.trim().is_empty() => url.trim().to_string(),
+        _ => return Ok(None),
+    };
+
+    match Url::parse(&url) {
+        Ok(parsed) if parsed.scheme() == "https" || parsed.scheme() == "http" => (),
+        _ => return Err(String::from("INTEGRATION_WEBHOOK_URL must be an http(s) url")),
+    };
+
+    let secret = match env::var("INTEGRATION_WEBHOOK_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret,
+        _ => return Err(String::from("INTEGRATION_WEBHOOK_SECRET must be set when INTEGRATION_WEBHOOK_URL is")),
+    };
+
+    Ok(Some(IntegrationWebhook { url, secret }))
+}
+
+// stripe is optional, it's enabled by setting STRIPE_WEBHOOK_SECRET
+pub fn load_stripe_settings() -> Result<Option<StripeSettings>, String> {
+    let webhook_secret = match env::var("STRIPE_WEBHOOK_SECRET") {
+        Ok(webhook_secret) if !webhook_secret.is_empty() => webhook_secret,
+        _ => return Ok(None),
+    };
+
+    let prices = match env::var("STRIPE_PRICE_MAP") {
+        Ok(raw) => parse_stripe_price_map(&raw)?,
+        Err(_) => return Err(String::from("STRIPE_PRICE_MAP must be set when STRIPE_WEBHOOK_SECRET is")),
+    };
+
+    let signature_tolerance = match env::var("STRIPE_SIGNATURE_TOLERANCE_SECS") {
+        Ok(tolerance) => match tolerance.parse::<i64>() {
+            Ok(tolerance) if tolerance > 0 => tolerance,
+            _ => return Err(String::from("STRIPE_SIGNATURE_TOLERANCE_SECS must be a positive number")),
+        },
+        Err(_) => 300,
+    };
+
+    Ok(Some(StripeSettings { webhook_secret, prices, signature_tolerance }))
+}
+
+// legacy single pro product configuration, used when no variant map is configured
+pub fn legacy_variant_map(pro_monthly_variant_id: i64, pro_annually_variant_id: i64) -> HashMap<i64, VariantPlan> {
+    let mut variants = HashMap::new();
+    variants.insert(pro_monthly_variant_id, VariantPlan {
+        slug: Slug::PRO,
+        frequency: SubscriptionFrequencyClass::MONTHLY,
+        features: Slug::PRO.features(),
+    });
+    variants.insert(pro_annually_variant_id, VariantPlan {
+        slug: Slug::PRO,
+        frequency: SubscriptionFrequencyClass::ANNUALLY,
+        features: Slug::PRO.features(),
+    });
+
+    variants
+}
+
+pub fn load_products() -> Result<Products, String> {
+    if let Ok(raw) = env::var("LEMONSQUEEZY_VARIANT_MAP") {
+        let variants = parse_variant_map(&raw)?;
+        return Ok(Products { variants });
+    }
+
+    let mut variant_ids = vec![];
+    for key in ["PRO_MONTHLY_VARIANT_ID", "PRO_ANNUALLY_VARIANT_ID"] {
+        match env::var(key).map(|id| id.parse::<i64>()) {
+            Ok(Ok(id)) => variant_ids.push(id),
+            _ => return Err(format!("{} must be a number when LEMONSQUEEZY_VARIANT_MAP isn't set", key)),
+        };
+    }
+
+    Ok(Products {
+        variants: legacy_variant_map(variant_ids[0], variant_ids[1]),
+    })
+}
+
+// parses PLAN_PRICES, keyed by subscription slug
+pub fn parse_plan_prices(raw: &str) -> Result<HashMap<String, PlanPrices>, String> {
+    let raw_map: HashMap<String, PlanPrices> = match serde_json::from_str(raw) {
+        Ok(raw_map) => raw_map,
+        Err(err) => return Err(format!("invalid json: {}", err)),
+    };
+
+    let mut prices = HashMap::new();
+    for (slug, plan_prices) in raw_map.into_iter() {
+        let slug = slug.to_lowercase();
+        if slug != Slug::FREE.to_string() && slug != Slug::PRO.to_string() {
+            return Err(format!("unknown slug {}", slug));
+        }
+
+        if plan_prices.monthly < 0 || plan_prices.annually < 0 {
+            return Err(format!("prices for {} must be positive", slug));
+        }
+
+        prices.insert(slug, plan_prices);
+    }
+
+    Ok(prices)
+}
+
+pub fn load_plan_prices() -> Result<HashMap<String, PlanPrices>, String> {
+    match env::var("PLAN_PRICES") {
+        Ok(raw) => parse_plan_prices(&raw),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+// DEFAULT_SUBSCRIPTION_SLUG=pro starts every new customer on a DEFAULT_SUBSCRIPTION_TRIAL_DAYS trial
+pub fn load_default_subscription() -> Result<DefaultSubscription, String> {
+    let slug = match env::var("DEFAULT_SUBSCRIPTION_SLUG") {
+        Ok(slug) => match slug.trim().to_lowercase().as_str() {
+            "free" => Slug::FREE,
+            "pro" => Slug::PRO,
+            _ => return Err(format!("DEFAULT_SUBSCRIPTION_SLUG {} is not a known slug", slug)),
+        },
+        Err(_) => Slug::FREE,
+    };
+
+    let frequency = match env::var("DEFAULT_SUBSCRIPTION_FREQUENCY") {
+        Ok(frequency) => match frequency.trim().to_lowercase().as_str() {
+            "monthly" => SubscriptionFrequencyClass::MONTHLY,
+            "yearly" | "annually" => SubscriptionFrequencyClass::ANNUALLY,
+            _ => return Err(format!("DEFAULT_SUBSCRIPTION_FREQUENCY {} is not a known frequency", frequency)),
+        },
+        Err(_) => SubscriptionFrequencyClass::MONTHLY,
+    };
+
+    let trial_days = match env::var("DEFAULT_SUBSCRIPTION_TRIAL_DAYS") {
+        Ok(days) => match days.parse::<i64>() {
+            Ok(days) if days > 0 => days,
+            _ => return Err(String::from("DEFAULT_SUBSCRIPTION_TRIAL_DAYS must be a positive number")),
+        },
+        Err(_) => 14,
+    };
+
+    Ok(DefaultSubscription { slug, frequency, trial_days })
+}
+
+// comma separated cidr ranges, a bare address is a single host range, e.g. 10.0.0.0/8,fdaa::/16,172.16.0.1
+pub fn parse_trusted_proxies(raw: &str) -> Result<Vec<IpNet>, String> {
+    let mut trusted_proxies = vec![];
+    for range in raw.split(',').map(|range| range.trim()).filter(|range| !range.is_empty()) {
+        let range = match range.parse::<IpNet>() {
+            Ok(range) => range,
+            Err(_) => match range.parse::<IpAddr>() {
+                Ok(ip) => IpNet::from(ip),
+                Err(_) => return Err(format!("TRUSTED_PROXIES {} is not a valid cidr range", range)),
+            },
+        };
+
+        trusted_proxies.push(range);
+    }
+
+    Ok(trusted_proxies)
+}
+
+pub fn load_trusted_proxies() -> Result<Vec<IpNet>, String> {
+    match env::var("TRUSTED_PROXIES") {
+        Ok(raw) => parse_trusted_proxies(&raw),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+// comma separated client=uri pairs, e.g. web=https://app.example.com/oauth/google,mobile=com.example.app:/oauth/google
+pub fn parse_oauth_redirect_uris(raw: &str) -> Result<HashMap<String, String>, String> {
+    let mut redirect_uris = HashMap::new();
+    for entry in raw.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let (client, uri) = match entry.split_once('=') {
+            Some((client, uri)) => (client.trim().to_lowercase(), uri.trim()),
+            None => return Err(format!("GOOGLE_OAUTH_REDIRECT_URIS {} must look like client=uri", entry)),
+        };
+
+        if client.is_empty() || !client.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("GOOGLE_OAUTH_REDIRECT_URIS {} is not a valid client name", client));
+        }
+
+        // fragments never reach the server and google rejects them anyway
+        match Url::parse(uri) {
+            Ok(url) if url.fragment().is_none() => (),
+            _ => return Err(format!("GOOGLE_OAUTH_REDIRECT_URIS {} is not a valid redirect uri", uri)),
+        };
+
+        if redirect_uris.insert(client.clone(), uri.to_string()).is_some() {
+            return Err(format!("GOOGLE_OAUTH_REDIRECT_URIS client {} is listed twice", client));
+        }
+    }
+
+    Ok(redirect_uris)
+}
+
+pub fn load_oauth_redirect_uris() -> Result<HashMap<String, String>, String> {
+    match env::var("GOOGLE_OAUTH_REDIRECT_URIS") {
+        Ok(raw) => parse_oauth_redirect_uris(&raw),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_missing_key_grouped_by_subsystem() {
+        let mut report = ConfigReport::new();
+        report.require("Mongo", "CONFIG_REPORT_TEST_MISSING_MONGO_URI");
+        report.require("Redis", "CONFIG_REPORT_TEST_MISSING_REDIS_URI");
+        report.require("Mongo", "CONFIG_REPORT_TEST_MISSING_MONGO_DB");
+        report.add_issue("Redis", String::from("REDIS_POOL must be a number"));
+
+        assert!(!report.is_empty());
+        assert_eq!(report.issues.len(), 4);
+        assert_eq!(
+            report.render(),
+            [
+                "[Mongo]",
+                "  - CONFIG_REPORT_TEST_MISSING_MONGO_URI must be set",
+                "  - CONFIG_REPORT_TEST_MISSING_MONGO_DB must be set",
+                "[Redis]",
+                "  - CONFIG_REPORT_TEST_MISSING_REDIS_URI must be set",
+                "  - REDIS_POOL must be a number",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn empty_report_renders_nothing() {
+        let report = ConfigReport::new();
+        assert!(report.is_empty());
+        assert_eq!(report.render(), "");
+    }
+
+    #[test]
+    fn unset_optional_keys_use_the_default() {
+        let mut report = ConfigReport::new();
+        assert_eq!(report.optional_parsed::<u64>("Tokens", "CONFIG_REPORT_TEST_UNSET_TTL", "number", 900), 900);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn invalid_optional_keys_are_reported() {
+        env::set_var("CONFIG_REPORT_TEST_BAD_FLAG", "maybe");
+        env::set_var("CONFIG_REPORT_TEST_ZERO_INTERVAL", "0");
+
+        let mut report = ConfigReport::new();
+        assert!(!report.optional_parsed::<bool>("Server", "CONFIG_REPORT_TEST_BAD_FLAG", "boolean", false));
+        let interval = report.optional_checked::<u64>(
+            "Jobs",
+            "CONFIG_REPORT_TEST_ZERO_INTERVAL",
+            "CONFIG_REPORT_TEST_ZERO_INTERVAL must be a positive number",
+            3600,
+            |secs| *secs > 0,
+        );
+
+        assert_eq!(interval, 3600);
+        assert_eq!(
+            report.render(),
+            [
+                "[Server]",
+                "  - CONFIG_REPORT_TEST_BAD_FLAG must be a boolean",
+                "[Jobs]",
+                "  - CONFIG_REPORT_TEST_ZERO_INTERVAL must be a positive number",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn failed_loaders_are_reported() {
+        let mut report = ConfigReport::new();
+        assert_eq!(report.check("Stripe", Ok::<u8, String>(1)), Some(1));
+        assert_eq!(report.check::<u8>("Stripe", Err(String::from("STRIPE_SECRET_KEY must be set"))), None);
+        assert_eq!(report.issues, vec![(String::from("Stripe"), String::from("STRIPE_SECRET_KEY must be set"))]);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{http::StatusCode, Json};
+use chrono::Utc;
+use hickory_resolver::{error::ResolveErrorKind, proto::op::ResponseCode, TokioAsyncResolver};
+use redis::{Commands, RedisError};
+use serde_json::json;
+
+use crate::{server::AppState, types::customer::GenericResponse};
+
+use super::api_messages::{APIMessages, EmailMessages, RedisMessages};
+
+// tokens live under their own namespace so the check and verify endpoints can't read arbitrary keys
+pub fn email_verification_key(token: &str) -> String {
+    format!("email_verification:{}", token)
+}
+
+// set alongside each verification token so owners can see which addresses are waiting on a link
+pub fn pending_verification_key(email: &str) -> String {
+    format!("email_verification_pending:{}", email)
+}
+
+pub fn email_send_budget_key(customer_id: &str) -> String {
+    format!("email_budget:{}:{}", customer_id, Utc::now().format("%Y-%m-%d"))
+}
+
+// every email we send on behalf of a customer counts against a daily budget, protects the Brevo quota
+pub fn consume_email_send_budget(state: &Arc<AppState>, customer_id: &str) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let mut redis_conn = match state.redis_connection.get_connection() {
+        Ok(redis_conn) => redis_conn,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::FailedToConnect).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    let key = email_send_budget_key(customer_id);
+    let sent: i64 = match redis_conn.incr(&key, 1) {
+        Ok(sent) => sent,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::ErrorSettingKey).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    if sent == 1 {
+        let _: Result<bool, RedisError> = redis_conn.expire(&key, 86400);
+    }
+
+    let budget = state.email_provider_settings.daily_send_budget;
+    if sent > budget {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(GenericResponse {
+                message: APIMessages::Email(EmailMessages::DailySendBudgetExceeded).to_string(),
+                data: json!({
+                    "daily_send_budget": budget,
+                }),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+const MX_LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+const MX_CACHE_TTL: u64 = 86400; // seconds, domains rarely lose their mail servers
+const MX_NEGATIVE_CACHE_TTL: u64 = 3600;
+
+pub fn email_mx_key(domain: &str) -> String {
+    format!("email_mx:{}", domain)
+}
+
+// None when dns couldn't give an answer, a domain without MX still takes mail at its address records
+async fn domain_accepts_mail(resolver: &TokioAsyncResolver, domain: &str) -> Option<bool> {
+    let fqdn = format!("{}.", domain);
+    let lookup = match tokio::time::timeout(MX_LOOKUP_TIMEOUT, resolver.mx_lookup(fqdn.as_str())).await {
+        Ok(lookup) => lookup,
+        Err(_) => return None,
+    };
+
+    match lookup {
+        // a lone "." exchange is a null MX, the domain explicitly takes no mail
+        Ok(lookup) => Some(lookup.iter().any(|mx| !mx.exchange().is_root())),
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NXDomain, .. } => Some(false),
+            ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NoError, .. } => {
+                match tokio::time::timeout(MX_LOOKUP_TIMEOUT, resolver.lookup_ip(fqdn.as_str())).await {
+                    Ok(Ok(addresses)) => Some(addresses.iter().next().is_some()),
+                    Ok(Err(err)) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Some(false),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+    }
+}
+
+// CHECK_EMAIL_MX, fails open so a dns hiccup never blocks a signup
+pub async fn check_email_domain(state: &Arc<AppState>, email: &str) -> Result<(), (StatusCode, Json<GenericResponse>)> {
+    let resolver = match &state.email_mx_resolver {
+        Some(resolver) => resolver,
+        None => return Ok(()),
+    };
+
+    let domain = match email.rsplit_once('@') {
+        Some((_, domain)) => domain.to_lowercase(),
+        None => return Ok(()),
+    };
+
+    let key = email_mx_key(&domain);
+    let cached: Option<String> = state
+        .redis_connection
+        .get_connection()
+        .and_then(|mut redis_conn| redis_conn.get(&key))
+        .unwrap_or(None);
+
+    let accepts_mail = match cached {
+        Some(cached) => cached == "1",
+        None => match domain_accepts_mail(resolver, &domain).await {
+            Some(accepts_mail) => {
+                let (value, ttl) = match accepts_mail {
+                    true => ("1", MX_CACHE_TTL),
+                    false => ("0", MX_NEGATIVE_CACHE_TTL),
+                };
+
+                let stored: Result<(), RedisError> = state
+                    .redis_connection
+                    .get_connection()
+                    .and_then(|mut redis_conn| redis_conn.set_ex(&key, value, ttl));
+                if let Err(err) = stored {
+                    log::error!("error caching mx lookup for {}: {}", domain, err);
+                }
+
+                accepts_mail
+            }
+            None => {
+                log::warn!("mx lookup for {} failed, letting the address through", domain);
+                return Ok(());
+            }
+        },
+    };
+
+    if accepts_mail {
+        return Ok(());
+    }
+
+    Err((
+        StatusCode::BAD_REQUEST,
+        Json(GenericResponse {
+            message: APIMessages::Email(EmailMessages::NoMailServers).to_string(),
+            data: json!({"domain": domain}),
+            exit_code: 1,
+        }),
+    ))
+}
@@ -0,0 +1,109 @@
+use std::{str::FromStr, sync::Arc};
+
+use chrono::Utc;
+use log::error;
+use redis::Client;
+use serde::Serialize;
+
+pub const CUSTOMER_CREATED_EVENT: &str = "customer.created";
+pub const DEFAULT_EVENTS_STREAM: &str = "events";
+
+const STREAM_MAX_LEN: usize = 100_000; // approximate, old entries are trimmed by redis
+
+// EVENTS_BROKER, where domain events go, unset keeps them off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventsBroker {
+    None,
+    RedisStreams,
+}
+
+impl FromStr for EventsBroker {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<EventsBroker, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "" | "none" => Ok(EventsBroker::None),
+            "redis" | "redis_streams" => Ok(EventsBroker::RedisStreams),
+            _ => Err(()),
+        }
+    }
+}
+
+// one implementation per broker, publish is blocking and always called off the request path
+pub trait EventPublisher: Send + Sync {
+    fn publish(&self, event: &str, payload: &str) -> Result<(), String>;
+}
+
+pub struct NoopPublisher;
+
+impl EventPublisher for NoopPublisher {
+    fn publish(&self, _event: &str, _payload: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// XADD <stream> MAXLEN ~ STREAM_MAX_LEN * event <name> payload <json>
+pub struct RedisStreamPublisher {
+    pub client: Client,
+    pub stream: String,
+}
+
+impl EventPublisher for RedisStreamPublisher {
+    fn publish(&self, event: &str, payload: &str) -> Result<(), String> {
+        let mut redis_conn = self.client.get_connection().map_err(|err| err.to_string())?;
+        redis::cmd("XADD")
+            .arg(&self.stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(STREAM_MAX_LEN)
+            .arg("*")
+            .arg("event")
+            .arg(event)
+            .arg("payload")
+            .arg(payload)
+            .query::<String>(&mut redis_conn)
+            .map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomerCreatedEvent {
+    pub event: String,
+    pub customer_id: String,
+    pub class: String,
+    pub subscription_slug: String,
+    pub created_at: String,
+}
+
+impl CustomerCreatedEvent {
+    pub fn new(customer_id: &str, class: &str, subscription_slug: &str) -> CustomerCreatedEvent {
+        CustomerCreatedEvent {
+            event: String::from(CUSTOMER_CREATED_EVENT),
+            customer_id: customer_id.to_string(),
+            class: class.to_string(),
+            subscription_slug: subscription_slug.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+// fire and forget, a broker outage never fails the signup that triggered the event
+pub fn emit_event<T: Serialize>(publisher: &Arc<dyn EventPublisher>, event: &str, payload: &T) {
+    let payload = match serde_json::to_string(payload) {
+        Ok(payload) => payload,
+        Err(err) => {
+            error!("error serializing {} event: {}", event, err);
+            return;
+        }
+    };
+
+    let publisher = Arc::clone(publisher);
+    let event = event.to_string();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = publisher.publish(&event, &payload) {
+            error!("error publishing {} event: {}", event, err);
+        }
+    });
+}
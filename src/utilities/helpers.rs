@@ -2,36 +2,144 @@ use crate::types::{customer::{GenericResponse, CustomerType}, subscription::Subs
 use axum::{
     extract::rejection::JsonRejection,
     http::{StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
+use std::any::Any;
 use mongodb::bson::{to_document, Document};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use regex::Regex;
-use serde_json::json;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use unicode_security::{is_potential_mixed_script_confusable_char, MixedScript};
 
 use super::api_messages::{APIMessages, CustomerMessages, EmailMessages, InputMessages};
 
-pub fn payload_analyzer<T>(
+// used with #[serde(deserialize_with)] on incoming requests so handlers get normalized input
+pub fn deserialize_trimmed<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    Ok(value.trim().to_string())
+}
+
+pub fn deserialize_email<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    Ok(value.trim().to_lowercase())
+}
+
+// serde names the offending field in its message, e.g. "missing field `password_confirmation` at line 1 column 80"
+pub fn rejected_field(details: &str) -> Option<(InputMessages, String)> {
+    let field_regex = Regex::new(r"(missing|unknown) field `([^`]+)`").unwrap();
+    let captures = field_regex.captures(details)?;
+    let message = match &captures[1] {
+        "missing" => InputMessages::MissingField,
+        _ => InputMessages::UnknownField,
+    };
+
+    Some((message, captures[2].to_string()))
+}
+
+pub fn payload_analyzer<T: Serialize>(
     payload_result: Result<Json<T>, JsonRejection>,
 ) -> Result<Json<T>, (StatusCode, Json<GenericResponse>)> {
     let payload = match payload_result {
         Ok(payload) => payload,
         Err(err) => {
-            let message = format!("invalid.payload: {}", err);
+            let details = err.body_text();
+
+            // all of these are client mistakes, never a 500
+            let (status_code, message, field) = match err {
+                JsonRejection::MissingJsonContentType(_) => (
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    APIMessages::Input(InputMessages::UnsupportedMediaType),
+                    None,
+                ),
+                JsonRejection::JsonSyntaxError(_) => (
+                    StatusCode::BAD_REQUEST,
+                    APIMessages::Input(InputMessages::MalformedJson),
+                    None,
+                ),
+                JsonRejection::JsonDataError(_) => match rejected_field(&details) {
+                    Some((message, field)) => (StatusCode::BAD_REQUEST, APIMessages::Input(message), Some(field)),
+                    None => (StatusCode::BAD_REQUEST, APIMessages::Input(InputMessages::InvalidPayload), None),
+                },
+                _ => (
+                    StatusCode::BAD_REQUEST,
+                    APIMessages::Input(InputMessages::InvalidPayload),
+                    None,
+                ),
+            };
+
             let json = Json(GenericResponse {
-                message,
-                data: json!({}),
+                message: message.to_string(),
+                data: json!({
+                    "field": field,
+                    "details": details,
+                }),
                 exit_code: 1,
             });
 
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, json));
+            return Err((status_code, json));
         }
     };
 
+    // strings end up in mongo filters and Brevo payloads, so control characters are refused for every request type
+    if let Ok(value) = serde_json::to_value(&payload.0) {
+        if let Some(field) = find_disallowed_characters(&value, "") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Input(InputMessages::DisallowedCharacters).to_string(),
+                    data: json!({
+                        "field": field,
+                    }),
+                    exit_code: 1,
+                }),
+            ));
+        }
+    }
+
     Ok(payload)
 }
 
+// null bytes and control characters, line breaks and tabs are still allowed
+pub fn has_disallowed_characters(value: &str) -> bool {
+    value.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+}
+
+pub const DEFAULT_NAME_MAX_LENGTH: usize = 25;
+
+// NAME_CONFUSABLE_CHECK, e.g. a cyrillic "а" inside an otherwise latin name, single script names always pass
+pub fn has_confusable_script_mix(name: &str) -> bool {
+    !name.is_single_script() && name.chars().any(is_potential_mixed_script_confusable_char)
+}
+
+// returns the path of the first offending key or string, e.g. "metadata.crm_id"
+pub fn find_disallowed_characters(value: &Value, path: &str) -> Option<String> {
+    match value {
+        Value::String(string) if has_disallowed_characters(string) => Some(path.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .find_map(|(index, value)| find_disallowed_characters(value, &format!("{}[{}]", path, index))),
+        Value::Object(map) => map.iter().find_map(|(key, value)| {
+            let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            if has_disallowed_characters(key) {
+                return Some(key_path);
+            }
+
+            find_disallowed_characters(value, &key_path)
+        }),
+        _ => None,
+    }
+}
+
 pub async fn fallback(uri: Uri) -> (StatusCode, Json<GenericResponse>) {
     let message = format!("invalid.endpoint.{}", uri.path());
     (
@@ -44,6 +152,44 @@ pub async fn fallback(uri: Uri) -> (StatusCode, Json<GenericResponse>) {
     )
 }
 
+// panics inside handlers end up here instead of dropping the connection,
+// the incident id is returned to the client so it can be matched with the logs
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let details = if let Some(details) = err.downcast_ref::<String>() {
+        details.clone()
+    } else if let Some(details) = err.downcast_ref::<&str>() {
+        details.to_string()
+    } else {
+        String::from("unknown panic")
+    };
+
+    let incident_id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+
+    log::error!("handler panicked [incident_id: {}]: {}", incident_id, details);
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GenericResponse {
+            message: APIMessages::InternalServerError.to_string(),
+            data: json!({
+                "incident_id": incident_id,
+            }),
+            exit_code: 1,
+        }),
+    ).into_response()
+}
+
+pub const CUSTOMER_ID_LENGTH: usize = 30;
+
+// customer ids are always random_string(CUSTOMER_ID_LENGTH), anything else never reaches a mongo filter
+pub fn valid_customer_id(customer_id: &str) -> bool {
+    customer_id.len() == CUSTOMER_ID_LENGTH && customer_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
 pub async fn random_string(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
@@ -106,28 +252,126 @@ pub async fn valid_password(password: &String) -> Result<bool, (StatusCode, Json
     Ok(true)
 }
 
-pub async fn parse_class(raw_class: &String) -> Result<CustomerType, (StatusCode, Json<GenericResponse>)> {
-    let class: CustomerType;
-    if raw_class.to_lowercase() == "personal" {
-        class = CustomerType::PERSONAL;
-    } else if raw_class.to_lowercase() == "manager" {
-        class = CustomerType::MANAGER;
-    } else {
+// the password can't be any of the customer's emails, nor contain their local-part
+pub async fn password_differs_from_emails(password: &str, emails: &[String]) -> Result<bool, (StatusCode, Json<GenericResponse>)> {
+    let password = password.to_lowercase();
+    for email in emails.iter() {
+        let email = email.to_lowercase();
+        let local_part = email.split('@').next().unwrap_or("");
+
+        if password == email || (local_part.len() >= 3 && password.contains(local_part)) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(GenericResponse {
+                    message: APIMessages::Email(EmailMessages::EmailAndPasswordMustBeDifferent).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ));
+        }
+    }
+
+    Ok(true)
+}
+
+// "example.com" matches only that domain, "*.example.com" matches its subdomains too
+pub fn domain_matches(domain: &str, rule: &str) -> bool {
+    match rule.strip_prefix("*.") {
+        Some(parent) => domain == parent || domain.ends_with(&format!(".{}", parent)),
+        None => domain == rule,
+    }
+}
+
+// metadata keys are used as mongo field names, so only a safe charset is allowed
+pub async fn valid_metadata_entry(key: &str, value: &str) -> Result<bool, (StatusCode, Json<GenericResponse>)> {
+    let re = Regex::new(r"^[a-zA-Z0-9_-]{1,40}$").unwrap();
+    if !re.is_match(key) {
         return Err((
             StatusCode::BAD_REQUEST,
             Json(GenericResponse {
-                message: APIMessages::Customer(CustomerMessages::InvalidType).to_string(),
+                message: APIMessages::Input(InputMessages::InvalidMetadataKey).to_string(),
                 data: json!({}),
                 exit_code: 1,
             }),
         ));
     }
 
+    if value.len() > 500 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidMetadataValueLength).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    Ok(true)
+}
+
+// classes a customer can pick for themselves, developer accounts aren't self serve
+pub fn signup_class(raw_class: &str) -> Option<CustomerType> {
+    match raw_class.trim().to_lowercase().as_str() {
+        "personal" => Some(CustomerType::PERSONAL),
+        "manager" => Some(CustomerType::MANAGER),
+        _ => None,
+    }
+}
+
+pub async fn parse_class(raw_class: &String) -> Result<CustomerType, (StatusCode, Json<GenericResponse>)> {
+    let class = match signup_class(raw_class) {
+        Some(class) => class,
+        None => return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Customer(CustomerMessages::InvalidType).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+    };
+
     return Ok(class)
 }
 
+pub const MAX_PICTURE_URL_LENGTH: usize = 2048;
+
+// pictures are rendered by clients, so only absolute http(s) urls are stored
+pub fn valid_picture_url(picture: &str) -> bool {
+    if picture.len() > MAX_PICTURE_URL_LENGTH {
+        return false;
+    }
+
+    match reqwest::Url::parse(picture) {
+        Ok(url) => (url.scheme() == "https" || url.scheme() == "http") && url.host_str().is_some(),
+        Err(_) => false,
+    }
+}
+
+// quote a csv field only when needed, doubling inner quotes
+pub fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') || value.contains('\r') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+
+    value.to_string()
+}
+
+// every webhook appends a log, so the customer document would otherwise grow toward mongo's 16MB limit
+pub const MAX_HISTORY_LOGS: usize = 200;
+
+// logs are stored oldest first, only the most recent MAX_HISTORY_LOGS are kept
+pub fn trim_history_logs(history_logs: &mut Vec<SubscriptionHistoryLog>) {
+    if history_logs.len() > MAX_HISTORY_LOGS {
+        let overflow = history_logs.len() - MAX_HISTORY_LOGS;
+        history_logs.drain(..overflow);
+    }
+}
+
 pub async fn add_subscription_history_log_and_to_bson(mut history_logs: Vec<SubscriptionHistoryLog>, log: SubscriptionHistoryLog) -> Vec<Document> {
     history_logs.push(log);
+    trim_history_logs(&mut history_logs);
     let bson_history_logs: Vec<Document> = history_logs.iter()
     .map(|log| {
         match to_document(log) {
@@ -140,4 +384,84 @@ pub async fn add_subscription_history_log_and_to_bson(mut history_logs: Vec<Subs
     .collect();
 
     return bson_history_logs;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(count: usize) -> Vec<SubscriptionHistoryLog> {
+        (0..count)
+            .map(|index| SubscriptionHistoryLog {
+                event: format!("event_{}", index),
+                date: String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trimming_keeps_the_most_recent_logs() {
+        let mut history_logs = logs(MAX_HISTORY_LOGS + 50);
+        trim_history_logs(&mut history_logs);
+
+        assert_eq!(history_logs.len(), MAX_HISTORY_LOGS);
+        assert_eq!(history_logs.first().unwrap().event, "event_50");
+        assert_eq!(history_logs.last().unwrap().event, format!("event_{}", MAX_HISTORY_LOGS + 49));
+    }
+
+    #[test]
+    fn trimming_leaves_short_histories_alone() {
+        let mut history_logs = logs(3);
+        trim_history_logs(&mut history_logs);
+        assert_eq!(history_logs.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn appending_past_the_cap_drops_the_oldest_entry() {
+        let history_logs = add_subscription_history_log_and_to_bson(logs(MAX_HISTORY_LOGS + 10), SubscriptionHistoryLog {
+            event: String::from("subscription_created"),
+            date: String::new(),
+        }).await;
+
+        assert_eq!(history_logs.len(), MAX_HISTORY_LOGS);
+        assert_eq!(history_logs.first().unwrap().get_str("event"), Ok("event_11"));
+        assert_eq!(history_logs.last().unwrap().get_str("event"), Ok("subscription_created"));
+    }
+
+    #[test]
+    fn ascii_names_are_not_confusable() {
+        assert!(!has_confusable_script_mix("Ada Lovelace"));
+        assert!(!has_confusable_script_mix("R2-D2"));
+    }
+
+    #[test]
+    fn single_script_names_are_not_confusable() {
+        assert!(!has_confusable_script_mix("José Núñez"));
+        assert!(!has_confusable_script_mix("Анна Иванова"));
+    }
+
+    #[test]
+    fn a_cyrillic_letter_inside_a_latin_name_is_flagged() {
+        assert!(has_confusable_script_mix("P\u{0430}yPal"));
+        assert!(has_confusable_script_mix("\u{0410}dmin"));
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_returns_a_json_500() {
+        use axum::{body::{to_bytes, Body}, http::Request, routing::get, Router};
+        use tower::ServiceExt;
+        use tower_http::catch_panic::CatchPanicLayer;
+
+        let app = Router::new()
+            .route("/panic", get(|| async { panic!("deliberate") as StatusCode }))
+            .layer(CatchPanicLayer::custom(handle_panic));
+
+        let response = app.oneshot(Request::builder().uri("/panic").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["message"], "generic.internal_server_error");
+        assert_eq!(body["exit_code"], 1);
+        assert_eq!(body["data"]["incident_id"].as_str().unwrap().len(), 16);
+    }
+}
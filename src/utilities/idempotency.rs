@@ -0,0 +1,393 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use redis::{Client, Commands, RedisError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{controllers::identity::get_user_session_from_req, server::AppState, types::customer::GenericResponse};
+
+use super::api_messages::{APIMessages, InputMessages, RedisMessages};
+
+const IN_FLIGHT_MARKER: &str = "__in_flight__";
+const IN_FLIGHT_TTL: u64 = 60; // a panicked or lost request frees its key soon, requests time out after 10s
+const IDEMPOTENCY_TTL: u64 = 86400;
+const MAX_STORED_BODY: usize = 1024 * 1024; // /api/me responses are a few hundred bytes
+
+pub type ApiResponse = (StatusCode, Json<GenericResponse>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: GenericResponse,
+    #[serde(default)]
+    pub request_hash: String, // a key replays only for the exact request that stored it
+    #[serde(default)]
+    pub headers: Vec<(String, String)>, // e.g. the session cookie, content headers are rebuilt from the body
+}
+
+impl StoredResponse {
+    pub fn into_api_response(self) -> ApiResponse {
+        (StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK), Json(self.body))
+    }
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let headers = self.headers.clone();
+        let mut response = self.into_api_response().into_response();
+        for (name, value) in headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                response.headers_mut().append(name, value);
+            }
+        }
+
+        response
+    }
+}
+
+// what a replay needs besides the body
+pub fn replayable_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| **name != CONTENT_LENGTH && **name != CONTENT_TYPE)
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+pub fn idempotency_request_hash(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+pub fn extract_idempotency_key(headers: &HeaderMap) -> Result<Option<String>, (StatusCode, Json<GenericResponse>)> {
+    let key = match headers.get("Idempotency-Key") {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 && key.chars().all(|c| c.is_ascii_graphic()) => {
+            Ok(Some(key.to_string()))
+        },
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::InvalidIdempotencyKey).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+    }
+}
+
+// keys are scoped per endpoint (and per customer when authenticated)
+pub fn idempotency_redis_key(scope: &str, key: &str) -> String {
+    format!("idempotency:{}:{}", scope, key)
+}
+
+// what a request gets when its key is already taken, the stored response or why it can't have it
+pub fn stored_claim_outcome(stored: &str, request_hash: &str) -> Result<StoredResponse, ApiResponse> {
+    if stored == IN_FLIGHT_MARKER {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::IdempotentRequestInProgress).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        ));
+    }
+
+    match serde_json::from_str::<StoredResponse>(&stored) {
+        Ok(stored) if stored.request_hash != request_hash => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(GenericResponse {
+                message: APIMessages::Input(InputMessages::IdempotencyKeyReused).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+        Ok(stored) => Ok(stored),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericResponse {
+                message: APIMessages::InternalServerError.to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
+    }
+}
+
+// claims the key for this request, or returns the stored response of a previous one with the same body
+pub fn begin_idempotent_request(
+    redis_connection: &Client,
+    redis_key: &str,
+    request_hash: &str,
+) -> Result<Option<StoredResponse>, ApiResponse> {
+    let mut redis_conn = match redis_connection.get_connection() {
+        Ok(redis_conn) => redis_conn,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GenericResponse {
+                    message: APIMessages::Redis(RedisMessages::FailedToConnect).to_string(),
+                    data: json!({}),
+                    exit_code: 1,
+                }),
+            ))
+        }
+    };
+
+    // the key can expire between SET NX and GET, the second attempt then claims it
+    for _ in 0..2 {
+        let claimed: Result<Option<String>, RedisError> = redis::cmd("SET")
+            .arg(redis_key)
+            .arg(IN_FLIGHT_MARKER)
+            .arg("NX")
+            .arg("EX")
+            .arg(IN_FLIGHT_TTL)
+            .query(&mut redis_conn);
+
+        match claimed {
+            Ok(Some(_)) => return Ok(None),
+            Ok(None) => (),
+            Err(_) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(GenericResponse {
+                        message: APIMessages::Redis(RedisMessages::ErrorSettingKey).to_string(),
+                        data: json!({}),
+                        exit_code: 1,
+                    }),
+                ))
+            }
+        };
+
+        let stored: Option<String> = match redis_conn.get(redis_key) {
+            Ok(stored) => stored,
+            Err(_) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(GenericResponse {
+                        message: APIMessages::Redis(RedisMessages::ErrorFetching).to_string(),
+                        data: json!({}),
+                        exit_code: 1,
+                    }),
+                ))
+            }
+        };
+
+        if let Some(stored) = stored {
+            return stored_claim_outcome(&stored, request_hash).map(Some);
+        }
+    }
+
+    Err((
+        StatusCode::CONFLICT,
+        Json(GenericResponse {
+            message: APIMessages::Input(InputMessages::IdempotentRequestInProgress).to_string(),
+            data: json!({}),
+            exit_code: 1,
+        }),
+    ))
+}
+
+// stores the final response, errors release the key so a corrected request can retry with it
+pub fn finish_idempotent_request(
+    redis_connection: &Client,
+    redis_key: &str,
+    request_hash: &str,
+    response: &ApiResponse,
+    headers: &HeaderMap,
+) {
+    let mut redis_conn = match redis_connection.get_connection() {
+        Ok(redis_conn) => redis_conn,
+        Err(err) => {
+            log::error!("error storing idempotent response: {}", err);
+            return;
+        }
+    };
+
+    let (status, Json(body)) = response;
+    if status.is_client_error() || status.is_server_error() {
+        let _: Result<bool, RedisError> = redis_conn.del(redis_key);
+        return;
+    }
+
+    let stored = StoredResponse {
+        status: status.as_u16(),
+        body: GenericResponse {
+            message: body.message.clone(),
+            data: body.data.clone(),
+            exit_code: body.exit_code,
+        },
+        request_hash: request_hash.to_string(),
+        headers: replayable_headers(headers),
+    };
+
+    let stored = match serde_json::to_string(&stored) {
+        Ok(stored) => stored,
+        Err(_) => return,
+    };
+
+    let result: Result<bool, RedisError> = redis_conn.set_ex(redis_key, stored, IDEMPOTENCY_TTL);
+    if let Err(err) = result {
+        log::error!("error storing idempotent response: {}", err);
+    }
+}
+
+// wraps /api/me, a mutating request with an Idempotency-Key runs once per customer, method and path
+pub async fn idempotency_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+
+    let key = match extract_idempotency_key(request.headers()) {
+        Ok(Some(key)) => key,
+        Ok(None) => return next.run(request).await,
+        Err(response) => return response.into_response(),
+    };
+
+    // unauthenticated requests are left to the handler, it answers them with a 401
+    let session_data = match get_user_session_from_req(request.headers().clone(), &state.redis_connection).await {
+        Ok(session_data) => session_data,
+        Err(_) => return next.run(request).await,
+    };
+
+    let scope = format!("me:{}:{}:{}", session_data.customer_id, request.method(), request.uri().path());
+    let redis_key = idempotency_redis_key(&scope, &key);
+
+    // the body is read up front so a reused key with a different body can be told apart
+    let (request_parts, request_body) = request.into_parts();
+    let request_bytes = match to_bytes(request_body, MAX_STORED_BODY).await {
+        Ok(request_bytes) => request_bytes,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let request_hash = idempotency_request_hash(&request_bytes);
+    let request = Request::from_parts(request_parts, Body::from(request_bytes));
+
+    match begin_idempotent_request(&state.redis_connection, &redis_key, &request_hash) {
+        Ok(Some(stored_response)) => return stored_response.into_response(),
+        Ok(None) => (),
+        Err(response) => return response.into_response(),
+    };
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, MAX_STORED_BODY).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let _: Result<bool, RedisError> = state.redis_connection.get_connection().and_then(|mut redis_conn| redis_conn.del(&redis_key));
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // only our json envelope can be replayed, anything else gives the key back
+    match serde_json::from_slice::<GenericResponse>(&bytes) {
+        Ok(generic_response) => finish_idempotent_request(
+            &state.redis_connection,
+            &redis_key,
+            &request_hash,
+            &(parts.status, Json(generic_response)),
+            &parts.headers,
+        ),
+        Err(_) => {
+            let _: Result<bool, RedisError> = state.redis_connection.get_connection().and_then(|mut redis_conn| redis_conn.del(&redis_key));
+        }
+    };
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(status: StatusCode, request_hash: &str) -> String {
+        serde_json::to_string(&StoredResponse {
+            status: status.as_u16(),
+            body: GenericResponse {
+                message: String::from("customer.created"),
+                data: json!({"id": "customer"}),
+                exit_code: 0,
+            },
+            request_hash: request_hash.to_string(),
+            headers: vec![(String::from("set-cookie"), String::from("session_token=abc; Path=/"))],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn the_same_request_gets_the_stored_response() {
+        let request_hash = idempotency_request_hash(br#"{"name":"Ada"}"#);
+        let (status, Json(body)) = stored_claim_outcome(&stored(StatusCode::CREATED, &request_hash), &request_hash)
+            .unwrap()
+            .into_api_response();
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body.message, "customer.created");
+        assert_eq!(body.data, json!({"id": "customer"}));
+    }
+
+    #[test]
+    fn a_different_body_cannot_reuse_the_key() {
+        let request_hash = idempotency_request_hash(br#"{"name":"Ada"}"#);
+        let other_hash = idempotency_request_hash(br#"{"name":"Grace"}"#);
+        let (status, Json(body)) = stored_claim_outcome(&stored(StatusCode::CREATED, &request_hash), &other_hash).unwrap_err();
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.message, APIMessages::Input(InputMessages::IdempotencyKeyReused).to_string());
+    }
+
+    #[test]
+    fn unreadable_stored_responses_are_server_errors() {
+        let (status, _) = stored_claim_outcome("not json", "hash").unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn a_duplicate_while_the_first_runs_is_a_conflict() {
+        let (status, Json(body)) = stored_claim_outcome(IN_FLIGHT_MARKER, "hash").unwrap_err();
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.message, APIMessages::Input(InputMessages::IdempotentRequestInProgress).to_string());
+    }
+
+    #[test]
+    fn a_replay_keeps_the_original_headers() {
+        let request_hash = idempotency_request_hash(br#"{"name":"Ada"}"#);
+        let response = stored_claim_outcome(&stored(StatusCode::OK, &request_hash), &request_hash)
+            .unwrap()
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("set-cookie").unwrap(), "session_token=abc; Path=/");
+    }
+
+    #[test]
+    fn content_headers_are_not_stored() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("12"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("x-request-id", HeaderValue::from_static("abc"));
+
+        assert_eq!(replayable_headers(&headers), vec![(String::from("x-request-id"), String::from("abc"))]);
+    }
+}
+
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+pub const EVENT_HEADER: &str = "X-Event-Name";
+pub const EMAIL_VERIFIED_EVENT: &str = "email.verified";
+
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+
+// loaded from INTEGRATION_WEBHOOK_URL and INTEGRATION_WEBHOOK_SECRET, None keeps the feature off
+#[derive(Debug, Clone)]
+pub struct IntegrationWebhook {
+    pub url: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmailVerifiedEvent {
+    pub event: String,
+    pub customer_id: String,
+    pub email: String,
+    pub verified_at: String,
+}
+
+impl EmailVerifiedEvent {
+    pub fn new(customer_id: &str, email: &str) -> EmailVerifiedEvent {
+        EmailVerifiedEvent {
+            event: String::from(EMAIL_VERIFIED_EVENT),
+            customer_id: customer_id.to_string(),
+            email: email.to_string(),
+            verified_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+// hex hmac sha256 of the raw body, the same scheme LemonSqueezy uses for its deliveries to us
+pub fn sign_payload(secret: &str, payload: &[u8]) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(payload);
+
+    Some(hex::encode(mac.finalize().into_bytes()))
+}
+
+// fire and forget, a slow or failing receiver never affects the request that triggered the event
+pub fn dispatch_email_verified(webhook: &Option<IntegrationWebhook>, customer_id: &str, email: &str) {
+    let webhook = match webhook {
+        Some(webhook) => webhook.clone(),
+        None => return,
+    };
+
+    let event = EmailVerifiedEvent::new(customer_id, email);
+    tokio::spawn(async move {
+        if let Err(err) = deliver(&webhook, &event.event, &event).await {
+            error!("error delivering {} webhook for {}: {}", event.event, event.customer_id, err);
+        }
+    });
+}
+
+async fn deliver<T: Serialize>(webhook: &IntegrationWebhook, event_name: &str, event: &T) -> Result<(), String> {
+    let body = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+    let signature = sign_payload(&webhook.secret, &body).ok_or_else(|| String::from("invalid signing secret"))?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECS))
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    let response = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header(EVENT_HEADER, event_name)
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("receiver answered {}", response.status()));
+    }
+
+    info!("{} webhook delivered to {}", event_name, webhook.url);
+    Ok(())
+}
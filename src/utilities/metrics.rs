@@ -0,0 +1,28 @@
+use log::info;
+use metrics::counter;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+// installs the global recorder, the handle renders /metrics in the prometheus text format
+pub fn init_metrics() -> Option<PrometheusHandle> {
+    match PrometheusBuilder::new().install_recorder() {
+        Ok(handle) => Some(handle),
+        Err(err) => {
+            log::error!("error installing metrics recorder: {}", err);
+            None
+        }
+    }
+}
+
+// only counts real tier changes, status-only updates keep the same slug
+pub fn record_subscription_transition(customer_id: &str, from: &str, to: &str) {
+    if from == to {
+        return;
+    }
+
+    info!("subscription transition for {}: {} -> {}", customer_id, from, to);
+    counter!("subscription_transition_total", "from" => from.to_string(), "to" => to.to_string()).increment(1);
+}
+
+pub fn record_webhook_duplicate(event: &str) {
+    counter!("webhook_duplicate_skipped_total", "event" => event.to_string()).increment(1);
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use redis::{Commands, Connection, RedisError};
+use serde::Serialize;
+
+use crate::{
+    controllers::identity::{recovery_attempts_key, MAX_RECOVERY_ATTEMPTS, RECOVERY_ATTEMPTS_WINDOW},
+    server::AppState,
+    types::customer::Customer,
+};
+
+use super::email::email_send_budget_key;
+
+// read only snapshot of a redis backed limiter, the limiter itself stays the source of truth
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStatus {
+    pub limiter: String,
+    pub subject: Option<String>, // e.g. the email address a per email limiter counts against
+    pub limit: i64,
+    pub used: i64,
+    pub remaining: i64,
+    pub window_secs: i64,
+    pub resets_at: Option<String>, // none while the window hasn't started
+}
+
+// counters are created by INCR on first use, so a missing key means nothing was consumed yet
+fn read_counter(redis_conn: &mut Connection, key: &str) -> Result<(i64, i64), RedisError> {
+    let used: Option<i64> = redis_conn.get(key)?;
+    let ttl: i64 = redis_conn.ttl(key)?;
+
+    Ok((used.unwrap_or(0), ttl))
+}
+
+pub fn customer_rate_limits(state: &Arc<AppState>, customer: &Customer) -> Result<Vec<RateLimitStatus>, RedisError> {
+    let mut redis_conn = state.redis_connection.get_connection()?;
+    let mut statuses = vec![];
+
+    // the budget key is dated, it rolls over at utc midnight whatever its ttl says
+    let budget = state.email_provider_settings.daily_send_budget;
+    let (sent, _) = read_counter(&mut redis_conn, &email_send_budget_key(&customer.id))?;
+    let next_midnight = (Utc::now() + Duration::days(1)).date_naive().and_hms_opt(0, 0, 0).map(|midnight| midnight.and_utc().to_rfc3339());
+    statuses.push(RateLimitStatus {
+        limiter: String::from("email_send_budget"),
+        subject: None,
+        limit: budget,
+        used: sent,
+        remaining: (budget - sent).max(0),
+        window_secs: 86400,
+        resets_at: next_midnight,
+    });
+
+    for email in customer.emails.iter() {
+        let (attempts, ttl) = read_counter(&mut redis_conn, &recovery_attempts_key(&email.address))?;
+        statuses.push(RateLimitStatus {
+            limiter: String::from("recovery_attempts"),
+            subject: Some(email.address.clone()),
+            limit: MAX_RECOVERY_ATTEMPTS,
+            used: attempts,
+            remaining: (MAX_RECOVERY_ATTEMPTS - attempts).max(0),
+            window_secs: RECOVERY_ATTEMPTS_WINDOW,
+            resets_at: match ttl > 0 {
+                true => Some((Utc::now() + Duration::seconds(ttl)).to_rfc3339()),
+                false => None,
+            },
+        });
+    }
+
+    Ok(statuses)
+}
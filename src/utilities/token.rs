@@ -1,9 +1,9 @@
 use axum::http::HeaderMap;
 use axum::{http::StatusCode, Json};
 use jsonwebtoken::{
-    decode, encode, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+    decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
 };
-use redis::Commands;
+use redis::{Commands, Connection, RedisError};
 use redis::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -13,9 +13,11 @@ use std::{
 };
 
 use crate::controllers::identity::SessionScopes;
+use crate::storage::redis::with_retry;
 use crate::types::customer::GenericResponse;
 
 use super::api_messages::{APIMessages, RedisMessages, TokenMessages};
+use super::token_delivery::token_from_cookies;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -23,6 +25,22 @@ pub struct Claims {
     pub sub: String,
     pub aud: String,
     pub exp: usize,
+    #[serde(default)]
+    pub nbf: usize, // tokens issued before nbf existed decode as 0, always valid
+    #[serde(default)]
+    pub region: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub impersonated_by: String, // admin customer id, only on support impersonation tokens
+}
+
+pub const DEFAULT_TOKEN_LEEWAY_SECS: u64 = 5;
+
+// JWT_LEEWAY_SECS, tolerated clock skew for exp and nbf
+pub fn token_leeway() -> u64 {
+    match env::var("JWT_LEEWAY_SECS") {
+        Ok(leeway) => leeway.parse::<u64>().unwrap_or(DEFAULT_TOKEN_LEEWAY_SECS),
+        Err(_) => DEFAULT_TOKEN_LEEWAY_SECS,
+    }
 }
 
 pub fn scopes_to_string(scopes: Vec<SessionScopes>) -> String {
@@ -43,22 +61,39 @@ pub fn string_to_scopes(scopes: String) -> Vec<SessionScopes> {
     sanitized_scopes
 }
 
-pub fn create_token(id: &String, scopes: Vec<SessionScopes>) -> Result<std::string::String, String> {
-    let api_url = env::var("API_URL").unwrap_or(String::from("http://localhost:3000"));
+pub fn create_token(id: &String, region: &str, scopes: Vec<SessionScopes>) -> Result<std::string::String, String> {
     let expiration_time = env::var("API_TOKENS_EXPIRATION_TIME").unwrap_or(String::from("86400"));
+    create_token_with_ttl(id, region, scopes, expiration_time.parse::<usize>().unwrap())
+}
+
+// short lived tokens (elevated sessions) pick their own expiration
+pub fn create_token_with_ttl(id: &String, region: &str, scopes: Vec<SessionScopes>, ttl: usize) -> Result<std::string::String, String> {
+    sign_token(id, region, scopes, ttl, "")
+}
+
+// support access, the claim is what lets handlers refuse destructive actions
+pub fn create_impersonation_token(id: &String, region: &str, scopes: Vec<SessionScopes>, ttl: usize, impersonated_by: &str) -> Result<std::string::String, String> {
+    sign_token(id, region, scopes, ttl, impersonated_by)
+}
+
+fn sign_token(id: &String, region: &str, scopes: Vec<SessionScopes>, ttl: usize, impersonated_by: &str) -> Result<std::string::String, String> {
+    let api_url = env::var("API_URL").unwrap_or(String::from("http://localhost:3000"));
     let header = Header::new(Algorithm::HS512);
 
     let sanitized_scopes = scopes_to_string(scopes);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize;
 
     let claims = Claims {
         iss: api_url,
         sub: id.to_string(),
         aud: sanitized_scopes,
-        exp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize
-            + expiration_time.parse::<usize>().unwrap(),
+        exp: now + ttl,
+        nbf: now,
+        region: region.to_string(),
+        impersonated_by: impersonated_by.to_string(),
     };
 
     let signing_key = match env::var("API_TOKENS_SIGNING_KEY") {
@@ -77,7 +112,11 @@ pub fn create_token(id: &String, scopes: Vec<SessionScopes>) -> Result<std::stri
 }
 
 pub fn get_token_payload(token: &str) -> Result<TokenData<Claims>, String> {
-    let validation = Validation::new(Algorithm::HS512);
+    let mut validation = Validation::new(Algorithm::HS512);
+    validation.validate_nbf = true;
+    validation.leeway = token_leeway();
+    // aud carries the session scopes, not an audience, so the decoder must not match it against one
+    validation.validate_aud = false;
 
     let signing_key = match env::var("API_TOKENS_SIGNING_KEY") {
         Ok(key) => key,
@@ -90,32 +129,89 @@ pub fn get_token_payload(token: &str) -> Result<TokenData<Claims>, String> {
         &validation,
     ) {
         Ok(t) => t,
+        Err(err) if matches!(err.kind(), ErrorKind::ExpiredSignature) => {
+            return Err(APIMessages::Token(TokenMessages::Expired).to_string())
+        }
         Err(_) => return Err(APIMessages::Token(TokenMessages::ErrorValidating).to_string()),
     };
 
     Ok(token_data)
 }
 
+// exp and nbf are checked by the decoder with the leeway applied once
 pub fn validate_token(token: &str) -> Result<TokenData<Claims>, String> {
-    let token_data = get_token_payload(token)?;
+    get_token_payload(token)
+}
+
+// hmac happily signs with an empty key so that is checked on its own
+pub fn check_signing_key(key: &str) -> Result<(), String> {
+    match key.trim().is_empty() {
+        true => Err(String::from("API_TOKENS_SIGNING_KEY is empty")),
+        false => Ok(()),
+    }
+}
+
+// startup self-test
+pub fn token_round_trip_check() -> Result<(), String> {
+    check_signing_key(&env::var("API_TOKENS_SIGNING_KEY").unwrap_or_default())?;
 
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let id = String::from("self-test");
+    let token = create_token_with_ttl(&id, "default", vec![SessionScopes::ViewPublicID], 60)?;
+    let token_data = validate_token(&token)?;
 
-    if now.as_secs() > token_data.claims.exp as u64 {
-        return Err(APIMessages::Token(TokenMessages::Expired).to_string());
+    if token_data.claims.sub != id || string_to_scopes(token_data.claims.aud) != vec![SessionScopes::ViewPublicID] {
+        return Err(String::from("claims changed during the round-trip"));
     }
 
-    Ok(token_data)
+    Ok(())
 }
 
-pub async fn get_session_from_redis(
-    redis_connection: &Client,
-    token_string: &str,
-) -> Result<String, (StatusCode, Json<GenericResponse>)> {
-    let result = redis_connection.clone().get::<String, String>(token_string.to_string());
+pub fn customer_sessions_key(customer_id: &str) -> String {
+    format!("sessions:{}", customer_id)
+}
+
+// every issued token is indexed per customer so all sessions can be revoked at once
+pub fn track_session(redis_conn: &mut Connection, customer_id: &str, token: &str) -> Result<(), RedisError> {
+    let key = customer_sessions_key(customer_id);
+    redis_conn.sadd::<&String, &str, i64>(&key, token)?;
+    redis_conn.expire::<&String, bool>(&key, 604800)?;
+
+    Ok(())
+}
+
+pub fn revoke_session(redis_conn: &mut Connection, customer_id: &str, token: &str) -> Result<(), RedisError> {
+    redis_conn.del::<&str, i64>(token)?;
+    redis_conn.srem::<String, &str, i64>(customer_sessions_key(customer_id), token)?;
+
+    Ok(())
+}
+
+pub fn revoke_customer_sessions(redis_connection: &Client, customer_id: &str) -> Result<usize, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let key = customer_sessions_key(customer_id);
 
+    let tokens: Vec<String> = redis_conn.smembers(&key)?;
+    for token in tokens.iter() {
+        redis_conn.del::<&String, i64>(token)?;
+    }
+
+    redis_conn.del::<&String, i64>(&key)?;
+
+    Ok(tokens.len())
+}
+
+// a missing key is a revoked, rotated or expired session, only redis failures are 500s
+pub fn session_customer_id(result: Result<Option<String>, RedisError>) -> Result<String, (StatusCode, Json<GenericResponse>)> {
     match result {
-        Ok(id) => Ok(id),
+        Ok(Some(id)) => Ok(id),
+        Ok(None) => Err((
+            StatusCode::UNAUTHORIZED,
+            Json(GenericResponse {
+                message: APIMessages::Token(TokenMessages::Expired).to_string(),
+                data: json!({}),
+                exit_code: 1,
+            }),
+        )),
         Err(_) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(GenericResponse {
@@ -127,7 +223,21 @@ pub async fn get_session_from_redis(
     }
 }
 
+pub async fn get_session_from_redis(
+    redis_connection: &Client,
+    token_string: &str,
+) -> Result<String, (StatusCode, Json<GenericResponse>)> {
+    session_customer_id(with_retry(redis_connection, |redis_conn| redis_conn.get::<&str, Option<String>>(token_string)))
+}
+
+// the Authorization header wins, the session cookie is only read when it's missing
 pub async fn extract_token_from_headers(headers: &HeaderMap) -> Result<&str, (StatusCode, Json<GenericResponse>)> {
+    if headers.get("Authorization").is_none() {
+        if let Some(token) = token_from_cookies(headers) {
+            return Ok(token);
+        }
+    }
+
     match headers.get("Authorization") {
         Some(token) => match token.to_str() {
             Ok(token) => Ok(token),
@@ -150,3 +260,115 @@ pub async fn extract_token_from_headers(headers: &HeaderMap) -> Result<&str, (St
         )),
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use redis::ErrorKind as RedisErrorKind;
+
+    // every test that signs tokens sets this same key, so parallel tests never see another one
+    pub const SIGNING_KEY: &str = "token-tests-signing-key";
+
+    fn token_with_nbf(nbf_offset: usize) -> String {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as usize;
+        let claims = Claims {
+            iss: String::from("http://localhost:3000"),
+            sub: String::from("customer"),
+            aud: scopes_to_string(vec![SessionScopes::TotalAccess]),
+            exp: now + 3600,
+            nbf: now + nbf_offset,
+            region: String::new(),
+            impersonated_by: String::new(),
+        };
+
+        encode(&Header::new(Algorithm::HS512), &claims, &EncodingKey::from_secret(SIGNING_KEY.as_ref())).unwrap()
+    }
+
+    #[test]
+    fn a_future_nbf_is_rejected() {
+        assert!(validate_token(&token_with_nbf(600)).is_err());
+    }
+
+    #[test]
+    fn an_nbf_within_the_leeway_is_accepted() {
+        let token_data = validate_token(&token_with_nbf(DEFAULT_TOKEN_LEEWAY_SECS as usize - 2)).unwrap();
+        assert_eq!(token_data.claims.sub, "customer");
+    }
+
+    #[test]
+    fn an_empty_signing_key_fails_the_startup_check() {
+        assert!(check_signing_key("").is_err());
+        assert!(check_signing_key("   ").is_err());
+        assert!(check_signing_key(SIGNING_KEY).is_ok());
+    }
+
+    #[test]
+    fn a_valid_signing_key_survives_the_round_trip() {
+        env::set_var("API_TOKENS_SIGNING_KEY", SIGNING_KEY);
+        assert_eq!(token_round_trip_check(), Ok(()));
+    }
+
+    #[test]
+    fn a_stored_session_resolves_to_its_customer() {
+        assert_eq!(session_customer_id(Ok(Some(String::from("customer")))).ok(), Some(String::from("customer")));
+    }
+
+    #[test]
+    fn a_missing_session_is_unauthorized() {
+        let (status, Json(body)) = session_customer_id(Ok(None)).unwrap_err();
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.message, APIMessages::Token(TokenMessages::Expired).to_string());
+    }
+
+    #[test]
+    fn redis_failures_stay_server_errors() {
+        let err = RedisError::from((RedisErrorKind::IoError, "connection dropped"));
+        let (status, _) = session_customer_id(Err(err)).unwrap_err();
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // needs a reachable server, run with REDIS_URI set and --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn a_revoked_token_stops_working() {
+        let redis_connection = Client::open(env::var("REDIS_URI").unwrap()).unwrap();
+        let token = String::from("token-tests-revoked-session");
+
+        with_retry(&redis_connection, |redis_conn| {
+            redis_conn.set_ex::<&str, &str, ()>(&token, "customer", 60)?;
+            track_session(redis_conn, "customer", &token)
+        })
+        .unwrap();
+        assert_eq!(get_session_from_redis(&redis_connection, &token).await.ok(), Some(String::from("customer")));
+
+        with_retry(&redis_connection, |redis_conn| revoke_session(redis_conn, "customer", &token)).unwrap();
+        let (status, _) = get_session_from_redis(&redis_connection, &token).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // what the admin suspend relies on, needs REDIS_URI like the test above
+    #[tokio::test]
+    #[ignore]
+    async fn revoking_a_customer_ends_every_session() {
+        let redis_connection = Client::open(env::var("REDIS_URI").unwrap()).unwrap();
+        let tokens = [String::from("token-tests-suspended-1"), String::from("token-tests-suspended-2")];
+
+        for token in tokens.iter() {
+            with_retry(&redis_connection, |redis_conn| {
+                redis_conn.set_ex::<&str, &str, ()>(token, "suspended", 60)?;
+                track_session(redis_conn, "suspended", token)
+            })
+            .unwrap();
+        }
+
+        assert_eq!(revoke_customer_sessions(&redis_connection, "suspended").unwrap(), 2);
+        for token in tokens.iter() {
+            let (status, _) = get_session_from_redis(&redis_connection, token).await.unwrap_err();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+}
+
@@ -0,0 +1,211 @@
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, SET_COOKIE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+
+use crate::{server::AppState, types::customer::GenericResponse};
+
+pub const SESSION_COOKIE_NAME: &str = "session_token";
+
+// token responses are small, anything bigger isn't one of ours
+const MAX_BUFFERED_BODY: u64 = 64 * 1024;
+
+// how issued session tokens reach the client, TOKEN_DELIVERY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenDelivery {
+    Body,
+    Cookie, // HttpOnly cookie only, the token is removed from the json body
+    Both,
+}
+
+impl FromStr for TokenDelivery {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<TokenDelivery, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "body" => Ok(TokenDelivery::Body),
+            "cookie" => Ok(TokenDelivery::Cookie),
+            "both" => Ok(TokenDelivery::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TokenDelivery {
+    pub fn sets_cookie(&self) -> bool {
+        *self != TokenDelivery::Body
+    }
+}
+
+pub fn session_cookie(token: &str, max_age: i64) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE_NAME, token, max_age
+    )
+}
+
+pub fn cleared_session_cookie() -> String {
+    session_cookie("", 0)
+}
+
+pub fn token_from_cookies(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().strip_prefix(SESSION_COOKIE_NAME)?.strip_prefix('='))
+        .find(|token| !token.is_empty())
+}
+
+// only small json bodies are buffered, exports and other large or streamed bodies pass through untouched
+pub fn may_carry_token(headers: &HeaderMap, body_size: Option<u64>) -> bool {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+    is_json && body_size.is_some_and(|body_size| body_size <= MAX_BUFFERED_BODY)
+}
+
+// the session cookie for a response carrying data.token, the token leaves the body when only cookies are used
+pub fn deliver_token(generic_response: &mut GenericResponse, delivery: TokenDelivery, default_max_age: i64) -> Option<String> {
+    let token = generic_response.data.get("token").and_then(Value::as_str)?.to_string();
+
+    // elevated sessions report their own, shorter lifetime
+    let max_age = generic_response
+        .data
+        .get("expires_in")
+        .and_then(Value::as_i64)
+        .unwrap_or(default_max_age);
+
+    if delivery == TokenDelivery::Cookie {
+        if let Some(data) = generic_response.data.as_object_mut() {
+            data.remove("token");
+        }
+    }
+
+    Some(session_cookie(&token, max_age))
+}
+
+// wraps the identity router, any successful response carrying data.token also sets it as a cookie
+pub async fn token_delivery_middleware(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if !state.token_delivery.sets_cookie() || !response.status().is_success() {
+        return response;
+    }
+
+    if !may_carry_token(response.headers(), response.body().size_hint().exact()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, MAX_BUFFERED_BODY as usize).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let mut generic_response: GenericResponse = match serde_json::from_slice(&bytes) {
+        Ok(generic_response) => generic_response,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+
+    let cookie = match deliver_token(&mut generic_response, state.token_delivery, state.api_tokens_expiration_time) {
+        Some(cookie) => cookie,
+        None => return Response::from_parts(parts, Body::from(bytes)),
+    };
+
+    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+        parts.headers.append(SET_COOKIE, cookie);
+    }
+
+    if state.token_delivery != TokenDelivery::Cookie {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    match serde_json::to_vec(&generic_response) {
+        Ok(body) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(body))
+        },
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn token_response() -> GenericResponse {
+        GenericResponse {
+            message: String::from("token.created"),
+            data: json!({"token": "abc"}),
+            exit_code: 0,
+        }
+    }
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers
+    }
+
+    #[test]
+    fn sign_in_sets_the_session_cookie() {
+        let mut generic_response = token_response();
+        let cookie = deliver_token(&mut generic_response, TokenDelivery::Both, 3600).unwrap();
+
+        assert_eq!(cookie, session_cookie("abc", 3600));
+        assert_eq!(generic_response.data, json!({"token": "abc"}));
+    }
+
+    #[test]
+    fn cookie_only_delivery_removes_the_token_from_the_body() {
+        let mut generic_response = token_response();
+        deliver_token(&mut generic_response, TokenDelivery::Cookie, 3600).unwrap();
+
+        assert_eq!(generic_response.data, json!({}));
+    }
+
+    #[test]
+    fn responses_without_a_token_set_no_cookie() {
+        let mut generic_response = token_response();
+        generic_response.data = json!({"id": "customer"});
+
+        assert_eq!(deliver_token(&mut generic_response, TokenDelivery::Both, 3600), None);
+    }
+
+    #[test]
+    fn requests_read_the_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Cookie", HeaderValue::from_static("theme=dark; session_token=abc"));
+
+        assert_eq!(token_from_cookies(&headers), Some("abc"));
+    }
+
+    #[test]
+    fn large_streamed_and_non_json_bodies_pass_through() {
+        assert!(may_carry_token(&json_headers(), Some(512)));
+        assert!(!may_carry_token(&json_headers(), Some(MAX_BUFFERED_BODY + 1)));
+        assert!(!may_carry_token(&json_headers(), None));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-ndjson"));
+        assert!(!may_carry_token(&headers, Some(512)));
+    }
+}
+
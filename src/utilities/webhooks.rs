@@ -0,0 +1,331 @@
+use std::{str::FromStr, time::Duration};
+
+use axum::http::{header::CONTENT_TYPE, HeaderMap};
+use chrono::Utc;
+use redis::{Client, Commands, RedisError, Script};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::helpers::random_string;
+
+const RECENT_WEBHOOKS_KEY: &str = "webhooks:recent";
+const RECENT_WEBHOOKS_LIMIT: isize = 200;
+
+const DEAD_LETTER_KEY: &str = "webhooks:dead_letter";
+const DEAD_LETTER_LIMIT: isize = 100;
+const DEAD_LETTER_MAX_BODY: usize = 64 * 1024; // bytes kept per body
+
+const SUBSCRIPTION_LOCK_TTL: u64 = 30; // seconds, outlives any single handler so a crash can't wedge the customer
+const SUBSCRIPTION_LOCK_ATTEMPTS: u32 = 20;
+const SUBSCRIPTION_LOCK_WAIT_MS: u64 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub provider: String, // lemonsqueezy or stripe
+    pub delivery_id: String,
+    pub event: String,
+    pub outcome: String, // processed, failed or duplicate
+    pub processed_at: String,
+}
+
+// a body that didn't decode, kept so schema drift can be inspected instead of retried forever
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterWebhook {
+    pub provider: String,
+    pub error: String, // serde error with the path of the offending field
+    pub body: String,
+    pub truncated: bool,
+    pub received_at: String,
+}
+
+fn decode_json<T: DeserializeOwned>(json: &[u8]) -> Result<T, String> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(json);
+    serde_path_to_error::deserialize(deserializer).map_err(|err| match err.path().to_string().as_str() {
+        "." => err.inner().to_string(),
+        path => format!("{}: {}", path, err.inner()),
+    })
+}
+
+// providers post json, gateways that re-encode it as a form carry the event in the `payload` field
+// signatures are always checked against `body` as received, before this runs
+pub fn decode_webhook_body<T: DeserializeOwned>(headers: &HeaderMap, body: &[u8]) -> Result<T, String> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    if !content_type.starts_with("application/x-www-form-urlencoded") {
+        return decode_json(body);
+    }
+
+    match form_urlencoded::parse(body).find(|(key, _)| key == "payload") {
+        Some((_, payload)) => decode_json(payload.as_bytes()),
+        None => Err(String::from("form body without a payload field")),
+    }
+}
+
+// WEBHOOK_OVERSIZED_FIELDS, what happens to a provider string longer than WEBHOOK_MAX_FIELD_LENGTH
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OversizedFieldPolicy {
+    Reject,
+    Truncate,
+}
+
+impl FromStr for OversizedFieldPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<OversizedFieldPolicy, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "reject" => Ok(OversizedFieldPolicy::Reject),
+            "truncate" => Ok(OversizedFieldPolicy::Truncate),
+            _ => Err(()),
+        }
+    }
+}
+
+pub const DEFAULT_WEBHOOK_MAX_FIELD_LENGTH: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct WebhookFieldLimits {
+    pub max_length: usize, // in characters
+    pub policy: OversizedFieldPolicy,
+}
+
+impl WebhookFieldLimits {
+    // webhook strings end up in customer documents, Err carries the field name when the delivery must be rejected
+    pub fn enforce(&self, field: &str, value: &mut String) -> Result<(), String> {
+        let length = value.chars().count();
+        if length <= self.max_length {
+            return Ok(());
+        }
+
+        match self.policy {
+            OversizedFieldPolicy::Reject => {
+                log::warn!("webhook field {} rejected, {} characters over a limit of {}", field, length, self.max_length);
+                Err(field.to_string())
+            },
+            OversizedFieldPolicy::Truncate => {
+                log::warn!("webhook field {} truncated from {} to {} characters", field, length, self.max_length);
+                *value = value.chars().take(self.max_length).collect();
+                Ok(())
+            },
+        }
+    }
+}
+
+pub fn webhook_dedup_key(provider: &str, delivery_id: &str) -> String {
+    format!("webhook_dedup:{}:{}", provider, delivery_id)
+}
+
+// true when this is the first time the delivery is seen inside the dedup window
+pub fn claim_webhook_delivery(
+    redis_connection: &Client,
+    provider: &str,
+    delivery_id: &str,
+    ttl: u64,
+) -> Result<bool, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(webhook_dedup_key(provider, delivery_id))
+        .arg(Utc::now().to_rfc3339())
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query(&mut redis_conn)?;
+
+    Ok(claimed.is_some())
+}
+
+// failed deliveries give their claim back so the provider retry is processed
+pub fn release_webhook_delivery(redis_connection: &Client, provider: &str, delivery_id: &str) {
+    let result: Result<i64, RedisError> = redis_connection
+        .clone()
+        .del(webhook_dedup_key(provider, delivery_id));
+
+    if let Err(err) = result {
+        log::error!("error releasing webhook delivery {}: {}", delivery_id, err);
+    }
+}
+
+pub fn subscription_lock_key(customer_id: &str) -> String {
+    format!("sub_lock:{}", customer_id)
+}
+
+// serializes subscription writes per customer, history_logs is read, appended and written back by the handlers
+// waits about two seconds, None means another delivery still holds it and this one should be retried by the provider
+pub async fn acquire_subscription_lock(redis_connection: &Client, customer_id: &str) -> Result<Option<String>, RedisError> {
+    let key = subscription_lock_key(customer_id);
+    let lock_token = random_string(16).await;
+
+    for _ in 0..SUBSCRIPTION_LOCK_ATTEMPTS {
+        let mut redis_conn = redis_connection.get_connection()?;
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(&lock_token)
+            .arg("NX")
+            .arg("EX")
+            .arg(SUBSCRIPTION_LOCK_TTL)
+            .query(&mut redis_conn)?;
+
+        if acquired.is_some() {
+            return Ok(Some(lock_token));
+        }
+
+        tokio::time::sleep(Duration::from_millis(SUBSCRIPTION_LOCK_WAIT_MS)).await;
+    }
+
+    Ok(None)
+}
+
+// only the holder releases, a handler that outlived the ttl must not free someone else's lock
+pub fn release_subscription_lock(redis_connection: &Client, customer_id: &str, lock_token: &str) {
+    let script = Script::new(
+        r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) else return 0 end"#,
+    );
+
+    let result: Result<i64, RedisError> = redis_connection
+        .get_connection()
+        .and_then(|mut redis_conn| script.key(subscription_lock_key(customer_id)).arg(lock_token).invoke(&mut redis_conn));
+
+    if let Err(err) = result {
+        log::error!("error releasing subscription lock for {}: {}", customer_id, err);
+    }
+}
+
+// capped list, only meant for debugging delivery issues
+pub fn record_webhook_delivery(redis_connection: &Client, provider: &str, delivery_id: &str, event: &str, outcome: &str) {
+    let delivery = WebhookDelivery {
+        provider: provider.to_string(),
+        delivery_id: delivery_id.to_string(),
+        event: event.to_string(),
+        outcome: outcome.to_string(),
+        processed_at: Utc::now().to_rfc3339(),
+    };
+
+    let stored = match serde_json::to_string(&delivery) {
+        Ok(stored) => stored,
+        Err(_) => return,
+    };
+
+    let result: Result<(), RedisError> = redis_connection.get_connection().and_then(|mut redis_conn| {
+        redis_conn.lpush::<&str, String, i64>(RECENT_WEBHOOKS_KEY, stored)?;
+        redis_conn.ltrim::<&str, ()>(RECENT_WEBHOOKS_KEY, 0, RECENT_WEBHOOKS_LIMIT - 1)
+    });
+
+    if let Err(err) = result {
+        log::error!("error recording webhook delivery {}: {}", delivery_id, err);
+    }
+}
+
+// newest first
+pub fn recent_webhook_deliveries(redis_connection: &Client) -> Result<Vec<WebhookDelivery>, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let stored: Vec<String> = redis_conn.lrange(RECENT_WEBHOOKS_KEY, 0, RECENT_WEBHOOKS_LIMIT - 1)?;
+
+    Ok(stored
+        .iter()
+        .filter_map(|delivery| serde_json::from_str(delivery).ok())
+        .collect())
+}
+
+// capped like the recent deliveries, the body is stored as received
+pub fn record_dead_letter(redis_connection: &Client, provider: &str, error: &str, body: &[u8]) {
+    log::warn!("undecodable {} webhook: {}", provider, error);
+
+    let truncated = body.len() > DEAD_LETTER_MAX_BODY;
+    let body = &body[..body.len().min(DEAD_LETTER_MAX_BODY)];
+    let dead_letter = DeadLetterWebhook {
+        provider: provider.to_string(),
+        error: error.to_string(),
+        body: String::from_utf8_lossy(body).to_string(),
+        truncated,
+        received_at: Utc::now().to_rfc3339(),
+    };
+
+    let stored = match serde_json::to_string(&dead_letter) {
+        Ok(stored) => stored,
+        Err(_) => return,
+    };
+
+    let result: Result<(), RedisError> = redis_connection.get_connection().and_then(|mut redis_conn| {
+        redis_conn.lpush::<&str, String, i64>(DEAD_LETTER_KEY, stored)?;
+        redis_conn.ltrim::<&str, ()>(DEAD_LETTER_KEY, 0, DEAD_LETTER_LIMIT - 1)
+    });
+
+    if let Err(err) = result {
+        log::error!("error recording {} dead letter: {}", provider, err);
+    }
+}
+
+// newest first
+pub fn dead_letter_webhooks(redis_connection: &Client) -> Result<Vec<DeadLetterWebhook>, RedisError> {
+    let mut redis_conn = redis_connection.get_connection()?;
+    let stored: Vec<String> = redis_conn.lrange(DEAD_LETTER_KEY, 0, DEAD_LETTER_LIMIT - 1)?;
+
+    Ok(stored
+        .iter()
+        .filter_map(|dead_letter| serde_json::from_str(dead_letter).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn an_unreachable_redis_is_an_error_not_a_free_lock() {
+        let redis_connection = Client::open("redis://127.0.0.1:9/").unwrap();
+
+        assert!(acquire_subscription_lock(&redis_connection, "customer").await.is_err());
+    }
+
+    // needs a reachable server, run with REDIS_URI set and --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn concurrent_writers_keep_both_history_logs() {
+        let redis_connection = Arc::new(Client::open(env::var("REDIS_URI").unwrap()).unwrap());
+        let history_logs = Arc::new(Mutex::new(Vec::<String>::new()));
+
+        let writers = ["subscription_created", "subscription_updated"].map(|event| {
+            let redis_connection = redis_connection.clone();
+            let history_logs = history_logs.clone();
+
+            tokio::spawn(async move {
+                let lock_token = acquire_subscription_lock(&redis_connection, "webhooks-tests-lock").await.unwrap().unwrap();
+
+                // same read, append, write back the handlers do against the customer document
+                let mut logs = history_logs.lock().unwrap().clone();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                logs.push(String::from(event));
+                *history_logs.lock().unwrap() = logs;
+
+                release_subscription_lock(&redis_connection, "webhooks-tests-lock", &lock_token);
+            })
+        });
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        assert_eq!(history_logs.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn only_the_holder_releases_the_lock() {
+        let redis_connection = Client::open(env::var("REDIS_URI").unwrap()).unwrap();
+        let lock_token = acquire_subscription_lock(&redis_connection, "webhooks-tests-holder").await.unwrap().unwrap();
+
+        release_subscription_lock(&redis_connection, "webhooks-tests-holder", "someone-else");
+        assert_eq!(acquire_subscription_lock(&redis_connection, "webhooks-tests-holder").await.unwrap(), None);
+
+        release_subscription_lock(&redis_connection, "webhooks-tests-holder", &lock_token);
+        let lock_token = acquire_subscription_lock(&redis_connection, "webhooks-tests-holder").await.unwrap().unwrap();
+        release_subscription_lock(&redis_connection, "webhooks-tests-holder", &lock_token);
+    }
+}